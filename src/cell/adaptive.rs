@@ -0,0 +1,120 @@
+extern crate time;
+
+use super::Rate;
+use crate::error::SlicedError;
+
+/// How much a rate grows with each success, in units per period.
+const INCREASE: f64 = 1.0;
+
+/// How much of a rate is kept after an overload.
+const DECREASE: f64 = 0.5;
+
+/// Feedback is what a caller reports back about the service a key protects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feedback {
+    Success,
+    Overload,
+}
+
+impl Feedback {
+    pub fn parse(name: &str) -> Result<Feedback, SlicedError> {
+        match name.to_lowercase().as_str() {
+            "success" => Ok(Feedback::Success),
+            "overload" => Ok(Feedback::Overload),
+            _ => Err(error!("Unknown feedback: {}", name)),
+        }
+    }
+}
+
+/// Bounds are the slowest and fastest rates, in units per period, that
+/// feedback can take a key to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub min:    i64,
+    pub max:    i64,
+    pub period: time::Duration,
+}
+
+impl Bounds {
+    /// Works out a key's next rate from its current one with additive
+    /// increase, multiplicative decrease: every success speeds it up by one
+    /// unit per period while every overload halves it. A key that hasn't
+    /// been adapted yet starts from the maximum.
+    pub fn adapt(&self, current: Option<&Rate>, feedback: Feedback) -> Rate {
+        let count = match current {
+            Some(rate) => self.count(rate),
+            None => self.max as f64,
+        };
+
+        let count = match feedback {
+            Feedback::Success => count + INCREASE,
+            Feedback::Overload => count * DECREASE,
+        };
+        self.rate(count)
+    }
+
+    /// Brings a rate that was adapted under other bounds back within these.
+    pub fn clamp(&self, rate: &Rate) -> Rate {
+        self.rate(self.count(rate))
+    }
+
+    /// Returns the rate of count units per period, kept within the bounds.
+    fn rate(&self, count: f64) -> Rate {
+        let period = self.period.num_nanoseconds().unwrap() as f64;
+        let count = count.max(self.min as f64).min(self.max as f64);
+        Rate {
+            period: time::Duration::nanoseconds((period / count) as i64),
+        }
+    }
+
+    /// Returns how many units per period the given rate comes to.
+    pub fn count(&self, rate: &Rate) -> f64 {
+        self.period.num_nanoseconds().unwrap() as f64
+            / rate.period.num_nanoseconds().unwrap() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::cell::adaptive::*;
+
+    #[test]
+    fn it_adapts_rates_within_bounds() {
+        let bounds = Bounds {
+            min:    10,
+            max:    100,
+            period: time::Duration::seconds(1),
+        };
+
+        // Keys start at the maximum, which successes can't go past.
+        let rate = bounds.adapt(None, Feedback::Success);
+        assert_eq!(Rate::per_second(100), rate);
+
+        let rate = bounds.adapt(Some(&rate), Feedback::Overload);
+        assert_eq!(Rate::per_second(50), rate);
+        let rate = bounds.adapt(Some(&rate), Feedback::Success);
+        assert_eq!(Rate::per_second(51), rate);
+
+        // Overloads can't go below the minimum.
+        let mut rate = rate;
+        for _ in 0..10 {
+            rate = bounds.adapt(Some(&rate), Feedback::Overload);
+        }
+        assert_eq!(Rate::per_second(10), rate);
+        assert_eq!(10.0, bounds.count(&rate));
+
+        // Rates adapted under other bounds are brought back within these.
+        assert_eq!(Rate::per_second(100), bounds.clamp(&Rate::per_second(500)));
+        assert_eq!(Rate::per_second(10), bounds.clamp(&Rate::per_second(2)));
+        assert_eq!(Rate::per_second(20), bounds.clamp(&Rate::per_second(20)));
+    }
+
+    #[test]
+    fn it_parses_feedback() {
+        assert_eq!(Feedback::Success, Feedback::parse("success").unwrap());
+        assert_eq!(Feedback::Overload, Feedback::parse("OVERLOAD").unwrap());
+        assert!(Feedback::parse("meh").is_err());
+    }
+}
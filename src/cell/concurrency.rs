@@ -0,0 +1,339 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::error::SlicedError;
+use crate::redis;
+use crate::redis::redmod;
+
+static mut SEMAPHORE_TYPE: *mut redmod::RedisModuleType = 0 as *mut redmod::RedisModuleType;
+
+/// `ConcurrencyResult` describes the state of a `Semaphore` in the same terms
+/// that `RateLimitResult` describes a rate limit.
+#[derive(Debug, PartialEq)]
+pub struct ConcurrencyResult {
+    pub limit:       i64,
+    pub remaining:   i64,
+    pub reset_after: time::Duration,
+    pub retry_after: time::Duration,
+}
+
+/// `Semaphore` caps how much work can be in flight for a key at once. Each
+/// unit of work holds a lease, identified by a token, that's given back when
+/// it's done. Leases carry an expiry so that the slots of workers that die
+/// without releasing them are eventually recovered.
+///
+/// All times are in milliseconds since the epoch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Semaphore {
+    /// The limit given with the most recent acquisition.
+    pub max:        i64,
+    /// Token of the next lease handed out. Tokens are never reused for a key.
+    pub next_token: i64,
+    /// Expiry of every outstanding lease keyed by its token.
+    pub leases:     BTreeMap<i64, i64>,
+}
+
+impl Semaphore {
+    /// Takes a lease lasting lease_ms if fewer than max are outstanding.
+    /// Returns its token, or None if the semaphore is full.
+    pub fn acquire(
+        &mut self,
+        max: i64,
+        lease_ms: i64,
+        now: i64,
+    ) -> (Option<i64>, ConcurrencyResult) {
+        self.expire(now);
+        self.max = max;
+
+        if self.leases.len() as i64 >= max {
+            return (None, self.result(now));
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+        self.leases.insert(token, now + lease_ms);
+        (Some(token), self.result(now))
+    }
+
+    /// Extends an outstanding lease so that it lasts another lease_ms from
+    /// now. Returns false if there's no such lease, either because it was
+    /// released or because it has already expired.
+    pub fn renew(&mut self, token: i64, lease_ms: i64, now: i64) -> bool {
+        self.expire(now);
+        match self.leases.get_mut(&token) {
+            Some(expires_at) => {
+                *expires_at = now + lease_ms;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives back a lease. Returns false if there's no such lease.
+    pub fn release(&mut self, token: i64, now: i64) -> bool {
+        self.expire(now);
+        self.leases.remove(&token).is_some()
+    }
+
+    /// Drops every lease that's expired as of now. Timers normally take care
+    /// of this, but they don't survive a restart.
+    pub fn expire(&mut self, now: i64) {
+        let expired: Vec<i64> = self
+            .leases
+            .iter()
+            .filter(|&(_, expires_at)| *expires_at <= now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired.iter() {
+            self.leases.remove(token);
+        }
+    }
+
+    /// Returns the moment the last outstanding lease expires, or None if
+    /// there are none.
+    pub fn expires_at(&self) -> Option<i64> {
+        self.leases.values().max().cloned()
+    }
+
+    /// Returns the arguments of CL.SETLEASE that put back this semaphore as
+    /// is: its limit, its next token, and every lease with its expiry.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.max.to_string(), self.next_token.to_string()];
+        for (token, expires_at) in self.leases.iter() {
+            args.push(token.to_string());
+            args.push(expires_at.to_string());
+        }
+        args
+    }
+
+    pub fn result(&self, now: i64) -> ConcurrencyResult {
+        let remaining = self.max - self.leases.len() as i64;
+        ConcurrencyResult {
+            limit:       self.max,
+            remaining:   if remaining > 0 { remaining } else { 0 },
+            reset_after: time::Duration::milliseconds(
+                self.expires_at().map(|at| at - now).unwrap_or(0),
+            ),
+            // A slot opens up when the soonest lease expires, unless one is
+            // released before then.
+            retry_after: if remaining > 0 {
+                time::Duration::milliseconds(-1)
+            } else {
+                time::Duration::milliseconds(
+                    self.leases.values().min().map(|at| at - now).unwrap_or(0),
+                )
+            },
+        }
+    }
+}
+
+/// Called when Redis loads the module.
+pub fn load(ctx: *mut redmod::RedisModuleCtx) -> redmod::Status {
+    let redis_type = redmod::create_data_type(
+        ctx,
+        format!("{}\0", "cl-semphr").as_ptr(),
+        0,
+        Some(Cell_Type_Semaphore_RDBLoad),
+        Some(Cell_Type_Semaphore_RDBSave),
+        Some(Cell_Type_Semaphore_AOFRewrite),
+        Some(Cell_Type_Semaphore_MemUsage),
+        None,
+        Some(Cell_Type_Semaphore_Free),
+    );
+    if redis_type.is_null() {
+        return redmod::Status::Err;
+    }
+
+    unsafe {
+        SEMAPHORE_TYPE = redis_type;
+    }
+    redmod::Status::Ok
+}
+
+pub fn semaphore_type() -> *mut redmod::RedisModuleType {
+    unsafe { SEMAPHORE_TYPE }
+}
+
+/// Runs f against the semaphore at key. A missing key reads as an empty
+/// semaphore that's only stored if f leaves a lease in it. Afterwards the key
+/// is deleted if no leases are left, or made to expire along with its last
+/// lease otherwise.
+pub fn with_semaphore<F, R>(r: &redis::Redis, key: &str, f: F) -> Result<R, SlicedError>
+where
+    F: FnOnce(&mut Semaphore) -> R,
+{
+    let key = r.open_key_writable(key);
+    let (res, expires_at) = match key.get_value::<Semaphore>(semaphore_type())? {
+        Some(semaphore) => {
+            let res = f(semaphore);
+            let expires_at = semaphore.expires_at();
+
+            // Deleting the key frees the semaphore, so it can't be touched
+            // after this.
+            if expires_at.is_none() {
+                key.delete()?;
+            }
+            (res, expires_at)
+        }
+        None => {
+            let mut semaphore = Box::new(Semaphore::default());
+            let res = f(&mut semaphore);
+            let expires_at = semaphore.expires_at();
+            if expires_at.is_some() {
+                key.set_value(semaphore_type(), semaphore)?;
+            }
+            (res, expires_at)
+        }
+    };
+
+    if let Some(expires_at) = expires_at {
+        let ttl = expires_at - redmod::milliseconds();
+        key.set_expire(time::Duration::milliseconds(if ttl > 0 { ttl } else { 1 }))?;
+    }
+    Ok(res)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Semaphore_RDBLoad(
+    rdb: *mut redmod::RedisModuleIO,
+    encver: libc::c_int,
+) -> *mut u8 {
+    let io = redis::RedisIO { io: rdb };
+    let mut semaphore = Box::new(Semaphore {
+        max:        io.load_signed(),
+        next_token: io.load_signed(),
+        leases:     BTreeMap::new(),
+    });
+    for _ in 0..io.load_unsigned() {
+        let token = io.load_signed();
+        let expires_at = io.load_signed();
+        semaphore.leases.insert(token, expires_at);
+    }
+    Box::into_raw(semaphore) as *mut u8
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Semaphore_RDBSave(rdb: *mut redmod::RedisModuleIO, value: *mut u8) {
+    let io = redis::RedisIO { io: rdb };
+    let semaphore = unsafe { &*(value as *mut Semaphore) };
+    io.save_signed(semaphore.max);
+    io.save_signed(semaphore.next_token);
+    io.save_unsigned(semaphore.leases.len() as u64);
+    for (token, expires_at) in semaphore.leases.iter() {
+        io.save_signed(*token);
+        io.save_signed(*expires_at);
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Semaphore_AOFRewrite(
+    aof: *mut redmod::RedisModuleIO,
+    key: *mut redmod::RedisModuleString,
+    value: *mut u8,
+) {
+    let io = redis::RedisIO { io: aof };
+    let semaphore = unsafe { &*(value as *mut Semaphore) };
+    let key = match redis::string_from_redis(key) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    let mut args = vec![key];
+    args.extend(semaphore.to_args());
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let _ = io.emit_aof("CL.SETLEASE", &args);
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Semaphore_MemUsage(value: *const u8) -> libc::size_t {
+    let semaphore = unsafe { &*(value as *const Semaphore) };
+    mem::size_of::<Semaphore>() + semaphore.leases.len() * 2 * mem::size_of::<i64>()
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Semaphore_Free(value: *mut u8) {
+    drop(unsafe { Box::from_raw(value as *mut Semaphore) });
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::cell::concurrency::*;
+
+    #[test]
+    fn it_caps_outstanding_leases() {
+        let mut semaphore = Semaphore::default();
+
+        let (token1, res) = semaphore.acquire(2, 1000, 0);
+        assert_eq!(Some(0), token1);
+        assert_eq!(1, res.remaining);
+
+        let (token2, res) = semaphore.acquire(2, 1000, 100);
+        assert_eq!(Some(1), token2);
+        assert_eq!(0, res.remaining);
+
+        // Full: the first slot opens when the first lease expires.
+        let (token3, res) = semaphore.acquire(2, 1000, 200);
+        assert_eq!(None, token3);
+        assert_eq!(
+            ConcurrencyResult {
+                limit:       2,
+                remaining:   0,
+                reset_after: time::Duration::milliseconds(900),
+                retry_after: time::Duration::milliseconds(800),
+            },
+            res
+        );
+
+        // Releasing frees a slot right away.
+        assert_eq!(true, semaphore.release(0, 300));
+        assert_eq!(false, semaphore.release(0, 300));
+        let (token4, _) = semaphore.acquire(2, 1000, 300);
+        assert_eq!(Some(2), token4);
+    }
+
+    #[test]
+    fn it_expires_and_renews_leases() {
+        let mut semaphore = Semaphore::default();
+
+        let (token, _) = semaphore.acquire(1, 1000, 0);
+        let token = token.unwrap();
+
+        // Renewing pushes the expiry out from the time of the renewal.
+        assert_eq!(true, semaphore.renew(token, 1000, 900));
+        assert_eq!(Some(1900), semaphore.expires_at());
+
+        // Once expired, a lease can neither be renewed nor released and its
+        // slot is available again.
+        assert_eq!(false, semaphore.renew(token, 1000, 1900));
+        assert_eq!(false, semaphore.release(token, 1900));
+        let (next, _) = semaphore.acquire(1, 1000, 1900);
+        assert_eq!(Some(token + 1), next);
+    }
+
+    #[test]
+    fn it_lists_its_leases_as_arguments() {
+        let mut semaphore = Semaphore::default();
+        semaphore.acquire(3, 1000, 0);
+        semaphore.acquire(3, 1000, 500);
+
+        let args: Vec<String> = ["3", "2", "0", "1000", "1", "1500"]
+            .iter()
+            .map(|arg| String::from(*arg))
+            .collect();
+        assert_eq!(args, semaphore.to_args());
+    }
+}
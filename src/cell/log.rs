@@ -0,0 +1,143 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::error::SlicedError;
+use crate::redis;
+use crate::redis::redmod;
+
+static mut LOG_TYPE: *mut redmod::RedisModuleType = 0 as *mut redmod::RedisModuleType;
+
+/// `Log` is the value of a key limited with the sliding window log: the time
+/// of every unit admitted during the last window, kept as a ring of
+/// timestamps under a single key so that a limit of any size costs one key
+/// and one replicated command per call.
+///
+/// The key's expiry is kept with the log so that it can be rewritten along
+/// with it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Log {
+    /// When the key expires in milliseconds since the epoch.
+    pub expires_at: i64,
+    /// Time of every unit in nanoseconds since the epoch, oldest first.
+    pub times:      VecDeque<i64>,
+}
+
+impl Log {
+    /// Returns the arguments of CL.SETLOG that put back this log as is: its
+    /// expiry followed by every timestamp.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.expires_at.to_string()];
+        args.extend(self.times.iter().map(|time| time.to_string()));
+        args
+    }
+}
+
+/// Called when Redis loads the module.
+pub fn load(ctx: *mut redmod::RedisModuleCtx) -> redmod::Status {
+    let redis_type = redmod::create_data_type(
+        ctx,
+        format!("{}\0", "cl-swnlog").as_ptr(),
+        0,
+        Some(Cell_Type_Log_RDBLoad),
+        Some(Cell_Type_Log_RDBSave),
+        Some(Cell_Type_Log_AOFRewrite),
+        Some(Cell_Type_Log_MemUsage),
+        None,
+        Some(Cell_Type_Log_Free),
+    );
+    if redis_type.is_null() {
+        return redmod::Status::Err;
+    }
+
+    unsafe {
+        LOG_TYPE = redis_type;
+    }
+    redmod::Status::Ok
+}
+
+pub fn log_type() -> *mut redmod::RedisModuleType {
+    unsafe { LOG_TYPE }
+}
+
+/// Replaces the log at an open key and gives the key a new TTL. Fails without
+/// touching the key if it holds another type.
+pub fn put(
+    key: &redis::RedisKeyWritable,
+    log: Log,
+    ttl: time::Duration,
+) -> Result<(), SlicedError> {
+    match key.get_value::<Log>(log_type())? {
+        Some(existing) => *existing = log,
+        None => key.set_value(log_type(), Box::new(log))?,
+    }
+    key.set_expire(ttl)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Log_RDBLoad(
+    rdb: *mut redmod::RedisModuleIO,
+    encver: libc::c_int,
+) -> *mut u8 {
+    let io = redis::RedisIO { io: rdb };
+    let mut log = Box::new(Log {
+        expires_at: io.load_signed(),
+        times:      VecDeque::new(),
+    });
+    for _ in 0..io.load_unsigned() {
+        log.times.push_back(io.load_signed());
+    }
+    Box::into_raw(log) as *mut u8
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Log_RDBSave(rdb: *mut redmod::RedisModuleIO, value: *mut u8) {
+    let io = redis::RedisIO { io: rdb };
+    let log = unsafe { &*(value as *mut Log) };
+    io.save_signed(log.expires_at);
+    io.save_unsigned(log.times.len() as u64);
+    for time in log.times.iter() {
+        io.save_signed(*time);
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Log_AOFRewrite(
+    aof: *mut redmod::RedisModuleIO,
+    key: *mut redmod::RedisModuleString,
+    value: *mut u8,
+) {
+    let io = redis::RedisIO { io: aof };
+    let log = unsafe { &*(value as *mut Log) };
+    let key = match redis::string_from_redis(key) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    let mut args = vec![key];
+    args.extend(log.to_args());
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let _ = io.emit_aof("CL.SETLOG", &args);
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Log_MemUsage(value: *const u8) -> libc::size_t {
+    let log = unsafe { &*(value as *const Log) };
+    mem::size_of::<Log>() + log.times.capacity() * mem::size_of::<i64>()
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Log_Free(value: *mut u8) {
+    drop(unsafe { Box::from_raw(value as *mut Log) });
+}
@@ -1,6 +1,15 @@
 extern crate time;
 
+pub mod adaptive;
+pub mod concurrency;
+pub mod log;
+pub mod policy;
+pub mod state;
+pub mod stats;
 pub mod store;
+pub mod window;
+
+use std::cmp;
 
 use crate::error::SlicedError;
 
@@ -8,7 +17,7 @@ use crate::error::SlicedError;
 // operations before returning an error.
 const MAX_CAS_ATTEMPTS: i64 = 5;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Rate {
     pub period: time::Duration,
 }
@@ -48,6 +57,96 @@ pub struct RateLimitResult {
     pub retry_after: time::Duration,
 }
 
+/// ShapeResult describes what `RateLimiter::shape` did with a request.
+#[derive(Debug, PartialEq)]
+pub struct ShapeResult {
+    pub limit:       i64,
+    /// How long the request has to wait before it may run. For a rejected
+    /// request, how long it would have had to wait.
+    pub delay:       time::Duration,
+    /// When the request may run, or None if it was rejected.
+    pub run_at:      Option<time::Tm>,
+    pub reset_after: time::Duration,
+    pub retry_after: time::Duration,
+}
+
+/// Limiter is implemented by every rate limiting algorithm. They all work on
+/// top of the same `store::Store` and describe their state with the same
+/// `RateLimitResult` so that callers can switch between them freely.
+pub trait Limiter {
+    /// Checks whether applying quantity to key would exceed the limit and
+    /// updates the store if it wouldn't. A quantity of 0 peeks at the state
+    /// of the key without changing it.
+    fn rate_limit(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError>;
+
+    /// Clears every piece of state the algorithm keeps for key so that it
+    /// starts over with its full limit. Returns whether there was any.
+    fn reset(&mut self, key: &str) -> Result<bool, SlicedError>;
+}
+
+/// Algorithm selects which `Limiter` implementation is used to enforce a
+/// RateQuota.
+///
+/// The window based algorithms interpret a quota so that they agree with GCRA
+/// on both burst and long-term rate: a window admits `max_burst + 1` units and
+/// lasts as long as it takes `max_rate` to emit that many.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Gcra,
+    FixedWindow,
+    SlidingWindowLog,
+    SlidingWindowCounter,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Algorithm, SlicedError> {
+        match name.to_lowercase().as_str() {
+            "gcra" => Ok(Algorithm::Gcra),
+            "fixed-window" => Ok(Algorithm::FixedWindow),
+            "sliding-window-log" => Ok(Algorithm::SlidingWindowLog),
+            "sliding-window-counter" => Ok(Algorithm::SlidingWindowCounter),
+            _ => Err(error!("Unknown rate limiting algorithm: {}", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::Gcra => "gcra",
+            Algorithm::FixedWindow => "fixed-window",
+            Algorithm::SlidingWindowLog => "sliding-window-log",
+            Algorithm::SlidingWindowCounter => "sliding-window-counter",
+        }
+    }
+
+    /// Builds a limiter for this algorithm on top of the given store.
+    pub fn limiter<'a, T: 'a + store::Store>(
+        &self,
+        store: &'a mut T,
+        quota: &RateQuota,
+    ) -> Box<Limiter + 'a> {
+        match *self {
+            Algorithm::Gcra => Box::new(RateLimiter::new(store, quota)),
+            Algorithm::FixedWindow => Box::new(window::FixedWindowLimiter::new(store, quota)),
+            Algorithm::SlidingWindowLog => {
+                Box::new(window::SlidingWindowLogLimiter::new(store, quota))
+            }
+            Algorithm::SlidingWindowCounter => {
+                Box::new(window::SlidingWindowCounterLimiter::new(store, quota))
+            }
+        }
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Algorithm {
+        Algorithm::Gcra
+    }
+}
+
 pub struct RateLimiter<'a, T: 'a + store::Store> {
     pub store: &'a mut T,
 
@@ -66,6 +165,7 @@ pub struct RateLimiter<'a, T: 'a + store::Store> {
 
 impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
     pub fn new(store: &'a mut T, quota: &RateQuota) -> RateLimiter<'a, T> {
+        store.use_quota(quota);
         RateLimiter {
             delay_variation_tolerance: time::Duration::nanoseconds(
                 quota.max_rate.period.num_nanoseconds().unwrap() * (quota.max_burst + 1),
@@ -91,24 +191,7 @@ impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
         key: &str,
         quantity: i64,
     ) -> Result<(bool, RateLimitResult), SlicedError> {
-        let mut rlc = RateLimitResult {
-            limit:       self.limit,
-            remaining:   0,
-            retry_after: time::Duration::seconds(-1),
-            reset_after: time::Duration::seconds(-1),
-        };
-
-        let increment = time::Duration::nanoseconds(
-            self.emission_interval.num_nanoseconds().unwrap() * quantity,
-        );
-        self.log_start(key, quantity, increment);
-
-        // Rust actually detects that this variable can only ever be assigned
-        // once despite our loops and conditions so it doesn't have to be
-        // mutable. Amazing.
-        let limited: bool;
-
-        let mut ttl: time::Duration;
+        self.log_start(key, quantity);
 
         // Looping here is not about retrying communication failures, it's
         // about retrying contention. While we're performing our calculations
@@ -122,83 +205,275 @@ impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
         loop {
             log_debug!(self.store, "iteration = {}", i);
 
-            // tat refers to the theoretical arrival time that would be expected
-            // from equally spaced requests at exactly the rate limit.
-            let (tat_val, now) = self.store.get_with_time(key)?;
+            let decision = self.evaluate(key, quantity)?;
+            if decision.limited || self.commit(key, &decision)? {
+                self.log_end(&decision.result);
+                return Ok((decision.limited, decision.result));
+            }
 
-            let tat = match tat_val {
-                -1 => now,
-                _ => from_nanoseconds(tat_val),
-            };
-            log_debug!(
-                self.store,
-                "tat = {} (from store = {})",
-                tat.rfc3339(),
-                tat_val
-            );
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
+            }
+        }
+    }
 
-            let new_tat = if now > tat {
-                now + increment
-            } else {
-                tat + increment
-            };
-            log_debug!(self.store, "new_tat = {}", new_tat.rfc3339());
+    /// Takes as much of batch as the limit allows right now, all in one go,
+    /// so that a client can spend it locally instead of asking for every
+    /// unit. Returns how many units were granted, which is 0 if not even one
+    /// would be allowed.
+    ///
+    /// The units are only good until the key's TAT (reset_after in the
+    /// result) is reached, after which they'd have been replenished anyway.
+    /// Whatever goes unused should be handed back with `refund`.
+    pub fn lease(
+        &mut self,
+        key: &str,
+        batch: i64,
+    ) -> Result<(i64, RateLimitResult), SlicedError> {
+        self.log_start(key, batch);
 
-            // Block the request if the next permitted time is in the future.
-            let allow_at = new_tat - self.delay_variation_tolerance;
-            let diff = now - allow_at;
-            log_debug!(
-                self.store,
-                "diff = {}ms (now - allow_at)",
-                diff.num_milliseconds()
-            );
+        let mut i = 0;
+        loop {
+            log_debug!(self.store, "iteration = {}", i);
 
-            if diff < time::Duration::zero() {
-                log_debug!(
-                    self.store,
-                    "BLOCKED retry_after = {}ms",
-                    -diff.num_milliseconds()
-                );
+            let available = self.evaluate(key, 0)?.result.remaining;
+            let mut granted = cmp::min(batch, cmp::max(available, 0));
 
-                if increment <= self.delay_variation_tolerance {
-                    rlc.retry_after = -diff;
-                }
+            // remaining is worked out in microseconds, so it can be off by
+            // one from what the TAT allows to the nanosecond.
+            let mut decision = self.evaluate(key, granted)?;
+            while decision.limited && granted > 0 {
+                granted -= 1;
+                decision = self.evaluate(key, granted)?;
+            }
 
-                limited = true;
-                ttl = tat - now;
-                break;
+            // Report when a single unit would next be allowed.
+            if granted == 0 {
+                let decision = self.evaluate(key, 1)?;
+                self.log_end(&decision.result);
+                return Ok((0, decision.result));
             }
 
-            let new_tat_ns = nanoseconds(new_tat);
-            ttl = new_tat - now;
-            log_debug!(self.store, "ALLOWED");
+            if self.commit(key, &decision)? {
+                log_debug!(self.store, "granted = {}", granted);
+                self.log_end(&decision.result);
+                return Ok((granted, decision.result));
+            }
 
-            // If the key was originally missing, set it if if doesn't exist.
-            // If it was there, try to compare and swap.
-            //
-            // Both of these cases are designed to work around the fact that
-            // another limiter could be running in parallel.
-            let updated = if tat_val == -1 {
-                self.store.set_if_not_exists_with_ttl(key, new_tat_ns, ttl)?
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
+            }
+        }
+    }
+
+    /// Shapes traffic instead of policing it: rather than being rejected once
+    /// the burst is used up, a request is scheduled to run at the time the
+    /// limit allows and the TAT moves on past the burst to account for it.
+    /// Requests are only rejected if they'd have to wait longer than
+    /// max_delay, in which case the key is left as it is.
+    ///
+    /// Returns whether the request was rejected.
+    pub fn shape(
+        &mut self,
+        key: &str,
+        quantity: i64,
+        max_delay: time::Duration,
+    ) -> Result<(bool, ShapeResult), SlicedError> {
+        self.log_start(key, quantity);
+        log_debug!(self.store, "max_delay = {}ms", max_delay.num_milliseconds());
+
+        let mut i = 0;
+        loop {
+            log_debug!(self.store, "iteration = {}", i);
+
+            // The request can run as soon as it's within the burst.
+            let decision = self.evaluate(key, quantity)?;
+            let now = decision.now;
+            let new_tat = from_nanoseconds(decision.new_tat_val);
+            let allow_at = new_tat - self.delay_variation_tolerance;
+            let delay = if allow_at > now {
+                allow_at - now
             } else {
-                self.store
-                    .compare_and_swap_with_ttl(key, tat_val, new_tat_ns, ttl)?
+                time::Duration::zero()
             };
+            log_debug!(self.store, "delay = {}ms", delay.num_milliseconds());
+
+            if delay > max_delay {
+                log_debug!(self.store, "REJECTED");
+                let reset_after = if decision.ttl > time::Duration::zero() {
+                    decision.ttl
+                } else {
+                    time::Duration::zero()
+                };
+                return Ok((
+                    true,
+                    ShapeResult {
+                        limit: self.limit,
+                        delay,
+                        run_at: None,
+                        reset_after,
+                        retry_after: delay - max_delay,
+                    },
+                ));
+            }
 
-            if updated {
-                limited = false;
-                break;
+            // A scheduled request moves the TAT on even if it's past the
+            // burst, so it's committed as if it had been allowed.
+            let ttl = new_tat - now;
+            let decision = Decision {
+                limited: false,
+                ttl,
+                ..decision
+            };
+            if self.commit(key, &decision)? {
+                log_debug!(self.store, "SCHEDULED");
+                return Ok((
+                    false,
+                    ShapeResult {
+                        limit: self.limit,
+                        delay,
+                        run_at: Some(now + delay),
+                        reset_after: ttl,
+                        retry_after: time::Duration::seconds(-1),
+                    },
+                ));
             }
 
             i += 1;
             if i > MAX_CAS_ATTEMPTS {
-                return Err(error!(
-                    "Failed to update rate limit after \
-                     {} attempts",
-                    MAX_CAS_ATTEMPTS
-                ));
+                return Err(attempts_exhausted());
+            }
+        }
+    }
+
+    /// Reports the state of a key without changing it. Along with the usual
+    /// result it returns the key's TAT in nanoseconds since the epoch, or -1
+    /// if the key is unset.
+    pub fn inspect(&mut self, key: &str) -> Result<(i64, RateLimitResult), SlicedError> {
+        let decision = self.evaluate(key, 0)?;
+        Ok((decision.tat_val, decision.result))
+    }
+
+    /// Works out whether applying quantity to a key would be allowed, but
+    /// leaves the key as it is either way.
+    pub fn check(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError> {
+        let decision = self.evaluate(key, quantity)?;
+        Ok((decision.limited, decision.result))
+    }
+
+    /// Gives back capacity that was taken by an earlier call to `rate_limit`,
+    /// for example because the request it guarded failed before doing any
+    /// work. The TAT is moved back by quantity but never before now, so a
+    /// refund can't bank more capacity than the limit allows.
+    pub fn refund(&mut self, key: &str, quantity: i64) -> Result<RateLimitResult, SlicedError> {
+        let mut i = 0;
+        loop {
+            let (tat_val, now) = self.store.get_with_time(key)?;
+
+            // Nothing to give back if the key is already fully replenished.
+            if tat_val == -1 || from_nanoseconds(tat_val) <= now {
+                return Ok(self.evaluate(key, 0)?.result);
+            }
+
+            let tat = from_nanoseconds(tat_val);
+            let refunded = tat - self.increment(quantity);
+            let new_tat = if refunded > now { refunded } else { now };
+            log_debug!(self.store, "refund new_tat = {}", new_tat.rfc3339());
+
+            if self.store.compare_and_swap_with_ttl(
+                key,
+                tat_val,
+                nanoseconds(new_tat),
+                new_tat - now,
+            )? {
+                return Ok(self.evaluate(key, 0)?.result);
+            }
+
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
             }
         }
+    }
+
+    /// Clears a key so that it starts over with its full burst. Returns
+    /// whether there was anything to clear.
+    pub fn reset(&mut self, key: &str) -> Result<bool, SlicedError> {
+        self.store.delete(key)
+    }
+
+    /// Works out what applying the given quantity to a key would do without
+    /// writing anything back to the store. The returned `Decision` can then be
+    /// handed to `commit` to make it stick.
+    fn evaluate(&self, key: &str, quantity: i64) -> Result<Decision, SlicedError> {
+        // tat refers to the theoretical arrival time that would be expected
+        // from equally spaced requests at exactly the rate limit.
+        let (tat_val, now) = self.store.get_with_time(key)?;
+        Ok(self.decide(tat_val, now, quantity))
+    }
+
+    /// Works out what applying quantity would do to a key whose TAT is
+    /// tat_val (-1 if unset) as of now.
+    fn decide(&self, tat_val: i64, now: time::Tm, quantity: i64) -> Decision {
+        let mut rlc = RateLimitResult {
+            limit:       self.limit,
+            remaining:   0,
+            retry_after: time::Duration::seconds(-1),
+            reset_after: time::Duration::seconds(-1),
+        };
+
+        let increment = self.increment(quantity);
+
+        let tat = match tat_val {
+            -1 => now,
+            _ => from_nanoseconds(tat_val),
+        };
+        log_debug!(
+            self.store,
+            "tat = {} (from store = {})",
+            tat.rfc3339(),
+            tat_val
+        );
+
+        let new_tat = if now > tat {
+            now + increment
+        } else {
+            tat + increment
+        };
+        log_debug!(self.store, "new_tat = {}", new_tat.rfc3339());
+
+        // Block the request if the next permitted time is in the future.
+        let allow_at = new_tat - self.delay_variation_tolerance;
+        let diff = now - allow_at;
+        log_debug!(
+            self.store,
+            "diff = {}ms (now - allow_at)",
+            diff.num_milliseconds()
+        );
+
+        let limited = diff < time::Duration::zero();
+        let ttl = if limited {
+            log_debug!(
+                self.store,
+                "BLOCKED retry_after = {}ms",
+                -diff.num_milliseconds()
+            );
+
+            if increment <= self.delay_variation_tolerance {
+                rlc.retry_after = -diff;
+            }
+
+            tat - now
+        } else {
+            log_debug!(self.store, "ALLOWED");
+            new_tat - now
+        };
 
         let next = self.delay_variation_tolerance - ttl;
         if next > -self.emission_interval {
@@ -208,8 +483,55 @@ impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
         }
         rlc.reset_after = ttl;
 
-        self.log_end(&rlc);
-        Ok((limited, rlc))
+        Decision {
+            limited,
+            tat_val,
+            new_tat_val: nanoseconds(new_tat),
+            now,
+            ttl,
+            result: rlc,
+        }
+    }
+
+    /// Evaluates quantity against a key as it would be left by an earlier
+    /// decision on it that hasn't been committed yet. Committing the result
+    /// writes both at once.
+    fn evaluate_after(&self, previous: &Decision, quantity: i64) -> Decision {
+        let pending = if previous.limited {
+            previous.tat_val
+        } else {
+            previous.new_tat_val
+        };
+        let mut decision = self.decide(pending, previous.now, quantity);
+        decision.tat_val = previous.tat_val;
+        decision
+    }
+
+    /// Writes an allowed decision back to the store. Returns false if another
+    /// limiter changed the key since the decision was evaluated.
+    fn commit(&mut self, key: &str, decision: &Decision) -> Result<bool, SlicedError> {
+        // If the key was originally missing, set it if if doesn't exist.
+        // If it was there, try to compare and swap.
+        //
+        // Both of these cases are designed to work around the fact that
+        // another limiter could be running in parallel.
+        if decision.tat_val == -1 {
+            self.store
+                .set_if_not_exists_with_ttl(key, decision.new_tat_val, decision.ttl)
+        } else {
+            self.store.compare_and_swap_with_ttl(
+                key,
+                decision.tat_val,
+                decision.new_tat_val,
+                decision.ttl,
+            )
+        }
+    }
+
+    fn increment(&self, quantity: i64) -> time::Duration {
+        time::Duration::nanoseconds(
+            self.emission_interval.num_nanoseconds().unwrap() * quantity,
+        )
     }
 
     fn log_end(&self, rlc: &RateLimitResult) {
@@ -231,7 +553,7 @@ impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
         );
     }
 
-    fn log_start(&self, key: &str, quantity: i64, increment: time::Duration) {
+    fn log_start(&self, key: &str, quantity: i64) {
         log_debug!(self.store, "");
         log_debug!(self.store, "-----");
         log_debug!(self.store, "key = {}", key);
@@ -249,17 +571,146 @@ impl<'a, T: 'a + store::Store> RateLimiter<'a, T> {
         log_debug!(
             self.store,
             "tat_increment = {}ms (emission_interval * quantity)",
-            increment.num_milliseconds()
+            self.increment(quantity).num_milliseconds()
         );
     }
 }
 
+impl<'a, T: 'a + store::Store> Limiter for RateLimiter<'a, T> {
+    fn rate_limit(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError> {
+        RateLimiter::rate_limit(self, key, quantity)
+    }
+
+    fn reset(&mut self, key: &str) -> Result<bool, SlicedError> {
+        RateLimiter::reset(self, key)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct RateQuota {
     pub max_burst: i64,
     pub max_rate:  Rate,
 }
 
+/// Decision is the outcome of evaluating a quantity against a key before
+/// anything has been written back to the store.
+struct Decision {
+    limited:     bool,
+    tat_val:     i64,
+    new_tat_val: i64,
+    now:         time::Tm,
+    ttl:         time::Duration,
+    result:      RateLimitResult,
+}
+
+/// RateLimitRequest is one of the limits checked together by
+/// `rate_limit_all`.
+#[derive(Debug, PartialEq)]
+pub struct RateLimitRequest<'k> {
+    pub key:      &'k str,
+    pub quota:    RateQuota,
+    pub quantity: i64,
+}
+
+/// Checks several limits at once with all-or-nothing semantics. Every limit
+/// is evaluated first and the store is only updated if none of them would
+/// throttle, so a denial never consumes capacity from the limits that would
+/// have allowed the request.
+///
+/// Returns the index of the first limit that throttled (if any) along with a
+/// RateLimitResult for every request in the order they were given. When the
+/// call is throttled, results for the limits that didn't trip describe their
+/// current state as if they had been peeked at with a quantity of 0.
+///
+/// As with `RateLimiter::rate_limit`, updates are retried on contention, but
+/// only an atomic store (like `InternalRedisStore`) can guarantee that a race
+/// is never observed halfway through committing.
+pub fn rate_limit_all<T: store::Store>(
+    store: &mut T,
+    requests: &[RateLimitRequest],
+) -> Result<(Option<usize>, Vec<RateLimitResult>), SlicedError> {
+    // A key that comes up more than once is evaluated against the state the
+    // limits before it would leave it in, and only its last decision, which
+    // covers all of them, is committed.
+    let last: Vec<bool> = requests
+        .iter()
+        .enumerate()
+        .map(|(i, request)| requests[i + 1..].iter().all(|r| r.key != request.key))
+        .collect();
+
+    let mut i = 0;
+    loop {
+        let mut decisions: Vec<Decision> = Vec::with_capacity(requests.len());
+        for (j, request) in requests.iter().enumerate() {
+            let limiter = RateLimiter::new(store, &request.quota);
+            let previous = (0..j).rev().find(|&k| requests[k].key == request.key);
+            let decision = match previous {
+                Some(k) => limiter.evaluate_after(&decisions[k], request.quantity),
+                None => limiter.evaluate(request.key, request.quantity)?,
+            };
+            decisions.push(decision);
+        }
+
+        if let Some(tripped) = decisions.iter().position(|d| d.limited) {
+            let mut results = Vec::with_capacity(requests.len());
+            for (request, decision) in requests.iter().zip(decisions) {
+                if decision.limited {
+                    results.push(decision.result);
+                } else {
+                    let limiter = RateLimiter::new(store, &request.quota);
+                    results.push(limiter.evaluate(request.key, 0)?.result);
+                }
+            }
+            return Ok((Some(tripped), results));
+        }
+
+        let mut committed = 0;
+        let mut failed = None;
+        for (j, request) in requests.iter().enumerate() {
+            if !last[j] {
+                continue;
+            }
+            let mut limiter = RateLimiter::new(store, &request.quota);
+            if !limiter.commit(request.key, &decisions[j])? {
+                failed = Some(j);
+                break;
+            }
+            committed += 1;
+        }
+
+        let failed = match failed {
+            Some(j) => j,
+            None => return Ok((None, decisions.into_iter().map(|d| d.result).collect())),
+        };
+
+        // Losing a race after some of the limits have already been updated
+        // leaves us with nothing sensible to retry.
+        if committed > 0 {
+            return Err(error!(
+                "Rate limit for {} changed while committing",
+                requests[failed].key
+            ));
+        }
+
+        i += 1;
+        if i > MAX_CAS_ATTEMPTS {
+            return Err(attempts_exhausted());
+        }
+    }
+}
+
+fn attempts_exhausted() -> SlicedError {
+    error!(
+        "Failed to update rate limit after \
+         {} attempts",
+        MAX_CAS_ATTEMPTS
+    )
+}
+
 fn from_nanoseconds(x: i64) -> time::Tm {
     let ns = (10 as i64).pow(9);
     time::at(time::Timespec {
@@ -277,8 +728,10 @@ fn nanoseconds(x: time::Tm) -> i64 {
 mod tests {
     extern crate time;
 
+    use crate::cell::store::Store;
     use crate::cell::*;
     use crate::error::SlicedError;
+    use std::collections::VecDeque;
     use std::error::Error;
 
     #[test]
@@ -432,6 +885,386 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_inspects_refunds_and_resets() {
+        let quota = RateQuota {
+            max_burst: 4,
+            max_rate:  Rate::per_second(1),
+        };
+        let now = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = now;
+        let mut limiter = RateLimiter::new(&mut test_store, &quota);
+
+        let (_, results) = limiter.rate_limit("foo", 3).unwrap();
+        assert_eq!(2, results.remaining);
+
+        // Inspecting doesn't use anything up.
+        let (tat, results) = limiter.inspect("foo").unwrap();
+        assert_eq!(nanoseconds(now + time::Duration::seconds(3)), tat);
+        assert_eq!(2, results.remaining);
+        assert_eq!(2, limiter.inspect("foo").unwrap().1.remaining);
+
+        let results = limiter.refund("foo", 1).unwrap();
+        assert_eq!(3, results.remaining);
+        assert_eq!(time::Duration::seconds(2), results.reset_after);
+
+        // Refunding more than was used stops at a full limit instead of
+        // banking extra capacity.
+        let results = limiter.refund("foo", 10).unwrap();
+        assert_eq!(5, results.remaining);
+        assert_eq!(nanoseconds(now), limiter.inspect("foo").unwrap().0);
+        let (_, results) = limiter.rate_limit("foo", 1).unwrap();
+        assert_eq!(4, results.remaining);
+
+        assert_eq!(true, limiter.reset("foo").unwrap());
+        assert_eq!(false, limiter.reset("foo").unwrap());
+        assert_eq!(-1, limiter.inspect("foo").unwrap().0);
+    }
+
+    #[test]
+    fn it_leases_what_the_limit_allows() {
+        let quota = RateQuota {
+            max_burst: 4,
+            max_rate:  Rate::per_second(1),
+        };
+        let now = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = now;
+        let mut limiter = RateLimiter::new(&mut test_store, &quota);
+
+        let (granted, results) = limiter.lease("foo", 3).unwrap();
+        assert_eq!(3, granted);
+        assert_eq!(2, results.remaining);
+        assert_eq!(time::Duration::seconds(3), results.reset_after);
+
+        // Only part of a batch larger than what's left is granted.
+        let (granted, results) = limiter.lease("foo", 10).unwrap();
+        assert_eq!(2, granted);
+        assert_eq!(0, results.remaining);
+
+        // Nothing's left, so the reply says when to come back.
+        let (granted, results) = limiter.lease("foo", 10).unwrap();
+        assert_eq!(0, granted);
+        assert_eq!(time::Duration::seconds(1), results.retry_after);
+
+        // Handing back unused units makes them available again.
+        limiter.refund("foo", 2).unwrap();
+        let (granted, _) = limiter.lease("foo", 10).unwrap();
+        assert_eq!(2, granted);
+    }
+
+    #[test]
+    fn it_shapes_requests_up_to_a_maximum_delay() {
+        let quota = RateQuota {
+            max_burst: 1,
+            max_rate:  Rate::per_second(1),
+        };
+        let now = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let max_delay = time::Duration::seconds(2);
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = now;
+        let mut limiter = RateLimiter::new(&mut test_store, &quota);
+
+        // The burst runs right away.
+        for _ in 0..2 {
+            let (rejected, results) = limiter.shape("foo", 1, max_delay).unwrap();
+            assert_eq!(false, rejected);
+            assert_eq!(Some(now), results.run_at);
+        }
+
+        // Past it, requests are spaced out at the limit's rate.
+        let (_, results) = limiter.shape("foo", 1, max_delay).unwrap();
+        assert_eq!(time::Duration::seconds(1), results.delay);
+        assert_eq!(Some(now + time::Duration::seconds(1)), results.run_at);
+        let (_, results) = limiter.shape("foo", 1, max_delay).unwrap();
+        assert_eq!(time::Duration::seconds(2), results.delay);
+        assert_eq!(time::Duration::seconds(4), results.reset_after);
+
+        // Until they'd have to wait too long.
+        let (rejected, results) = limiter.shape("foo", 1, max_delay).unwrap();
+        assert_eq!(true, rejected);
+        assert_eq!(None, results.run_at);
+        assert_eq!(time::Duration::seconds(1), results.retry_after);
+        assert_eq!(time::Duration::seconds(4), results.reset_after);
+    }
+
+    #[test]
+    fn it_rate_limits_all_or_nothing() {
+        let start = time::now_utc();
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = start;
+
+        let requests = [
+            RateLimitRequest {
+                key:      "per_second",
+                quota:    RateQuota {
+                    max_burst: 0,
+                    max_rate:  Rate::per_second(1),
+                },
+                quantity: 1,
+            },
+            RateLimitRequest {
+                key:      "per_day",
+                quota:    RateQuota {
+                    max_burst: 9,
+                    max_rate:  Rate::per_day(10),
+                },
+                quantity: 1,
+            },
+        ];
+
+        let (tripped, results) = rate_limit_all(&mut test_store, &requests).unwrap();
+        assert_eq!(None, tripped);
+        assert_eq!(0, results[0].remaining);
+        assert_eq!(9, results[1].remaining);
+
+        // The per second limit trips, so the per day limit must not be charged.
+        let (tripped, results) = rate_limit_all(&mut test_store, &requests).unwrap();
+        assert_eq!(Some(0), tripped);
+        assert_eq!(time::Duration::seconds(1), results[0].retry_after);
+        assert_eq!(9, results[1].remaining);
+
+        let mut limiter = RateLimiter::new(&mut test_store, &requests[1].quota);
+        let (_, peeked) = limiter.rate_limit("per_day", 0).unwrap();
+        assert_eq!(9, peeked.remaining);
+    }
+
+    #[test]
+    fn it_rate_limits_duplicate_keys_against_pending_state() {
+        let start = time::now_utc();
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = start;
+
+        let requests = [
+            RateLimitRequest {
+                key:      "foo",
+                quota:    RateQuota {
+                    max_burst: 2,
+                    max_rate:  Rate::per_second(1),
+                },
+                quantity: 1,
+            },
+            RateLimitRequest {
+                key:      "foo",
+                quota:    RateQuota {
+                    max_burst: 2,
+                    max_rate:  Rate::per_second(1),
+                },
+                quantity: 1,
+            },
+        ];
+
+        // The second limit sees what the first one takes, and both are
+        // committed together.
+        let (tripped, results) = rate_limit_all(&mut test_store, &requests).unwrap();
+        assert_eq!(None, tripped);
+        assert_eq!(2, results[0].remaining);
+        assert_eq!(1, results[1].remaining);
+
+        // Only one unit is left, so the second limit trips and nothing is
+        // taken.
+        let (tripped, _) = rate_limit_all(&mut test_store, &requests).unwrap();
+        assert_eq!(Some(1), tripped);
+
+        let mut limiter = RateLimiter::new(&mut test_store, &requests[0].quota);
+        let (_, peeked) = limiter.rate_limit("foo", 0).unwrap();
+        assert_eq!(1, peeked.remaining);
+    }
+
+    #[test]
+    fn it_parses_algorithms() {
+        assert_eq!(Algorithm::Gcra, Algorithm::parse("GCRA").unwrap());
+        assert_eq!(
+            Algorithm::SlidingWindowLog,
+            Algorithm::parse("sliding-window-log").unwrap()
+        );
+        assert!(Algorithm::parse("leaky").is_err());
+    }
+
+    #[test]
+    fn it_rate_limits_with_a_fixed_window() {
+        // Limit of 5 per 5 second window starting at a window boundary.
+        let quota = RateQuota {
+            max_burst: 4,
+            max_rate:  Rate::per_second(1),
+        };
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+
+        test_store.clock = start;
+        let (limited, results) = Algorithm::FixedWindow
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 5)
+            .unwrap();
+        assert_eq!(false, limited);
+        assert_eq!(0, results.remaining);
+        assert_eq!(time::Duration::seconds(5), results.reset_after);
+
+        test_store.clock = start + time::Duration::seconds(4);
+        let (limited, results) = Algorithm::FixedWindow
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 1)
+            .unwrap();
+        assert_eq!(true, limited);
+        assert_eq!(time::Duration::seconds(1), results.retry_after);
+
+        // The whole limit is available again in the next window.
+        test_store.clock = start + time::Duration::seconds(5);
+        let (limited, results) = Algorithm::FixedWindow
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 5)
+            .unwrap();
+        assert_eq!(false, limited);
+        assert_eq!(0, results.remaining);
+    }
+
+    #[test]
+    fn it_rate_limits_with_a_sliding_window_log() {
+        let quota = RateQuota {
+            max_burst: 2,
+            max_rate:  Rate::per_second(1),
+        };
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+
+        for n in 0..3 {
+            test_store.clock = start + time::Duration::seconds(n);
+            let (limited, results) = Algorithm::SlidingWindowLog
+                .limiter(&mut test_store, &quota)
+                .rate_limit("foo", 1)
+                .unwrap();
+            assert_eq!(false, limited);
+            assert_eq!(2 - n, results.remaining);
+        }
+
+        // Every unit is in the one log value rather than under a key of its
+        // own.
+        let (log, _) = test_store.get_log_with_time("foo:swl").unwrap();
+        assert_eq!(3, log.len());
+
+        // The first unit ages out of the 3 second window at start + 3s.
+        test_store.clock = start + time::Duration::milliseconds(2500);
+        let (limited, results) = Algorithm::SlidingWindowLog
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 1)
+            .unwrap();
+        assert_eq!(true, limited);
+        assert_eq!(time::Duration::milliseconds(500), results.retry_after);
+
+        test_store.clock = start + time::Duration::seconds(3);
+        let (limited, results) = Algorithm::SlidingWindowLog
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 1)
+            .unwrap();
+        assert_eq!(false, limited);
+        assert_eq!(0, results.remaining);
+    }
+
+    #[test]
+    fn it_rate_limits_with_a_sliding_window_counter() {
+        // Limit of 4 per 4 second window starting at a window boundary.
+        let quota = RateQuota {
+            max_burst: 3,
+            max_rate:  Rate::per_second(1),
+        };
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+
+        test_store.clock = start;
+        let (limited, _) = Algorithm::SlidingWindowCounter
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 4)
+            .unwrap();
+        assert_eq!(false, limited);
+
+        // Halfway through the next window half of the previous one still
+        // counts, leaving room for 2 units.
+        test_store.clock = start + time::Duration::seconds(6);
+        let (limited, results) = Algorithm::SlidingWindowCounter
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 0)
+            .unwrap();
+        assert_eq!(false, limited);
+        assert_eq!(2, results.remaining);
+
+        let (limited, results) = Algorithm::SlidingWindowCounter
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 3)
+            .unwrap();
+        assert_eq!(true, limited);
+        assert_eq!(time::Duration::seconds(1), results.retry_after);
+    }
+
+    #[test]
+    fn it_keeps_the_state_of_each_algorithm_apart() {
+        let quota = RateQuota {
+            max_burst: 3,
+            max_rate:  Rate::per_second(1),
+        };
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+        test_store.clock = start;
+
+        let (limited, _) = Algorithm::FixedWindow
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 4)
+            .unwrap();
+        assert_eq!(false, limited);
+
+        // Switching algorithms starts over instead of reading the fixed
+        // window's counter as one of its own.
+        let (limited, results) = Algorithm::SlidingWindowCounter
+            .limiter(&mut test_store, &quota)
+            .rate_limit("foo", 0)
+            .unwrap();
+        assert_eq!(false, limited);
+        assert_eq!(4, results.remaining);
+    }
+
+    #[test]
+    fn it_resets_the_state_of_each_algorithm() {
+        let quota = RateQuota {
+            max_burst: 3,
+            max_rate:  Rate::per_second(1),
+        };
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let mut memory_store = store::MemoryStore::new_verbose();
+        let mut test_store = TestStore::new(&mut memory_store);
+
+        for algorithm in [
+            Algorithm::Gcra,
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+        ]
+        .iter()
+        {
+            // The sliding window counter still weighs the previous window.
+            test_store.clock = start;
+            let _ = algorithm.limiter(&mut test_store, &quota).rate_limit("foo", 4);
+            test_store.clock = start + time::Duration::seconds(5);
+            let _ = algorithm.limiter(&mut test_store, &quota).rate_limit("foo", 1);
+
+            let mut limiter = algorithm.limiter(&mut test_store, &quota);
+            assert_eq!(true, limiter.reset("foo").unwrap());
+            assert_eq!(false, limiter.reset("foo").unwrap());
+
+            let (limited, results) = limiter.rate_limit("foo", 0).unwrap();
+            assert_eq!(false, limited);
+            assert_eq!(4, results.remaining);
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     struct RateLimitCase {
         num:         i64,
@@ -499,11 +1332,37 @@ mod tests {
             }
         }
 
+        fn compare_and_swap_log_with_ttl(
+            &mut self,
+            key: &str,
+            old: &VecDeque<i64>,
+            new: VecDeque<i64>,
+            ttl: time::Duration,
+        ) -> Result<bool, SlicedError> {
+            if self.fail_updates {
+                Ok(false)
+            } else {
+                self.store.compare_and_swap_log_with_ttl(key, old, new, ttl)
+            }
+        }
+
         fn get_with_time(&self, key: &str) -> Result<(i64, time::Tm), SlicedError> {
             let tup = self.store.get_with_time(key)?;
             Ok((tup.0, self.clock))
         }
 
+        fn get_log_with_time(
+            &self,
+            key: &str,
+        ) -> Result<(VecDeque<i64>, time::Tm), SlicedError> {
+            let tup = self.store.get_log_with_time(key)?;
+            Ok((tup.0, self.clock))
+        }
+
+        fn delete(&mut self, key: &str) -> Result<bool, SlicedError> {
+            self.store.delete(key)
+        }
+
         fn log_debug(&self, message: &str) {
             self.store.log_debug(message)
         }
@@ -0,0 +1,270 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::BTreeMap;
+
+use spin::Mutex;
+
+use super::adaptive::Bounds;
+use super::{Algorithm, Rate, RateQuota};
+use crate::error::SlicedError;
+use crate::redis;
+use crate::redis::redmod;
+
+/// Version of the aux fields that policies are saved in. Version 0 didn't
+/// have adaptive bounds, and version 1 saved an adapted rate along with them.
+const POLICY_ENCODING_VERSION: libc::c_int = 2;
+
+lazy_static! {
+    /// Every named policy ordered by name. They're module state rather than a
+    /// key so that they apply to every db and can't be deleted or renamed
+    /// like user data; they're carried through RDB files as aux fields.
+    static ref POLICIES: Mutex<BTreeMap<String, Policy>> = Mutex::new(BTreeMap::new());
+}
+
+/// Policy is a RateQuota stored on the server under a name so that clients can
+/// refer to it instead of repeating its parameters with every call. The
+/// parameters are kept as they were given so they can be reported back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub max_burst: i64,
+    pub count:     i64,
+    pub period:    i64,
+    pub algorithm: Algorithm,
+    /// Bounds that `CL.FEEDBACK` keeps the adapted rate of keys limited
+    /// under the policy within, if it was set with ADAPT.
+    pub adaptive:  Option<Bounds>,
+}
+
+impl Policy {
+    pub fn quota(&self) -> RateQuota {
+        RateQuota {
+            max_burst: self.max_burst,
+            max_rate:  Rate::per_period(self.count, time::Duration::seconds(self.period)),
+        }
+    }
+
+    /// Returns the bounds that an adaptive rate of min to max per period of
+    /// the policy would be kept within.
+    pub fn bounds(&self, min: i64, max: i64) -> Bounds {
+        Bounds {
+            min,
+            max,
+            period: time::Duration::seconds(self.period),
+        }
+    }
+}
+
+/// Called when Redis loads the module.
+pub fn load(ctx: *mut redmod::RedisModuleCtx) -> redmod::Status {
+    let redis_type = redmod::create_aux_data_type(
+        ctx,
+        format!("{}\0", "cl-policy").as_ptr(),
+        POLICY_ENCODING_VERSION,
+        Some(Cell_Type_Policy_AuxLoad),
+        Some(Cell_Type_Policy_AuxSave),
+        redmod::AUX_AFTER_RDB,
+    );
+    if redis_type.is_null() {
+        return redmod::Status::Err;
+    }
+    redmod::Status::Ok
+}
+
+/// Makes sure that policies survive an AOF rewrite. Redis only asks a module to
+/// rewrite the keys of its data types, so policies, which aren't a key, only
+/// make it into a rewritten AOF as aux fields of its RDB preamble. Without the
+/// preamble a rewrite would silently drop every one of them, so they're
+/// refused instead.
+pub fn check_persistence(r: &redis::Redis) -> Result<(), SlicedError> {
+    let reply = r.call("CONFIG", &["GET", "aof-use-rdb-preamble"])?;
+    if preamble_disabled(&reply) {
+        return Err(error!(
+            "Policies need aof-use-rdb-preamble to survive an AOF rewrite"
+        ));
+    }
+    Ok(())
+}
+
+/// Tells from the reply to `CONFIG GET aof-use-rdb-preamble` whether the
+/// preamble is turned off. Versions of Redis that don't know the setting
+/// don't have the preamble either.
+fn preamble_disabled(reply: &redis::Reply) -> bool {
+    match *reply {
+        redis::Reply::Array(ref pair) => match pair.get(1) {
+            Some(&redis::Reply::String(ref value)) => value.as_slice() == b"no",
+            _ => true,
+        },
+        _ => true,
+    }
+}
+
+/// Looks up a named policy, returning None if there's no such policy.
+pub fn find(name: &str) -> Option<Policy> {
+    POLICIES.lock().get(name).cloned()
+}
+
+/// Looks up a named policy that's expected to exist.
+pub fn get(name: &str) -> Result<Policy, SlicedError> {
+    find(name).ok_or_else(|| error!("No such policy: {}", name))
+}
+
+/// Adds a named policy or replaces an existing one with the same name.
+pub fn set(name: &str, policy: Policy) {
+    POLICIES.lock().insert(String::from(name), policy);
+}
+
+/// Removes a named policy. Returns whether it existed.
+pub fn delete(name: &str) -> bool {
+    POLICIES.lock().remove(name).is_some()
+}
+
+/// Returns the names of every policy in order.
+pub fn names() -> Vec<String> {
+    POLICIES.lock().keys().cloned().collect()
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Policy_AuxLoad(
+    rdb: *mut redmod::RedisModuleIO,
+    encver: libc::c_int,
+    when: libc::c_int,
+) -> redmod::Status {
+    let io = redis::RedisIO { io: rdb };
+    let mut policies = BTreeMap::new();
+    for _ in 0..io.load_unsigned() {
+        let (name, policy) = match load_policy(&io, encver) {
+            Ok(loaded) => loaded,
+            Err(_) => return redmod::Status::Err,
+        };
+        policies.insert(name, policy);
+    }
+
+    // The file replaces whatever policies there were, just like it replaces
+    // the keyspace.
+    *POLICIES.lock() = policies;
+    redmod::Status::Ok
+}
+
+fn load_policy(
+    io: &redis::RedisIO,
+    encver: libc::c_int,
+) -> Result<(String, Policy), SlicedError> {
+    let name = io.load_string()?;
+    let mut policy = Policy {
+        max_burst: io.load_signed(),
+        count:     io.load_signed(),
+        period:    io.load_signed(),
+        algorithm: Algorithm::parse(&io.load_string()?)?,
+        adaptive:  None,
+    };
+
+    if encver >= 1 && io.load_unsigned() == 1 {
+        let (min, max) = (io.load_signed(), io.load_signed());
+        policy.adaptive = Some(policy.bounds(min, max));
+
+        // Version 1 adapted a single rate for the whole policy. Rates are
+        // adapted per key now, so it's dropped.
+        if encver == 1 {
+            io.load_signed();
+        }
+    }
+    Ok((name, policy))
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_Policy_AuxSave(
+    rdb: *mut redmod::RedisModuleIO,
+    when: libc::c_int,
+) {
+    let io = redis::RedisIO { io: rdb };
+    let policies = POLICIES.lock();
+    io.save_unsigned(policies.len() as u64);
+    for (name, policy) in policies.iter() {
+        io.save_string(name);
+        io.save_signed(policy.max_burst);
+        io.save_signed(policy.count);
+        io.save_signed(policy.period);
+        io.save_string(policy.algorithm.name());
+        match policy.adaptive {
+            Some(ref bounds) => {
+                io.save_unsigned(1);
+                io.save_signed(bounds.min);
+                io.save_signed(bounds.max);
+            }
+            None => io.save_unsigned(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::cell::policy::*;
+
+    #[test]
+    fn it_converts_policies_to_quotas() {
+        let policy = Policy {
+            max_burst: 15,
+            count:     30,
+            period:    60,
+            algorithm: Algorithm::Gcra,
+            adaptive:  None,
+        };
+
+        assert_eq!(
+            RateQuota {
+                max_burst: 15,
+                max_rate:  Rate::per_period(30, time::Duration::minutes(1)),
+            },
+            policy.quota()
+        );
+
+        // Adaptive bounds only apply to keys that have had feedback.
+        let adaptive = Policy {
+            adaptive: Some(policy.bounds(10, 100)),
+            ..policy.clone()
+        };
+        assert_eq!(policy.quota(), adaptive.quota());
+    }
+
+    #[test]
+    fn it_requires_the_preamble_to_rewrite_policies() {
+        let config = |value: &str| {
+            redis::Reply::Array(vec![
+                redis::Reply::String(b"aof-use-rdb-preamble".to_vec()),
+                redis::Reply::String(value.as_bytes().to_vec()),
+            ])
+        };
+
+        assert!(!preamble_disabled(&config("yes")));
+        assert!(preamble_disabled(&config("no")));
+
+        // An unknown setting comes back as an empty array.
+        assert!(preamble_disabled(&redis::Reply::Array(vec![])));
+    }
+
+    #[test]
+    fn it_stores_policies_by_name() {
+        let policy = Policy {
+            max_burst: 5,
+            count:     10,
+            period:    1,
+            algorithm: Algorithm::FixedWindow,
+            adaptive:  None,
+        };
+        set("it-stores-policies", policy.clone());
+        assert_eq!(Some(policy), find("it-stores-policies"));
+        assert!(names().contains(&String::from("it-stores-policies")));
+
+        assert!(delete("it-stores-policies"));
+        assert!(!delete("it-stores-policies"));
+        assert_eq!(None, find("it-stores-policies"));
+        assert!(get("it-stores-policies").is_err());
+    }
+}
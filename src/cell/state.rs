@@ -0,0 +1,249 @@
+extern crate libc;
+extern crate time;
+
+use std::mem;
+
+use super::{Rate, RateQuota};
+use crate::error::SlicedError;
+use crate::redis;
+use crate::redis::redmod;
+
+static mut STATE_TYPE: *mut redmod::RedisModuleType = 0 as *mut redmod::RedisModuleType;
+
+/// Version that states are saved in. Version 0 didn't have adapted rates.
+const ENCODING_VERSION: libc::c_int = 1;
+
+/// `State` is the value of a rate limited key. Along with the number the
+/// limiter works with (a TAT for GCRA, a count or timestamp for the window
+/// based algorithms) it remembers the quota it was last written under so that
+/// the key can be inspected without the caller having to repeat it.
+///
+/// Keys that `CL.FEEDBACK` has adapted also carry the emission interval they
+/// were adapted to, which GCRA uses in place of the one it's called with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct State {
+    pub value:             i64,
+    pub max_burst:         i64,
+    pub emission_interval: i64,
+    /// Adapted emission interval in nanoseconds, or 0 if the key hasn't had
+    /// any feedback.
+    pub adapted_interval:  i64,
+}
+
+impl State {
+    pub fn new(value: i64, quota: Option<&RateQuota>) -> State {
+        match quota {
+            Some(quota) => State {
+                value,
+                max_burst: quota.max_burst,
+                emission_interval: quota.max_rate.period.num_nanoseconds().unwrap(),
+                ..State::default()
+            },
+            None => State {
+                value,
+                ..State::default()
+            },
+        }
+    }
+
+    /// Returns the quota the value was last written under, or None if it
+    /// wasn't recorded.
+    pub fn quota(&self) -> Option<RateQuota> {
+        if self.emission_interval <= 0 {
+            return None;
+        }
+        Some(RateQuota {
+            max_burst: self.max_burst,
+            max_rate:  Rate {
+                period: time::Duration::nanoseconds(self.emission_interval),
+            },
+        })
+    }
+
+    /// Returns the rate that feedback adapted the key to, or None if it
+    /// hasn't had any.
+    pub fn adapted_rate(&self) -> Option<Rate> {
+        if self.adapted_interval <= 0 {
+            return None;
+        }
+        Some(Rate {
+            period: time::Duration::nanoseconds(self.adapted_interval),
+        })
+    }
+}
+
+/// Called when Redis loads the module.
+pub fn load(ctx: *mut redmod::RedisModuleCtx) -> redmod::Status {
+    let redis_type = redmod::create_data_type(
+        ctx,
+        format!("{}\0", "cl-thrttl").as_ptr(),
+        ENCODING_VERSION,
+        Some(Cell_Type_State_RDBLoad),
+        Some(Cell_Type_State_RDBSave),
+        Some(Cell_Type_State_AOFRewrite),
+        Some(Cell_Type_State_MemUsage),
+        None,
+        Some(Cell_Type_State_Free),
+    );
+    if redis_type.is_null() {
+        return redmod::Status::Err;
+    }
+
+    unsafe {
+        STATE_TYPE = redis_type;
+    }
+    redmod::Status::Ok
+}
+
+/// Looks up the state of a key, returning None if it's unset.
+pub fn find(r: &redis::Redis, key: &str) -> Result<Option<State>, SlicedError> {
+    let key = r.open_key(key);
+    match key.key_type() {
+        // Older versions kept a bare TAT as a string, without its quota.
+        redmod::KeyType::String => {
+            let value = key.read()?.unwrap_or_default().parse::<i64>()?;
+            Ok(Some(State::new(value, None)))
+        }
+        _ => Ok(key.get_value::<State>(state_type())?.map(|state| *state)),
+    }
+}
+
+pub fn state_type() -> *mut redmod::RedisModuleType {
+    unsafe { STATE_TYPE }
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_State_RDBLoad(
+    rdb: *mut redmod::RedisModuleIO,
+    encver: libc::c_int,
+) -> *mut u8 {
+    let io = redis::RedisIO { io: rdb };
+    let mut state = Box::new(State {
+        value:             io.load_signed(),
+        max_burst:         io.load_signed(),
+        emission_interval: io.load_signed(),
+        adapted_interval:  0,
+    });
+    if encver >= 1 {
+        state.adapted_interval = io.load_signed();
+    }
+    Box::into_raw(state) as *mut u8
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_State_RDBSave(rdb: *mut redmod::RedisModuleIO, value: *mut u8) {
+    let io = redis::RedisIO { io: rdb };
+    let state = unsafe { &*(value as *mut State) };
+    io.save_signed(state.value);
+    io.save_signed(state.max_burst);
+    io.save_signed(state.emission_interval);
+    io.save_signed(state.adapted_interval);
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_State_AOFRewrite(
+    aof: *mut redmod::RedisModuleIO,
+    key: *mut redmod::RedisModuleString,
+    value: *mut u8,
+) {
+    // Redis follows up with a PEXPIREAT for the key on its own, so only the
+    // value needs to be rewritten here, with an expiry of 0 to leave it be.
+    let io = redis::RedisIO { io: aof };
+    let state = unsafe { &*(value as *mut State) };
+    let key = match redis::string_from_redis(key) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    let value = state.value.to_string();
+    let max_burst = state.max_burst.to_string();
+    let emission_interval = state.emission_interval.to_string();
+    let adapted_interval = state.adapted_interval.to_string();
+    let _ = io.emit_aof(
+        "CL.SETSTATE",
+        &[
+            key.as_str(),
+            value.as_str(),
+            max_burst.as_str(),
+            emission_interval.as_str(),
+            "0",
+            adapted_interval.as_str(),
+        ],
+    );
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_State_MemUsage(value: *const u8) -> libc::size_t {
+    mem::size_of::<State>()
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Cell_Type_State_Free(value: *mut u8) {
+    drop(unsafe { Box::from_raw(value as *mut State) });
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::cell::state::*;
+
+    #[test]
+    fn it_records_the_quota_with_the_value() {
+        let quota = RateQuota {
+            max_burst: 4,
+            max_rate:  Rate::per_second(10),
+        };
+
+        assert_eq!(
+            State {
+                value:             123,
+                max_burst:         4,
+                emission_interval: 100_000_000,
+                adapted_interval:  0,
+            },
+            State::new(123, Some(&quota))
+        );
+        assert_eq!(
+            State {
+                value:             123,
+                max_burst:         0,
+                emission_interval: 0,
+                adapted_interval:  0,
+            },
+            State::new(123, None)
+        );
+
+        assert_eq!(
+            Some(RateQuota {
+                max_burst: 4,
+                max_rate:  Rate::per_second(10),
+            }),
+            State::new(123, Some(&quota)).quota()
+        );
+        assert_eq!(None, State::new(123, None).quota());
+    }
+
+    #[test]
+    fn it_keeps_the_adapted_rate_apart_from_the_quota() {
+        let quota = RateQuota {
+            max_burst: 4,
+            max_rate:  Rate::per_second(10),
+        };
+        let mut state = State::new(123, Some(&quota));
+        assert_eq!(None, state.adapted_rate());
+
+        state.adapted_interval = 50_000_000;
+        assert_eq!(Some(Rate::per_second(20)), state.adapted_rate());
+        assert_eq!(Some(quota), state.quota());
+    }
+}
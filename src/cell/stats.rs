@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Number of keys tracked by `Stats::throttled_keys`.
+pub const TOP_KEYS: usize = 32;
+
+/// `Counters` tally the decisions made by a limit: how many calls were allowed
+/// and denied, and the total quantity each of them asked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub allowed_calls:    i64,
+    pub denied_calls:     i64,
+    pub allowed_quantity: i64,
+    pub denied_quantity:  i64,
+}
+
+impl Counters {
+    pub fn record(&mut self, throttled: bool, quantity: i64) {
+        if throttled {
+            self.denied_calls += 1;
+            self.denied_quantity += quantity;
+        } else {
+            self.allowed_calls += 1;
+            self.allowed_quantity += quantity;
+        }
+    }
+}
+
+/// How often a key tracked by `TopK` was seen. The true count is somewhere
+/// between `count - error` and `count`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub count: i64,
+    pub error: i64,
+}
+
+/// `TopK` finds the keys seen most often in a stream using the space-saving
+/// algorithm, which needs no more memory than the number of keys it tracks.
+///
+/// A key that isn't tracked yet replaces the one with the lowest count and
+/// inherits that count as its error. Any key seen more often than the lowest
+/// tracked count is guaranteed to be tracked.
+#[derive(Debug)]
+pub struct TopK {
+    capacity: usize,
+    keys:     HashMap<String, Estimate>,
+}
+
+impl TopK {
+    pub fn new(capacity: usize) -> TopK {
+        TopK {
+            capacity,
+            keys: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, key: &str, weight: i64) {
+        if let Some(estimate) = self.keys.get_mut(key) {
+            estimate.count += weight;
+            return;
+        }
+
+        if self.keys.len() < self.capacity {
+            self.keys.insert(
+                String::from(key),
+                Estimate {
+                    count: weight,
+                    error: 0,
+                },
+            );
+            return;
+        }
+
+        let evicted = self
+            .keys
+            .iter()
+            .min_by_key(|&(_, estimate)| estimate.count)
+            .map(|(key, estimate)| (key.clone(), estimate.count));
+        if let Some((evicted, count)) = evicted {
+            self.keys.remove(&evicted);
+            self.keys.insert(
+                String::from(key),
+                Estimate {
+                    count: count + weight,
+                    error: count,
+                },
+            );
+        }
+    }
+
+    /// Returns every tracked key, most often seen first.
+    pub fn top(&self) -> Vec<(&str, Estimate)> {
+        let mut top: Vec<(&str, Estimate)> = self
+            .keys
+            .iter()
+            .map(|(key, estimate)| (key.as_str(), *estimate))
+            .collect();
+        top.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)));
+        top
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+/// `Stats` keeps track of the decisions made by the module since it was loaded
+/// or last reset: overall, per named policy, and which keys were throttled the
+/// most.
+#[derive(Debug)]
+pub struct Stats {
+    pub overall:        Counters,
+    pub policies:       BTreeMap<String, Counters>,
+    pub throttled_keys: TopK,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            overall:        Counters::default(),
+            policies:       BTreeMap::new(),
+            throttled_keys: TopK::new(TOP_KEYS),
+        }
+    }
+
+    /// Records a decision on key. Decisions made under a quota that was given
+    /// inline rather than by policy name only count towards the overall
+    /// totals.
+    pub fn record(
+        &mut self,
+        policy: Option<&str>,
+        key: &str,
+        throttled: bool,
+        quantity: i64,
+    ) {
+        self.overall.record(throttled, quantity);
+        if let Some(policy) = policy {
+            self.policies
+                .entry(String::from(policy))
+                .or_insert_with(Counters::default)
+                .record(throttled, quantity);
+        }
+        if throttled {
+            self.throttled_keys.record(key, 1);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.overall = Counters::default();
+        self.policies.clear();
+        self.throttled_keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cell::stats::*;
+
+    #[test]
+    fn it_counts_decisions_overall_and_per_policy() {
+        let mut stats = Stats::new();
+        stats.record(Some("api"), "user1", false, 2);
+        stats.record(Some("api"), "user1", true, 3);
+        stats.record(None, "user2", true, 1);
+
+        assert_eq!(
+            Counters {
+                allowed_calls:    1,
+                denied_calls:     2,
+                allowed_quantity: 2,
+                denied_quantity:  4,
+            },
+            stats.overall
+        );
+        assert_eq!(1, stats.policies.len());
+        assert_eq!(1, stats.policies["api"].denied_calls);
+        assert_eq!(3, stats.policies["api"].denied_quantity);
+
+        stats.reset();
+        assert_eq!(Counters::default(), stats.overall);
+        assert!(stats.policies.is_empty());
+        assert!(stats.throttled_keys.top().is_empty());
+    }
+
+    #[test]
+    fn it_keeps_the_most_frequent_keys() {
+        let mut top = TopK::new(2);
+        for _ in 0..5 {
+            top.record("heavy", 1);
+        }
+        top.record("light", 1);
+        top.record("light", 1);
+
+        // A new key pushes out the least frequent one and inherits its count
+        // as the error.
+        top.record("new", 1);
+        assert_eq!(
+            vec![
+                ("heavy", Estimate { count: 5, error: 0 }),
+                ("new", Estimate { count: 3, error: 2 }),
+            ],
+            top.top()
+        );
+    }
+}
@@ -1,8 +1,14 @@
 extern crate time;
 
+use super::log::{self, Log};
+use super::state::{self, State};
+use super::RateQuota;
+use crate::clock::{self, Clock};
 use crate::error::SlicedError;
 use crate::redis;
-use std::collections::HashMap;
+use crate::redis::redmod;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 
 /// Store exposes the atomic data store operations that the GCRA rate limiter
 /// needs to function correctly.
@@ -24,12 +30,33 @@ pub trait Store {
         ttl: time::Duration,
     ) -> Result<bool, SlicedError>;
 
+    /// Like `compare_and_swap_with_ttl`, but for a key that holds a log of
+    /// timestamps rather than a single value. An empty old log matches a key
+    /// that's unset.
+    fn compare_and_swap_log_with_ttl(
+        &mut self,
+        key: &str,
+        old: &VecDeque<i64>,
+        new: VecDeque<i64>,
+        ttl: time::Duration,
+    ) -> Result<bool, SlicedError>;
+
+    /// Removes the given key. Returns whether it existed.
+    fn delete(&mut self, key: &str) -> Result<bool, SlicedError>;
+
     /// Gets the given key's value and the current time as dictated by the
     /// store (this is done so that rate limiters running on a variety of
     /// different nodes can operate with a consistent clock instead of using
     /// their own). If the key was unset, -1 is returned.
     fn get_with_time(&self, key: &str) -> Result<(i64, time::Tm), SlicedError>;
 
+    /// Like `get_with_time`, but for a key that holds a log of timestamps. If
+    /// the key was unset, the log is empty.
+    fn get_log_with_time(
+        &self,
+        key: &str,
+    ) -> Result<(VecDeque<i64>, time::Tm), SlicedError>;
+
     /// Logs a debug message to the data store.
     fn log_debug(&self, message: &str);
 
@@ -41,6 +68,10 @@ pub trait Store {
         value: i64,
         ttl: time::Duration,
     ) -> Result<bool, SlicedError>;
+
+    /// Tells the store which quota the values written from now on belong to.
+    /// Stores that have nowhere to keep it are free to ignore it.
+    fn use_quota(&mut self, _quota: &RateQuota) {}
 }
 
 /// `MemoryStore` is a simple implementation of Store that persists data in an in-memory
@@ -48,8 +79,9 @@ pub trait Store {
 ///
 /// Note that the implementation is currently not thread-safe and will need a mutex added
 /// if it's ever used for anything serious.
-#[derive(Default)]
 pub struct MemoryStore {
+    clock:   Box<Clock>,
+    logs:    HashMap<String, VecDeque<i64>>,
     map:     HashMap<String, i64>,
     verbose: bool,
 }
@@ -61,8 +93,28 @@ impl MemoryStore {
 
     pub fn new_verbose() -> MemoryStore {
         MemoryStore {
-            map:     HashMap::new(),
             verbose: true,
+            ..Self::default()
+        }
+    }
+
+    /// Creates a store that tells the time with the given clock instead of the
+    /// system's.
+    pub fn with_clock(clock: Box<Clock>) -> MemoryStore {
+        MemoryStore {
+            clock,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore {
+            clock:   Box::new(clock::SystemClock),
+            logs:    HashMap::new(),
+            map:     HashMap::new(),
+            verbose: false,
         }
     }
 }
@@ -84,13 +136,41 @@ impl Store for MemoryStore {
         Ok(true)
     }
 
+    fn compare_and_swap_log_with_ttl(
+        &mut self,
+        key: &str,
+        old: &VecDeque<i64>,
+        new: VecDeque<i64>,
+        _: time::Duration,
+    ) -> Result<bool, SlicedError> {
+        if self.logs.get(key).unwrap_or(&VecDeque::new()) != old {
+            return Ok(false);
+        }
+
+        self.logs.insert(String::from(key), new);
+        Ok(true)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, SlicedError> {
+        let log = self.logs.remove(key).is_some();
+        Ok(self.map.remove(key).is_some() || log)
+    }
+
     fn get_with_time(&self, key: &str) -> Result<(i64, time::Tm), SlicedError> {
         match self.map.get(key) {
-            Some(n) => Ok((*n, time::now_utc())),
-            None => Ok((-1, time::now_utc())),
+            Some(n) => Ok((*n, self.clock.now())),
+            None => Ok((-1, self.clock.now())),
         }
     }
 
+    fn get_log_with_time(
+        &self,
+        key: &str,
+    ) -> Result<(VecDeque<i64>, time::Tm), SlicedError> {
+        let log = self.logs.get(key).cloned().unwrap_or_default();
+        Ok((log, self.clock.now()))
+    }
+
     fn log_debug(&self, message: &str) {
         if self.verbose {
             println!("memory_store: {}", message);
@@ -117,13 +197,204 @@ impl Store for MemoryStore {
 /// that it's designed to run from within a Redis runtime. This allows us to
 /// cut some corners around atomicity because we can safety assume that all
 /// operations will be atomic.
+///
+/// Values are kept in the native `state::State` data type rather than as
+/// strings, so nothing needs to be parsed or formatted on the way in or out.
+/// The last key used stays open so that the read and the write a limiter does
+/// on the same key only open it once.
+///
+/// A read-only store opens keys for reading only and refuses to write, which
+/// lets it serve readonly commands, including on replicas.
 pub struct InternalRedisStore<'a> {
-    r: &'a redis::Redis,
+    r:         &'a redis::Redis,
+    clock:     &'a Clock,
+    key:       RefCell<Option<(String, redis::RedisKeyWritable)>>,
+    quota:     Option<State>,
+    read_only: bool,
 }
 
 impl<'a> InternalRedisStore<'a> {
     pub fn new(r: &'a redis::Redis) -> InternalRedisStore<'a> {
-        InternalRedisStore { r }
+        InternalRedisStore::with_clock(r, clock::system())
+    }
+
+    pub fn with_clock(r: &'a redis::Redis, clock: &'a Clock) -> InternalRedisStore<'a> {
+        InternalRedisStore {
+            r,
+            clock,
+            key: RefCell::new(None),
+            quota: None,
+            read_only: false,
+        }
+    }
+
+    pub fn read_only(r: &'a redis::Redis) -> InternalRedisStore<'a> {
+        InternalRedisStore {
+            read_only: true,
+            ..InternalRedisStore::new(r)
+        }
+    }
+
+    /// Reads the given key with f, reusing the open key from the previous
+    /// call if the store can write.
+    fn read_key<F, R>(&self, key: &str, f: F) -> Result<R, SlicedError>
+    where
+        F: FnOnce(&ReadKey) -> Result<R, SlicedError>,
+    {
+        if self.read_only {
+            f(&self.r.open_key(key))
+        } else {
+            self.with_key(key, |k| f(k))
+        }
+    }
+
+    /// Runs f against the given key, reusing the open key from the previous
+    /// call if it was for the same one.
+    fn with_key<F, R>(&self, key: &str, f: F) -> Result<R, SlicedError>
+    where
+        F: FnOnce(&redis::RedisKeyWritable) -> Result<R, SlicedError>,
+    {
+        if self.read_only {
+            return Err(error!("Can't write {} from a read-only command", key));
+        }
+
+        let mut open = self.key.borrow_mut();
+        let reuse = match *open {
+            Some((ref name, _)) => name == key,
+            None => false,
+        };
+        if !reuse {
+            // Close the previous key before opening the next one.
+            *open = None;
+            *open = Some((String::from(key), self.r.open_key_writable(key)));
+        }
+        f(&open.as_ref().unwrap().1)
+    }
+
+    /// Replaces the key's value, keeping the quota it's being written under
+    /// next to it.
+    fn put(
+        &self,
+        name: &str,
+        key: &redis::RedisKeyWritable,
+        value: i64,
+        ttl: time::Duration,
+    ) -> Result<(), SlicedError> {
+        let mut new = self.quota.unwrap_or_default();
+        new.value = value;
+        match key.get_value::<State>(state::state_type()) {
+            // Whatever rate feedback has adapted the key to sticks.
+            Ok(Some(state)) => {
+                new.adapted_interval = state.adapted_interval;
+                *state = new
+            }
+
+            // Also replaces a TAT that an older version left as a string.
+            _ => key.set_value(state::state_type(), Box::new(new))?,
+        }
+        key.set_expire(ttl)?;
+
+        // The value depends on the time on this server, so rather than the
+        // command that produced it, replicate the value itself along with an
+        // absolute expiry. That way replicas and AOF reloads end up with
+        // exactly the same state.
+        let expire_at = self.clock.milliseconds() + ttl.num_milliseconds();
+        self.r.replicate(
+            "CL.SETSTATE",
+            &[
+                name,
+                new.value.to_string().as_str(),
+                new.max_burst.to_string().as_str(),
+                new.emission_interval.to_string().as_str(),
+                expire_at.to_string().as_str(),
+                new.adapted_interval.to_string().as_str(),
+            ],
+        )
+    }
+
+    /// Replicates a key's TTL as an absolute time so that it doesn't matter
+    /// when the replica or AOF gets around to applying it.
+    fn replicate_expire(
+        &self,
+        name: &str,
+        ttl: time::Duration,
+    ) -> Result<(), SlicedError> {
+        let expire_at = self.clock.milliseconds() + ttl.num_milliseconds();
+        self.r
+            .replicate("PEXPIREAT", &[name, expire_at.to_string().as_str()])
+    }
+
+    /// Tells the time by the store's clock.
+    fn now(&self) -> time::Tm {
+        let now = self.clock.now();
+
+        // The clock is held where it was rather than being allowed to go
+        // backwards, which would make TATs look further away than they are.
+        if let Some(regression) = self.clock.take_regression() {
+            self.r.log(
+                redis::LogLevel::Warning,
+                &format!(
+                    "Clock went backwards by {}ms; holding time until it catches up",
+                    regression.num_milliseconds()
+                ),
+            );
+        }
+        now
+    }
+}
+
+/// `ReadKey` is what the store reads from a key, whichever mode it was opened
+/// in.
+trait ReadKey {
+    fn key_type(&self) -> redmod::KeyType;
+    fn read(&self) -> Result<Option<String>, SlicedError>;
+    fn state(&self) -> Result<Option<State>, SlicedError>;
+    fn log(&self) -> Result<Option<VecDeque<i64>>, SlicedError>;
+}
+
+impl ReadKey for redis::RedisKey {
+    fn key_type(&self) -> redmod::KeyType {
+        redis::RedisKey::key_type(self)
+    }
+
+    fn read(&self) -> Result<Option<String>, SlicedError> {
+        redis::RedisKey::read(self)
+    }
+
+    fn state(&self) -> Result<Option<State>, SlicedError> {
+        Ok(self.get_value::<State>(state::state_type())?.map(|state| *state))
+    }
+
+    fn log(&self) -> Result<Option<VecDeque<i64>>, SlicedError> {
+        Ok(self.get_value::<Log>(log::log_type())?.map(|log| log.times.clone()))
+    }
+}
+
+impl ReadKey for redis::RedisKeyWritable {
+    fn key_type(&self) -> redmod::KeyType {
+        redis::RedisKeyWritable::key_type(self)
+    }
+
+    fn read(&self) -> Result<Option<String>, SlicedError> {
+        redis::RedisKeyWritable::read(self)
+    }
+
+    fn state(&self) -> Result<Option<State>, SlicedError> {
+        Ok(self.get_value::<State>(state::state_type())?.map(|state| *state))
+    }
+
+    fn log(&self) -> Result<Option<VecDeque<i64>>, SlicedError> {
+        Ok(self.get_value::<Log>(log::log_type())?.map(|log| log.times.clone()))
+    }
+}
+
+/// Reads a key's value, or -1 if it's unset.
+fn value_of(key: &ReadKey) -> Result<i64, SlicedError> {
+    match key.key_type() {
+        // Older versions kept the TAT as a string. Keep honoring those until
+        // they're rewritten or expire.
+        redmod::KeyType::String => Ok(key.read()?.unwrap().parse::<i64>()?),
+        _ => Ok(key.state()?.map(|state| state.value).unwrap_or(-1)),
     }
 }
 
@@ -135,41 +406,74 @@ impl<'a> Store for InternalRedisStore<'a> {
         new: i64,
         ttl: time::Duration,
     ) -> Result<bool, SlicedError> {
-        let key = self.r.open_key_writable(key);
-        match key.read()? {
-            Some(s) => {
-                // While we will usually have a value here to parse, it's possible that
-                // in the case of a very fast rate the key's already been
-                // expired even since the beginning of this operation.
-                // Check whether the value is empty to handle that possibility.
-                if !s.is_empty() && s.parse::<i64>()? == old {
-                    // Still the old value: perform the swap.
-                    key.write(new.to_string().as_str())?;
-                    key.set_expire(ttl)?;
-                    Ok(true)
-                } else {
-                    // Not the old value: something else must have set it. Take no
-                    // action.
-                    Ok(false)
-                }
+        self.with_key(key, |k| {
+            // It's possible that in the case of a very fast rate the key's
+            // already been expired even since the beginning of this operation,
+            // in which case it reads as unset and won't match.
+            if value_of(k)? == old && old != -1 {
+                // Still the old value: perform the swap.
+                self.put(key, k, new, ttl)?;
+                Ok(true)
+            } else {
+                // Not the old value: something else must have set it. Take no
+                // action.
+                Ok(false)
             }
+        })
+    }
 
-            // Value wasn't set.
-            None => Ok(false),
-        }
+    fn compare_and_swap_log_with_ttl(
+        &mut self,
+        key: &str,
+        old: &VecDeque<i64>,
+        new: VecDeque<i64>,
+        ttl: time::Duration,
+    ) -> Result<bool, SlicedError> {
+        self.with_key(key, |k| {
+            let current = k.get_value::<Log>(log::log_type())?;
+            if current.map(|log| &log.times).unwrap_or(&VecDeque::new()) != old {
+                return Ok(false);
+            }
+
+            // However long the log is, it goes out as a single command that
+            // carries the absolute expiry along with every timestamp.
+            let new = Log {
+                expires_at: self.clock.milliseconds() + ttl.num_milliseconds(),
+                times:      new,
+            };
+            let mut args = vec![String::from(key)];
+            args.extend(new.to_args());
+            log::put(k, new, ttl)?;
+
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            self.r.replicate("CL.SETLOG", &args)?;
+            Ok(true)
+        })
     }
 
-    fn get_with_time(&self, key: &str) -> Result<(i64, time::Tm), SlicedError> {
-        // TODO: currently leveraging that CommandError and CellError are the
-        // same thing, but we should probably reconcile this.
-        let key = self.r.open_key(key);
-        match key.read()? {
-            Some(s) => {
-                let n = s.parse::<i64>()?;
-                Ok((n, time::now_utc()))
+    fn delete(&mut self, key: &str) -> Result<bool, SlicedError> {
+        self.with_key(key, |k| {
+            if k.key_type() == redmod::KeyType::Empty {
+                Ok(false)
+            } else {
+                k.delete()?;
+                self.r.replicate("DEL", &[key])?;
+                Ok(true)
             }
-            None => Ok((-1, time::now_utc())),
-        }
+        })
+    }
+
+    fn get_with_time(&self, key: &str) -> Result<(i64, time::Tm), SlicedError> {
+        let value = self.read_key(key, value_of)?;
+        Ok((value, self.now()))
+    }
+
+    fn get_log_with_time(
+        &self,
+        key: &str,
+    ) -> Result<(VecDeque<i64>, time::Tm), SlicedError> {
+        let log = self.read_key(key, |k| Ok(k.log()?.unwrap_or_default()))?;
+        Ok((log, self.now()))
     }
 
     fn log_debug(&self, message: &str) {
@@ -182,15 +486,20 @@ impl<'a> Store for InternalRedisStore<'a> {
         value: i64,
         ttl: time::Duration,
     ) -> Result<bool, SlicedError> {
-        let key = self.r.open_key_writable(key);
-        let res = if key.is_empty()? {
-            key.write(value.to_string().as_str())?;
-            Ok(true)
-        } else {
-            Ok(false)
-        };
-        key.set_expire(ttl)?;
-        res
+        self.with_key(key, |k| {
+            if k.key_type() == redmod::KeyType::Empty {
+                self.put(key, k, value, ttl)?;
+                Ok(true)
+            } else {
+                k.set_expire(ttl)?;
+                self.replicate_expire(key, ttl)?;
+                Ok(false)
+            }
+        })
+    }
+
+    fn use_quota(&mut self, quota: &RateQuota) {
+        self.quota = Some(State::new(0, Some(quota)));
     }
 }
 
@@ -199,6 +508,8 @@ mod tests {
     extern crate time;
 
     use crate::cell::store::*;
+    use crate::clock::ManualClock;
+    use std::collections::VecDeque;
 
     #[test]
     fn it_performs_compare_and_swap_with_ttl() {
@@ -222,6 +533,46 @@ mod tests {
         assert_eq!(false, res2.unwrap());
     }
 
+    #[test]
+    fn it_performs_compare_and_swap_log_with_ttl() {
+        let mut store = MemoryStore::default();
+        let log: VecDeque<i64> = vec![1, 2].into_iter().collect();
+
+        // An unset key only matches an empty log.
+        let res1 = store.compare_and_swap_log_with_ttl(
+            "foo",
+            &log,
+            log.clone(),
+            time::Duration::zero(),
+        );
+        assert_eq!(false, res1.unwrap());
+
+        let res2 = store.compare_and_swap_log_with_ttl(
+            "foo",
+            &VecDeque::new(),
+            log.clone(),
+            time::Duration::zero(),
+        );
+        assert_eq!(true, res2.unwrap());
+        assert_eq!(log, store.get_log_with_time("foo").unwrap().0);
+
+        assert_eq!(true, store.delete("foo").unwrap());
+        assert!(store.get_log_with_time("foo").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn it_performs_delete() {
+        let mut store = MemoryStore::default();
+
+        assert_eq!(false, store.delete("foo").unwrap());
+
+        let _ = store
+            .set_if_not_exists_with_ttl("foo", 123, time::Duration::zero())
+            .unwrap();
+        assert_eq!(true, store.delete("foo").unwrap());
+        assert_eq!(-1, store.get_with_time("foo").unwrap().0);
+    }
+
     #[test]
     fn it_performs_get_with_time() {
         let mut store = MemoryStore::default();
@@ -238,6 +589,13 @@ mod tests {
         assert_eq!(123, res2.unwrap().0);
     }
 
+    #[test]
+    fn it_tells_the_time_with_its_clock() {
+        let now = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let store = MemoryStore::with_clock(Box::new(ManualClock::new(now)));
+        assert_eq!((-1, now), store.get_with_time("foo").unwrap());
+    }
+
     #[test]
     fn it_performs_set_if_not_exists_with_ttl() {
         let mut store = MemoryStore::default();
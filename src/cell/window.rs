@@ -0,0 +1,351 @@
+extern crate time;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::store::Store;
+use super::{
+    attempts_exhausted, nanoseconds, Limiter, RateLimitResult, RateQuota, MAX_CAS_ATTEMPTS,
+};
+use crate::error::SlicedError;
+
+// The state each algorithm keeps besides the base key lives under keys that
+// are namespaced by algorithm, so that switching the algorithm of a key never
+// picks up another one's state.
+const FIXED_WINDOW_TAG: &'static str = "fw";
+const SLIDING_WINDOW_LOG_TAG: &'static str = "swl";
+const SLIDING_WINDOW_COUNTER_TAG: &'static str = "swc";
+
+/// `FixedWindowLimiter` counts units in consecutive windows that are aligned
+/// to the epoch. It's the cheapest algorithm to run, but allows up to twice
+/// the limit through when a burst straddles the boundary between two windows.
+///
+/// Each window is kept under its own key (`<key>:fw:<window number>`) which
+/// expires once the window is over.
+pub struct FixedWindowLimiter<'a, T: 'a + Store> {
+    pub store: &'a mut T,
+    limit:     i64,
+    window:    i64,
+}
+
+impl<'a, T: 'a + Store> FixedWindowLimiter<'a, T> {
+    pub fn new(store: &'a mut T, quota: &RateQuota) -> FixedWindowLimiter<'a, T> {
+        store.use_quota(quota);
+        let (limit, window) = window_for(quota);
+        FixedWindowLimiter {
+            store,
+            limit,
+            window,
+        }
+    }
+}
+
+impl<'a, T: 'a + Store> Limiter for FixedWindowLimiter<'a, T> {
+    fn rate_limit(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError> {
+        let mut i = 0;
+        loop {
+            // The window depends on the store's clock, so read it through the
+            // base key before deciding which window key to look at.
+            let (_, now) = self.store.get_with_time(key)?;
+            let now = nanoseconds(now);
+            let elapsed = now % self.window;
+            let window_key = sub_key(key, FIXED_WINDOW_TAG, now / self.window);
+            let reset_after = time::Duration::nanoseconds(self.window - elapsed);
+
+            let (count_val, _) = self.store.get_with_time(&window_key)?;
+            let used = cmp::max(count_val, 0);
+
+            let mut rlc = RateLimitResult {
+                limit:       self.limit,
+                remaining:   self.limit - used,
+                retry_after: time::Duration::seconds(-1),
+                reset_after: if used > 0 {
+                    reset_after
+                } else {
+                    time::Duration::zero()
+                },
+            };
+
+            if used + quantity > self.limit {
+                // A request larger than the whole window can never succeed,
+                // so there's no point in telling the caller to retry.
+                if quantity <= self.limit {
+                    rlc.retry_after = reset_after;
+                }
+                return Ok((true, rlc));
+            }
+
+            if quantity == 0 {
+                return Ok((false, rlc));
+            }
+
+            if put(self.store, &window_key, count_val, used + quantity, reset_after)? {
+                rlc.remaining -= quantity;
+                rlc.reset_after = reset_after;
+                return Ok((false, rlc));
+            }
+
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
+            }
+        }
+    }
+
+    fn reset(&mut self, key: &str) -> Result<bool, SlicedError> {
+        // Only the current window counts, earlier ones are left to expire.
+        let (_, now) = self.store.get_with_time(key)?;
+        let now = nanoseconds(now);
+        self.store.delete(&sub_key(key, FIXED_WINDOW_TAG, now / self.window))
+    }
+}
+
+/// `SlidingWindowLogLimiter` remembers the time of every unit admitted during
+/// the last window and only admits more once the oldest ones have aged out.
+/// It's exact, but needs storage proportional to the limit.
+///
+/// The log is a single value (`<key>:swl`) holding the timestamps oldest
+/// first, so every call reads and writes one key however large the limit is.
+pub struct SlidingWindowLogLimiter<'a, T: 'a + Store> {
+    pub store: &'a mut T,
+    limit:     i64,
+    window:    i64,
+}
+
+impl<'a, T: 'a + Store> SlidingWindowLogLimiter<'a, T> {
+    pub fn new(store: &'a mut T, quota: &RateQuota) -> SlidingWindowLogLimiter<'a, T> {
+        store.use_quota(quota);
+        let (limit, window) = window_for(quota);
+        SlidingWindowLogLimiter {
+            store,
+            limit,
+            window,
+        }
+    }
+}
+
+impl<'a, T: 'a + Store> Limiter for SlidingWindowLogLimiter<'a, T> {
+    fn rate_limit(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError> {
+        let log_key = log_key(key);
+        let window = time::Duration::nanoseconds(self.window);
+
+        let mut i = 0;
+        loop {
+            let (old, now) = self.store.get_log_with_time(&log_key)?;
+            let now = nanoseconds(now);
+            let cutoff = now - self.window;
+
+            // Units that have aged out of the window no longer count. They're
+            // only dropped from the stored log the next time it's written.
+            let log: VecDeque<i64> =
+                old.iter().cloned().skip_while(|t| *t <= cutoff).collect();
+            let used = log.len() as i64;
+
+            // The limit may have been lowered since the log was written.
+            let free = cmp::max(self.limit - used, 0);
+
+            let mut rlc = RateLimitResult {
+                limit:       self.limit,
+                remaining:   free,
+                retry_after: time::Duration::seconds(-1),
+                reset_after: match log.back() {
+                    Some(newest) => time::Duration::nanoseconds(newest - cutoff),
+                    None => time::Duration::zero(),
+                },
+            };
+
+            if quantity > free {
+                if quantity <= self.limit {
+                    // Enough of the oldest units have to age out first.
+                    let blocking = log[(used + quantity - self.limit - 1) as usize];
+                    rlc.retry_after = time::Duration::nanoseconds(blocking - cutoff);
+                }
+                return Ok((true, rlc));
+            }
+
+            if quantity == 0 {
+                return Ok((false, rlc));
+            }
+
+            let mut new = log;
+            for _ in 0..quantity {
+                new.push_back(now);
+            }
+            if self
+                .store
+                .compare_and_swap_log_with_ttl(&log_key, &old, new, window)?
+            {
+                rlc.remaining -= quantity;
+                rlc.reset_after = window;
+                return Ok((false, rlc));
+            }
+
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
+            }
+        }
+    }
+
+    fn reset(&mut self, key: &str) -> Result<bool, SlicedError> {
+        self.store.delete(&log_key(key))
+    }
+}
+
+/// `SlidingWindowCounterLimiter` approximates a sliding log by weighting the
+/// count of the previous fixed window by how much of it still overlaps the
+/// sliding window. It only needs two counters per key and smooths out the
+/// boundary bursts of a plain fixed window.
+pub struct SlidingWindowCounterLimiter<'a, T: 'a + Store> {
+    pub store: &'a mut T,
+    limit:     i64,
+    window:    i64,
+}
+
+impl<'a, T: 'a + Store> SlidingWindowCounterLimiter<'a, T> {
+    pub fn new(store: &'a mut T, quota: &RateQuota) -> SlidingWindowCounterLimiter<'a, T> {
+        store.use_quota(quota);
+        let (limit, window) = window_for(quota);
+        SlidingWindowCounterLimiter {
+            store,
+            limit,
+            window,
+        }
+    }
+}
+
+impl<'a, T: 'a + Store> Limiter for SlidingWindowCounterLimiter<'a, T> {
+    fn rate_limit(
+        &mut self,
+        key: &str,
+        quantity: i64,
+    ) -> Result<(bool, RateLimitResult), SlicedError> {
+        let mut i = 0;
+        loop {
+            let (_, now) = self.store.get_with_time(key)?;
+            let now = nanoseconds(now);
+            let elapsed = now % self.window;
+            let current_key = sub_key(key, SLIDING_WINDOW_COUNTER_TAG, now / self.window);
+            let previous_key =
+                sub_key(key, SLIDING_WINDOW_COUNTER_TAG, now / self.window - 1);
+
+            let (current_val, _) = self.store.get_with_time(&current_key)?;
+            let (previous_val, _) = self.store.get_with_time(&previous_key)?;
+            let current = cmp::max(current_val, 0);
+            let previous = cmp::max(previous_val, 0) as f64;
+
+            // The fraction of the previous window still covered by the sliding
+            // window ending now.
+            let overlap = (self.window - elapsed) as f64 / self.window as f64;
+            let estimate = previous * overlap + current as f64;
+
+            let mut rlc = RateLimitResult {
+                limit:       self.limit,
+                remaining:   cmp::max(self.limit - estimate.ceil() as i64, 0),
+                retry_after: time::Duration::seconds(-1),
+                reset_after: if current > 0 {
+                    time::Duration::nanoseconds(2 * self.window - elapsed)
+                } else if previous > 0.0 {
+                    time::Duration::nanoseconds(self.window - elapsed)
+                } else {
+                    time::Duration::zero()
+                },
+            };
+
+            if estimate + quantity as f64 > self.limit as f64 {
+                if quantity <= self.limit {
+                    let headroom = (self.limit - current - quantity) as f64;
+                    let wait = if headroom >= 0.0 {
+                        // Enough room in this window once the previous one
+                        // has slid far enough out of view.
+                        self.window as f64 * (1.0 - headroom / previous) - elapsed as f64
+                    } else {
+                        // Have to wait for the next window and for enough of
+                        // this one to slide out of view.
+                        (self.window - elapsed) as f64
+                            + self.window as f64
+                                * (1.0 - (self.limit - quantity) as f64 / current as f64)
+                    };
+                    rlc.retry_after = time::Duration::nanoseconds(wait.ceil() as i64);
+                }
+                return Ok((true, rlc));
+            }
+
+            if quantity == 0 {
+                return Ok((false, rlc));
+            }
+
+            // The current window's count has to outlive it since it'll be the
+            // previous window for the one that comes next.
+            let ttl = time::Duration::nanoseconds(2 * self.window - elapsed);
+            if put(self.store, &current_key, current_val, current + quantity, ttl)? {
+                rlc.remaining = cmp::max(
+                    self.limit - (estimate + quantity as f64).ceil() as i64,
+                    0,
+                );
+                rlc.reset_after = ttl;
+                return Ok((false, rlc));
+            }
+
+            i += 1;
+            if i > MAX_CAS_ATTEMPTS {
+                return Err(attempts_exhausted());
+            }
+        }
+    }
+
+    fn reset(&mut self, key: &str) -> Result<bool, SlicedError> {
+        // The previous window still weighs on the current one, so both go.
+        let (_, now) = self.store.get_with_time(key)?;
+        let now = nanoseconds(now);
+        let current_key = sub_key(key, SLIDING_WINDOW_COUNTER_TAG, now / self.window);
+        let previous_key =
+            sub_key(key, SLIDING_WINDOW_COUNTER_TAG, now / self.window - 1);
+        let current = self.store.delete(&current_key)?;
+        let previous = self.store.delete(&previous_key)?;
+        Ok(current || previous)
+    }
+}
+
+/// Derives the number of units admitted per window along with the window's
+/// length in nanoseconds from a quota.
+fn window_for(quota: &RateQuota) -> (i64, i64) {
+    let limit = quota.max_burst + 1;
+    let window = quota.max_rate.period.num_nanoseconds().unwrap() * limit;
+    (limit, window)
+}
+
+/// Names the key that holds the sliding window log of key.
+fn log_key(key: &str) -> String {
+    format!("{}:{}", key, SLIDING_WINDOW_LOG_TAG)
+}
+
+/// Names a key that holds part of the state an algorithm keeps for key.
+fn sub_key<N: fmt::Display>(key: &str, tag: &str, n: N) -> String {
+    format!("{}:{}:{}", key, tag, n)
+}
+
+/// Replaces a value that was read earlier, creating the key if it was
+/// missing. Returns false if the key changed in the meantime.
+fn put<T: Store>(
+    store: &mut T,
+    key: &str,
+    old: i64,
+    new: i64,
+    ttl: time::Duration,
+) -> Result<bool, SlicedError> {
+    if old == -1 {
+        store.set_if_not_exists_with_ttl(key, new, ttl)
+    } else {
+        store.compare_and_swap_with_ttl(key, old, new, ttl)
+    }
+}
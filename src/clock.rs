@@ -0,0 +1,188 @@
+extern crate time;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use spin::Mutex;
+
+/// `Clock` tells the time to the rate limiter's stores and the stream ID
+/// generator, so that tests can control it and so that the module can cope
+/// with the system clock misbehaving.
+pub trait Clock {
+    /// Returns the current time in UTC.
+    fn now(&self) -> time::Tm;
+
+    /// Returns how far the clock has gone backwards since the last time this
+    /// was called, or None if it hasn't. Clocks that can't tell always return
+    /// None.
+    fn take_regression(&self) -> Option<time::Duration> {
+        None
+    }
+
+    /// Returns the current time in milliseconds since the epoch.
+    fn milliseconds(&self) -> i64 {
+        let ts = self.now().to_timespec();
+        ts.sec * 1000 + i64::from(ts.nsec) / 1_000_000
+    }
+}
+
+impl<C: Clock> Clock for Rc<C> {
+    fn now(&self) -> time::Tm {
+        (**self).now()
+    }
+
+    fn take_regression(&self) -> Option<time::Duration> {
+        (**self).take_regression()
+    }
+}
+
+impl<'a, C: Clock + ?Sized> Clock for &'a C {
+    fn now(&self) -> time::Tm {
+        (**self).now()
+    }
+
+    fn take_regression(&self) -> Option<time::Duration> {
+        (**self).take_regression()
+    }
+}
+
+/// `SystemClock` reads the wall clock of the machine.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Tm {
+        time::now_utc()
+    }
+}
+
+/// `ManualClock` only moves when it's told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Cell<time::Tm>,
+}
+
+impl ManualClock {
+    pub fn new(now: time::Tm) -> ManualClock {
+        ManualClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: time::Tm) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, by: time::Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> time::Tm {
+        self.now.get()
+    }
+}
+
+/// `SkewDetectingClock` keeps another clock from going backwards. When the
+/// underlying clock reads earlier than a time already handed out (after an NTP
+/// correction, say) the latest time is repeated until it catches up, and the
+/// regression is kept so that it can be reported.
+pub struct SkewDetectingClock<C: Clock> {
+    clock: C,
+    skew:  Mutex<Skew>,
+}
+
+#[derive(Default)]
+struct Skew {
+    latest:     Option<time::Tm>,
+    regression: Option<time::Duration>,
+}
+
+impl<C: Clock> SkewDetectingClock<C> {
+    pub fn new(clock: C) -> SkewDetectingClock<C> {
+        SkewDetectingClock {
+            clock,
+            skew: Mutex::new(Skew::default()),
+        }
+    }
+}
+
+impl<C: Clock> Clock for SkewDetectingClock<C> {
+    fn now(&self) -> time::Tm {
+        let now = self.clock.now();
+        let mut skew = self.skew.lock();
+        match skew.latest {
+            Some(latest) if latest > now => {
+                let regression = latest - now;
+                if skew.regression.map(|r| r < regression).unwrap_or(true) {
+                    skew.regression = Some(regression);
+                }
+                latest
+            }
+            _ => {
+                skew.latest = Some(now);
+                now
+            }
+        }
+    }
+
+    fn take_regression(&self) -> Option<time::Duration> {
+        self.skew.lock().regression.take()
+    }
+}
+
+lazy_static! {
+    static ref SYSTEM: SkewDetectingClock<SystemClock> =
+        SkewDetectingClock::new(SystemClock);
+}
+
+/// Returns the clock the module runs on: the system clock, kept from going
+/// backwards.
+pub fn system() -> &'static SkewDetectingClock<SystemClock> {
+    &SYSTEM
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::clock::*;
+
+    #[test]
+    fn it_only_moves_a_manual_clock_when_told() {
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let clock = ManualClock::new(start);
+        assert_eq!(start, clock.now());
+        assert_eq!(1_000_000_000_000, clock.milliseconds());
+
+        clock.advance(time::Duration::milliseconds(1500));
+        assert_eq!(1_000_000_001_500, clock.milliseconds());
+
+        clock.set(start);
+        assert_eq!(start, clock.now());
+    }
+
+    #[test]
+    fn it_holds_time_when_the_clock_goes_backwards() {
+        let start = time::at_utc(time::Timespec::new(1_000_000_000, 0));
+        let manual = Rc::new(ManualClock::new(start));
+        let clock = SkewDetectingClock::new(manual.clone());
+
+        assert_eq!(start, clock.now());
+        assert_eq!(None, clock.take_regression());
+
+        manual.advance(time::Duration::seconds(-2));
+        assert_eq!(start, clock.now());
+        manual.advance(time::Duration::seconds(1));
+        assert_eq!(start, clock.now());
+
+        // The largest regression is reported, and only once.
+        assert_eq!(Some(time::Duration::seconds(2)), clock.take_regression());
+        assert_eq!(None, clock.take_regression());
+
+        // Time moves on once the clock has caught up.
+        manual.advance(time::Duration::seconds(2));
+        assert_eq!(start + time::Duration::seconds(1), clock.now());
+    }
+}
@@ -0,0 +1,124 @@
+extern crate libc;
+
+use crate::error::SlicedError;
+use crate::redis::{Command, Redis};
+use crate::redis::redmod;
+
+use crate::cell;
+use crate::cell::policy;
+use crate::cell::store;
+
+use super::parse_i64;
+use super::stats;
+use super::throttle::effective_quota;
+
+pub fn load(
+    ctx: *mut redmod::RedisModuleCtx,
+    _argv: *mut *mut redmod::RedisModuleString,
+    _argc: libc::c_int,
+) -> redmod::Status {
+    let command = CallCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Call_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    return redmod::Status::Ok
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Call_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&CallCommand {}, ctx, argv, argc)
+}
+
+// CallCommand runs another Redis command only if a rate limit allows it, so
+// that expensive commands can be limited without changing the clients that
+// send them.
+pub struct CallCommand {}
+
+impl CallCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <policy> -- <command> [<arg> ...] or {} <key> \
+             <max_burst> <count per period> <period> -- <command> [<arg> ...]",
+            self.name(),
+            self.name()
+        )
+    }
+}
+
+impl Command for CallCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.call"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let separator = args
+            .iter()
+            .position(|arg| *arg == "--")
+            .ok_or_else(|| self.usage())?;
+        if separator + 1 >= args.len() {
+            return Err(self.usage());
+        }
+
+        let key = args[1];
+        let (policy_name, policy) = match separator {
+            3 => (Some(args[2]), policy::get(args[2])?),
+            5 => (
+                None,
+                policy::Policy {
+                    max_burst: parse_i64(args[2])?,
+                    count:     parse_i64(args[3])?,
+                    period:    parse_i64(args[4])?,
+                    algorithm: cell::Algorithm::Gcra,
+                    adaptive:  None,
+                },
+            ),
+            _ => return Err(self.usage()),
+        };
+
+        let (throttled, result) = {
+            let quota = effective_quota(&r, key, &policy)?;
+            let mut store = store::InternalRedisStore::new(&r);
+            let mut limiter = policy.algorithm.limiter(&mut store, &quota);
+            limiter.rate_limit(key, 1)?
+        };
+        stats::record(policy_name, key, throttled, 1);
+
+        // The error code lets clients tell a throttled call apart from one
+        // that failed, and the rest can be parsed for when to try again.
+        if throttled {
+            return r.reply_error(&format!(
+                "THROTTLED retry_after_ms={} reset_after_ms={} limit={} remaining={}",
+                result.retry_after.num_milliseconds(),
+                result.reset_after.num_milliseconds(),
+                result.limit,
+                result.remaining
+            ));
+        }
+
+        let reply = r.call_replicated(args[separator + 1], &args[separator + 2..])?;
+        r.reply(&reply)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
@@ -0,0 +1,325 @@
+extern crate libc;
+
+use std::collections::BTreeMap;
+
+use crate::error::SlicedError;
+use crate::redis::{Command, Redis};
+use crate::redis::redmod;
+
+use crate::cell::concurrency;
+
+use super::parse_i64;
+
+pub fn load(
+    ctx: *mut redmod::RedisModuleCtx,
+    _argv: *mut *mut redmod::RedisModuleString,
+    _argc: libc::c_int,
+) -> redmod::Status {
+    let command = AcquireCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Acquire_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = RenewCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Renew_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = ReleaseCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Release_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = SetLeaseCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(SetLease_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    return redmod::Status::Ok
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Acquire_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&AcquireCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Renew_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&RenewCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Release_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ReleaseCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn SetLease_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&SetLeaseCommand {}, ctx, argv, argc)
+}
+
+// AcquireCommand takes one of a limited number of concurrent leases on a key.
+pub struct AcquireCommand {}
+
+impl Command for AcquireCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.acquire"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 4 {
+            return Err(error!("Usage: {} <key> <max> <lease_ms>", self.name()));
+        }
+
+        let key = args[1];
+        let max = parse_i64(args[2])?;
+        let lease_ms = parse_i64(args[3])?;
+        if max < 1 || lease_ms < 1 {
+            return Err(error!("Max and lease must both be positive"));
+        }
+
+        let now = redmod::milliseconds();
+        let (token, result) =
+            update_semaphore(&r, key, |s| s.acquire(max, lease_ms, now))?;
+        if token.is_some() {
+            expire_after(&r, key, lease_ms);
+        }
+
+        reply_result(&r, token, &result)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// RenewCommand extends a lease that's still outstanding.
+pub struct RenewCommand {}
+
+impl Command for RenewCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.renew"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 4 {
+            return Err(error!("Usage: {} <key> <token> <lease_ms>", self.name()));
+        }
+
+        let key = args[1];
+        let token = parse_i64(args[2])?;
+        let lease_ms = parse_i64(args[3])?;
+        if lease_ms < 1 {
+            return Err(error!("Lease must be positive"));
+        }
+
+        let now = redmod::milliseconds();
+        let (renewed, result) = update_semaphore(&r, key, |s| {
+            (s.renew(token, lease_ms, now), s.result(now))
+        })?;
+        if renewed {
+            expire_after(&r, key, lease_ms);
+        }
+
+        reply_result(&r, if renewed { Some(token) } else { None }, &result)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// ReleaseCommand gives back a lease so its slot can be used by someone else.
+pub struct ReleaseCommand {}
+
+impl Command for ReleaseCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.release"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 3 {
+            return Err(error!("Usage: {} <key> <token>", self.name()));
+        }
+
+        let token = parse_i64(args[2])?;
+        let now = redmod::milliseconds();
+        let released = update_semaphore(&r, args[1], |s| s.release(token, now))?;
+        r.reply_integer(if released { 1 } else { 0 })
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// SetLeaseCommand puts back a semaphore as is, replacing any leases it had.
+// It's what changes to semaphores are replicated as, and what the semaphore
+// data type rewrites itself into in the AOF. It isn't meant to be called by
+// clients.
+pub struct SetLeaseCommand {}
+
+impl Command for SetLeaseCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.setlease"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 4 || args.len() % 2 != 0 {
+            return Err(error!(
+                "Usage: {} <key> <max> <next token> [<token> <expires at ms> ...]",
+                self.name()
+            ));
+        }
+
+        let max = parse_i64(args[2])?;
+        let next_token = parse_i64(args[3])?;
+        let mut leases = BTreeMap::new();
+        for lease in args[4..].chunks(2) {
+            leases.insert(parse_i64(lease[0])?, parse_i64(lease[1])?);
+        }
+
+        // Leases are put back even if they've expired by this instance's clock
+        // so that it ends up exactly like the one the command came from. Their
+        // key expires along with them anyway.
+        concurrency::with_semaphore(&r, args[1], |s| {
+            s.max = max;
+            s.next_token = next_token;
+            s.leases = leases;
+        })?;
+        r.replicate_verbatim()?;
+        r.reply_string("OK")
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// Runs f against the semaphore at key like concurrency::with_semaphore, and
+// replicates the semaphore that results if f changed it. Replicas and the AOF
+// are given leases with absolute expiries rather than the command so that
+// they don't depend on the clock of whoever applies them.
+fn update_semaphore<F, R>(r: &Redis, key: &str, f: F) -> Result<R, SlicedError>
+where
+    F: FnOnce(&mut concurrency::Semaphore) -> R,
+{
+    let (res, args) = concurrency::with_semaphore(r, key, |s| {
+        let before = s.clone();
+        let res = f(s);
+        (res, if *s != before { Some(s.to_args()) } else { None })
+    })?;
+
+    if let Some(args) = args {
+        let mut replicated: Vec<&str> = vec![key];
+        replicated.extend(args.iter().map(|arg| arg.as_str()));
+        r.replicate("CL.SETLEASE", &replicated)?;
+    }
+    Ok(res)
+}
+
+// Frees the slots of leases on key that haven't been released or renewed by
+// the time lease_ms is up. Timers for leases that were released or renewed in
+// the meantime find nothing to do.
+fn expire_after(r: &Redis, key: &str, lease_ms: i64) {
+    let key = String::from(key);
+    r.start_timer(lease_ms, move |r| {
+        let now = redmod::milliseconds();
+        let _ = update_semaphore(&r, &key, |s| s.expire(now));
+    });
+}
+
+// Reply with an array laid out like the one CL.THROTTLE replies with, except
+// that durations are in milliseconds and the lease's token is appended (nil if
+// no lease was granted).
+fn reply_result(
+    r: &Redis,
+    token: Option<i64>,
+    result: &concurrency::ConcurrencyResult,
+) -> Result<(), SlicedError> {
+    r.reply_array(6)?;
+    r.reply_integer(if token.is_none() { 1 } else { 0 })?;
+    r.reply_integer(result.limit)?;
+    r.reply_integer(result.remaining)?;
+    r.reply_integer(result.retry_after.num_milliseconds())?;
+    r.reply_integer(result.reset_after.num_milliseconds())?;
+    match token {
+        Some(token) => r.reply_integer(token),
+        None => r.reply_null(),
+    }
+}
@@ -1,10 +1,15 @@
 extern crate libc;
 extern crate time;
 
+pub mod call;
 pub mod cmd;
+pub mod concurrency;
+pub mod policy;
+pub mod stats;
 pub mod throttle;
 pub mod stream;
 pub mod version;
+pub mod wait;
 
 use crate::error::{SlicedError};
 
@@ -0,0 +1,177 @@
+extern crate libc;
+extern crate time;
+
+use crate::error::SlicedError;
+use crate::redis::{Command, Redis};
+use crate::redis::redmod;
+
+use crate::cell;
+use crate::cell::policy;
+
+use super::parse_i64;
+
+pub fn load(
+    ctx: *mut redmod::RedisModuleCtx,
+    _argv: *mut *mut redmod::RedisModuleString,
+    _argc: libc::c_int,
+) -> redmod::Status {
+    let command = PolicyCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Policy_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    return redmod::Status::Ok
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Policy_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&PolicyCommand {}, ctx, argv, argc)
+}
+
+// PolicyCommand manages named quotas that CL.THROTTLE can refer to with its
+// POLICY form so that limits can be changed without redeploying clients.
+pub struct PolicyCommand {}
+
+impl PolicyCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} SET <name> <max_burst> <count per period> <period> \
+             [ALGO <algorithm>] [ADAPT <min count> <max count>] | GET <name> | \
+             DEL <name> | LIST",
+            self.name()
+        )
+    }
+}
+
+impl Command for PolicyCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.policy"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 2 {
+            return Err(self.usage());
+        }
+
+        match args[1].to_lowercase().as_str() {
+            "set" => {
+                if args.len() < 6 {
+                    return Err(self.usage());
+                }
+
+                let mut policy = policy::Policy {
+                    max_burst: parse_i64(args[3])?,
+                    count:     parse_i64(args[4])?,
+                    period:    parse_i64(args[5])?,
+                    algorithm: cell::Algorithm::default(),
+                    adaptive:  None,
+                };
+
+                let mut i = 6;
+                while i < args.len() {
+                    if args[i].eq_ignore_ascii_case("algo") {
+                        let name = args.get(i + 1).ok_or_else(|| self.usage())?;
+                        policy.algorithm = cell::Algorithm::parse(name)?;
+                        i += 2;
+                    } else if args[i].eq_ignore_ascii_case("adapt") {
+                        if i + 2 >= args.len() {
+                            return Err(self.usage());
+                        }
+                        let min = parse_i64(args[i + 1])?;
+                        let max = parse_i64(args[i + 2])?;
+                        let bounds = policy.bounds(min, max);
+                        if bounds.min < 1 || bounds.max < bounds.min {
+                            return Err(error!("Bounds must satisfy 1 <= min <= max"));
+                        }
+                        if bounds.period < time::Duration::seconds(1) {
+                            return Err(error!("Period must be at least 1 second"));
+                        }
+                        policy.adaptive = Some(bounds);
+                        i += 3;
+                    } else {
+                        return Err(self.usage());
+                    }
+                }
+
+                policy::check_persistence(&r)?;
+                policy::set(args[2], policy);
+                r.replicate_verbatim()?;
+                r.reply_string("OK")
+            }
+            "get" => {
+                if args.len() != 3 {
+                    return Err(self.usage());
+                }
+
+                // A missing policy is a nil reply rather than an error here.
+                match policy::find(args[2]) {
+                    Some(policy) => {
+                        r.reply_array(6)?;
+                        r.reply_integer(policy.max_burst)?;
+                        r.reply_integer(policy.count)?;
+                        r.reply_integer(policy.period)?;
+                        r.reply_string(policy.algorithm.name())?;
+
+                        // The bounds that CL.FEEDBACK keeps the rate of keys
+                        // within in counts per period, or nils if not adaptive.
+                        match policy.adaptive {
+                            Some(ref bounds) => {
+                                r.reply_integer(bounds.min)?;
+                                r.reply_integer(bounds.max)
+                            }
+                            None => {
+                                r.reply_null()?;
+                                r.reply_null()
+                            }
+                        }
+                    }
+                    None => r.reply_null(),
+                }
+            }
+            "del" => {
+                if args.len() != 3 {
+                    return Err(self.usage());
+                }
+
+                let existed = policy::delete(args[2]);
+                r.replicate_verbatim()?;
+                r.reply_integer(if existed { 1 } else { 0 })
+            }
+            "list" => {
+                if args.len() != 2 {
+                    return Err(self.usage());
+                }
+
+                let names = policy::names();
+                r.reply_array(names.len() as i64)?;
+                for name in names.iter() {
+                    r.reply_string(name)?;
+                }
+                Ok(())
+            }
+            _ => Err(self.usage()),
+        }
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
@@ -0,0 +1,118 @@
+extern crate libc;
+
+use spin::Mutex;
+
+use crate::error::SlicedError;
+use crate::redis::{Command, Redis};
+use crate::redis::redmod;
+
+use crate::cell::stats;
+
+lazy_static! {
+    static ref STATS: Mutex<stats::Stats> = Mutex::new(stats::Stats::new());
+}
+
+/// Records a rate limiting decision on key. policy is the name of the policy
+/// the quota came from, if it came from one.
+pub fn record(policy: Option<&str>, key: &str, throttled: bool, quantity: i64) {
+    STATS.lock().record(policy, key, throttled, quantity);
+}
+
+pub fn load(
+    ctx: *mut redmod::RedisModuleCtx,
+    _argv: *mut *mut redmod::RedisModuleString,
+    _argc: libc::c_int,
+) -> redmod::Status {
+    let command = StatsCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Stats_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    return redmod::Status::Ok
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Stats_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&StatsCommand {}, ctx, argv, argc)
+}
+
+// StatsCommand reports how many calls were allowed and denied and which keys
+// were throttled the most since the module was loaded or the stats were last
+// reset. Stats are kept per server and aren't persisted or replicated.
+pub struct StatsCommand {}
+
+impl Command for StatsCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.stats"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let reset = match args.len() {
+            1 => false,
+            2 if args[1].eq_ignore_ascii_case("reset") => true,
+            _ => return Err(error!("Usage: {} [RESET]", self.name())),
+        };
+
+        let mut stats = STATS.lock();
+        if reset {
+            stats.reset();
+            return r.reply_string("OK");
+        }
+
+        r.reply_array(6)?;
+        r.reply_string("overall")?;
+        reply_counters(&r, &stats.overall)?;
+
+        r.reply_string("policies")?;
+        r.reply_array(2 * stats.policies.len() as i64)?;
+        for (name, counters) in stats.policies.iter() {
+            r.reply_string(name)?;
+            reply_counters(&r, counters)?;
+        }
+
+        // Each key comes with how often it was throttled and by how much that
+        // may be overestimated.
+        let top = stats.throttled_keys.top();
+        r.reply_string("throttled_keys")?;
+        r.reply_array(top.len() as i64)?;
+        for (key, estimate) in top {
+            r.reply_array(3)?;
+            r.reply_string(key)?;
+            r.reply_integer(estimate.count)?;
+            r.reply_integer(estimate.error)?;
+        }
+        Ok(())
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+// Reply with allowed calls, denied calls, allowed quantity and denied quantity
+// in that order.
+fn reply_counters(r: &Redis, counters: &stats::Counters) -> Result<(), SlicedError> {
+    r.reply_array(4)?;
+    r.reply_integer(counters.allowed_calls)?;
+    r.reply_integer(counters.denied_calls)?;
+    r.reply_integer(counters.allowed_quantity)?;
+    r.reply_integer(counters.denied_quantity)
+}
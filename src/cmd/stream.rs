@@ -1,9 +1,11 @@
 extern crate libc;
-extern crate time;
 
-use crate::error::SlicedError;
-use crate::redis::{Command, Redis};
+use crate::redis::Command;
 use crate::redis::redmod;
+use crate::stream::cmd::{
+    AckCommand, AddCommand, CheckCommand, ClaimCommand, ConfigCommand, GroupCommand,
+    RangeCommand, ReadCommand, ReadGroupCommand, RevRangeCommand,
+};
 
 ///
 pub fn load(
@@ -11,7 +13,7 @@ pub fn load(
     _argv: *mut *mut redmod::RedisModuleString,
     _argc: libc::c_int,
 ) -> redmod::Status {
-    let command = AddCommand {};
+    let command = AddCommand;
     if redmod::create_command(
         ctx,
         format!("{}\0", command.name()).as_ptr(),
@@ -23,6 +25,119 @@ pub fn load(
     ) == redmod::Status::Err {
         return redmod::Status::Err;
     }
+
+    let command = ConfigCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamConfig_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = RangeCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamRange_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = RevRangeCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamRevRange_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = ReadCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamRead_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    let command = GroupCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamGroup_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    let command = ReadGroupCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamReadGroup_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    let command = AckCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamAck_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+    let command = ClaimCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamClaim_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = CheckCommand;
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(StreamCheck_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
     return redmod::Status::Ok;
 }
 
@@ -34,37 +149,104 @@ pub extern "C" fn StreamAdd_RedisCommand(
     argv: *mut *mut redmod::RedisModuleString,
     argc: libc::c_int,
 ) -> redmod::Status {
-    Command::harness(&AddCommand {}, ctx, argv, argc)
+    Command::harness(&AddCommand, ctx, argv, argc)
 }
 
-struct AddCommand {}
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamConfig_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ConfigCommand, ctx, argv, argc)
+}
 
-impl AddCommand {
-    extern "C" fn timer_callback(_value: *mut libc::c_void) {
-//        log_debug!(self, "timer_callback() called");
-    }
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRange_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&RangeCommand, ctx, argv, argc)
 }
 
-impl Command for AddCommand {
-    // Should return the name of the command to be registered.
-    fn name(&self) -> &'static str {
-        "mo.add"
-    }
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRevRange_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&RevRangeCommand, ctx, argv, argc)
+}
 
-    fn run(&self, r: Redis, _: &[&str]) -> Result<(), SlicedError> {
-        let _time_reply = r.call("GET", &["hi"]).unwrap();
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRead_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ReadCommand, ctx, argv, argc)
+}
 
-        // Get throttle key
-        r.reply_array(4)?;
-        r.reply_integer(1)?;
-        r.reply_integer(2)?;
-        r.reply_integer(5)?;
-        r.reply_integer(10)?;
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamGroup_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&GroupCommand, ctx, argv, argc)
+}
 
-        Ok(())
-    }
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamReadGroup_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ReadGroupCommand, ctx, argv, argc)
+}
 
-    fn str_flags(&self) -> &'static str {
-        "write"
-    }
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamAck_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&AckCommand, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamClaim_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ClaimCommand, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamCheck_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&CheckCommand, ctx, argv, argc)
 }
@@ -7,9 +7,15 @@ use crate::redis::{Command, Redis};
 use crate::redis::redmod;
 
 use crate::cell;
+use crate::cell::adaptive;
+use crate::cell::log;
+use crate::cell::policy;
+use crate::cell::state;
 use crate::cell::store;
 
 use super::parse_i64;
+use super::stats;
+use super::wait;
 
 pub fn load(
     ctx: *mut redmod::RedisModuleCtx,
@@ -28,6 +34,123 @@ pub fn load(
     ) == redmod::Status::Err {
         return redmod::Status::Err;
     }
+
+    let command = MultiThrottleCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(MultiThrottle_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = LeaseCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Lease_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = ShapeCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Shape_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = InspectCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Inspect_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = RefundCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Refund_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = ResetCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Reset_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = FeedbackCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(Feedback_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = SetStateCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(SetState_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    let command = SetLogCommand {};
+    if redmod::create_command(
+        ctx,
+        format!("{}\0", command.name()).as_ptr(),
+        Some(SetLog_RedisCommand),
+        format!("{}\0", command.str_flags()).as_ptr(),
+        0,
+        0,
+        0,
+    ) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
     return redmod::Status::Ok
 }
 
@@ -42,9 +165,122 @@ pub extern "C" fn Throttle_RedisCommand(
     Command::harness(&ThrottleCommand {}, ctx, argv, argc)
 }
 
-// ThrottleCommand provides GCRA rate limiting as a command in Redis.
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn MultiThrottle_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&MultiThrottleCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Lease_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&LeaseCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Shape_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ShapeCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Inspect_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&InspectCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Refund_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&RefundCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Reset_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ResetCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Feedback_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&FeedbackCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn SetState_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&SetStateCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn SetLog_RedisCommand(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&SetLogCommand {}, ctx, argv, argc)
+}
+
+// ThrottleCommand provides rate limiting as a command in Redis. GCRA is used
+// unless another algorithm is picked with the ALGO option.
 pub struct ThrottleCommand {}
 
+impl ThrottleCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <max_burst> <count per period> <period> \
+             [<quantity>] [ALGO gcra|fixed-window|sliding-window-log|\
+             sliding-window-counter] [WAIT <timeout_ms>] or {} <key> POLICY \
+             <name> [<quantity>] [ALGO ...] [WAIT <timeout_ms>]",
+            self.name(),
+            self.name()
+        )
+    }
+}
+
 impl Command for ThrottleCommand {
     // Should return the name of the command to be registered.
     fn name(&self) -> &'static str {
@@ -54,51 +290,721 @@ impl Command for ThrottleCommand {
     //noinspection RsTypeCheck
     // Run the command.
     fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
-        if args.len() != 5 && args.len() != 6 {
-            return Err(error!(
-                "Usage: {} <key> <max_burst> <count per period> \
-                 <period> [<quantity>]",
-                self.name()
-            ));
+        if args.len() < 4 {
+            return Err(self.usage());
         }
 
         // the first argument is command name "cl.throttle" (ignore it)
         let key = args[1];
-        let max_burst = parse_i64(args[2])?;
-        let count = parse_i64(args[3])?;
-        let period = parse_i64(args[4])?;
-        let quantity = match args.get(5) {
-            Some(n) => parse_i64(n)?,
-            None => 1,
+
+        // The quota either comes from a named policy stored on the server or
+        // is given inline.
+        let policy_name = if args[2].eq_ignore_ascii_case("policy") {
+            Some(args[3])
+        } else {
+            None
+        };
+        let (mut policy, first_option) = if policy_name.is_some() {
+            (policy::get(args[3])?, 4)
+        } else if args.len() >= 5 {
+            (
+                policy::Policy {
+                    max_burst: parse_i64(args[2])?,
+                    count:     parse_i64(args[3])?,
+                    period:    parse_i64(args[4])?,
+                    algorithm: cell::Algorithm::default(),
+                    adaptive:  None,
+                },
+                5,
+            )
+        } else {
+            return Err(self.usage());
         };
 
+        // An optional quantity may be followed by any number of options.
+        let mut quantity = None;
+        let mut wait = None;
+        let mut i = first_option;
+        while i < args.len() {
+            if args[i].eq_ignore_ascii_case("algo") {
+                let name = args.get(i + 1).ok_or_else(|| self.usage())?;
+                policy.algorithm = cell::Algorithm::parse(name)?;
+                i += 2;
+            } else if args[i].eq_ignore_ascii_case("wait") {
+                let timeout_ms = parse_i64(args.get(i + 1).ok_or_else(|| self.usage())?)?;
+                if timeout_ms < 0 {
+                    return Err(error!("WAIT timeout can't be negative"));
+                }
+                wait = Some(timeout_ms);
+                i += 2;
+            } else if i == first_option {
+                quantity = Some(parse_i64(args[i])?);
+                i += 1;
+            } else {
+                return Err(self.usage());
+            }
+        }
+        let quantity = quantity.unwrap_or(1);
+
+        // A WAIT of 0 is the same as not waiting at all.
+        let wait = wait.filter(|timeout_ms| *timeout_ms > 0);
+        if wait.is_some() && policy.algorithm != cell::Algorithm::Gcra {
+            return Err(error!("WAIT is only supported by the gcra algorithm"));
+        }
+
+        let quota = effective_quota(&r, key, &policy)?;
+
+        // Anyone willing to wait gets in line behind those already waiting.
+        if let Some(timeout_ms) = wait {
+            if wait::has_waiters(key) {
+                let retry_after = time::Duration::zero();
+                wait::park(
+                    &r,
+                    key,
+                    policy_name,
+                    quota,
+                    quantity,
+                    timeout_ms,
+                    retry_after,
+                );
+                return Ok(());
+            }
+        }
+
         // We reinitialize a new store and rate limiter every time this command
         // is run, but these structures don't have a huge overhead to them so
         // it's not that big of a problem.
+        let (throttled, rate_limit_result) = {
+            let mut store = store::InternalRedisStore::new(&r);
+            let mut limiter = policy.algorithm.limiter(&mut store, &quota);
+            limiter.rate_limit(key, quantity)?
+        };
+
+        // Park the client until it's allowed, unless its request is too large
+        // to ever be allowed.
+        if let Some(timeout_ms) = wait {
+            if throttled && rate_limit_result.retry_after >= time::Duration::zero() {
+                let retry_after = rate_limit_result.retry_after;
+                wait::park(
+                    &r,
+                    key,
+                    policy_name,
+                    quota,
+                    quantity,
+                    timeout_ms,
+                    retry_after,
+                );
+                return Ok(());
+            }
+        }
+
+        // Clients that wait are counted once they've been let through or have
+        // given up.
+        stats::record(policy_name, key, throttled, quantity);
+
+        reply_result(&r, throttled, &rate_limit_result)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// MultiThrottleCommand checks several GCRA limits in a single call. Either all
+// of them are updated or, if any one of them would throttle, none are.
+pub struct MultiThrottleCommand {}
+
+impl Command for MultiThrottleCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.mthrottle"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        // the first argument is command name "cl.mthrottle" (ignore it)
+        let mut limits = &args[1..];
+        let mut quantity = 1;
+        if limits.len() > 2 && limits[limits.len() - 2].eq_ignore_ascii_case("quantity") {
+            quantity = parse_i64(limits[limits.len() - 1])?;
+            limits = &limits[..limits.len() - 2];
+        }
+
+        if limits.is_empty() || limits.len() % 4 != 0 {
+            return Err(error!(
+                "Usage: {} <key> <max_burst> <count per period> <period> \
+                 [<key> <max_burst> <count per period> <period> ...] \
+                 [QUANTITY <quantity>]",
+                self.name()
+            ));
+        }
+
+        let mut requests = Vec::with_capacity(limits.len() / 4);
+        for limit in limits.chunks(4) {
+            let max_burst = parse_i64(limit[1])?;
+            let count = parse_i64(limit[2])?;
+            let period = time::Duration::seconds(parse_i64(limit[3])?);
+            requests.push(cell::RateLimitRequest {
+                key: limit[0],
+                quota: with_adapted_rate(
+                    &r,
+                    limit[0],
+                    cell::RateQuota {
+                        max_burst,
+                        max_rate: cell::Rate::per_period(count, period),
+                    },
+                    None,
+                )?,
+                quantity,
+            });
+        }
+
+        let mut store = store::InternalRedisStore::new(&r);
+        let (tripped, results) = cell::rate_limit_all(&mut store, &requests)?;
+
+        // Only the limit that tripped counts as denied. The others weren't
+        // touched, so they aren't counted at all.
+        match tripped {
+            Some(i) => stats::record(None, requests[i].key, true, quantity),
+            None => {
+                for request in requests.iter() {
+                    stats::record(None, request.key, false, quantity);
+                }
+            }
+        }
+
+        // Reply with whether the call was throttled, the index of the first
+        // limit that tripped (-1 if none did) and then one nested array per
+        // limit in the same layout that CL.THROTTLE uses.
+        r.reply_array(2 + results.len() as i64)?;
+        r.reply_integer(if tripped.is_some() { 1 } else { 0 })?;
+        r.reply_integer(tripped.map(|i| i as i64).unwrap_or(-1))?;
+        for (i, result) in results.iter().enumerate() {
+            reply_result(&r, tripped == Some(i), result)?;
+        }
+
+        Ok(())
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// LeaseCommand hands out a batch of GCRA capacity that a client can spend
+// locally, so that it doesn't need a round trip for every unit.
+pub struct LeaseCommand {}
+
+impl LeaseCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <max_burst> <count per period> <period> <batch> \
+             or {} <key> POLICY <name> <batch>",
+            self.name(),
+            self.name()
+        )
+    }
+}
+
+impl Command for LeaseCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.lease"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 2 {
+            return Err(self.usage());
+        }
+
+        let key = args[1];
+        let policy_name = if args.len() == 5 { Some(args[3]) } else { None };
+        let policy = match args.len() {
+            5 if args[2].eq_ignore_ascii_case("policy") => policy::get(args[3])?,
+            6 => policy::Policy {
+                max_burst: parse_i64(args[2])?,
+                count:     parse_i64(args[3])?,
+                period:    parse_i64(args[4])?,
+                algorithm: cell::Algorithm::Gcra,
+                adaptive:  None,
+            },
+            _ => return Err(self.usage()),
+        };
+        if policy.algorithm != cell::Algorithm::Gcra {
+            return Err(error!("Leases are only supported by the gcra algorithm"));
+        }
+
+        let batch = parse_i64(args[args.len() - 1])?;
+        if batch < 1 {
+            return Err(error!("Batch must be positive"));
+        }
+
+        let quota = effective_quota(&r, key, &policy)?;
         let mut store = store::InternalRedisStore::new(&r);
-        let rate = cell::Rate::per_period(count, time::Duration::seconds(period));
-        let mut limiter = cell::RateLimiter::new(
-            &mut store,
-            &cell::RateQuota {
-                max_burst,
-                max_rate: rate,
+        let mut limiter = cell::RateLimiter::new(&mut store, &quota);
+        let (granted, result) = limiter.lease(key, batch)?;
+        if granted > 0 {
+            stats::record(policy_name, key, false, granted);
+        } else {
+            stats::record(policy_name, key, true, batch);
+        }
+
+        // Laid out like CL.THROTTLE except that it starts with the number of
+        // units granted and ends with how many milliseconds they stay valid.
+        // Unused units are handed back with CL.REFUND.
+        r.reply_array(6)?;
+        r.reply_integer(granted)?;
+        r.reply_integer(result.limit)?;
+        r.reply_integer(result.remaining)?;
+        r.reply_integer(result.retry_after.num_seconds())?;
+        r.reply_integer(result.reset_after.num_seconds())?;
+        r.reply_integer(if granted > 0 {
+            result.reset_after.num_milliseconds()
+        } else {
+            0
+        })
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// ShapeCommand schedules requests instead of rejecting them once the burst is
+// used up. Each one is told when it may run, which suits job queues that would
+// rather be slowed down than dropped.
+pub struct ShapeCommand {}
+
+impl ShapeCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <max_burst> <count per period> <period> \
+             <max_delay_ms> [<quantity>] or {} <key> POLICY <name> \
+             <max_delay_ms> [<quantity>]",
+            self.name(),
+            self.name()
+        )
+    }
+}
+
+impl Command for ShapeCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.shape"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let (policy_name, policy, rest) =
+            if args.len() >= 5 && args[2].eq_ignore_ascii_case("policy") {
+                (Some(args[3]), policy::get(args[3])?, &args[4..])
+            } else if args.len() >= 6 {
+                let policy = policy::Policy {
+                    max_burst: parse_i64(args[2])?,
+                    count:     parse_i64(args[3])?,
+                    period:    parse_i64(args[4])?,
+                    algorithm: cell::Algorithm::Gcra,
+                    adaptive:  None,
+                };
+                (None, policy, &args[5..])
+            } else {
+                return Err(self.usage());
+            };
+        if rest.len() > 2 {
+            return Err(self.usage());
+        }
+        if policy.algorithm != cell::Algorithm::Gcra {
+            return Err(error!("Shaping is only supported by the gcra algorithm"));
+        }
+
+        let key = args[1];
+        let max_delay = parse_i64(rest[0])?;
+        if max_delay < 0 {
+            return Err(error!("Maximum delay can't be negative"));
+        }
+        let quantity = match rest.get(1) {
+            Some(quantity) => parse_i64(quantity)?,
+            None => 1,
+        };
+
+        let quota = effective_quota(&r, key, &policy)?;
+        let mut store = store::InternalRedisStore::new(&r);
+        let mut limiter = cell::RateLimiter::new(&mut store, &quota);
+        let (rejected, result) =
+            limiter.shape(key, quantity, time::Duration::milliseconds(max_delay))?;
+        stats::record(policy_name, key, rejected, quantity);
+
+        // Everything is in milliseconds since waits are typically short:
+        // whether the request was rejected, the limit, how long to wait
+        // before running, when to run (since the epoch, -1 if rejected), how
+        // long until a rejected request could be retried (-1 if it wasn't)
+        // and how long until the key fully resets.
+        r.reply_array(6)?;
+        r.reply_integer(if rejected { 1 } else { 0 })?;
+        r.reply_integer(result.limit)?;
+        r.reply_integer(result.delay.num_milliseconds())?;
+        r.reply_integer(match result.run_at {
+            Some(run_at) => {
+                let ts = run_at.to_timespec();
+                ts.sec * 1000 + i64::from(ts.nsec) / 1_000_000
+            }
+            None => -1,
+        })?;
+        r.reply_integer(if rejected {
+            result.retry_after.num_milliseconds()
+        } else {
+            -1
+        })?;
+        r.reply_integer(result.reset_after.num_milliseconds())
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// InspectCommand reports the state of a key without changing it.
+pub struct InspectCommand {}
+
+impl Command for InspectCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.inspect"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let (quota, algorithm) = target_for(self.name(), &args[1..])?;
+        let key = args[1];
+
+        // Only GCRA records its quota with the key, the windows' keys can't
+        // even be found without it.
+        let quota = match quota {
+            Some(quota) => quota,
+            None if algorithm == cell::Algorithm::Gcra => {
+                match quota_for(&r, key, &[])? {
+                    Some(quota) => quota,
+                    None => return r.reply_null(),
+                }
+            }
+            None => {
+                return Err(error!(
+                    "Give the quota to inspect a {} key",
+                    algorithm.name()
+                ))
+            }
+        };
+
+        // Only GCRA keys are adapted by feedback.
+        let adapted = match algorithm {
+            cell::Algorithm::Gcra => state::find(&r, key)?.and_then(|s| s.adapted_rate()),
+            _ => None,
+        };
+        let quota = match adapted {
+            Some(ref rate) => cell::RateQuota {
+                max_burst: quota.max_burst,
+                max_rate:  rate.clone(),
             },
-        );
+            None => quota,
+        };
 
-        let (throttled, rate_limit_result) = limiter.rate_limit(key, quantity)?;
+        // This is registered readonly, so it mustn't open keys for writing.
+        let mut store = store::InternalRedisStore::read_only(&r);
+        let (tat, result) = if algorithm == cell::Algorithm::Gcra {
+            cell::RateLimiter::new(&mut store, &quota).inspect(key)?
+        } else {
+            let (_, result) = algorithm.limiter(&mut store, &quota).rate_limit(key, 0)?;
+            (-1, result)
+        };
 
-        // Reply with an array containing rate limiting results. Note that
-        // Redis' support for interesting data types is quite weak, so we have
-        // to jam a few square pegs into round holes. It's a little messy, but
-        // the interface comes out as pretty workable.
+        // Like CL.THROTTLE minus the parts that only make sense for a request,
+        // plus the TAT in milliseconds since the epoch (-1 if unset or if the
+        // algorithm isn't GCRA) and the emission interval in microseconds that
+        // CL.FEEDBACK has adapted the key to (-1 if it hasn't).
         r.reply_array(5)?;
-        r.reply_integer(if throttled { 1 } else { 0 })?;
-        r.reply_integer(rate_limit_result.limit)?;
-        r.reply_integer(rate_limit_result.remaining)?;
-        r.reply_integer(rate_limit_result.retry_after.num_seconds())?;
-        r.reply_integer(rate_limit_result.reset_after.num_seconds())?;
+        r.reply_integer(result.limit)?;
+        r.reply_integer(result.remaining)?;
+        r.reply_integer(result.reset_after.num_seconds())?;
+        r.reply_integer(if tat == -1 { -1 } else { tat / 1_000_000 })?;
+        r.reply_integer(match adapted {
+            Some(rate) => rate.period.num_microseconds().unwrap(),
+            None => -1,
+        })
+    }
 
-        Ok(())
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+// RefundCommand gives capacity back to a GCRA key, for example when a request
+// failed before it did any work.
+pub struct RefundCommand {}
+
+impl Command for RefundCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.refund"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 3 && args.len() != 6 {
+            return Err(error!(
+                "Usage: {} <key> <quantity> [<max_burst> <count per period> <period>]",
+                self.name()
+            ));
+        }
+
+        let key = args[1];
+        let quantity = parse_i64(args[2])?;
+        if quantity < 0 {
+            return Err(error!("Quantity can't be negative"));
+        }
+        let quota = match quota_for(&r, key, &args[3..])? {
+            Some(quota) => with_adapted_rate(&r, key, quota, None)?,
+            None => return r.reply_null(),
+        };
+
+        let mut store = store::InternalRedisStore::new(&r);
+        let mut limiter = cell::RateLimiter::new(&mut store, &quota);
+        let result = limiter.refund(key, quantity)?;
+
+        reply_result(&r, false, &result)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// ResetCommand clears a key so that it starts over with its full burst.
+pub struct ResetCommand {}
+
+impl Command for ResetCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.reset"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let (quota, algorithm) = target_for(self.name(), &args[1..])?;
+
+        // Which window keys there are depends on how long the windows are,
+        // otherwise the quota doesn't matter when clearing a key.
+        let quota = match quota {
+            Some(quota) => quota,
+            None => match algorithm {
+                cell::Algorithm::FixedWindow | cell::Algorithm::SlidingWindowCounter => {
+                    return Err(error!(
+                        "Give the quota to reset a {} key",
+                        algorithm.name()
+                    ))
+                }
+                _ => cell::RateQuota {
+                    max_burst: 0,
+                    max_rate:  cell::Rate::per_second(1),
+                },
+            },
+        };
+        let mut store = store::InternalRedisStore::new(&r);
+        let existed = algorithm.limiter(&mut store, &quota).reset(args[1])?;
+
+        r.reply_integer(if existed { 1 } else { 0 })
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// FeedbackCommand adapts the rate a GCRA key is limited at to how the service
+// behind it is coping: successes speed it up a little at a time and overloads
+// slow it down by half, always between the given bounds or those of a policy
+// set with ADAPT. The adapted rate is kept with the key's state and takes the
+// place of the quota's whenever the key is limited.
+pub struct FeedbackCommand {}
+
+impl Command for FeedbackCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.feedback"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let bounds = match args.len() {
+            5 if args[3].eq_ignore_ascii_case("policy") => policy::get(args[4])?
+                .adaptive
+                .ok_or_else(|| error!("Policy {} wasn't set with ADAPT", args[4]))?,
+            6 => {
+                let bounds = adaptive::Bounds {
+                    min:    parse_i64(args[3])?,
+                    max:    parse_i64(args[4])?,
+                    period: time::Duration::seconds(parse_i64(args[5])?),
+                };
+                if bounds.min < 1 || bounds.max < bounds.min {
+                    return Err(error!("Bounds must satisfy 1 <= min <= max"));
+                }
+                if bounds.period < time::Duration::seconds(1) {
+                    return Err(error!("Period must be at least 1 second"));
+                }
+                bounds
+            }
+            _ => {
+                return Err(error!(
+                    "Usage: {} <key> success|overload \
+                     (<min count per period> <max count per period> <period> | \
+                     POLICY <name>)",
+                    self.name()
+                ))
+            }
+        };
+        let key = args[1];
+        let feedback = adaptive::Feedback::parse(args[2])?;
+
+        let redis_key = r.open_key_writable(key);
+        let existing = match redis_key.key_type() {
+            // Older versions kept a bare TAT as a string, which is carried
+            // over into a state. Any other string belongs to someone else.
+            redmod::KeyType::String => {
+                match redis_key.read()?.unwrap_or_default().parse::<i64>() {
+                    Ok(value) => Some(state::State::new(value, None)),
+                    Err(_) => return Err(error!(redmod::ERRORMSG_WRONGTYPE)),
+                }
+            }
+            _ => redis_key
+                .get_value::<state::State>(state::state_type())?
+                .map(|state| *state),
+        };
+
+        let mut new = existing.unwrap_or_default();
+        let rate = bounds.adapt(new.adapted_rate().as_ref(), feedback);
+        new.adapted_interval = rate.period.num_nanoseconds().unwrap();
+        match redis_key.get_value::<state::State>(state::state_type()) {
+            Ok(Some(state)) => *state = new,
+            _ => redis_key.set_value(state::state_type(), Box::new(new))?,
+        }
+
+        // Feedback can come in before the key was ever throttled. The state it
+        // leaves behind expires after a period so that it doesn't outlive a
+        // key nobody limits. Existing keys keep their expiry.
+        let expires_at = match existing {
+            Some(_) => 0,
+            None => {
+                redis_key.set_expire(bounds.period)?;
+                redmod::milliseconds() + bounds.period.num_milliseconds()
+            }
+        };
+        r.replicate(
+            "CL.SETSTATE",
+            &[
+                key,
+                new.value.to_string().as_str(),
+                new.max_burst.to_string().as_str(),
+                new.emission_interval.to_string().as_str(),
+                expires_at.to_string().as_str(),
+                new.adapted_interval.to_string().as_str(),
+            ],
+        )?;
+
+        // Reply with the new rate in counts per period.
+        r.reply_integer(bounds.count(&rate).round() as i64)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// SetStateCommand overwrites the state of a rate limited key as is and makes
+// it expire at the given time in milliseconds since the epoch, or leaves its
+// expiry be if that's 0. It's internal: what the throttle commands replicate
+// and what the throttle data type rewrites itself into in the AOF. It isn't
+// meant to be called by clients, and refuses to touch keys holding anything
+// but throttle state.
+pub struct SetStateCommand {}
+
+impl Command for SetStateCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.setstate"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 6 && args.len() != 7 {
+            return Err(error!(
+                "Usage: {} <key> <value> <max_burst> <emission interval ns> \
+                 <expires at ms> [<adapted interval ns>]",
+                self.name()
+            ));
+        }
+
+        // A key that was never adapted can leave out its adapted interval.
+        let value = state::State {
+            value:             parse_i64(args[2])?,
+            max_burst:         parse_i64(args[3])?,
+            emission_interval: parse_i64(args[4])?,
+            adapted_interval:  match args.get(6) {
+                Some(interval) => parse_i64(interval)?,
+                None => 0,
+            },
+        };
+        let expires_at = parse_i64(args[5])?;
+
+        let key = r.open_key_writable(args[1]);
+        match key.key_type() {
+            // Older versions kept a bare TAT as a string, which is replaced.
+            // Any other string belongs to someone else.
+            redmod::KeyType::String => {
+                if key.read()?.unwrap_or_default().parse::<i64>().is_err() {
+                    return Err(error!(redmod::ERRORMSG_WRONGTYPE));
+                }
+                key.set_value(state::state_type(), Box::new(value))?;
+            }
+
+            // Fails with WRONGTYPE on keys of other types.
+            _ => match key.get_value::<state::State>(state::state_type())? {
+                Some(state) => *state = value,
+                None => key.set_value(state::state_type(), Box::new(value))?,
+            },
+        }
+
+        // The key expires when the instance the command came from said it
+        // would, even if that's already passed by this instance's clock.
+        if expires_at > 0 {
+            let ttl = expires_at - redmod::milliseconds();
+            key.set_expire(time::Duration::milliseconds(if ttl > 0 { ttl } else { 1 }))?;
+        }
+        r.replicate_verbatim()?;
+        r.reply_string("OK")
     }
 
     // Should return any flags to be registered with the name as a string
@@ -107,4 +1013,184 @@ impl Command for ThrottleCommand {
     fn str_flags(&self) -> &'static str {
         "write"
     }
-}
\ No newline at end of file
+}
+
+// SetLogCommand puts back the sliding window log of a key as is, replacing
+// whatever timestamps it had. Like CL.SETSTATE it's what the log replicates
+// and rewrites itself into in the AOF, and isn't meant to be called by
+// clients.
+pub struct SetLogCommand {}
+
+impl Command for SetLogCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.setlog"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 3 {
+            return Err(error!(
+                "Usage: {} <key> <expires at ms> [<time ns> ...]",
+                self.name()
+            ));
+        }
+
+        let mut value = log::Log {
+            expires_at: parse_i64(args[2])?,
+            times:      Default::default(),
+        };
+        for time in args[3..].iter() {
+            value.times.push_back(parse_i64(time)?);
+        }
+
+        // The key expires when the instance the command came from said it
+        // would, even if that's already passed by this instance's clock.
+        let ttl = value.expires_at - redmod::milliseconds();
+        let key = r.open_key_writable(args[1]);
+        log::put(
+            &key,
+            value,
+            time::Duration::milliseconds(if ttl > 0 { ttl } else { 1 }),
+        )?;
+        r.replicate_verbatim()?;
+        r.reply_string("OK")
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// Parses a key followed by what CL.INSPECT and CL.RESET take after it: an
+// optional quota, either inline (max_burst, count per period, period) or as
+// POLICY <name>, and an optional ALGO. The algorithm is the policy's unless
+// it's overridden, or GCRA. The quota is None if none was given.
+fn target_for(
+    name: &str,
+    args: &[&str],
+) -> Result<(Option<cell::RateQuota>, cell::Algorithm), SlicedError> {
+    let usage = || {
+        error!(
+            "Usage: {} <key> [<max_burst> <count per period> <period> | POLICY <name>] \
+             [ALGO <algorithm>]",
+            name
+        )
+    };
+    if args.is_empty() {
+        return Err(usage());
+    }
+
+    let mut quota = None;
+    let mut algorithm = cell::Algorithm::default();
+    let mut i = 1;
+    if args.len() >= 3 && args[1].eq_ignore_ascii_case("policy") {
+        let policy = policy::get(args[2])?;
+        quota = Some(policy.quota());
+        algorithm = policy.algorithm;
+        i = 3;
+    } else if args.len() >= 4 && !args[1].eq_ignore_ascii_case("algo") {
+        quota = Some(inline_quota(&args[1..4])?);
+        i = 4;
+    }
+
+    while i < args.len() {
+        if args[i].eq_ignore_ascii_case("algo") && i + 1 < args.len() {
+            algorithm = cell::Algorithm::parse(args[i + 1])?;
+            i += 2;
+        } else {
+            return Err(usage());
+        }
+    }
+    Ok((quota, algorithm))
+}
+
+// Returns the quota given in args (max_burst, count per period, period) or,
+// if none was given, the one recorded with the key. None means that there was
+// neither, which only happens when the key is unset.
+fn quota_for(
+    r: &Redis,
+    key: &str,
+    args: &[&str],
+) -> Result<Option<cell::RateQuota>, SlicedError> {
+    if args.len() == 3 {
+        return Ok(Some(inline_quota(args)?));
+    }
+
+    match state::find(r, key)? {
+        Some(state) => state
+            .quota()
+            .map(Some)
+            .ok_or_else(|| error!("No quota recorded with {}, give one explicitly", key)),
+        None => Ok(None),
+    }
+}
+
+// Returns the quota to limit key with under policy. That's the policy's own,
+// except that GCRA keys go at the rate CL.FEEDBACK has adapted them to, if any,
+// kept within the policy's bounds.
+pub fn effective_quota(
+    r: &Redis,
+    key: &str,
+    policy: &policy::Policy,
+) -> Result<cell::RateQuota, SlicedError> {
+    if policy.algorithm == cell::Algorithm::Gcra {
+        with_adapted_rate(r, key, policy.quota(), policy.adaptive.as_ref())
+    } else {
+        Ok(policy.quota())
+    }
+}
+
+// Returns quota with its rate replaced by the one key has been adapted to, if
+// any, clamped to bounds if there are some.
+fn with_adapted_rate(
+    r: &Redis,
+    key: &str,
+    quota: cell::RateQuota,
+    bounds: Option<&adaptive::Bounds>,
+) -> Result<cell::RateQuota, SlicedError> {
+    let rate = match state::find(r, key)?.and_then(|state| state.adapted_rate()) {
+        Some(rate) => rate,
+        None => return Ok(quota),
+    };
+    Ok(cell::RateQuota {
+        max_burst: quota.max_burst,
+        max_rate:  match bounds {
+            Some(bounds) => bounds.clamp(&rate),
+            None => rate,
+        },
+    })
+}
+
+// Returns the quota given as max_burst, count per period and period.
+fn inline_quota(args: &[&str]) -> Result<cell::RateQuota, SlicedError> {
+    let policy = policy::Policy {
+        max_burst: parse_i64(args[0])?,
+        count:     parse_i64(args[1])?,
+        period:    parse_i64(args[2])?,
+        algorithm: cell::Algorithm::Gcra,
+        adaptive:  None,
+    };
+    Ok(policy.quota())
+}
+
+// Reply with an array containing rate limiting results. Note that Redis'
+// support for interesting data types is quite weak, so we have to jam a few
+// square pegs into round holes. It's a little messy, but the interface comes
+// out as pretty workable.
+pub fn reply_result(
+    r: &Redis,
+    throttled: bool,
+    result: &cell::RateLimitResult,
+) -> Result<(), SlicedError> {
+    r.reply_array(5)?;
+    r.reply_integer(if throttled { 1 } else { 0 })?;
+    r.reply_integer(result.limit)?;
+    r.reply_integer(result.remaining)?;
+    r.reply_integer(result.retry_after.num_seconds())?;
+    r.reply_integer(result.reset_after.num_seconds())?;
+    Ok(())
+}
@@ -0,0 +1,299 @@
+extern crate libc;
+extern crate time;
+
+use std::collections::{HashMap, VecDeque};
+use std::ptr;
+
+use spin::Mutex;
+
+use crate::error::SlicedError;
+use crate::redis::{self, Command, Redis};
+use crate::redis::redmod;
+
+use crate::cell;
+use crate::cell::store;
+
+use super::stats;
+use super::throttle::reply_result;
+
+/// A client parked by `CL.THROTTLE ... WAIT` until its request is allowed.
+struct Waiter {
+    bc:       *mut redmod::RedisModuleBlockedClient,
+    policy:   Option<String>,
+    quota:    cell::RateQuota,
+    quantity: i64,
+}
+
+/// The clients waiting on a key in the order they arrived, along with the
+/// timer that will next check on them.
+#[derive(Default)]
+struct Queue {
+    waiters: VecDeque<Waiter>,
+    timer:   Option<redis::TimerID>,
+}
+
+/// Every key that has clients waiting on it.
+///
+/// Queues are only ever touched from the Redis event loop, but a static has to
+/// be Send regardless of that.
+struct Queues(HashMap<String, Queue>);
+
+unsafe impl Send for Queues {}
+
+lazy_static! {
+    static ref QUEUES: Mutex<Queues> = Mutex::new(Queues(HashMap::new()));
+}
+
+/// What a waiter is unblocked with: whether it's still throttled and the
+/// result to reply with.
+type Outcome = (bool, cell::RateLimitResult);
+
+/// Returns whether any clients are waiting on key. Clients that are willing
+/// to wait queue up behind them even if their request would be allowed right
+/// away, which is what keeps waiters in first come, first served order.
+pub fn has_waiters(key: &str) -> bool {
+    QUEUES
+        .lock()
+        .0
+        .get(key)
+        .map(|queue| !queue.waiters.is_empty())
+        .unwrap_or(false)
+}
+
+/// Blocks the calling client until quantity can be applied to key under quota
+/// or timeout_ms runs out. retry_after is when the request would next be
+/// allowed as of now. policy is the name of the policy the quota came from, if
+/// any, for the stats.
+pub fn park(
+    r: &Redis,
+    key: &str,
+    policy: Option<&str>,
+    quota: cell::RateQuota,
+    quantity: i64,
+    timeout_ms: i64,
+    retry_after: time::Duration,
+) {
+    let bc = redmod::block_client(
+        r.ctx,
+        Some(Throttle_WaitReply),
+        Some(Throttle_WaitTimeout),
+        Some(Throttle_WaitFree),
+        timeout_ms,
+    );
+    redmod::set_disconnect_callback(bc, Some(Throttle_WaitDisconnect));
+
+    let mut queues = QUEUES.lock();
+    let queue = queues.0.entry(String::from(key)).or_insert_with(Queue::default);
+    queue.waiters.push_back(Waiter {
+        bc,
+        policy: policy.map(String::from),
+        quota,
+        quantity,
+    });
+
+    // Only the client at the front of the queue needs a timer. The others are
+    // looked at once it's been let through.
+    if queue.timer.is_none() {
+        queue.timer = Some(schedule(r, key, retry_after));
+    }
+}
+
+/// Checks on the clients waiting on key after the given delay.
+fn schedule(r: &Redis, key: &str, after: time::Duration) -> redis::TimerID {
+    let key = String::from(key);
+
+    // Round up so that the timer doesn't fire a hair before the request is
+    // allowed and have to be scheduled all over again.
+    let millis = (after.num_microseconds().unwrap_or(0) + 999) / 1000;
+    r.start_timer(if millis > 0 { millis } else { 1 }, move |r| serve(&r, &key))
+}
+
+/// Lets through as many of the clients waiting on key as the limit allows, in
+/// order, and schedules another check for the first one that's still limited.
+fn serve(r: &Redis, key: &str) {
+    let mut queues = QUEUES.lock();
+    let empty = match queues.0.get_mut(key) {
+        Some(queue) => {
+            queue.timer = None;
+            while let Some(waiter) = queue.waiters.front() {
+                match rate_limit(r, key, waiter) {
+                    // Still limited, so neither is anyone behind it.
+                    Ok((true, ref result)) if result.retry_after >= zero() => {
+                        queue.timer = Some(schedule(r, key, result.retry_after));
+                        break;
+                    }
+
+                    // Let through, or limited for good because the request is
+                    // larger than the limit allows.
+                    Ok(outcome) => {
+                        let waiter = queue.waiters.pop_front().unwrap();
+                        record(key, &waiter, outcome.0);
+                        let outcome = Box::into_raw(Box::new(outcome));
+                        redmod::unblock_client(waiter.bc, outcome as *mut u8);
+                    }
+
+                    // The reply callback turns a missing outcome into an
+                    // error.
+                    Err(_) => {
+                        let waiter = queue.waiters.pop_front().unwrap();
+                        redmod::unblock_client(waiter.bc, ptr::null_mut());
+                    }
+                }
+            }
+            queue.waiters.is_empty()
+        }
+        None => false,
+    };
+
+    if empty {
+        queues.0.remove(key);
+    }
+}
+
+fn zero() -> time::Duration {
+    time::Duration::zero()
+}
+
+fn rate_limit(r: &Redis, key: &str, waiter: &Waiter) -> Result<Outcome, SlicedError> {
+    let mut store = store::InternalRedisStore::new(r);
+    let mut limiter = cell::RateLimiter::new(&mut store, &waiter.quota);
+    limiter.rate_limit(key, waiter.quantity)
+}
+
+fn record(key: &str, waiter: &Waiter, throttled: bool) {
+    let policy = waiter.policy.as_ref().map(|policy| policy.as_str());
+    stats::record(policy, key, throttled, waiter.quantity);
+}
+
+/// Takes a client out of whichever queue it's waiting in, returning the key
+/// it was waiting on. A queue that's left empty is dropped along with its
+/// timer.
+fn remove(
+    r: &Redis,
+    bc: *mut redmod::RedisModuleBlockedClient,
+) -> Option<(String, Waiter)> {
+    let mut queues = QUEUES.lock();
+    let found = queues.0.iter_mut().find_map(|(key, queue)| {
+        queue
+            .waiters
+            .iter()
+            .position(|waiter| waiter.bc == bc)
+            .and_then(|i| queue.waiters.remove(i))
+            .map(|waiter| (key.clone(), waiter))
+    });
+
+    if let Some((ref key, _)) = found {
+        if queues.0[key].waiters.is_empty() {
+            if let Some(timer) = queues.0.remove(key).and_then(|queue| queue.timer) {
+                r.cancel_timer(timer);
+            }
+        }
+    }
+    found
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Throttle_WaitReply(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&WaitReplyCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Throttle_WaitTimeout(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&WaitTimeoutCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Throttle_WaitDisconnect(
+    ctx: *mut redmod::RedisModuleCtx,
+    bc: *mut redmod::RedisModuleBlockedClient,
+) {
+    // Nobody's left to reply to, so just stop serving it.
+    remove(&Redis { ctx }, bc);
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn Throttle_WaitFree(
+    ctx: *mut redmod::RedisModuleCtx,
+    privdata: *mut libc::c_void,
+) {
+    if !privdata.is_null() {
+        drop(unsafe { Box::from_raw(privdata as *mut Outcome) });
+    }
+}
+
+// WaitReplyCommand replies to a waiting CL.THROTTLE once it's been let through.
+// It's run by Redis with the arguments of the original command.
+struct WaitReplyCommand {}
+
+impl Command for WaitReplyCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.throttle"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, _args: &[&str]) -> Result<(), SlicedError> {
+        let outcome = redmod::get_blocked_client_private_data(r.ctx) as *mut Outcome;
+        if outcome.is_null() {
+            return Err(error!("Failed to rate limit while waiting"));
+        }
+
+        let outcome = unsafe { &*outcome };
+        reply_result(&r, outcome.0, &outcome.1)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+// WaitTimeoutCommand replies to a waiting CL.THROTTLE that ran out of time with
+// the same throttled reply it would've gotten had it not waited at all. It's
+// run by Redis with the arguments of the original command.
+struct WaitTimeoutCommand {}
+
+impl Command for WaitTimeoutCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "cl.throttle"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, _args: &[&str]) -> Result<(), SlicedError> {
+        let bc = redmod::get_blocked_client_handle(r.ctx);
+        let (key, waiter) =
+            remove(&r, bc).ok_or_else(|| error!("Lost track of waiting client"))?;
+
+        let mut store = store::InternalRedisStore::new(&r);
+        let mut limiter = cell::RateLimiter::new(&mut store, &waiter.quota);
+        let (_, result) = limiter.check(&key, waiter.quantity)?;
+        record(&key, &waiter, true);
+        reply_result(&r, true, &result)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
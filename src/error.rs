@@ -27,6 +27,12 @@ impl From<std::num::ParseIntError> for SlicedError {
     }
 }
 
+impl From<crate::stream::StreamError> for SlicedError {
+    fn from(err: crate::stream::StreamError) -> SlicedError {
+        SlicedError::generic(&err.to_string())
+    }
+}
+
 impl fmt::Display for SlicedError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
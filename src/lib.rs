@@ -35,6 +35,7 @@ mod macros;
 /// Modules
 pub mod mmap;
 pub mod cell;
+pub mod clock;
 pub mod cmd;
 pub mod error;
 pub mod redis;
@@ -111,6 +112,34 @@ pub extern "C" fn RedisModule_DoLoad(
         return redmod::Status::Err;
     }
 
+    // Throttle state
+    if cell::state::load(ctx) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Sliding window logs
+    if cell::log::load(ctx) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Concurrency leases
+    if cell::concurrency::load(ctx) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Named throttle policies
+    if cell::policy::load(ctx) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Policies loaded from an RDB file would be lost on the next AOF rewrite
+    // without its preamble, so refuse to start rather than lose them later.
+    let r = redis::Redis { ctx };
+    if let Err(err) = cell::policy::check_persistence(&r) {
+        r.log(redis::LogLevel::Warning, &format!("{}", err));
+        return redmod::Status::Err;
+    }
+
     /**********************************************************************/
     // Load Commands
     /**********************************************************************/
@@ -125,11 +154,42 @@ pub extern "C" fn RedisModule_DoLoad(
         return redmod::Status::Err;
     }
 
+    // Load policy commands
+    if cmd::policy::load(ctx, argv, argc) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Load concurrency commands
+    if cmd::concurrency::load(ctx, argv, argc) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Load call commands
+    if cmd::call::load(ctx, argv, argc) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
+    // Load stats commands
+    if cmd::stats::load(ctx, argv, argc) == redmod::Status::Err {
+        return redmod::Status::Err;
+    }
+
     // Load stream commands
     if cmd::stream::load(ctx, argv, argc) == redmod::Status::Err {
         return redmod::Status::Err;
     }
 
+    /**********************************************************************/
+    // Recover Streams
+    /**********************************************************************/
+
+    // Pick up the streams left behind by a previous run before any command
+    // can get to them. Streams that can't be picked up are set aside, and if
+    // there's no getting at any of them the rest of the module still loads.
+    if let Err(err) = stream::start(&r) {
+        r.log(redis::LogLevel::Warning, &format!("Couldn't recover streams: {}", err));
+    }
+
     println!("slice/d module loaded... Happy slicing!");
     redmod::Status::Ok
 }
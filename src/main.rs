@@ -79,7 +79,7 @@ fn main() -> Result<(), StreamError> {
     let mut record_id = StreamID { ms: 0, seq: 0 };
 
     for _i in 0..10 {
-        record_id = next_id(&record_id);
+        record_id = next_id(&record_id, sliced::clock::system());
     }
 
     Ok(())
@@ -144,12 +144,16 @@ impl MmapInner {
     }
 
     pub fn is_resident(&self, offset: usize, len: usize) -> bool {
+        // mincore() only takes page aligned addresses.
+        let alignment = (self.ptr as usize + offset) % page_size();
+        let offset = offset as isize - alignment as isize;
+        let len = len + alignment;
         let vec_size = (len + page_size() - 1) / page_size();
 
         unsafe {
             if vec_size < 257 {
                 let v = &mut [0i8; 256];
-                if libc::mincore(self.ptr.offset(offset as isize), len, v.as_mut_ptr()) != 0 {
+                if libc::mincore(self.ptr.offset(offset), len, v.as_mut_ptr()) != 0 {
                     return false;
                 }
                 for i in 0..vec_size {
@@ -160,7 +164,7 @@ impl MmapInner {
                 true
             } else if vec_size < 1025 {
                 let v = &mut [0i8; 1024];
-                if libc::mincore(self.ptr.offset(offset as isize), len, v.as_mut_ptr()) != 0 {
+                if libc::mincore(self.ptr.offset(offset), len, v.as_mut_ptr()) != 0 {
                     return false;
                 }
                 for i in 0..vec_size {
@@ -171,7 +175,7 @@ impl MmapInner {
                 true
             } else if vec_size < 4097 {
                 let v = &mut [0i8; 4096];
-                if libc::mincore(self.ptr.offset(offset as isize), len, v.as_mut_ptr()) != 0 {
+                if libc::mincore(self.ptr.offset(offset), len, v.as_mut_ptr()) != 0 {
                     return false;
                 }
                 for i in 0..vec_size {
@@ -65,7 +65,8 @@ impl MemoizedValue {
     #[inline]
     pub fn new(value: Value) -> MemoizedValue {
         MemoizedValue {
-            encoded_size: value.encoded_size(),
+            // Include the backlen since that's what gets written.
+            encoded_size: value.size_for_write(),
             value: value,
         }
     }
@@ -649,7 +650,7 @@ pub fn str_len_6bit(p: *mut u8) -> u32 {
 pub fn str_len_12bit(p: *mut u8) -> u32 {
     unsafe {
         u32::from_le(
-            ((*p) & 0xFu8) as u32 | (*p.offset(1)) as u32
+            (((*p) & 0xFu8) as u32) << 8 | (*p.offset(1)) as u32
         )
     }
 }
@@ -1419,7 +1420,10 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(
+                        dst.offset(1 + size as isize),
+                        1 + size,
+                    );
                 } else if size < 4096 {
                     *dst.offset(0) = (size >> 8) as u8 | ENCODING_12BIT_STR;
                     *dst.offset(1) = (size & 0xff) as u8;
@@ -1429,7 +1433,10 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(
+                        dst.offset(2 + size as isize),
+                        2 + size,
+                    );
                 } else {
                     *dst.offset(0) = ENCODING_32BIT_STR;
                     *dst.offset(1) = (size & 0xff) as u8;
@@ -1442,7 +1449,10 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(
+                        dst.offset(5 + size as isize),
+                        5 + size,
+                    );
                 }
             }
         }
@@ -1621,6 +1631,8 @@ pub fn seek(lp: listpack, mut index: isize) -> Option<element> {
 pub fn new<'a, A>(allocator: &'a A) -> listpack where A: Allocator {
     let lp = allocator.alloc(HDR_USIZE + 1);
     set_total_bytes(lp, HDR_USIZE as u32 + 1);
+    set_num_elements(lp, 0);
+    unsafe { *lp.offset(HDR_SIZE) = EOF; }
     lp
 }
 
@@ -1811,10 +1823,6 @@ pub fn replace<'a, A>(
         let old_value = get(p);
         let old_size = old_value.size_for_write();
 
-        if old_size == encoded_size {
-            return Some((lp, p));
-        }
-
         // Calculate size delta.
         let delta = (encoded_size as isize) - (old_size as isize);
         if delta == 0 {
@@ -1878,6 +1886,8 @@ pub fn replace<'a, A>(
                         lp.offset((eleoff as isize) + delta),
                         old_listpack_bytes as usize - eleoff,
                     );
+
+                    v.encode(p, encoded_size);
                 }
                 None => {
                     // Grow allocation. We must do this before the shift since
@@ -2763,4 +2773,46 @@ mod tests {
             println!("Length: {}", get_num_elements(lp));
         }
     }
+
+    #[test]
+    fn it_round_trips_every_encoding() {
+        let short = "a".repeat(10);
+        let medium = "b".repeat(1000);
+        let long = "c".repeat(5000);
+        let values = vec![
+            Value::Int(7),
+            Value::Int(-1000),
+            Value::Int(30000),
+            Value::Int(-8000000),
+            Value::Int(2000000000),
+            Value::Int(i64::min_value()),
+            Value::String(short.as_ptr(), short.len() as u32),
+            Value::String(medium.as_ptr(), medium.len() as u32),
+            Value::String(long.as_ptr(), long.len() as u32),
+        ];
+
+        unsafe {
+            let mut lp = new(ALLOCATOR);
+            for value in values.iter() {
+                lp = append_val(ALLOCATOR, lp, value).1.unwrap();
+            }
+            assert_eq!(values.len() as u16, get_num_elements(lp));
+
+            // Walking backwards relies on every backlen being right.
+            let mut ele = last(lp);
+            for value in values.iter().rev() {
+                let p = ele.unwrap();
+                assert!(get(p) == *value);
+                ele = prev(lp, p);
+            }
+            assert!(ele.is_none());
+
+            // Growing the first element shifts everything after it.
+            let (lp, _) = replace(ALLOCATOR, lp, first(lp).unwrap(), Value::Int(300))
+                .unwrap();
+            assert!(get(first(lp).unwrap()) == Value::Int(300));
+            assert!(get(last(lp).unwrap()) == values[values.len() - 1]);
+            ALLOCATOR.dealloc(lp);
+        }
+    }
 }
\ No newline at end of file
@@ -6,6 +6,8 @@ use libc;
 use std::error::Error;
 use std::iter;
 use std::ptr;
+use std::slice;
+use std::str;
 use std::string;
 use time;
 
@@ -43,13 +45,16 @@ pub enum LogLevel {
 
 /// Reply represents the various types of a replies that we can receive after
 /// executing a Redis command.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Reply {
-    Array,
-    Error,
+    Array(Vec<Reply>),
+    Error(String),
     Integer(i64),
     Nil,
-    String(String),
+    /// A status reply like "OK".
+    Status(String),
+    /// A bulk string, which isn't necessarily UTF-8.
+    String(Vec<u8>),
     Unknown,
 }
 
@@ -156,49 +161,85 @@ pub struct Redis {
     pub ctx: *mut redmod::RedisModuleCtx,
 }
 
-extern "C" fn sliced_timer_callback(_value: *mut libc::c_void) {
-    // Ignore
-}
+/// A closure waiting on a timer. Redis only carries a thin pointer for us, so
+/// the trait object gets boxed a second time.
+type TimerClosure = Box<FnMut(Redis)>;
 
-extern "C" fn sliced_timer_callback_wrapper<F>(
-    closure: *mut libc::c_void) where F: Fn() {
-    let closure = closure as *mut F;
-    unsafe {
-        let res = (*closure)();
-    }
+extern "C" fn sliced_timer_callback(
+    ctx: *mut redmod::RedisModuleCtx,
+    data: *mut libc::c_void,
+) {
+    let mut closure = unsafe { Box::from_raw(data as *mut TimerClosure) };
+    (*closure)(Redis { ctx });
 }
 
 impl Redis {
     /// Executes the closure on the Redis event-loop after the specified
     /// time in milliseconds have elapsed.
-    pub fn start_timer<F>(&self, millis: i64, f: F) -> TimerID where F: Fn() {
-        let mut x = 0 as *mut u8;
+    pub fn start_timer<F>(&self, millis: i64, f: F) -> TimerID
+    where
+        F: FnOnce(Redis) + 'static,
+    {
+        // Timers fire exactly once, but a FnOnce can't be called through a
+        // box so it's adapted into a FnMut.
+        let mut f = Some(f);
+        let closure: Box<TimerClosure> = Box::new(Box::new(move |r| {
+            if let Some(f) = f.take() {
+                f(r)
+            }
+        }));
         redmod::create_timer(
             self.ctx,
             millis,
-            Some(sliced_timer_callback_wrapper::<F>),
-            (&mut x) as *mut _ as *mut libc::c_void)
+            Some(sliced_timer_callback),
+            Box::into_raw(closure) as *mut libc::c_void,
+        )
     }
 
     /// Executes the closure on the Redis event-loop.
     /// This can be called from background threads.
-    pub fn run<F>(&self, f: F) -> TimerID where F: Fn() {
-        let mut x = 0 as *mut u8;
-        redmod::create_timer(
-            self.ctx,
-            0,
-            Some(sliced_timer_callback_wrapper::<F>),
-            (&mut x) as *mut _ as *mut libc::c_void)
+    pub fn run<F>(&self, f: F) -> TimerID
+    where
+        F: FnOnce(Redis) + 'static,
+    {
+        self.start_timer(0, f)
     }
 
     /// Cancels a timer by it's ID.
     pub fn cancel_timer(&self, timer_id: TimerID) -> redmod::Status {
-        let mut x = 0 as *mut u8;
-        redmod::stop_timer(self.ctx, timer_id, (&mut x) as *mut _ as *mut *mut libc::c_void)
+        let mut data: *mut libc::c_void = ptr::null_mut();
+        let status = redmod::stop_timer(self.ctx, timer_id, &mut data);
+        if status == redmod::Status::Ok {
+            // The closure never ran so it's still ours to free.
+            drop(unsafe { Box::from_raw(data as *mut TimerClosure) });
+        }
+        status
     }
 
-    ///
+    /// Runs a Redis command and returns its reply. An error reply from the
+    /// command comes back as `Reply::Error` rather than as an `Err` so that
+    /// it can be relayed as is.
     pub fn call(&self, command: &str, args: &[&str]) -> Result<Reply, SlicedError> {
+        self.call_with(command, args, false)
+    }
+
+    /// Like `call`, but a command that changes the data set is also
+    /// replicated to replicas and the AOF along with anything else the
+    /// calling command replicates.
+    pub fn call_replicated(
+        &self,
+        command: &str,
+        args: &[&str],
+    ) -> Result<Reply, SlicedError> {
+        self.call_with(command, args, true)
+    }
+
+    fn call_with(
+        &self,
+        command: &str,
+        args: &[&str],
+        replicate: bool,
+    ) -> Result<Reply, SlicedError> {
         log_debug!(self, "{} [began] args = {:?}", command, args);
 
         // We use a "format" string to tell redis what types we're passing in.
@@ -208,7 +249,13 @@ impl Redis {
         // It would be nice to start passing some parameters as their actual
         // type (for example, i64s as long longs), but Redis stringifies these
         // on the other end anyway so the practical benefit will be minimal.
-        let format: String = iter::repeat("s").take(args.len()).collect();
+        //
+        // A trailing "!" asks Redis to replicate the command.
+        let flags = if replicate { "!" } else { "" };
+        let format: String = iter::repeat("s")
+            .take(args.len())
+            .chain(iter::once(flags))
+            .collect();
 
         // TODO: Use SmallVec
         let terminated_args: Vec<RedisString> =
@@ -258,11 +305,29 @@ impl Redis {
                 terminated_args[2].str_inner,
                 terminated_args[3].str_inner,
             ),
-            _ => return Err(SlicedError::Generic(
-                crate::error::GenericError::new("Can't support that many CALL arguments")
-            )),
+            // Any other number of arguments is passed as an array instead.
+            _ => {
+                let mut argv: Vec<*mut redmod::RedisModuleString> =
+                    terminated_args.iter().map(|s| s.str_inner).collect();
+                redmod::callv::call(
+                    self.ctx,
+                    format!("{}\0", command).as_ptr(),
+                    format!("v{}\0", flags).as_ptr(),
+                    argv.as_mut_ptr(),
+                    argv.len(),
+                )
+            }
         };
 
+        // Redis doesn't get as far as running the command if it doesn't know
+        // it or it was given the wrong number of arguments.
+        if raw_reply.is_null() {
+            return Err(error!(
+                "Couldn't call {}: unknown command or wrong number of arguments",
+                command
+            ));
+        }
+
         let reply_res = manifest_redis_reply(raw_reply);
         redmod::free_call_reply(raw_reply);
 
@@ -273,6 +338,101 @@ impl Redis {
         reply_res
     }
 
+    /// Replicates a command to replicas and the AOF in place of the one being
+    /// run. All the commands replicated during a single command are wrapped
+    /// in a MULTI/EXEC so that they're applied together.
+    pub fn replicate(&self, command: &str, args: &[&str]) -> Result<(), SlicedError> {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        self.replicate_bytes(command, &args)
+    }
+
+    /// Like `replicate`, but the arguments are taken as they are so that
+    /// binary data makes it to replicas intact.
+    pub fn replicate_bytes(
+        &self,
+        command: &str,
+        args: &[&[u8]],
+    ) -> Result<(), SlicedError> {
+        let format: String = iter::repeat("s").take(args.len()).collect();
+        let terminated_args: Vec<RedisString> =
+            args.iter().map(|s| RedisString::from_bytes(self.ctx, s)).collect();
+
+        let status = match args.len() {
+            1 => redmod::replicate1::call(
+                self.ctx,
+                format!("{}\0", command).as_ptr(),
+                format!("{}\0", format).as_ptr(),
+                terminated_args[0].str_inner,
+            ),
+            2 => redmod::replicate2::call(
+                self.ctx,
+                format!("{}\0", command).as_ptr(),
+                format!("{}\0", format).as_ptr(),
+                terminated_args[0].str_inner,
+                terminated_args[1].str_inner,
+            ),
+            3 => redmod::replicate3::call(
+                self.ctx,
+                format!("{}\0", command).as_ptr(),
+                format!("{}\0", format).as_ptr(),
+                terminated_args[0].str_inner,
+                terminated_args[1].str_inner,
+                terminated_args[2].str_inner,
+            ),
+            4 => redmod::replicate4::call(
+                self.ctx,
+                format!("{}\0", command).as_ptr(),
+                format!("{}\0", format).as_ptr(),
+                terminated_args[0].str_inner,
+                terminated_args[1].str_inner,
+                terminated_args[2].str_inner,
+                terminated_args[3].str_inner,
+            ),
+            // Any other number of arguments is passed as an array instead.
+            _ => {
+                let mut argv: Vec<*mut redmod::RedisModuleString> =
+                    terminated_args.iter().map(|s| s.str_inner).collect();
+                redmod::replicatev::call(
+                    self.ctx,
+                    format!("{}\0", command).as_ptr(),
+                    "v\0".as_ptr(),
+                    argv.as_mut_ptr(),
+                    argv.len(),
+                )
+            }
+        };
+        handle_status(status, "Error while replicating command")
+    }
+
+    /// Replicates the command being run to replicas and the AOF exactly as it
+    /// was called. Only safe for commands whose effect doesn't depend on
+    /// anything but their arguments and the data set.
+    pub fn replicate_verbatim(&self) -> Result<(), SlicedError> {
+        handle_status(
+            redmod::replicate_verbatim(self.ctx),
+            "Error while replicating command",
+        )
+    }
+
+    /// Returns whether this instance is a replica of another one, in which
+    /// case it's meant to only follow along with what it's sent.
+    pub fn is_replica(&self) -> bool {
+        redmod::get_context_flags(self.ctx) & redmod::CTX_FLAGS_SLAVE != 0
+    }
+
+    /// Returns whether the client running the command can be blocked. Lua
+    /// scripts and transactions can't wait, and neither can a replica on what
+    /// its master sends it.
+    pub fn can_block(&self) -> bool {
+        let flags = redmod::ContextFlags::from_bits_truncate(
+            redmod::get_context_flags(self.ctx),
+        );
+        let waitless = redmod::ContextFlags::LUA
+            | redmod::ContextFlags::MULTI
+            | redmod::ContextFlags::SLAVE;
+        !flags.intersects(waitless)
+    }
+
     ///
     pub fn redis_lock(&self) {
         return redmod::thread_safe_context_lock(self.ctx);
@@ -298,10 +458,12 @@ impl Redis {
         reply_res: Result<Reply, SlicedError>,
     ) -> Result<Reply, SlicedError> {
         match reply_res {
-            Ok(Reply::String(s)) => match s.parse::<i64>() {
-                Ok(n) => Ok(Reply::Integer(n)),
-                _ => Ok(Reply::String(s)),
-            },
+            Ok(Reply::String(s)) => {
+                match str::from_utf8(&s).ok().and_then(|s| s.parse::<i64>().ok()) {
+                    Some(n) => Ok(Reply::Integer(n)),
+                    None => Ok(Reply::String(s)),
+                }
+            }
             _ => reply_res,
         }
     }
@@ -364,6 +526,55 @@ impl Redis {
         )
     }
 
+    /// Replies with a status like "OK" rather than with a bulk string.
+    pub fn reply_status(&self, message: &str) -> Result<(), SlicedError> {
+        handle_status(
+            redmod::reply_with_simple_string(self.ctx, format!("{}\0", message).as_ptr()),
+            "Could not reply with status",
+        )
+    }
+
+    /// Replies with a bulk string that isn't necessarily UTF-8.
+    pub fn reply_buffer(&self, buf: &[u8]) -> Result<(), SlicedError> {
+        handle_status(
+            redmod::reply_with_string_buffer(self.ctx, buf.as_ptr(), buf.len()),
+            "Could not reply with string",
+        )
+    }
+
+    /// Replies with an error. message has to start with the error code, like
+    /// "ERR" in "ERR wrong number of arguments".
+    pub fn reply_error(&self, message: &str) -> Result<(), SlicedError> {
+        redmod::reply_with_error(self.ctx, format!("{}\0", message).as_ptr());
+        Ok(())
+    }
+
+    /// Replies with a reply received from `call`, as is.
+    pub fn reply(&self, reply: &Reply) -> Result<(), SlicedError> {
+        match *reply {
+            Reply::Array(ref elements) => {
+                self.reply_array(elements.len() as i64)?;
+                for element in elements {
+                    self.reply(element)?;
+                }
+                Ok(())
+            }
+            Reply::Error(ref message) => self.reply_error(message),
+            Reply::Integer(integer) => self.reply_integer(integer),
+            Reply::Nil => self.reply_null(),
+            Reply::Status(ref message) => self.reply_status(message),
+            Reply::String(ref buf) => self.reply_buffer(buf),
+            Reply::Unknown => Err(error!("Can't relay a reply of unknown type")),
+        }
+    }
+
+    pub fn reply_null(&self) -> Result<(), SlicedError> {
+        handle_status(
+            redmod::reply_with_null(self.ctx),
+            "Could not reply with null",
+        )
+    }
+
     pub fn reply_value(&self, value: listpack::Value) -> Result<(), SlicedError> {
         match value {
             listpack::Value::Int(v) => handle_status(
@@ -431,6 +642,22 @@ impl RedisKey {
         };
         Ok(val)
     }
+
+    /// Returns the type of the value stored in the key.
+    pub fn key_type(&self) -> redmod::KeyType {
+        redmod::key_type(self.key_inner)
+    }
+}
+
+impl RedisKey {
+    /// Returns the value of a key holding the given module data type, or None
+    /// if the key doesn't exist.
+    pub fn get_value<T>(
+        &self,
+        redis_type: *mut redmod::RedisModuleType,
+    ) -> Result<Option<&mut T>, SlicedError> {
+        get_module_value(self.key_inner, redis_type)
+    }
 }
 
 impl Drop for RedisKey {
@@ -486,6 +713,11 @@ impl RedisKeyWritable {
         Ok(Some(read_key(self.key_inner)?))
     }
 
+    /// Returns the type of the value stored in the key.
+    pub fn key_type(&self) -> redmod::KeyType {
+        redmod::key_type(self.key_inner)
+    }
+
     pub fn set_expire(&self, expire: time::Duration) -> Result<(), SlicedError> {
         match redmod::set_expire(self.key_inner, expire.num_milliseconds()) {
             redmod::Status::Ok => Ok(()),
@@ -505,6 +737,44 @@ impl RedisKeyWritable {
     }
 }
 
+impl RedisKeyWritable {
+    /// Returns the value of a key holding the given module data type, or None
+    /// if the key doesn't exist.
+    pub fn get_value<T>(
+        &self,
+        redis_type: *mut redmod::RedisModuleType,
+    ) -> Result<Option<&mut T>, SlicedError> {
+        get_module_value(self.key_inner, redis_type)
+    }
+
+    /// Stores a value of the given module data type in the key, replacing
+    /// whatever was there before. Ownership of the value passes to Redis,
+    /// which will hand it back to the type's free callback.
+    pub fn set_value<T>(
+        &self,
+        redis_type: *mut redmod::RedisModuleType,
+        value: Box<T>,
+    ) -> Result<(), SlicedError> {
+        let raw = Box::into_raw(value);
+        match redmod::module_type_set_value(self.key_inner, redis_type, raw as *mut u8) {
+            redmod::Status::Ok => Ok(()),
+            redmod::Status::Err => {
+                // Redis didn't take ownership so it's still ours to free.
+                drop(unsafe { Box::from_raw(raw) });
+                Err(error!("Error while setting key"))
+            }
+        }
+    }
+
+    /// Removes the key and its value.
+    pub fn delete(&self) -> Result<(), SlicedError> {
+        match redmod::delete_key(self.key_inner) {
+            redmod::Status::Ok => Ok(()),
+            redmod::Status::Err => Err(error!("Error while deleting key")),
+        }
+    }
+}
+
 impl Drop for RedisKeyWritable {
     // Frees resources appropriately as a RedisKey goes out of scope.
     fn drop(&mut self) {
@@ -512,6 +782,87 @@ impl Drop for RedisKeyWritable {
     }
 }
 
+/// `RedisIO` gives a module data type's RDB and AOF callbacks a higher-level
+/// interface over the raw IO handle that Redis passes them.
+#[derive(Clone, Copy)]
+pub struct RedisIO {
+    pub io: *mut redmod::RedisModuleIO,
+}
+
+impl RedisIO {
+    pub fn save_unsigned(&self, value: u64) {
+        redmod::save_unsigned(self.io, value)
+    }
+
+    pub fn load_unsigned(&self) -> u64 {
+        redmod::load_unsigned(self.io)
+    }
+
+    pub fn save_signed(&self, value: i64) {
+        redmod::save_signed(self.io, value)
+    }
+
+    pub fn load_signed(&self) -> i64 {
+        redmod::load_signed(self.io)
+    }
+
+    pub fn save_double(&self, value: f64) {
+        redmod::save_double(self.io, value)
+    }
+
+    pub fn load_double(&self) -> f64 {
+        redmod::load_double(self.io)
+    }
+
+    pub fn save_string(&self, value: &str) {
+        redmod::save_string_buffer(self.io, value.as_ptr(), value.len())
+    }
+
+    pub fn load_string(&self) -> Result<String, SlicedError> {
+        let mut length: libc::size_t = 0;
+        let bytes = redmod::load_string_buffer(self.io, &mut length);
+        let res = from_byte_string(bytes, length);
+        unsafe { redmod::redis_free(bytes) };
+        Ok(res?)
+    }
+
+    /// Writes a command into the AOF while it's being rewritten. Only valid
+    /// from within a data type's AOF rewrite callback.
+    pub fn emit_aof(&self, command: &str, args: &[&str]) -> Result<(), SlicedError> {
+        // More arguments than can be passed one by one are passed as an array
+        // of strings instead. Those don't need a context to be created.
+        if args.len() > 8 {
+            let strings: Vec<RedisString> =
+                args.iter().map(|s| RedisString::create(ptr::null_mut(), s)).collect();
+            let mut argv: Vec<*mut redmod::RedisModuleString> =
+                strings.iter().map(|s| s.str_inner).collect();
+            return handle_status(
+                redmod::emit_aofv::call(
+                    self.io,
+                    format!("{}\0", command).as_ptr(),
+                    "v\0".as_ptr(),
+                    argv.as_mut_ptr(),
+                    argv.len(),
+                ),
+                "Error while rewriting the AOF",
+            );
+        }
+
+        let terminated_args: Vec<String> =
+            args.iter().map(|s| format!("{}\0", s)).collect();
+        let raw_args: Vec<*const u8> =
+            terminated_args.iter().map(|s| s.as_ptr()).collect();
+        handle_status(
+            redmod::emit_aof::call(
+                self.io,
+                format!("{}\0", command).as_ptr(),
+                raw_args.as_slice(),
+            ),
+            "Can't support that many AOF arguments",
+        )
+    }
+}
+
 /// `RedisString` is an abstraction over a Redis string.
 ///
 /// Its primary function is to ensure the proper deallocation of resources when
@@ -530,6 +881,11 @@ impl RedisString {
         let str_inner = redmod::create_string(ctx, format!("{}\0", s).as_ptr(), s.len());
         RedisString { ctx, str_inner }
     }
+
+    fn from_bytes(ctx: *mut redmod::RedisModuleCtx, bytes: &[u8]) -> RedisString {
+        let str_inner = redmod::create_string(ctx, bytes.as_ptr(), bytes.len());
+        RedisString { ctx, str_inner }
+    }
 }
 
 /// String memory management
@@ -548,6 +904,19 @@ fn handle_status(status: redmod::Status, message: &str) -> Result<(), SlicedErro
     }
 }
 
+fn get_module_value<'a, T>(
+    key: *mut redmod::RedisModuleKey,
+    redis_type: *mut redmod::RedisModuleType,
+) -> Result<Option<&'a mut T>, SlicedError> {
+    match redmod::key_type(key) {
+        redmod::KeyType::Empty => Ok(None),
+        redmod::KeyType::Module if redmod::module_type_get_type(key) == redis_type => {
+            Ok(Some(unsafe { &mut *(redmod::module_type_get_value(key) as *mut T) }))
+        }
+        _ => Err(error!(redmod::ERRORMSG_WRONGTYPE)),
+    }
+}
+
 fn manifest_redis_reply(
     reply: *mut redmod::RedisModuleCallReply,
 ) -> Result<Reply, SlicedError> {
@@ -555,22 +924,51 @@ fn manifest_redis_reply(
         redmod::ReplyType::Integer => Ok(Reply::Integer(redmod::call_reply_integer(reply))),
         redmod::ReplyType::Nil => Ok(Reply::Nil),
         redmod::ReplyType::String => {
+            let mut length: libc::size_t = 0;
+            let bytes = redmod::call_reply_string_ptr(reply, &mut length);
+            let buf = unsafe { slice::from_raw_parts(bytes, length) }.to_vec();
+
+            // Status replies are strings too as far as the reply type goes.
+            let mut proto_length: libc::size_t = 0;
+            let proto = redmod::call_reply_proto(reply, &mut proto_length);
+            if proto_length > 0 && unsafe { *proto } == b'+' {
+                String::from_utf8(buf)
+                    .map(Reply::Status)
+                    .map_err(SlicedError::from)
+            } else {
+                Ok(Reply::String(buf))
+            }
+        }
+        redmod::ReplyType::Error => {
             let mut length: libc::size_t = 0;
             let bytes = redmod::call_reply_string_ptr(reply, &mut length);
             from_byte_string(bytes, length)
-                .map(Reply::String)
+                .map(Reply::Error)
                 .map_err(SlicedError::from)
         }
+        redmod::ReplyType::Array => {
+            let length = redmod::call_reply_length(reply);
+            let mut elements = Vec::with_capacity(length);
+            for i in 0..length {
+                let element = redmod::call_reply_array_element(reply, i);
+                elements.push(manifest_redis_reply(element)?);
+            }
+            Ok(Reply::Array(elements))
+        }
         redmod::ReplyType::Unknown => Ok(Reply::Unknown),
-
-        // TODO: I need to actually extract the error from Redis here. Also, it
-        // should probably be its own non-generic variety of CellError.
-        redmod::ReplyType::Error => Err(error!("Redis replied with an error.")),
-
-        other => Err(error!("Don't yet handle Redis type: {:?}", other)),
     }
 }
 
+/// Copies the contents of a string handed to us by Redis outside of a command's
+/// arguments, like the key given to a data type's AOF rewrite callback.
+pub fn string_from_redis(
+    redis_str: *mut redmod::RedisModuleString,
+) -> Result<String, SlicedError> {
+    let mut length: libc::size_t = 0;
+    let bytes = redmod::string_ptr_len(redis_str, &mut length);
+    Ok(from_byte_string(bytes, length)?)
+}
+
 #[deprecated]
 fn manifest_redis_string(
     redis_str: *mut redmod::RedisModuleString,
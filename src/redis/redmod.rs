@@ -265,6 +265,9 @@ pub struct RedisModuleTypeMethods {
     pub mem_usage: Option<RedisModuleTypeMemUsageFunc>,
     pub digest: Option<RedisModuleTypeDigestFunc>,
     pub free: Option<RedisModuleTypeFreeFunc>,
+    pub aux_load: Option<RedisModuleTypeAuxLoadFunc>,
+    pub aux_save: Option<RedisModuleTypeAuxSaveFunc>,
+    pub aux_save_triggers: libc::c_int,
 }
 
 ///
@@ -300,7 +303,7 @@ pub type RedisModuleNotificationFunc = extern "C" fn(
 pub type RedisModuleTypeLoadFunc = extern "C" fn(
     rdb: *mut RedisModuleIO,
     encver: libc::c_int,
-);
+) -> *mut u8;
 
 ///
 ///
@@ -322,11 +325,7 @@ pub type RedisModuleTypeRewriteFunc = extern "C" fn(
 ///
 ///
 ///
-pub type RedisModuleTypeMemUsageFunc = extern "C" fn(
-    rdb: *mut RedisModuleIO,
-    key: *mut RedisModuleString,
-    value: *mut u8,
-) -> libc::size_t;
+pub type RedisModuleTypeMemUsageFunc = extern "C" fn(value: *const u8) -> libc::size_t;
 
 ///
 ///
@@ -343,13 +342,30 @@ pub type RedisModuleTypeFreeFunc = extern "C" fn(
     value: *mut u8,
 );
 
+///
+///
+///
+pub type RedisModuleTypeAuxLoadFunc = extern "C" fn(
+    rdb: *mut RedisModuleIO,
+    encver: libc::c_int,
+    when: libc::c_int,
+) -> Status;
+
+///
+///
+///
+pub type RedisModuleTypeAuxSaveFunc = extern "C" fn(
+    rdb: *mut RedisModuleIO,
+    when: libc::c_int,
+);
+
 ///
 ///
 ///
 pub type RedisFreePrivDataFunc = extern "C" fn(
     ctx: *mut RedisModuleCtx,
     value: *mut libc::c_void,
-) -> *mut libc::c_void;
+);
 
 ///
 ///
@@ -366,7 +382,8 @@ pub type RedisModuleClusterMessageReceiver = extern "C" fn(
 ///
 ///
 pub type RedisModuleTimerProc = extern "C" fn(
-    value: *mut libc::c_void,
+    ctx: *mut RedisModuleCtx,
+    data: *mut libc::c_void,
 );
 
 ///
@@ -443,6 +460,38 @@ pub fn call_reply_string_ptr(
     unsafe { RedisModule_CallReplyStringPtr(str, len) }
 }
 
+///
+/// RedisModule_CallReplyProto
+///
+/// Returns the reply as Redis would send it to a client, which tells apart
+/// status replies ("+") from bulk strings ("$") where the reply type doesn't.
+#[inline(always)]
+pub fn call_reply_proto(
+    reply: *mut RedisModuleCallReply,
+    len: *mut libc::size_t,
+) -> *const u8 {
+    unsafe { RedisModule_CallReplyProto(reply, len) }
+}
+
+///
+/// RedisModule_CallReplyLength
+///
+#[inline(always)]
+pub fn call_reply_length(reply: *mut RedisModuleCallReply) -> libc::size_t {
+    unsafe { RedisModule_CallReplyLength(reply) }
+}
+
+///
+/// RedisModule_CallReplyArrayElement
+///
+#[inline(always)]
+pub fn call_reply_array_element(
+    reply: *mut RedisModuleCallReply,
+    idx: libc::size_t,
+) -> *mut RedisModuleCallReply {
+    unsafe { RedisModule_CallReplyArrayElement(reply, idx) }
+}
+
 /// Register a new command in the Redis server, that will be handled by
 /// calling the function pointer 'func' using the RedisModule calling
 /// convention. The function returns REDISMODULE_ERR if the specified command
@@ -729,6 +778,17 @@ pub fn reply_with_error(ctx: *mut RedisModuleCtx,
     unsafe { RedisModule_ReplyWithError(ctx, err) }
 }
 
+/// Reply with a simple string (+... \r\n in RESP protocol). This replies
+/// are suitable only when sending a small non-binary string with small
+/// overhead, like "OK" or similar replies.
+///
+/// The function always returns REDISMODULE_OK.
+#[inline(always)]
+pub fn reply_with_simple_string(ctx: *mut RedisModuleCtx,
+                                msg: *const u8) -> Status {
+    unsafe { RedisModule_ReplyWithSimpleString(ctx, msg) }
+}
+
 /// Send an integer reply to the client, with the specified long long value.
 /// The function always returns REDISMODULE_OK.
 #[inline(always)]
@@ -923,6 +983,9 @@ pub fn get_context_flags(ctx: *mut RedisModuleCtx) -> libc::c_int {
     unsafe { RedisModule_GetContextFlags(ctx) }
 }
 
+/// Context flag set when the Redis instance is a slave.
+pub const CTX_FLAGS_SLAVE: libc::c_int = 1 << 3;
+
 /// Change the currently selected DB. Returns an error if the id
 /// is out of range.
 ///
@@ -1220,11 +1283,132 @@ pub fn create_data_type(ctx: *mut RedisModuleCtx,
     }
 }
 
+/// Aux fields are saved before the keyspace of an RDB file.
+pub const AUX_BEFORE_RDB: libc::c_int = 1 << 0;
+/// Aux fields are saved after the keyspace of an RDB file.
+pub const AUX_AFTER_RDB: libc::c_int = 1 << 1;
+
+/// Like create_data_type, but registers a type that's never the value of a
+/// key. It only carries the module's own state through RDB files instead,
+/// which Redis saves and loads with the aux_save and aux_load callbacks
+/// wherever aux_save_triggers asks for them.
+///
+/// The aux fields end up everywhere an RDB file does, which includes the
+/// payload of a full resync and the preamble of a rewritten AOF.
+#[inline(always)]
+pub fn create_aux_data_type(ctx: *mut RedisModuleCtx,
+                            name: *const u8,
+                            encver: libc::c_int,
+                            aux_load: Option<RedisModuleTypeAuxLoadFunc>,
+                            aux_save: Option<RedisModuleTypeAuxSaveFunc>,
+                            aux_save_triggers: libc::c_int) -> *mut RedisModuleType {
+    unsafe {
+        Export_RedisModule_CreateAuxDataType(ctx,
+                                             name,
+                                             encver,
+                                             aux_load,
+                                             aux_save,
+                                             aux_save_triggers)
+    }
+}
+
+/// If the key is open for writing, set the specified module type object
+/// as the value of the key, deleting the old value if any.
+/// On success REDISMODULE_OK is returned. If the key is not open for
+/// writing or there is an active iterator, REDISMODULE_ERR is returned.
+#[inline(always)]
+pub fn module_type_set_value(key: *mut RedisModuleKey,
+                             mt: *mut RedisModuleType,
+                             value: *mut u8) -> Status {
+    unsafe { RedisModule_ModuleTypeSetValue(key, mt, value) }
+}
+
+/// Assuming RedisModule_KeyType() returned REDISMODULE_KEYTYPE_MODULE on
+/// the key, returns the moduel type pointer of the value stored at key.
+///
+/// If the key is NULL, is not associated with a module type, or is empty,
+/// then NULL is returned instead.
+#[inline(always)]
+pub fn module_type_get_type(key: *mut RedisModuleKey) -> *mut RedisModuleType {
+    unsafe { RedisModule_ModuleTypeGetType(key) }
+}
+
+/// Assuming RedisModule_KeyType() returned REDISMODULE_KEYTYPE_MODULE on
+/// the key, returns the module type low-level value stored at key, as
+/// it was set by the user via RedisModule_ModuleTypeSetValue().
+///
+/// If the key is NULL, is not associated with a module type, or is empty,
+/// then NULL is returned instead.
+#[inline(always)]
+pub fn module_type_get_value(key: *mut RedisModuleKey) -> *mut u8 {
+    unsafe { RedisModule_ModuleTypeGetValue(key) }
+}
+
 /* --------------------------------------------------------------------------
  * RDB loading and saving functions
  * -------------------------------------------------------------------------- */
 
-// TODO: Implement RDB API
+/// Save an unsigned 64 bit value into the RDB file. This function should only
+/// be called in the context of the rdb_save method of modules implementing new
+/// data types.
+#[inline(always)]
+pub fn save_unsigned(io: *mut RedisModuleIO, value: libc::uint64_t) {
+    unsafe { RedisModule_SaveUnsigned(io, value) }
+}
+
+/// Load an unsigned 64 bit value from the RDB file. This function should only
+/// be called in the context of the rdb_load method of modules implementing
+/// new data types.
+#[inline(always)]
+pub fn load_unsigned(io: *mut RedisModuleIO) -> libc::uint64_t {
+    unsafe { RedisModule_LoadUnsigned(io) }
+}
+
+/// Like RedisModule_SaveUnsigned() but for signed 64 bit values.
+#[inline(always)]
+pub fn save_signed(io: *mut RedisModuleIO, value: libc::int64_t) {
+    unsafe { RedisModule_SaveSigned(io, value) }
+}
+
+/// Like RedisModule_LoadUnsigned() but for signed 64 bit values.
+#[inline(always)]
+pub fn load_signed(io: *mut RedisModuleIO) -> libc::int64_t {
+    unsafe { RedisModule_LoadSigned(io) }
+}
+
+/// Like RedisModule_SaveString() but takes a raw C pointer and length
+/// as input.
+#[inline(always)]
+pub fn save_string_buffer(io: *mut RedisModuleIO, buf: *const u8, len: libc::size_t) {
+    unsafe { RedisModule_SaveStringBuffer(io, buf, len) }
+}
+
+/// Like RedisModule_LoadString() but returns an heap allocated string that
+/// was allocated with RedisModule_Alloc(), and can be resized or freed with
+/// RedisModule_Realloc() or RedisModule_Free().
+///
+/// The size of the string is stored at '*lenptr' if not NULL.
+/// The returned string is not automatically NULL termianted, it is loaded
+/// exactly as it was stored inisde the RDB file.
+#[inline(always)]
+pub fn load_string_buffer(io: *mut RedisModuleIO, len: *mut libc::size_t) -> *mut u8 {
+    unsafe { RedisModule_LoadStringBuffer(io, len) }
+}
+
+/// In the context of the rdb_save method of a module data type, saves a double
+/// value to the RDB file. The double can be a valid number, a NaN or infinity.
+/// It is possible to load back the value with RedisModule_LoadDouble().
+#[inline(always)]
+pub fn save_double(io: *mut RedisModuleIO, value: libc::c_double) {
+    unsafe { RedisModule_SaveDouble(io, value) }
+}
+
+/// In the context of the rdb_save method of a module data type, loads back the
+/// double value saved by RedisModule_SaveDouble().
+#[inline(always)]
+pub fn load_double(io: *mut RedisModuleIO) -> libc::c_double {
+    unsafe { RedisModule_LoadDouble(io) }
+}
 
 /* --------------------------------------------------------------------------
  * Key digest API (DEBUG DIGEST interface for modules types)
@@ -1236,7 +1420,86 @@ pub fn create_data_type(ctx: *mut RedisModuleCtx,
  * AOF API for modules data types
  * -------------------------------------------------------------------------- */
 
-// TODO: Implement AOF API
+/// Emits a command into the AOF during the AOF rewriting process. This function
+/// is only called in the context of the aof_rewrite method of data types
+/// exported by a module. The command works exactly like RedisModule_Call() in
+/// the way the parameters are passed, but it does not return anything as the
+/// error handling is performed by Redis itself.
+///
+/// Arguments are passed as C strings (the "c" format specifier) so that no
+/// context is needed to create them.
+pub mod emit_aof {
+    pub fn call(
+        io: *mut crate::redis::redmod::RedisModuleIO,
+        cmdname: *const u8,
+        args: &[*const u8],
+    ) -> crate::redis::redmod::Status {
+        let fmt = format!("{}\0", "c".repeat(args.len()));
+        unsafe {
+            match args.len() {
+                1 => RedisModule_EmitAOF(io, cmdname, fmt.as_ptr(), args[0]),
+                2 => RedisModule_EmitAOF(io, cmdname, fmt.as_ptr(), args[0], args[1]),
+                3 => RedisModule_EmitAOF(io, cmdname, fmt.as_ptr(), args[0], args[1], args[2]),
+                4 => RedisModule_EmitAOF(
+                    io, cmdname, fmt.as_ptr(), args[0], args[1], args[2], args[3],
+                ),
+                5 => RedisModule_EmitAOF(
+                    io, cmdname, fmt.as_ptr(), args[0], args[1], args[2], args[3], args[4],
+                ),
+                6 => RedisModule_EmitAOF(
+                    io, cmdname, fmt.as_ptr(), args[0], args[1], args[2], args[3], args[4],
+                    args[5],
+                ),
+                7 => RedisModule_EmitAOF(
+                    io, cmdname, fmt.as_ptr(), args[0], args[1], args[2], args[3], args[4],
+                    args[5], args[6],
+                ),
+                8 => RedisModule_EmitAOF(
+                    io, cmdname, fmt.as_ptr(), args[0], args[1], args[2], args[3], args[4],
+                    args[5], args[6], args[7],
+                ),
+                _ => return crate::redis::redmod::Status::Err,
+            }
+        }
+        crate::redis::redmod::Status::Ok
+    }
+
+    #[allow(improper_ctypes)]
+    extern "C" {
+        pub static RedisModule_EmitAOF:
+        unsafe extern "C" fn(
+            io: *mut crate::redis::redmod::RedisModuleIO,
+            cmdname: *const u8,
+            fmt: *const u8,
+            ...
+        );
+    }
+}
+
+pub mod emit_aofv {
+    pub fn call(
+        io: *mut crate::redis::redmod::RedisModuleIO,
+        cmdname: *const u8,
+        fmt: *const u8,
+        argv: *mut *mut crate::redis::redmod::RedisModuleString,
+        argc: libc::size_t,
+    ) -> crate::redis::redmod::Status {
+        unsafe { RedisModule_EmitAOF(io, cmdname, fmt, argv, argc) };
+        crate::redis::redmod::Status::Ok
+    }
+
+    #[allow(improper_ctypes)]
+    extern "C" {
+        pub static RedisModule_EmitAOF:
+        unsafe extern "C" fn(
+            io: *mut crate::redis::redmod::RedisModuleIO,
+            cmdname: *const u8,
+            fmt: *const u8,
+            argv: *mut *mut crate::redis::redmod::RedisModuleString,
+            argc: libc::size_t,
+        );
+    }
+}
 
 /* --------------------------------------------------------------------------
  * Logging
@@ -1561,6 +1824,18 @@ extern "C" {
         free: Option<RedisModuleTypeFreeFunc>,
     ) -> *mut RedisModuleType;
 
+    ///
+    /// Taps into the C helper shim
+    ///
+    pub fn Export_RedisModule_CreateAuxDataType(
+        ctx: *mut RedisModuleCtx,
+        name: *const u8,
+        encver: libc::c_int,
+        aux_load: Option<RedisModuleTypeAuxLoadFunc>,
+        aux_save: Option<RedisModuleTypeAuxSaveFunc>,
+        aux_save_triggers: libc::c_int,
+    ) -> *mut RedisModuleType;
+
     static RedisModule_IsKeysPositionRequest:
     extern "C" fn(ctx: *mut RedisModuleCtx) -> libc::c_int;
 
@@ -1579,6 +1854,41 @@ extern "C" {
     pub static RedisModule_Milliseconds:
     extern "C" fn() -> libc::c_longlong;
 
+    static RedisModule_ModuleTypeSetValue:
+    extern "C" fn(key: *mut RedisModuleKey,
+                  mt: *mut RedisModuleType,
+                  value: *mut u8) -> Status;
+
+    static RedisModule_ModuleTypeGetType:
+    extern "C" fn(key: *mut RedisModuleKey) -> *mut RedisModuleType;
+
+    static RedisModule_ModuleTypeGetValue:
+    extern "C" fn(key: *mut RedisModuleKey) -> *mut u8;
+
+    static RedisModule_SaveUnsigned:
+    extern "C" fn(io: *mut RedisModuleIO, value: libc::uint64_t);
+
+    static RedisModule_LoadUnsigned:
+    extern "C" fn(io: *mut RedisModuleIO) -> libc::uint64_t;
+
+    static RedisModule_SaveSigned:
+    extern "C" fn(io: *mut RedisModuleIO, value: libc::int64_t);
+
+    static RedisModule_LoadSigned:
+    extern "C" fn(io: *mut RedisModuleIO) -> libc::int64_t;
+
+    static RedisModule_SaveStringBuffer:
+    extern "C" fn(io: *mut RedisModuleIO, buf: *const u8, len: libc::size_t);
+
+    static RedisModule_LoadStringBuffer:
+    extern "C" fn(io: *mut RedisModuleIO, len: *mut libc::size_t) -> *mut u8;
+
+    static RedisModule_SaveDouble:
+    extern "C" fn(io: *mut RedisModuleIO, value: libc::c_double);
+
+    static RedisModule_LoadDouble:
+    extern "C" fn(io: *mut RedisModuleIO) -> libc::c_double;
+
     pub static Export_RedisModule_Alloc:
     extern "C" fn(size: usize) -> *mut u8;
 
@@ -1603,6 +1913,16 @@ extern "C" {
     static RedisModule_CallReplyStringPtr:
     extern "C" fn(str: *mut RedisModuleCallReply, len: *mut libc::size_t) -> *const u8;
 
+    static RedisModule_CallReplyProto:
+    extern "C" fn(reply: *mut RedisModuleCallReply, len: *mut libc::size_t) -> *const u8;
+
+    static RedisModule_CallReplyLength:
+    extern "C" fn(reply: *mut RedisModuleCallReply) -> libc::size_t;
+
+    static RedisModule_CallReplyArrayElement:
+    extern "C" fn(reply: *mut RedisModuleCallReply, idx: libc::size_t)
+                  -> *mut RedisModuleCallReply;
+
     static RedisModule_CloseKey: extern "C" fn(kp: *mut RedisModuleKey);
 
     static RedisModule_KeyType: extern "C" fn(kp: *mut RedisModuleKey) -> KeyType;
@@ -1693,6 +2013,9 @@ extern "C" {
     static RedisModule_ReplyWithError:
     extern "C" fn(ctx: *mut RedisModuleCtx, err: *const u8);
 
+    static RedisModule_ReplyWithSimpleString:
+    extern "C" fn(ctx: *mut RedisModuleCtx, msg: *const u8) -> Status;
+
     static RedisModule_ReplyWithLongLong:
     extern "C" fn(ctx: *mut RedisModuleCtx, ll: libc::c_longlong) -> Status;
 
@@ -2059,3 +2382,59 @@ pub mod replicate4 {
         ) -> crate::redis::redmod::Status;
     }
 }
+
+///
+/// RedisModule_Call with the "v" format, which takes the arguments as an array
+/// so that any number of them can be passed.
+///
+pub mod callv {
+    pub fn call(
+        ctx: *mut crate::redis::redmod::RedisModuleCtx,
+        cmdname: *const u8,
+        fmt: *const u8,
+        argv: *mut *mut crate::redis::redmod::RedisModuleString,
+        argc: libc::size_t,
+    ) -> *mut crate::redis::redmod::RedisModuleCallReply {
+        unsafe { RedisModule_Call(ctx, cmdname, fmt, argv, argc) }
+    }
+
+    #[allow(improper_ctypes)]
+    extern "C" {
+        pub static RedisModule_Call:
+        extern "C" fn(
+            ctx: *mut crate::redis::redmod::RedisModuleCtx,
+            cmdname: *const u8,
+            fmt: *const u8,
+            argv: *mut *mut crate::redis::redmod::RedisModuleString,
+            argc: libc::size_t,
+        ) -> *mut crate::redis::redmod::RedisModuleCallReply;
+    }
+}
+
+///
+/// RedisModule_Replicate with the "v" format, which takes the arguments as an
+/// array so that any number of them can be passed.
+///
+pub mod replicatev {
+    pub fn call(
+        ctx: *mut crate::redis::redmod::RedisModuleCtx,
+        cmdname: *const u8,
+        fmt: *const u8,
+        argv: *mut *mut crate::redis::redmod::RedisModuleString,
+        argc: libc::size_t,
+    ) -> crate::redis::redmod::Status {
+        unsafe { RedisModule_Replicate(ctx, cmdname, fmt, argv, argc) }
+    }
+
+    #[allow(improper_ctypes)]
+    extern "C" {
+        pub static RedisModule_Replicate:
+        extern "C" fn(
+            ctx: *mut crate::redis::redmod::RedisModuleCtx,
+            cmdname: *const u8,
+            fmt: *const u8,
+            argv: *mut *mut crate::redis::redmod::RedisModuleString,
+            argc: libc::size_t,
+        ) -> crate::redis::redmod::Status;
+    }
+}
@@ -83,6 +83,22 @@ RedisModuleType *Export_RedisModule_CreateDataType(RedisModuleCtx *ctx,
     return RedisModule_CreateDataType(ctx, name, encver, &tm);
 }
 
+RedisModuleType *Export_RedisModule_CreateAuxDataType(RedisModuleCtx *ctx,
+                                                      const char *name,
+                                                      int encver,
+                                                      RedisModuleTypeAuxLoadFunc auxload,
+                                                      RedisModuleTypeAuxSaveFunc auxsave,
+                                                      int auxtriggers) {
+    RedisModuleTypeMethods tm = {
+            .version = REDISMODULE_TYPE_METHOD_VERSION,
+            .aux_load = auxload,
+            .aux_save = auxsave,
+            .aux_save_triggers = auxtriggers
+    };
+
+    return RedisModule_CreateDataType(ctx, name, encver, &tm);
+}
+
 int Export_RedisModule_SubscribeToKeyspaceEvents(RedisModuleCtx *ctx, int types, RedisModuleNotificationFunc cb) {
     return RedisModule_SubscribeToKeyspaceEvents(ctx, types, cb);
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
 use crate::mmap::{Mmap, MmapMut, MmapOptions};
 use crate::redis::listpack;
 use spin::Mutex;
-use std::fs::File;
+use std::cmp;
+use std::fs::{File, OpenOptions};
 use std::io;
+use std::io::{Seek, SeekFrom, Write};
 use std::io::Error as IoError;
 use std::io::Result as IoResult;
 use std::path::Path;
@@ -32,7 +34,7 @@ impl AOF {
         // Truncate
         match len {
             0 => {
-                f.set_len(size);
+                f.set_len(size)?;
             }
             _ => {
                 // Let's not allow shrinking here.
@@ -56,6 +58,30 @@ impl AOF {
         }
     }
 
+    /// Creates a new file of the specified size that starts out with no
+    /// packs in it.
+    pub fn create(path: &Path, size: u64) -> IoResult<AOF> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let mut aof = AOF::new(f, size)?;
+        aof.mmap[0] = listpack::EOF;
+        Ok(aof)
+    }
+
+    /// Opens a file that's already there at the size it has. Everything in it
+    /// counts as written until the offset is moved.
+    pub fn open(path: &Path) -> IoResult<AOF> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let len = f.metadata()?.len();
+        AOF::new(f, len)
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.mmap.len()
@@ -66,6 +92,90 @@ impl AOF {
         self.offset
     }
 
+    /// Moves the end of the written data. Everything past it may be
+    /// overwritten.
+    #[inline]
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Copies buf into the file at offset. This never blocks unless the pages
+    /// need to be faulted in. Returns false without writing anything if buf
+    /// doesn't fit.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> bool {
+        if offset + buf.len() > self.mmap.len() {
+            return false;
+        }
+        self.mmap[offset..offset + buf.len()].copy_from_slice(buf);
+        true
+    }
+
+    /// Returns the bytes written so far.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mmap[..self.offset]
+    }
+
+    /// Whether the pages of the file that hold len bytes at offset are in
+    /// memory, so reading them doesn't block.
+    #[inline]
+    pub fn is_resident(&self, offset: usize, len: usize) -> bool {
+        self.mmap.is_resident(offset, len)
+    }
+
+    /// Synchronously flushes everything written so far to disk, including the
+    /// EOF byte that follows it.
+    pub fn flush(&self) -> IoResult<()> {
+        let len = cmp::min(self.offset + 1, self.mmap.len());
+        self.mmap.flush_range(0, len)
+    }
+
+    /// Flushes everything written so far and waits for the file to reach the
+    /// disk. This blocks so it belongs on the I/O thread.
+    pub fn sync(&self) -> IoResult<()> {
+        self.flush()?;
+        self.file.sync_data()
+    }
+
+    /// Returns another handle to the file so that it can be synced while
+    /// writes to the map carry on.
+    pub fn try_clone_file(&self) -> IoResult<File> {
+        self.file.try_clone()
+    }
+
+    /// Reserves the blocks for the whole file up front so that writes through
+    /// the map never fault on a full disk.
+    #[cfg(target_os = "linux")]
+    pub fn preallocate(&self) -> IoResult<()> {
+        use std::os::unix::io::AsRawFd;
+        let len = self.mmap.len() as libc::off_t;
+        match unsafe { libc::posix_fallocate(self.file.as_raw_fd(), 0, len) } {
+            0 => Ok(()),
+            errno => Err(IoError::from_raw_os_error(errno)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn preallocate(&self) -> IoResult<()> {
+        Ok(())
+    }
+
+    /// Ends the file with tail, right after the EOF that follows what was
+    /// written so far, and cuts off the rest. Everything is synced to disk
+    /// before the file is mapped again, read-only this time.
+    pub fn seal(self, tail: &[u8]) -> IoResult<Mmap> {
+        self.flush()?;
+        let AOF { mut file, mmap, offset } = self;
+        drop(mmap);
+
+        let end = offset as u64 + 1;
+        file.set_len(end + tail.len() as u64)?;
+        file.seek(SeekFrom::Start(end))?;
+        file.write_all(tail)?;
+        file.sync_all()?;
+        unsafe { MmapOptions::new().map(&file) }
+    }
+
     pub fn try_read(&self, offset: u64, buf: *mut u8, size: usize) -> IoResult<()> {
         Ok(())
     }
@@ -1,21 +1,1523 @@
+use libc;
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::collections::HashSet;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::clock;
+use crate::error::SlicedError;
+use crate::redis::listpack;
+use crate::redis::redmod;
+use crate::redis::sds::SDS;
+use crate::redis::{Command, LogLevel, Redis};
+
+use super::id::{mstime, parse_id, StreamID};
+use super::group::Reclaim;
+use super::io::POLL_INTERVAL_MS;
+use super::park::{ParkedAdd, PARKED};
+use super::range::{self, Entry, Fault, RangeRead};
+use super::reclaim;
+use super::tail::{Tailer, TAILERS};
+use super::{compression_name, dedupe_unit_name, manager, parse_compression};
+use super::{parse_dedupe_unit, unsupported_compression, Stream, StreamError};
+use super::COMPRESS_LZ4;
+
 
 /// MO.XADD
 pub struct AddCommand;
 
-/// MO.XDEL
-pub struct DelCommand;
+impl AddCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <id|*> <field> <value> [<field> <value> ...]",
+            self.name()
+        )
+    }
+}
 
-/// MO.XTRIM
-pub struct TrimCommand;
+impl Command for AddCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xadd"
+    }
 
-/// MO.XCLAIM
-pub struct ClaimCommand;
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 5 || (args.len() - 3) % 2 != 0 {
+            return Err(self.usage());
+        }
+
+        let id = match args[2] {
+            "*" => None,
+            id => Some(parse_id(id, 0).ok_or_else(invalid_id)?),
+        };
+
+        let manager = manager()?;
+        let stream = match manager.get_stream(args[1]) {
+            Some(stream) => stream,
+            None => manager.create_stream(SDS::new(args[1]))?,
+        };
+
+        // An append to a stream that has appends waiting lines up behind
+        // them.
+        if PARKED.lock().holds(args[1]) {
+            return park(&r, args[1], id, &args[3..]);
+        }
+        match append(&r, &stream, args[1], id, &args[3..]) {
+            Err(StreamError::WouldBlock) => park(&r, args[1], id, &args[3..]),
+            added => reply_added(&r, id.is_some(), added),
+        }
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+type AddOutcome = Result<StreamID, StreamError>;
+
+/// Appends a record to a stream, wakes the clients waiting on it and
+/// replicates it with the ID that was picked so that replicas and the AOF end
+/// up with the same one. Nothing is written when an error is returned.
+///
+/// Creating, sealing and syncing segments is left to the I/O thread, which
+/// includes creating the next segment an append that has to wait is waiting
+/// on.
+fn append<S: AsRef<str>>(
+    r: &Redis,
+    stream: &Rc<UnsafeCell<Stream>>,
+    key: &str,
+    id: Option<StreamID>,
+    fields: &[S],
+) -> AddOutcome {
+    let mut kv: Vec<listpack::MemoizedValue> = fields
+        .iter()
+        .map(|arg| arg.as_ref())
+        .map(|arg| listpack::parse_raw_memoized(arg.as_ptr(), arg.len()))
+        .collect();
+
+    let added = unsafe { (*stream.get()).add(id, &mut kv) };
+    let manager = manager()?;
+    unsafe { (*stream.get()).schedule(manager.storage()) }?;
+    manager.storage().watch(r);
+    let id = added?;
+
+    appended(r, key, &id);
+    let id_str = id.to_string();
+    let mut replicated: Vec<&str> = Vec::with_capacity(fields.len() + 2);
+    replicated.push(key);
+    replicated.push(&id_str);
+    replicated.extend(fields.iter().map(|field| field.as_ref()));
+    r.replicate(AddCommand.name(), &replicated)
+        .map_err(|err| StreamError::Generic(err.to_string()))?;
+    Ok(id)
+}
+
+/// Replies to an `MO.XADD` with the ID of the record it added. A duplicate
+/// gets a `DUPLICATE` error that carries the ID of the original instead.
+fn reply_added(r: &Redis, explicit: bool, added: AddOutcome) -> Result<(), SlicedError> {
+    match added {
+        Ok(id) => r.reply_string(&id.to_string()),
+        Err(StreamError::BadInput) if explicit => Err(error!(
+            "The ID specified in {} is equal or smaller than the target stream \
+             top item",
+            AddCommand.name()
+        )),
+        // Nothing was written, so there was nothing to replicate either.
+        Err(StreamError::Duplicate(original)) => r.reply_error(&format!(
+            "DUPLICATE {} was added with the same dedupe key",
+            original
+        )),
+        Err(err) => Err(SlicedError::from(err)),
+    }
+}
+
+/// Holds an append back until the next segment of its stream is ready, rather
+/// than failing it. The client is blocked until it's written.
+///
+/// Clients that can't be blocked are replied to right away with the ID they
+/// gave, as the record is written with it once the segment is ready. The
+/// commands a replica is sent always carry one. Without one there's nothing
+/// to reply with, so the append fails instead.
+fn park(
+    r: &Redis,
+    key: &str,
+    id: Option<StreamID>,
+    fields: &[&str],
+) -> Result<(), SlicedError> {
+    let bc = if r.can_block() {
+        Some(redmod::block_client(
+            r.ctx,
+            Some(StreamAdd_Reply),
+            None,
+            Some(StreamAdd_Free),
+            0,
+        ))
+    } else if id.is_none() {
+        return Err(error!("The stream is waiting on its next segment, try again"));
+    } else {
+        None
+    };
+
+    let retry = PARKED.lock().park(ParkedAdd {
+        bc,
+        key: String::from(key),
+        id,
+        fields: fields.iter().map(|field| String::from(*field)).collect(),
+    });
+    if retry {
+        r.start_timer(POLL_INTERVAL_MS, |r| resume_parked(&r));
+    }
+    match (bc, id) {
+        (None, Some(id)) => r.reply_string(&id.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the appends that were parked, in the order they came in, and
+/// replies to their clients. The ones whose stream is still waiting on its
+/// next segment are parked again, along with the ones behind them.
+fn resume_parked(r: &Redis) {
+    let adds = PARKED.lock().take();
+    let mut waiting = HashSet::new();
+    for parked in adds {
+        if waiting.contains(&parked.key) {
+            PARKED.lock().park(parked);
+            continue;
+        }
+        let key = &parked.key;
+        let added = manager()
+            .and_then(|manager| manager.get_stream(key).ok_or(StreamError::NotExists))
+            .and_then(|stream| append(r, &stream, key, parked.id, &parked.fields));
+        match (added, parked.bc) {
+            (Err(StreamError::WouldBlock), _) => {
+                waiting.insert(parked.key.clone());
+                PARKED.lock().park(parked);
+            }
+            (added, Some(bc)) => {
+                let outcome: Box<AddOutcome> = Box::new(added);
+                redmod::unblock_client(bc, Box::into_raw(outcome) as *mut u8);
+            }
+            // The client was already replied to.
+            (Err(err), None) => r.log(
+                LogLevel::Warning,
+                &format!("Failed to add a record to {}: {}", parked.key, err),
+            ),
+            (Ok(_), None) => {}
+        }
+    }
+    if !waiting.is_empty() {
+        r.start_timer(POLL_INTERVAL_MS, |r| resume_parked(&r));
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamAdd_Reply(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&AddReplyCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamAdd_Free(
+    ctx: *mut redmod::RedisModuleCtx,
+    privdata: *mut libc::c_void,
+) {
+    if !privdata.is_null() {
+        drop(unsafe { Box::from_raw(privdata as *mut AddOutcome) });
+    }
+}
+
+// AddReplyCommand replies to a MO.XADD that had to wait on the next segment
+// of its stream. It's run by Redis with the arguments of the original
+// command.
+struct AddReplyCommand {}
+
+impl Command for AddReplyCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xadd"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let outcome = redmod::get_blocked_client_private_data(r.ctx) as *mut AddOutcome;
+        if outcome.is_null() {
+            return Err(error!("Failed to add to the stream"));
+        }
+        let explicit = args.get(2).map_or(false, |id| *id != "*");
+        reply_added(&r, explicit, unsafe { (*outcome).clone() })
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+/// MO.XCONFIG
+pub struct ConfigCommand;
+
+impl ConfigCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> [COMPRESSION <none|zstd> [<level>] | \
+             DEDUPE <window> <MS|RECORDS>]",
+            self.name()
+        )
+    }
+}
+
+impl Command for ConfigCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xconfig"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 2 && args.len() != 4 && args.len() != 5 {
+            return Err(self.usage());
+        }
+
+        let stream = match manager()?.get_stream(args[1]) {
+            Some(stream) => stream,
+            None => return Err(error!("No such stream")),
+        };
+        let stream = unsafe { &mut *stream.get() };
+
+        if args.len() > 2 {
+            let mut config = *stream.config();
+            match args[2].to_lowercase().as_str() {
+                "compression" => {
+                    config.compression = match parse_compression(args[3]) {
+                        Some(COMPRESS_LZ4) => {
+                            let err = unsupported_compression(COMPRESS_LZ4);
+                            return Err(SlicedError::from(err));
+                        }
+                        Some(compression) => compression,
+                        None => return Err(self.usage()),
+                    };
+                    if let Some(level) = args.get(4) {
+                        config.compression_level = match level.parse::<i32>() {
+                            Ok(level) if level >= 1 && level <= 22 => level,
+                            _ => {
+                                return Err(error!(
+                                    "Compression level must be between 1 and 22"
+                                ))
+                            }
+                        };
+                    }
+                }
+                "dedupe" if args.len() == 5 => {
+                    config.dedupe_window = args[3].parse::<u64>().map_err(|_| {
+                        error!("Dedupe window must be a non-negative integer")
+                    })?;
+                    config.dedupe_unit =
+                        parse_dedupe_unit(args[4]).ok_or_else(|| self.usage())?;
+                }
+                _ => return Err(self.usage()),
+            }
+            stream.configure(config)?;
+            r.replicate_verbatim()?;
+        }
+
+        // Reply with the settings as they are now along with how much the
+        // sealed segments take up.
+        let config = stream.config();
+        let stats = stream.archive_stats();
+        r.reply_array(14)?;
+        r.reply_string("compression")?;
+        r.reply_string(compression_name(config.compression))?;
+        r.reply_string("compression-level")?;
+        r.reply_integer(config.compression_level as i64)?;
+        r.reply_string("dedupe-window")?;
+        r.reply_integer(config.dedupe_window as i64)?;
+        r.reply_string("dedupe-unit")?;
+        r.reply_string(dedupe_unit_name(config.dedupe_unit))?;
+        r.reply_string("segments")?;
+        r.reply_integer(stats.segments as i64)?;
+        r.reply_string("total-size")?;
+        r.reply_integer(stats.total_size as i64)?;
+        r.reply_string("total-size-compressed")?;
+        r.reply_integer(stats.total_size_compressed as i64)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+/// MO.XCHECK
+pub struct CheckCommand;
+
+impl Command for CheckCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xcheck"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() != 1 {
+            return Err(error!("Usage: {}", self.name()));
+        }
+
+        // A dry run of recovery over every stream on disk, sealed segments
+        // included. Nothing is modified or picked up.
+        let reports = manager()?.recover(true)?;
+        r.reply_array(reports.len() as i64)?;
+        for report in &reports {
+            r.reply_array(13)?;
+            r.reply_string(&report.name)?;
+            r.reply_string("segments")?;
+            r.reply_integer(report.segments as i64)?;
+            r.reply_string("bad-segments")?;
+            r.reply_array(report.bad_segments.len() as i64)?;
+            for path in &report.bad_segments {
+                r.reply_string(&path.to_string_lossy())?;
+            }
+            r.reply_string("packs")?;
+            r.reply_integer(report.tail.packs as i64)?;
+            r.reply_string("records")?;
+            r.reply_integer(report.tail.records as i64)?;
+            r.reply_string("discarded")?;
+            r.reply_integer(report.tail.discarded as i64)?;
+            r.reply_string("error")?;
+            match report.error {
+                Some(ref err) => r.reply_string(err)?,
+                None => r.reply_null()?,
+            }
+        }
+        Ok(())
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+fn invalid_id() -> SlicedError {
+    error!("Invalid stream ID specified as stream command argument")
+}
+
+/// MO.XRANGE
+pub struct RangeCommand;
+
+impl Command for RangeCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xrange"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        read_range(&r, self.name(), args, false)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+/// MO.XREVRANGE
+pub struct RevRangeCommand;
+
+impl Command for RevRangeCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xrevrange"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        read_range(&r, self.name(), args, true)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+/// Runs MO.XRANGE, or MO.XREVRANGE which takes the end of the range first.
+fn read_range(
+    r: &Redis,
+    name: &str,
+    args: &[&str],
+    rev: bool,
+) -> Result<(), SlicedError> {
+    let usage = || {
+        let bounds = if rev { "<end> <start>" } else { "<start> <end>" };
+        error!("Usage: {} <key> {} [COUNT <count>]", name, bounds)
+    };
+    if args.len() != 4 && args.len() != 6 {
+        return Err(usage());
+    }
+
+    let (start, end) = if rev { (args[3], args[2]) } else { (args[2], args[3]) };
+    let start = parse_bound(start, true)?;
+    let end = parse_bound(end, false)?;
+    let count = match args.get(4) {
+        Some(option) if option.to_lowercase() == "count" => match args[5].parse::<i64>() {
+            Ok(count) if count < 0 => Some(0),
+            Ok(count) => Some(count as usize),
+            Err(_) => return Err(error!("value is not an integer or out of range")),
+        },
+        Some(_) => return Err(usage()),
+        None => None,
+    };
+
+    // Like Redis, a stream that isn't there is the same as an empty one.
+    let stream = match manager()?.get_stream(args[1]) {
+        Some(stream) => stream,
+        None => return r.reply_array(0),
+    };
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return r.reply_array(0),
+    };
+
+    let mut read = RangeRead::new(stream, start, end, rev, count);
+    match read.resume()? {
+        None => reply_entries(r, &read.into_entries()),
+        Some(fault) => suspend(r, read, fault),
+    }
+}
+
+/// Parses a bound of a range. "-" and "+" are the smallest and largest ID.
+/// An ID without a sequence covers all of its millisecond. An exclusive
+/// bound starts with "(" and is None if nothing is left within it.
+fn parse_bound(arg: &str, start: bool) -> Result<Option<StreamID>, SlicedError> {
+    match arg {
+        "-" => return Ok(Some(StreamID::MIN)),
+        "+" => return Ok(Some(StreamID::MAX)),
+        _ => {}
+    }
+
+    let (exclusive, arg) = if arg.starts_with('(') {
+        (true, &arg[1..])
+    } else {
+        (false, arg)
+    };
+    let missing_seq = if start { 0 } else { u64::max_value() };
+    let id = parse_id(arg, missing_seq).ok_or_else(invalid_id)?;
+    Ok(match (exclusive, start) {
+        (false, _) => Some(id),
+        (true, true) => id.incr(),
+        (true, false) => id.decr(),
+    })
+}
+
+/// Replies with records the way XRANGE does.
+fn reply_entries(r: &Redis, entries: &[Entry]) -> Result<(), SlicedError> {
+    r.reply_array(entries.len() as i64)?;
+    for entry in entries {
+        r.reply_array(2)?;
+        r.reply_string(&entry.id.to_string())?;
+        r.reply_array(entry.fields.len() as i64 * 2)?;
+        for &(ref field, ref value) in entry.fields.iter() {
+            r.reply_buffer(field)?;
+            r.reply_buffer(value)?;
+        }
+    }
+    Ok(())
+}
+
+/// What a client blocked on a read is unblocked with.
+enum ReadReply {
+    Range(Vec<Entry>),
+    /// The records of each stream that had any, for MO.XREAD.
+    Streams(Vec<(String, Vec<Entry>)>),
+    /// The records MO.XREADGROUP read from each stream, which changed the
+    /// state of the group.
+    Delivered(Delivered),
+}
+
+/// What MO.XREADGROUP read and what that did to the group of each stream.
+struct Delivered {
+    group: String,
+    consumer: String,
+    streams: Vec<(String, Vec<Entry>)>,
+    /// The records that are pending for the consumer since the read and the
+    /// ID of the last record the group delivered, by key.
+    changes: Vec<(String, Vec<StreamID>, StreamID)>,
+}
+
+impl Delivered {
+    /// Replicates what the read did rather than the read, which depends on
+    /// what's idle here. Like Redis does for XREADGROUP, the records are
+    /// claimed by the consumer with FORCE and the group is set to the last
+    /// ID it delivered.
+    fn replicate(&self, r: &Redis) -> Result<(), SlicedError> {
+        for &(ref key, ref claimed, ref last_id) in self.changes.iter() {
+            if !claimed.is_empty() {
+                let ids: Vec<String> = claimed.iter().map(|id| id.to_string()).collect();
+                let mut replicated: Vec<&str> =
+                    vec![key.as_str(), self.group.as_str(), self.consumer.as_str(), "0"];
+                replicated.extend(ids.iter().map(|id| id.as_str()));
+                replicated.push("FORCE");
+                r.replicate("mo.xclaim", &replicated)?;
+            }
+            let last_id = last_id.to_string();
+            r.replicate("mo.xgroup", &["SETID", key, &self.group, &last_id])?;
+        }
+        Ok(())
+    }
+}
+
+type ReadOutcome = Result<ReadReply, StreamError>;
+
+/// A read that stops at packs that aren't in memory.
+trait Resumable: 'static {
+    /// Reads until it's done or it gets to a pack that has to be read on the
+    /// I/O thread.
+    fn resume(&mut self) -> Result<Option<Fault>, StreamError>;
+
+    /// What's read once it's done.
+    fn finish(self) -> ReadOutcome;
+}
+
+impl Resumable for RangeRead {
+    fn resume(&mut self) -> Result<Option<Fault>, StreamError> {
+        RangeRead::resume(self)
+    }
+
+    fn finish(self) -> ReadOutcome {
+        Ok(ReadReply::Range(self.into_entries()))
+    }
+}
+
+/// Blocks the client while the pack a read needs is read on the I/O thread
+/// so that the event-loop doesn't wait on the disk. The read picks up from
+/// there and the client is unblocked once it's done, however many packs that
+/// takes.
+fn suspend<R: Resumable>(r: &Redis, read: R, fault: Fault) -> Result<(), SlicedError> {
+    let bc = redmod::block_client(
+        r.ctx,
+        Some(StreamRead_Reply),
+        None,
+        Some(StreamRead_Free),
+        0,
+    );
+    if let Err(err) = fault_in(bc, read, fault) {
+        // Nothing was handed to the I/O thread so the reply can be made
+        // right here.
+        redmod::abort_block(bc);
+        return Err(SlicedError::from(err));
+    }
+    manager()?.storage().watch(r);
+    Ok(())
+}
+
+/// Has the pack of a fault read on the I/O thread and resumes the read once
+/// it's in.
+fn fault_in<R: Resumable>(
+    bc: *mut redmod::RedisModuleBlockedClient,
+    mut read: R,
+    fault: Fault,
+) -> Result<u64, StreamError> {
+    let storage = manager()?.storage();
+    fault.read(storage, move |result| {
+        let outcome = match result.and_then(|_| read.resume()) {
+            Ok(None) => read.finish(),
+            Ok(Some(fault)) => match fault_in(bc, read, fault) {
+                Ok(_) => return,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        unblock(bc, outcome);
+    })
+}
+
+fn unblock(bc: *mut redmod::RedisModuleBlockedClient, outcome: ReadOutcome) {
+    let outcome: Box<ReadOutcome> = Box::new(outcome);
+    redmod::unblock_client(bc, Box::into_raw(outcome) as *mut u8);
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRead_Reply(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ReadReplyCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRead_Timeout(
+    ctx: *mut redmod::RedisModuleCtx,
+    argv: *mut *mut redmod::RedisModuleString,
+    argc: libc::c_int,
+) -> redmod::Status {
+    Command::harness(&ReadTimeoutCommand {}, ctx, argv, argc)
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRead_Disconnect(
+    ctx: *mut redmod::RedisModuleCtx,
+    bc: *mut redmod::RedisModuleBlockedClient,
+) {
+    // Nobody's left to reply to. The handle still has to be given back, which
+    // a read that's under way does once it's done.
+    if TAILERS.lock().remove(bc).is_some() {
+        redmod::unblock_client(bc, ptr::null_mut());
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(unused_variables)]
+#[no_mangle]
+pub extern "C" fn StreamRead_Free(
+    ctx: *mut redmod::RedisModuleCtx,
+    privdata: *mut libc::c_void,
+) {
+    if !privdata.is_null() {
+        drop(unsafe { Box::from_raw(privdata as *mut ReadOutcome) });
+    }
+}
+
+// ReadReplyCommand replies to a read that had to wait, either on the I/O
+// thread or for records to be appended. It's run by Redis with the arguments
+// of the original command.
+struct ReadReplyCommand {}
+
+impl Command for ReadReplyCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xread"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, _args: &[&str]) -> Result<(), SlicedError> {
+        let outcome = redmod::get_blocked_client_private_data(r.ctx) as *mut ReadOutcome;
+        if outcome.is_null() {
+            return Err(error!("Failed to read the stream"));
+        }
+
+        match unsafe { &*outcome } {
+            &Ok(ReadReply::Range(ref entries)) => reply_entries(&r, entries),
+            &Ok(ReadReply::Streams(ref streams)) => reply_streams(&r, streams),
+            &Ok(ReadReply::Delivered(ref delivered)) => {
+                delivered.replicate(&r)?;
+                reply_streams(&r, &delivered.streams)
+            }
+            &Err(ref err) => Err(SlicedError::from(err.clone())),
+        }
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+// ReadTimeoutCommand replies to a MO.XREAD that blocked for longer than it
+// was willing to wait without anything being appended. It's run by Redis with
+// the arguments of the original command.
+struct ReadTimeoutCommand {}
+
+impl Command for ReadTimeoutCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xread"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, _args: &[&str]) -> Result<(), SlicedError> {
+        // A client that was woken has a read under way which gives the handle
+        // back once it's done. One that's still waiting has to give it back
+        // here.
+        let bc = redmod::get_blocked_client_handle(r.ctx);
+        if TAILERS.lock().remove(bc).is_some() {
+            redmod::unblock_client(bc, ptr::null_mut());
+        }
+        r.reply_null()
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+/// MO.XREAD
+pub struct ReadCommand;
+
+impl ReadCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} [COUNT <count>] [BLOCK <milliseconds>] STREAMS <key> \
+             [<key> ...] <id|$> [<id|$> ...]",
+            self.name()
+        )
+    }
+}
+
+impl Command for ReadCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xread"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        let mut count = None;
+        let mut block = None;
+        let mut i = 1;
+        while i < args.len() {
+            match args[i].to_lowercase().as_str() {
+                "count" if i + 1 < args.len() => {
+                    let n = args[i + 1]
+                        .parse::<i64>()
+                        .map_err(|_| error!("value is not an integer or out of range"))?;
+                    // Like XREAD, no count is the same as no limit.
+                    count = if n > 0 { Some(n as usize) } else { None };
+                }
+                "block" if i + 1 < args.len() => {
+                    let millis = args[i + 1]
+                        .parse::<i64>()
+                        .map_err(|_| {
+                            error!("timeout is not an integer or out of range")
+                        })?;
+                    if millis < 0 {
+                        return Err(error!("timeout is negative"));
+                    }
+                    block = Some(millis);
+                }
+                "streams" => break,
+                _ => return Err(self.usage()),
+            }
+            i += 2;
+        }
+
+        let rest = &args[cmp::min(i + 1, args.len())..];
+        if rest.is_empty() || rest.len() % 2 != 0 {
+            return Err(self.usage());
+        }
+        let (keys, ids) = rest.split_at(rest.len() / 2);
+
+        // "$" is whatever the stream is at now, so only records that are
+        // appended from here on are read.
+        let manager = manager()?;
+        let mut streams = Vec::with_capacity(keys.len());
+        for (key, id) in keys.iter().zip(ids.iter()) {
+            let id = match *id {
+                "$" => manager
+                    .get_stream(key)
+                    .and_then(|stream| unsafe { (*stream.get()).last_id() })
+                    .unwrap_or(StreamID::MIN),
+                id => parse_id(id, 0).ok_or_else(invalid_id)?,
+            };
+            streams.push((String::from(*key), id));
+        }
+
+        let mut read = StreamsRead::new(&streams, count);
+        if let Some(fault) = read.resume()? {
+            return suspend(&r, read, fault);
+        }
+        let found = read.into_streams();
+        let timeout = match block {
+            Some(timeout) if found.is_empty() => timeout,
+            _ => return reply_streams(&r, &found),
+        };
+
+        // Nothing to read yet. The client is woken once records are appended
+        // past the ID it gave for any of its streams.
+        let bc = redmod::block_client(
+            r.ctx,
+            Some(StreamRead_Reply),
+            Some(StreamRead_Timeout),
+            Some(StreamRead_Free),
+            timeout,
+        );
+        redmod::set_disconnect_callback(bc, Some(StreamRead_Disconnect));
+        TAILERS.lock().block(Tailer { bc, streams, count });
+        Ok(())
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "readonly"
+    }
+}
+
+/// Reads the records after an ID in each of a number of streams, one stream
+/// after the other.
+struct StreamsRead {
+    reads: Vec<(String, RangeRead)>,
+    /// The read that's under way.
+    at: usize,
+}
+
+impl StreamsRead {
+    /// Starts a read of no more than count records past the ID of each
+    /// stream. Streams that aren't there have nothing to read.
+    fn new(streams: &[(String, StreamID)], count: Option<usize>) -> StreamsRead {
+        let manager = manager().ok();
+        let reads = streams
+            .iter()
+            .filter_map(|&(ref key, ref id)| {
+                let stream = manager.as_ref()?.get_stream(key)?;
+                let start = id.incr()?;
+                let read = RangeRead::new(stream, start, StreamID::MAX, false, count);
+                Some((key.clone(), read))
+            })
+            .collect();
+        StreamsRead { reads, at: 0 }
+    }
+
+    fn into_streams(self) -> Vec<(String, Vec<Entry>)> {
+        self.reads
+            .into_iter()
+            .map(|(key, read)| (key, read.into_entries()))
+            .filter(|&(_, ref entries)| !entries.is_empty())
+            .collect()
+    }
+}
+
+impl Resumable for StreamsRead {
+    fn resume(&mut self) -> Result<Option<Fault>, StreamError> {
+        while self.at < self.reads.len() {
+            if let Some(fault) = self.reads[self.at].1.resume()? {
+                return Ok(Some(fault));
+            }
+            self.at += 1;
+        }
+        Ok(None)
+    }
+
+    fn finish(self) -> ReadOutcome {
+        Ok(ReadReply::Streams(self.into_streams()))
+    }
+}
+
+/// Replies with the records of each stream the way XREAD does, or with nil
+/// if there aren't any.
+fn reply_streams(r: &Redis, streams: &[(String, Vec<Entry>)]) -> Result<(), SlicedError> {
+    if streams.is_empty() {
+        return r.reply_null();
+    }
+    r.reply_array(streams.len() as i64)?;
+    for &(ref key, ref entries) in streams {
+        r.reply_array(2)?;
+        r.reply_string(key)?;
+        reply_entries(r, entries)?;
+    }
+    Ok(())
+}
+
+/// Lets the clients blocked in MO.XREAD on key know that id was appended.
+/// The ones that are behind it are woken on the next tick.
+pub fn appended(r: &Redis, key: &str, id: &StreamID) {
+    if TAILERS.lock().appended(key, id) {
+        r.run(|r| wake(&r));
+    }
+}
+
+/// Wakes the clients blocked on streams that have had records appended past
+/// the ID they're waiting on. Each reads its streams and is unblocked with
+/// what it read.
+fn wake(r: &Redis) {
+    let manager = match manager() {
+        Ok(manager) => manager,
+        Err(_) => return,
+    };
+    let woken = TAILERS.lock().take_ready(|key| {
+        manager.get_stream(key).and_then(|stream| unsafe { (*stream.get()).last_id() })
+    });
+
+    let mut faulted = false;
+    for tailer in woken {
+        let mut read = StreamsRead::new(&tailer.streams, tailer.count);
+        let outcome = match read.resume() {
+            Ok(None) => read.finish(),
+            Ok(Some(fault)) => match fault_in(tailer.bc, read, fault) {
+                Ok(_) => {
+                    faulted = true;
+                    continue;
+                }
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        unblock(tailer.bc, outcome);
+    }
+
+    if faulted {
+        manager.storage().watch(r);
+    }
+}
+
+/// MO.XGROUP
+pub struct GroupCommand;
+
+impl GroupCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} CREATE <key> <group> <id|$> [MKSTREAM] | DESTROY <key> <group> | \
+             SETID <key> <group> <id|$> | RECLAIM <key> <group> <idle-timeout> \
+             [DEADLETTER <key> <max-deliveries>]",
+            self.name()
+        )
+    }
+}
+
+impl Command for GroupCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xgroup"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 4 {
+            return Err(self.usage());
+        }
+
+        let manager = manager()?;
+        match args[1].to_lowercase().as_str() {
+            "create" if args.len() == 5 || args.len() == 6 => {
+                let mkstream = match args.get(5) {
+                    Some(option) if option.to_lowercase() == "mkstream" => true,
+                    Some(_) => return Err(self.usage()),
+                    None => false,
+                };
+                let stream = match manager.get_stream(args[2]) {
+                    Some(stream) => stream,
+                    None if mkstream => manager.create_stream(SDS::new(args[2]))?,
+                    None => {
+                        return Err(error!(
+                            "The XGROUP subcommand requires the key to exist. Note \
+                             that for CREATE you may want to use the MKSTREAM option \
+                             to create an empty stream automatically."
+                        ))
+                    }
+                };
+                let stream = unsafe { &mut *stream.get() };
+
+                let last_id = match args[4] {
+                    "$" => stream.last_id().unwrap_or(StreamID::MIN),
+                    id => parse_id(id, 0).ok_or_else(invalid_id)?,
+                };
+                match stream.create_group(args[3], last_id) {
+                    Err(StreamError::Exists) => {
+                        return Err(error!("BUSYGROUP Consumer Group name already exists"))
+                    }
+                    result => result?,
+                }
+
+                // Replicas are given the ID $ stood for here.
+                let last_id = last_id.to_string();
+                let mut replicated = vec!["CREATE", args[2], args[3], last_id.as_str()];
+                if let Some(option) = args.get(5) {
+                    replicated.push(option);
+                }
+                r.replicate(self.name(), &replicated)?;
+                r.reply_string("OK")
+            }
+            "setid" if args.len() == 5 => {
+                let stream = manager
+                    .get_stream(args[2])
+                    .ok_or_else(|| no_group(args[2], args[3]))?;
+                let stream = unsafe { &mut *stream.get() };
+                let last_id = match args[4] {
+                    "$" => stream.last_id().unwrap_or(StreamID::MIN),
+                    id => parse_id(id, 0).ok_or_else(invalid_id)?,
+                };
+                stream
+                    .group(args[3])
+                    .ok_or_else(|| no_group(args[2], args[3]))?
+                    .set_last_id(last_id);
+                let last_id = last_id.to_string();
+                r.replicate(self.name(), &["SETID", args[2], args[3], &last_id])?;
+                r.reply_string("OK")
+            }
+            "destroy" if args.len() == 4 => {
+                let destroyed = match manager.get_stream(args[2]) {
+                    Some(stream) => unsafe { (*stream.get()).destroy_group(args[3]) },
+                    None => false,
+                };
+                if destroyed {
+                    r.replicate_verbatim()?;
+                }
+                r.reply_integer(destroyed as i64)
+            }
+            "reclaim" if args.len() == 5 || args.len() == 8 => {
+                let idle_timeout = args[4].parse::<u64>().map_err(|_| {
+                    error!("Idle timeout must be a number of milliseconds")
+                })?;
+                let (dead_letter, max_deliveries) = match args.get(5) {
+                    Some(option) if option.to_lowercase() == "deadletter" => {
+                        let max_deliveries = match args[7].parse::<u64>() {
+                            Ok(max) if max > 0 => max,
+                            _ => {
+                                return Err(error!(
+                                    "Max deliveries must be a positive integer"
+                                ))
+                            }
+                        };
+                        (Some(String::from(args[6])), max_deliveries)
+                    }
+                    Some(_) => return Err(self.usage()),
+                    None => (None, 0),
+                };
+                if dead_letter.as_ref().map_or(false, |key| key == args[2]) {
+                    return Err(error!("A stream can't be its own dead-letter stream"));
+                }
+
+                let stream = manager
+                    .get_stream(args[2])
+                    .ok_or_else(|| no_group(args[2], args[3]))?;
+                let group = unsafe { (*stream.get()).group(args[3]) }
+                    .ok_or_else(|| no_group(args[2], args[3]))?;
+                group.set_reclaim(Reclaim { idle_timeout, max_deliveries, dead_letter });
+                if idle_timeout > 0 {
+                    reclaim::watch(&r, args[2], args[3]);
+                }
+                r.replicate_verbatim()?;
+                r.reply_string("OK")
+            }
+            _ => Err(self.usage()),
+        }
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
 
 /// MO.XACK
 pub struct AckCommand;
 
-/// MO.GROUP
-pub struct GroupCommand;
+impl Command for AckCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xack"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 4 {
+            return Err(error!("Usage: {} <key> <group> <id> [<id> ...]", self.name()));
+        }
+
+        // Every ID is checked before any of them is acked.
+        let ids = args[3..]
+            .iter()
+            .map(|id| parse_id(id, 0).ok_or_else(invalid_id))
+            .collect::<Result<Vec<StreamID>, SlicedError>>()?;
+
+        let stream = match manager()?.get_stream(args[1]) {
+            Some(stream) => stream,
+            None => return r.reply_integer(0),
+        };
+        let group = match unsafe { (*stream.get()).group(args[2]) } {
+            Some(group) => group,
+            None => return r.reply_integer(0),
+        };
+
+        let acked = ids.iter().filter(|id| group.ack(id)).count();
+        if acked > 0 {
+            r.replicate_verbatim()?;
+        }
+        r.reply_integer(acked as i64)
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+/// MO.XREADGROUP
+pub struct ReadGroupCommand;
+
+impl ReadGroupCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} GROUP <group> <consumer> [COUNT <count>] [NOACK] STREAMS \
+             <key> [<key> ...] <id|>> [<id|>> ...]",
+            self.name()
+        )
+    }
+}
+
+impl Command for ReadGroupCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xreadgroup"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 4 || args[1].to_lowercase() != "group" {
+            return Err(self.usage());
+        }
+        let (group, consumer) = (args[2], args[3]);
+
+        let mut count = None;
+        let mut noack = false;
+        let mut i = 4;
+        while i < args.len() {
+            match args[i].to_lowercase().as_str() {
+                "count" if i + 1 < args.len() => {
+                    let n = args[i + 1]
+                        .parse::<i64>()
+                        .map_err(|_| error!("value is not an integer or out of range"))?;
+                    count = if n > 0 { Some(n as usize) } else { None };
+                    i += 2;
+                }
+                "noack" => {
+                    noack = true;
+                    i += 1;
+                }
+                "streams" => break,
+                _ => return Err(self.usage()),
+            }
+        }
+
+        let rest = &args[cmp::min(i + 1, args.len())..];
+        if rest.is_empty() || rest.len() % 2 != 0 {
+            return Err(self.usage());
+        }
+        let (keys, ids) = rest.split_at(rest.len() / 2);
+
+        // ">" reads the records no consumer got yet. Any other ID rereads the
+        // records pending for the consumer after it.
+        let manager = manager()?;
+        let mut reads = Vec::with_capacity(keys.len());
+        for (key, id) in keys.iter().zip(ids.iter()) {
+            let stream = manager.get_stream(key);
+            let last_id = stream
+                .as_ref()
+                .and_then(|stream| unsafe { (*stream.get()).group(group) })
+                .map(|group| group.last_id());
+            let (stream, last_id) = match (stream, last_id) {
+                (Some(stream), Some(last_id)) => (stream, last_id),
+                _ => return Err(no_group(key, group)),
+            };
+
+            let read = match *id {
+                ">" => {
+                    let start = last_id.incr().unwrap_or(StreamID::MAX);
+                    let mut read = RangeRead::new(
+                        Rc::clone(&stream),
+                        start,
+                        StreamID::MAX,
+                        false,
+                        count,
+                    );
+                    read.keep_packs();
+                    GroupStreamRead::New(stream, read)
+                }
+                id => {
+                    let after = parse_id(id, 0).ok_or_else(invalid_id)?;
+                    let pending = unsafe { (*stream.get()).group(group) }
+                        .ok_or_else(|| no_group(key, group))?
+                        .read_pending(consumer, &after, count);
+                    GroupStreamRead::Pending(pending)
+                }
+            };
+            reads.push((String::from(*key), read));
+        }
+
+        let mut read = GroupRead {
+            group: String::from(group),
+            consumer: String::from(consumer),
+            count,
+            noack,
+            reads,
+            at: 0,
+        };
+        if let Some(fault) = read.resume()? {
+            return suspend(&r, read, fault);
+        }
+        match read.finish()? {
+            ReadReply::Delivered(ref delivered) => {
+                delivered.replicate(&r)?;
+                reply_streams(&r, &delivered.streams)
+            }
+            _ => Err(error!("Failed to read the stream")),
+        }
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+/// MO.XCLAIM
+pub struct ClaimCommand;
+
+impl ClaimCommand {
+    fn usage(&self) -> SlicedError {
+        error!(
+            "Usage: {} <key> <group> <consumer> <min-idle-time> <id> [<id> ...] [FORCE] \
+             [JUSTID]",
+            self.name()
+        )
+    }
+}
+
+impl Command for ClaimCommand {
+    // Should return the name of the command to be registered.
+    fn name(&self) -> &'static str {
+        "mo.xclaim"
+    }
+
+    // Run the command.
+    fn run(&self, r: Redis, args: &[&str]) -> Result<(), SlicedError> {
+        if args.len() < 6 {
+            return Err(self.usage());
+        }
+        let (key, group, consumer) = (args[1], args[2], args[3]);
+        let min_idle = args[4]
+            .parse::<i64>()
+            .map_err(|_| error!("Invalid min-idle-time argument for XCLAIM"))?;
+
+        let (mut force, mut justid) = (false, false);
+        let mut ids = &args[5..];
+        while let Some(option) = ids.last() {
+            match option.to_lowercase().as_str() {
+                "force" => force = true,
+                "justid" => justid = true,
+                _ => break,
+            }
+            ids = &ids[..ids.len() - 1];
+        }
+        if ids.is_empty() {
+            return Err(self.usage());
+        }
+        let ids = ids
+            .iter()
+            .map(|id| parse_id(id, 0).ok_or_else(invalid_id))
+            .collect::<Result<Vec<StreamID>, SlicedError>>()?;
+
+        let manager = manager()?;
+        let stream = manager.get_stream(key).ok_or_else(|| no_group(key, group))?;
+
+        // FORCE makes the records that aren't pending pending for the
+        // consumer first. That's how replicas are given the records a read
+        // delivered. Their packs are read in so that they can be read again
+        // as pending records.
+        let mut forced = Vec::new();
+        let mut faulted = None;
+        if force {
+            for id in ids.iter() {
+                let (master_id, pack, fault) =
+                    match range::locate(unsafe { &*stream.get() }, id)? {
+                        Some(found) => found,
+                        None => continue,
+                    };
+                if let Some(fault) = fault {
+                    if faulted != Some(master_id) {
+                        fault.read(manager.storage(), |_| {})?;
+                        faulted = Some(master_id);
+                    }
+                }
+                forced.push((*id, master_id, pack));
+            }
+        }
+        if faulted.is_some() {
+            manager.storage().watch(&r);
+        }
+
+        let group_state = unsafe { (*stream.get()).group(group) }
+            .ok_or_else(|| no_group(key, group))?;
+        group_state.force(consumer, forced)?;
+        let now = mstime(clock::system());
+        let min_idle = if min_idle > 0 { min_idle as u64 } else { 0 };
+        let claimed = group_state.claim(consumer, &ids, min_idle, now, justid)?;
+
+        // Replicas claim exactly the records that were claimed here, however
+        // long they've been idle over there.
+        if !claimed.is_empty() {
+            let claimed_ids: Vec<String> =
+                claimed.iter().map(|id| id.to_string()).collect();
+            let mut replicated: Vec<&str> = vec![key, group, consumer, "0"];
+            replicated.extend(claimed_ids.iter().map(|id| id.as_str()));
+            if force {
+                replicated.push("FORCE");
+            }
+            if justid {
+                replicated.push("JUSTID");
+            }
+            r.replicate(self.name(), &replicated)?;
+        }
+
+        if justid {
+            r.reply_array(claimed.len() as i64)?;
+            for id in claimed.iter() {
+                r.reply_string(&id.to_string())?;
+            }
+            return Ok(());
+        }
+        reply_entries(&r, &group_state.read_records(&claimed))
+    }
+
+    // Should return any flags to be registered with the name as a string
+    // separated list. See the Redis module API documentation for a complete
+    // list of the ones that are available.
+    fn str_flags(&self) -> &'static str {
+        "write"
+    }
+}
+
+fn no_group(key: &str, group: &str) -> SlicedError {
+    error!(
+        "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+        key, group
+    )
+}
+
+/// What MO.XREADGROUP reads from a stream.
+enum GroupStreamRead {
+    /// Records that haven't been delivered yet. They're delivered once
+    /// they've been read.
+    New(Rc<UnsafeCell<Stream>>, RangeRead),
+    /// Records that are pending for the consumer, which are always in memory.
+    Pending(Vec<Entry>),
+}
+
+/// Reads for MO.XREADGROUP, one stream after the other.
+struct GroupRead {
+    group: String,
+    consumer: String,
+    count: Option<usize>,
+    noack: bool,
+    reads: Vec<(String, GroupStreamRead)>,
+    /// The read that's under way.
+    at: usize,
+}
+
+impl Resumable for GroupRead {
+    fn resume(&mut self) -> Result<Option<Fault>, StreamError> {
+        while self.at < self.reads.len() {
+            if let GroupStreamRead::New(_, ref mut read) = self.reads[self.at].1 {
+                if let Some(fault) = read.resume()? {
+                    return Ok(Some(fault));
+                }
+            }
+            self.at += 1;
+        }
+        Ok(None)
+    }
+
+    fn finish(self) -> ReadOutcome {
+        let now = mstime(clock::system());
+        let mut streams = Vec::with_capacity(self.reads.len());
+        let mut changes = Vec::new();
+        for (key, read) in self.reads {
+            match read {
+                // Like XREADGROUP, a stream is in the reply even if nothing's
+                // pending in it.
+                GroupStreamRead::Pending(entries) => streams.push((key, entries)),
+                GroupStreamRead::New(stream, read) => {
+                    let (mut entries, packs) = read.into_parts();
+                    let group = match unsafe { (*stream.get()).group(&self.group) } {
+                        Some(group) => group,
+                        None => {
+                            return Err(StreamError::Generic(format!(
+                                "NOGROUP consumer group '{}' was destroyed",
+                                self.group
+                            )))
+                        }
+                    };
+
+                    // Records that were idle for too long with some other
+                    // consumer come before new ones. Each of them is
+                    // replicated as a claim, even one that couldn't be read.
+                    let last_id = group.last_id();
+                    let (mut claimed, mut delivered) = group.redeliver(
+                        &self.consumer,
+                        self.count,
+                        now,
+                        reclaim::REDELIVER_BUDGET,
+                    )?;
+                    if let Some(count) = self.count {
+                        entries.truncate(count - claimed.len());
+                    }
+                    let entries = group.deliver(
+                        &self.consumer,
+                        entries,
+                        packs,
+                        self.noack,
+                        now,
+                    )?;
+
+                    // New records aren't pending with NOACK.
+                    if !self.noack {
+                        claimed.extend(entries.iter().map(|entry| entry.id));
+                    }
+                    delivered.extend(entries);
+                    if !claimed.is_empty() || group.last_id() != last_id {
+                        changes.push((key.clone(), claimed, group.last_id()));
+                    }
+                    if !delivered.is_empty() {
+                        streams.push((key, delivered));
+                    }
+                }
+            }
+        }
+        Ok(ReadReply::Delivered(Delivered {
+            group: self.group,
+            consumer: self.consumer,
+            streams,
+            changes,
+        }))
+    }
+}
+
+/// MO.XDEL
+pub struct DelCommand;
+
+/// MO.XTRIM
+pub struct TrimCommand;
 
 /// MO.STREAM
 pub struct StreamCommand;
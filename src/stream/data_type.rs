@@ -24,9 +24,10 @@ pub fn load(ctx: *mut redmod::RedisModuleCtx) -> redmod::Status {
 #[allow(unused_variables)]
 #[no_mangle]
 pub extern "C" fn Sliced_Type_Stream_RDBLoad(rdb: *mut redmod::RedisModuleIO,
-                                             encver: libc::c_int) {
+                                             encver: libc::c_int) -> *mut u8 {
 //        log_debug!(self, "Histogram_RDBLoad");
     println!("slice/d Stream RDBLoad");
+    ptr::null_mut()
 }
 
 #[allow(non_snake_case)]
@@ -85,9 +86,7 @@ pub extern "C" fn Sliced_Type_Stream_AOFRewrite(rdb: *mut redmod::RedisModuleIO,
 #[allow(non_snake_case)]
 #[allow(unused_variables)]
 #[no_mangle]
-pub extern "C" fn Sliced_Type_Stream_MemUsage(rdb: *mut redmod::RedisModuleIO,
-                                              key: *mut redmod::RedisModuleString,
-                                              value: *mut u8) -> libc::size_t {
+pub extern "C" fn Sliced_Type_Stream_MemUsage(value: *const u8) -> libc::size_t {
     println!("slice/d Stream MemUsage");
     return 0;
 }
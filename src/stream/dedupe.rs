@@ -0,0 +1,199 @@
+use std::collections::{HashMap, VecDeque};
+use std::slice;
+
+use crate::redis::listpack::{MemoizedValue, Value};
+
+use super::id::StreamID;
+use super::range::Entry;
+use super::writer::FIELD_DUPE_KEY;
+use super::{StreamConfig, StreamError, DEDUPE_RECORDS};
+
+/// The dedupe keys of the records of a stream that are within its dedupe
+/// window, along with the ID each was written with.
+///
+/// Keys expire in the order they were written. A window of time is measured
+/// by the IDs of the records rather than by a clock, so a replica, or the
+/// same stream after a restart, expires keys exactly like the master did.
+#[derive(Default)]
+pub struct Dedupe {
+    window: u64,
+    by_records: bool,
+    keys: HashMap<Vec<u8>, StreamID>,
+    /// Keys in the order they were written, along with the number of records
+    /// that were written before them.
+    order: VecDeque<(u64, StreamID, Vec<u8>)>,
+    /// Number of records written so far.
+    written: u64,
+}
+
+impl Dedupe {
+    pub fn new(config: &StreamConfig) -> Dedupe {
+        let mut dedupe = Dedupe::default();
+        dedupe.configure(config);
+        dedupe
+    }
+
+    /// Picks up the dedupe window of a stream. Keys that fall out of a
+    /// smaller window expire on the next check.
+    pub fn configure(&mut self, config: &StreamConfig) {
+        self.window = config.dedupe_window;
+        self.by_records = config.dedupe_unit == DEDUPE_RECORDS;
+        if self.window == 0 {
+            self.keys.clear();
+            self.order.clear();
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.window > 0
+    }
+
+    /// Returns the ID of the record that was written with key, if it's still
+    /// within the window of a record that would be written with id.
+    pub fn check(&mut self, key: &[u8], id: &StreamID) -> Option<StreamID> {
+        self.expire(id);
+        self.keys.get(key).cloned()
+    }
+
+    /// Notes that a record was written with id and, maybe, a dedupe key.
+    pub fn written(&mut self, id: &StreamID, key: Option<Vec<u8>>) {
+        match key {
+            Some(key) if self.is_enabled() => {
+                self.keys.insert(key.clone(), *id);
+                self.order.push_back((self.written, *id, key));
+            }
+            _ => {}
+        }
+        self.written += 1;
+    }
+
+    /// Determines whether a record with id, which had records_after records
+    /// written after it, can still be within the window as of last_id.
+    pub fn reaches(&self, id: &StreamID, records_after: u64, last_id: &StreamID) -> bool {
+        if self.by_records {
+            records_after < self.window
+        } else {
+            last_id.ms.saturating_sub(id.ms) < self.window
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Lets go of the keys that are out of the window of a record that would
+    /// be written with id.
+    fn expire(&mut self, id: &StreamID) {
+        loop {
+            let expired = match self.order.front() {
+                Some(&(at, ref written_id, _)) => {
+                    !self.reaches(written_id, self.written - at - 1, id)
+                }
+                None => false,
+            };
+            if !expired {
+                break;
+            }
+            if let Some((_, written_id, key)) = self.order.pop_front() {
+                if self.keys.get(&key) == Some(&written_id) {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the dedupe key of a record, which is the value of the field
+/// named `?`. The field has to come first, as that's where it's looked for
+/// once the record is written, so a record that has it anywhere else is
+/// rejected rather than written with it as data.
+pub fn dupe_key(kv: &[MemoizedValue]) -> Result<Option<Vec<u8>>, StreamError> {
+    let field = Value::String(FIELD_DUPE_KEY.as_ptr(), FIELD_DUPE_KEY.len() as u32);
+    if kv.iter().step_by(2).skip(1).any(|kv| kv.value == field) {
+        return Err(StreamError::Generic(format!(
+            "the {} field has to be the first one",
+            FIELD_DUPE_KEY
+        )));
+    }
+    if kv.len() < 2 || kv[0].value != field {
+        return Ok(None);
+    }
+    Ok(Some(match kv[1].value {
+        Value::Int(v) => v.to_string().into_bytes(),
+        Value::String(p, len) => unsafe {
+            slice::from_raw_parts(p, len as usize).to_vec()
+        },
+    }))
+}
+
+/// Returns the dedupe key of a record that was read back.
+pub fn entry_dupe_key(entry: &Entry) -> Option<Vec<u8>> {
+    let &(ref field, ref value) = entry.fields.first()?;
+    if field.as_slice() != FIELD_DUPE_KEY.as_bytes() {
+        return None;
+    }
+    Some(value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::fixture::record;
+    use crate::stream::{DEDUPE_MS, DEFAULT_CONFIG};
+
+    fn id(ms: u64, seq: u64) -> StreamID {
+        StreamID { ms, seq }
+    }
+
+    fn dedupe(window: u64, unit: i32) -> Dedupe {
+        let mut config = *DEFAULT_CONFIG;
+        config.dedupe_window = window;
+        config.dedupe_unit = unit;
+        Dedupe::new(&config)
+    }
+
+    #[test]
+    fn it_remembers_keys_for_a_number_of_records() {
+        let mut dedupe = dedupe(3, DEDUPE_RECORDS);
+        dedupe.written(&id(1, 0), Some(b"a".to_vec()));
+        dedupe.written(&id(1, 1), None);
+        assert_eq!(Some(id(1, 0)), dedupe.check(b"a", &id(1, 2)));
+        dedupe.written(&id(1, 2), Some(b"b".to_vec()));
+        assert_eq!(Some(id(1, 0)), dedupe.check(b"a", &id(1, 3)));
+
+        // The fourth record on is past the window of the first.
+        dedupe.written(&id(1, 3), None);
+        assert_eq!(None, dedupe.check(b"a", &id(1, 4)));
+        assert_eq!(Some(id(1, 2)), dedupe.check(b"b", &id(1, 4)));
+        assert_eq!(1, dedupe.len());
+    }
+
+    #[test]
+    fn it_remembers_keys_for_a_time() {
+        let mut dedupe = dedupe(1000, DEDUPE_MS);
+        dedupe.written(&id(5000, 0), Some(b"a".to_vec()));
+        dedupe.written(&id(5500, 0), Some(b"b".to_vec()));
+        assert_eq!(Some(id(5000, 0)), dedupe.check(b"a", &id(5999, 0)));
+        assert_eq!(None, dedupe.check(b"a", &id(6000, 0)));
+        assert_eq!(Some(id(5500, 0)), dedupe.check(b"b", &id(6000, 0)));
+
+        // A key can be written again once it expired.
+        dedupe.written(&id(6000, 0), Some(b"a".to_vec()));
+        assert_eq!(Some(id(6000, 0)), dedupe.check(b"a", &id(6001, 0)));
+
+        dedupe.configure(DEFAULT_CONFIG);
+        assert!(!dedupe.is_enabled());
+        assert_eq!(0, dedupe.len());
+    }
+
+    #[test]
+    fn it_only_takes_the_dedupe_field_first() {
+        let key = dupe_key(&record(&["?", "a", "f", "?"])).unwrap();
+        assert_eq!(Some(b"a".to_vec()), key);
+        assert_eq!(None, dupe_key(&record(&["f", "v"])).unwrap());
+        assert!(dupe_key(&record(&["f", "v", "?", "a"])).is_err());
+        assert!(dupe_key(&record(&["?", "a", "?", "b"])).is_err());
+    }
+}
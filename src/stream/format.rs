@@ -0,0 +1,709 @@
+use crate::alloc::alloc;
+use crate::redis::listpack;
+use crate::redis::listpack::{MemoizedValue, UnsafeAppender, Value};
+use std::borrow::Cow;
+use std::cmp;
+use std::path::Path;
+use std::ptr;
+use super::id::StreamID;
+use super::writer::STREAM_ITEM_FLAG_SAMEFIELDS;
+use super::StreamError;
+
+/// The segment file the writer appends to.
+pub const TAIL_FILE: &'static str = "0.dat";
+
+/// The segment file that's set aside to take over once the tail fills up.
+pub const NEXT_FILE: &'static str = "next.dat";
+
+/// Where a compressed copy of the tail segment is written before it's
+/// renamed after its first record.
+pub const SEALING_FILE: &'static str = "sealing.tmp";
+
+/// Extension of every segment file. Sealed segments are named after the ID
+/// of their first record, "{ms}-{seq}.dat".
+pub const SEGMENT_EXT: &'static str = "dat";
+
+/// Extension a segment that filled up goes by until its index is on disk, so
+/// that every file that ends in `SEGMENT_EXT` other than the tail segment can
+/// be opened from its index.
+pub const UNSEALED_EXT: &'static str = "sealing";
+
+/// The file that holds the state of the consumer groups of a stream.
+pub const GROUPS_FILE: &'static str = "groups";
+
+/// Where the groups file is written before it takes the place of the last
+/// one.
+pub const GROUPS_TMP_FILE: &'static str = "groups.tmp";
+
+/*
+ * On-disk layout of a segment file.
+ *
+ * Packs are laid out back to back starting at offset 0. A pack is the
+ * listpack of a Redis Streams Rax node minus its header and its first two
+ * elements (count and deleted), which change with every write and would
+ * break the append-only writes. Those are kept in a trailer instead that is
+ * rewritten each time the pack grows.
+ *
+ * +------------+-------/-------+----------+-----+-----+-----+-------+-----+-----+
+ * | num-fields | ... records | lp-count | EOF | ms  | seq | count | crc | EOF |
+ * +------------+-------/-------+----------+-----+-----+-----+-------+-----+-----+
+ *
+ * ms and seq make up the master ID of the pack, count is the number of
+ * records within it. Every element is a regular listpack element so packs
+ * can be walked in either direction just like a listpack.
+ *
+ * crc is the CRC32C of everything in the pack that comes before it. It's a
+ * 4 byte little endian string rather than an integer so that the size of
+ * the trailer doesn't depend on it.
+ *
+ * An EOF byte where the next pack would start marks the end of the packs.
+ * The writer keeps one after the tail pack at all times.
+ */
+
+/*
+ * Sealed segments end with an index of their packs so that they can be
+ * opened without reading the packs themselves. The index starts right after
+ * the EOF that ends the packs.
+ *
+ * +-------/-------+-----+--------/--------+--------+
+ * | ... packs ... | EOF | index entries   | footer |
+ * +-------/-------+-----+--------/--------+--------+
+ *
+ * Index entry - one per pack, in order. crc is the same as in the trailer
+ * of the pack.
+ * +----+-----+--------+--------+------------+-------+-----+-----+
+ * | ms | seq | offset | length | raw length | count | crc | EOF |
+ * +----+-----+--------+--------+------------+-------+-----+-----+
+ *
+ * Packs may be compressed with zstd when the segment is sealed, each on its
+ * own so that any of them can still be read without the others. length is
+ * what the pack takes up in the file and raw length what it takes up once
+ * it's decompressed. They're the same for packs that were left as they are.
+ *
+ * Footer - fixed size and little endian so it can be found from the end.
+ * +-------------------+---------------+-----------+
+ * | index offset (u64)| packs (u32)   | magic (4) |
+ * +-------------------+---------------+-----------+
+ */
+
+/// Last bytes of every sealed segment file.
+pub const INDEX_MAGIC: &'static [u8] = b"SLIX";
+
+/// Number of bytes of the footer that ends a sealed segment file.
+pub const FOOTER_SIZE: usize = 16;
+
+/// Number of bytes the checksum element takes up in the trailer of a pack.
+pub const CRC_SIZE: usize = 6;
+
+/// First byte of the checksum element, a string of 4 bytes.
+const CRC_ENCODING: u8 = 0x80 | 4;
+
+/// Fewest bytes an index entry can take up: 7 integers of a single byte
+/// each plus their backlen, then the EOF that ends the entry.
+const MIN_INDEX_ENTRY_SIZE: usize = 7 * 2 + 1;
+
+lazy_static! {
+    static ref CRC32C_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// Carries the CRC32C (Castagnoli) of some bytes on over data. Start with 0.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let table = &*CRC32C_TABLE;
+    !data.iter().fold(!crc, |crc, b| {
+        table[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Where a pack is within a sealed segment.
+#[derive(Clone, Copy)]
+pub struct IndexEntry {
+    /// Master ID of the pack, which is also the ID of its first record.
+    pub id: StreamID,
+    pub offset: u32,
+    pub length: u32,
+    /// Number of bytes of the pack once it's decompressed.
+    pub raw_length: u32,
+    pub count: u16,
+    /// Checksum of the pack.
+    pub crc: u32,
+}
+
+/// Number of bytes a pack on disk takes besides its entries.
+pub fn trailer_size(master_id: &StreamID, count: u16) -> u32 {
+    2 + Value::Int(master_id.ms as i64).size_for_write()
+        + Value::Int(master_id.seq as i64).size_for_write()
+        + Value::Int(count as i64).size_for_write()
+        + CRC_SIZE as u32
+}
+
+/// Encodes a listpack element to the end of buf.
+pub fn put(buf: &mut Vec<u8>, value: Value) {
+    let value = MemoizedValue::new(value);
+    let start = buf.len();
+    buf.resize(start + value.encoded_size as usize, 0);
+    value.write(buf[start..].as_mut_ptr());
+}
+
+/// Encodes the trailer of a pack to the end of buf. crc is the checksum of
+/// the entries of the pack. Returns the checksum of the whole pack.
+pub fn put_trailer(buf: &mut Vec<u8>, master_id: &StreamID, count: u16, crc: u32) -> u32 {
+    let start = buf.len();
+    buf.push(listpack::EOF);
+    put(buf, Value::Int(master_id.ms as i64));
+    put(buf, Value::Int(master_id.seq as i64));
+    put(buf, Value::Int(count as i64));
+    let crc = crc32c(crc, &buf[start..]);
+
+    buf.push(CRC_ENCODING);
+    put_le(buf, crc as u64, 4);
+    buf.push(5);
+    buf.push(listpack::EOF);
+    crc
+}
+
+/// Returns the checksum a pack has in its trailer if its bytes match it.
+pub fn pack_crc(data: &[u8]) -> Option<u32> {
+    if data.len() < CRC_SIZE + 1 || data[data.len() - 1] != listpack::EOF {
+        return None;
+    }
+    let covered = data.len() - 1 - CRC_SIZE;
+    let element = &data[covered..data.len() - 1];
+    if element[0] != CRC_ENCODING || element[CRC_SIZE - 1] != 5 {
+        return None;
+    }
+    let crc = get_le(&element[1..5]) as u32;
+    if crc32c(0, &data[..covered]) != crc {
+        return None;
+    }
+    Some(crc)
+}
+
+/// Returns the offset within a Redis Streams listpack at which the part
+/// that's stored on disk starts, right past the count and deleted elements
+/// of the master entry.
+pub fn entries_offset(lp: listpack::listpack) -> u32 {
+    let count = listpack::first(lp).expect("pack without a master entry");
+    let num_fields = listpack::skip(listpack::skip(count));
+    (num_fields as usize - lp as usize) as u32
+}
+
+/// Encodes the index of a segment followed by its footer to the end of buf.
+/// index_offset is where buf starts in the file.
+pub fn put_index(buf: &mut Vec<u8>, index_offset: u64, entries: &[IndexEntry]) {
+    for entry in entries {
+        put(buf, Value::Int(entry.id.ms as i64));
+        put(buf, Value::Int(entry.id.seq as i64));
+        put(buf, Value::Int(entry.offset as i64));
+        put(buf, Value::Int(entry.length as i64));
+        put(buf, Value::Int(entry.raw_length as i64));
+        put(buf, Value::Int(entry.count as i64));
+        put(buf, Value::Int(entry.crc as i64));
+        buf.push(listpack::EOF);
+    }
+    put_le(buf, index_offset, 8);
+    put_le(buf, entries.len() as u64, 4);
+    buf.extend_from_slice(INDEX_MAGIC);
+}
+
+/// Reads the index at the end of a sealed segment file.
+pub fn read_index(data: &[u8]) -> Result<Vec<IndexEntry>, StreamError> {
+    if data.len() < FOOTER_SIZE || &data[data.len() - 4..] != INDEX_MAGIC {
+        return Err(corrupt_index());
+    }
+    let footer = data.len() - FOOTER_SIZE;
+    let index_offset = get_le(&data[footer..footer + 8]) as usize;
+    let packs = get_le(&data[footer + 8..footer + 12]) as usize;
+    // The count comes from the file, so it's only trusted as far as the
+    // index could actually hold that many entries.
+    if index_offset > footer || packs > (footer - index_offset) / MIN_INDEX_ENTRY_SIZE {
+        return Err(corrupt_index());
+    }
+
+    let mut entries = Vec::with_capacity(packs);
+    let mut pos = index_offset;
+    for _ in 0..packs {
+        let mut values = [0u64; 7];
+        for value in values.iter_mut() {
+            *value = read_int(data, &mut pos, footer)?;
+        }
+        if pos >= footer || data[pos] != listpack::EOF {
+            return Err(corrupt_index());
+        }
+        pos += 1;
+
+        entries.push(IndexEntry {
+            id: StreamID { ms: values[0], seq: values[1] },
+            offset: values[2] as u32,
+            length: values[3] as u32,
+            raw_length: values[4] as u32,
+            count: values[5] as u16,
+            crc: values[6] as u32,
+        });
+    }
+    if pos != footer {
+        return Err(corrupt_index());
+    }
+    Ok(entries)
+}
+
+/// Compresses a pack with zstd at level. Returns None if that doesn't make
+/// it any smaller, in which case it's better off left as it is.
+pub fn compress_pack(data: &[u8], level: i32) -> Result<Option<Vec<u8>>, StreamError> {
+    let compressed = zstd::block::compress(data, level)?;
+    if compressed.len() >= data.len() {
+        return Ok(None);
+    }
+    Ok(Some(compressed))
+}
+
+/// Returns the bytes of the pack at offset in a segment file the way they
+/// were written, decompressing them if they have to be.
+pub fn unpack(
+    data: &[u8],
+    offset: u32,
+    length: u32,
+    raw_length: u32,
+) -> Option<Cow<[u8]>> {
+    let start = offset as usize;
+    let end = start + length as usize;
+    if end > data.len() {
+        return None;
+    }
+    if length == raw_length {
+        return Some(Cow::Borrowed(&data[start..end]));
+    }
+
+    let raw = zstd::block::decompress(&data[start..end], raw_length as usize).ok()?;
+    if raw.len() != raw_length as usize {
+        return None;
+    }
+    Some(Cow::Owned(raw))
+}
+
+/// Loads the pack at offset in the segment file at path after checking it
+/// against the checksum it's expected to have.
+pub fn read_pack(
+    path: &Path,
+    data: &[u8],
+    offset: u32,
+    length: u32,
+    raw_length: u32,
+    crc: u32,
+) -> Result<listpack::listpack, StreamError> {
+    match unpack(data, offset, length, raw_length) {
+        Some(ref raw) if pack_crc(raw) == Some(crc) => {
+            load_pack(raw).ok_or(StreamError::OutOfMemory)
+        }
+        _ => Err(StreamError::Corrupt {
+            segment: path.to_string_lossy().into_owned(),
+            offset,
+        }),
+    }
+}
+
+/// Rebuilds the Redis Streams listpack of a pack from its bytes in a segment
+/// file. Returns None if they don't make up a pack.
+pub fn load_pack(data: &[u8]) -> Option<listpack::listpack> {
+    // Walk the entries to find the trailer.
+    let start = data.as_ptr() as *mut u8;
+    let mut entries = 0;
+    let mut elements = 0;
+    while entries < data.len() && data[entries] != listpack::EOF {
+        let next = listpack::skip(unsafe { start.offset(entries as isize) });
+        entries = next as usize - start as usize;
+        elements += 1;
+    }
+    if entries + 1 >= data.len() {
+        return None;
+    }
+
+    // The trailer holds the master ID and then the count.
+    let ms = unsafe { start.offset(entries as isize + 1) };
+    let count = match listpack::get(listpack::skip(listpack::skip(ms))) {
+        Value::Int(count) => count,
+        _ => return None,
+    };
+
+    let count = MemoizedValue::new(Value::Int(count));
+    let deleted = MemoizedValue::new(Value::Int(0));
+    let size = listpack::HDR_USIZE as u32
+        + count.encoded_size
+        + deleted.encoded_size
+        + entries as u32
+        + 1;
+    let lp = alloc(size as usize);
+    if lp.is_null() {
+        return None;
+    }
+
+    unsafe {
+        let mut writer = UnsafeAppender::new(lp, listpack::HDR_USIZE as u32 + 1);
+        writer.append(&count);
+        writer.append(&deleted);
+        let dst = lp.offset((size - 1) as isize - entries as isize);
+        ptr::copy_nonoverlapping(start, dst, entries);
+        *lp.offset(size as isize - 1) = listpack::EOF;
+    }
+    listpack::set_total_bytes(lp, size);
+    let elements = cmp::min(elements + 2, listpack::HDR_NUMELE_UNKNOWN as usize);
+    listpack::set_num_elements(lp, elements as u16);
+    Some(lp)
+}
+
+/// An intact pack found by `check_pack`.
+pub struct CheckedPack {
+    pub entry: IndexEntry,
+    /// ID of the last record in the pack.
+    pub last_id: StreamID,
+    /// Number of fields in the master entry.
+    pub num_fields: u16,
+}
+
+/// Checks the pack at offset in a segment file: that every element is
+/// well-formed, that the lp-count of every entry adds up, that the trailer
+/// matches the entries and that it all matches the checksum. Returns the
+/// pack if it's intact.
+pub fn check_pack(data: &[u8], offset: usize) -> Option<CheckedPack> {
+    let mut reader = Reader { data, pos: offset };
+
+    // Master entry.
+    let num_fields = reader.int()?;
+    if num_fields < 1 || num_fields > u16::max_value() as i64 {
+        return None;
+    }
+    for _ in 0..num_fields {
+        reader.element()?;
+    }
+    if reader.int()? != 0 {
+        return None;
+    }
+
+    // Entries.
+    let mut count = 0u64;
+    let mut diff = (0i64, 0i64);
+    while !reader.at_eof() {
+        let flags = reader.int()?;
+        diff = (reader.int()?, reader.int()?);
+        let lp_count = if flags & STREAM_ITEM_FLAG_SAMEFIELDS as i64 != 0 {
+            for _ in 0..num_fields {
+                reader.element()?;
+            }
+            num_fields + 3
+        } else {
+            let fields = reader.int()?;
+            if fields < 1 {
+                return None;
+            }
+            for _ in 0..fields * 2 {
+                reader.element()?;
+            }
+            fields * 2 + 4
+        };
+        if reader.int()? != lp_count {
+            return None;
+        }
+        count += 1;
+    }
+
+    // Trailer.
+    reader.eof()?;
+    let id = StreamID {
+        ms: reader.int()? as u64,
+        seq: reader.int()? as u64,
+    };
+    if count == 0 || count > u16::max_value() as u64 || reader.int()? as u64 != count {
+        return None;
+    }
+    reader.element()?;
+    reader.eof()?;
+    let crc = pack_crc(&data[offset..reader.pos])?;
+
+    Some(CheckedPack {
+        entry: IndexEntry {
+            id,
+            offset: offset as u32,
+            length: (reader.pos - offset) as u32,
+            raw_length: (reader.pos - offset) as u32,
+            count: count as u16,
+            crc,
+        },
+        last_id: StreamID {
+            ms: id.ms.wrapping_add(diff.0 as u64),
+            seq: id.seq.wrapping_add(diff.1 as u64),
+        },
+        num_fields: num_fields as u16,
+    })
+}
+
+/// The intact packs at the start of a tail segment file, as found by
+/// `scan`.
+pub struct Scan {
+    pub packs: Vec<CheckedPack>,
+    /// Offset right past the last intact pack, where the EOF should be.
+    pub end: usize,
+    /// Number of bytes from end up to the last one that isn't zero, when
+    /// something other than the EOF was found there.
+    pub torn: usize,
+}
+
+/// Walks the packs of a tail segment file until the EOF that ends them, or
+/// until one doesn't check out.
+pub fn scan(data: &[u8]) -> Scan {
+    let mut packs: Vec<CheckedPack> = Vec::new();
+    let mut end = 0;
+    while end < data.len() && data[end] != listpack::EOF {
+        let pack = match check_pack(data, end) {
+            Some(pack) => pack,
+            None => break,
+        };
+        // IDs only ever go up.
+        if let Some(last) = packs.last() {
+            if pack.entry.id <= last.last_id {
+                break;
+            }
+        }
+        end += pack.entry.length as usize;
+        packs.push(pack);
+    }
+
+    let torn = if end < data.len() && data[end] == listpack::EOF {
+        0
+    } else {
+        data[end..].iter().rposition(|b| *b != 0).map(|pos| pos + 1).unwrap_or(0)
+    };
+    Scan { packs, end, torn }
+}
+
+/// Returns the number of bytes taken up by the listpack element at pos,
+/// including its backlen, or None if it isn't well-formed or doesn't fit
+/// within data.
+pub fn element_size(data: &[u8], pos: usize) -> Option<usize> {
+    let byte = |i: usize| data.get(pos + i).map(|b| *b as usize);
+    let b = byte(0)?;
+    let encoded = if b & 0x80 == 0 {
+        1
+    } else if b & 0xC0 == 0x80 {
+        1 + (b & 0x3F)
+    } else if b & 0xE0 == 0xC0 {
+        2
+    } else if b & 0xF0 == 0xE0 {
+        2 + ((b & 0x0F) << 8 | byte(1)?)
+    } else {
+        match b {
+            0xF0 => 5 + (byte(1)? | byte(2)? << 8 | byte(3)? << 16 | byte(4)? << 24),
+            0xF1 => 3,
+            0xF2 => 4,
+            0xF3 => 5,
+            0xF4 => 9,
+            _ => return None,
+        }
+    };
+
+    let mut backlen = [0u8; listpack::MAX_BACKLEN_SIZE];
+    let backlen_size = listpack::encode_backlen(&mut backlen, encoded as u64);
+    let end = pos + encoded + backlen_size;
+    if end > data.len() || data[pos + encoded..end] != backlen[..backlen_size] {
+        return None;
+    }
+    Some(encoded + backlen_size)
+}
+
+/// Reads listpack elements out of bytes that can't be trusted without going
+/// past their end.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn at_eof(&self) -> bool {
+        self.pos < self.data.len() && self.data[self.pos] == listpack::EOF
+    }
+
+    fn eof(&mut self) -> Option<()> {
+        if !self.at_eof() {
+            return None;
+        }
+        self.pos += 1;
+        Some(())
+    }
+
+    fn element(&mut self) -> Option<Value> {
+        let size = element_size(self.data, self.pos)?;
+        let value = listpack::get(self.data[self.pos..].as_ptr() as *mut u8);
+        self.pos += size;
+        Some(value)
+    }
+
+    fn int(&mut self) -> Option<i64> {
+        match self.element()? {
+            Value::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn read_int(data: &[u8], pos: &mut usize, end: usize) -> Result<u64, StreamError> {
+    // Every element starts before the footer, which leaves room to read
+    // any integer encoding without going past the end.
+    if *pos >= end || data[*pos] == listpack::EOF {
+        return Err(corrupt_index());
+    }
+    let ele = data[*pos..].as_ptr() as *mut u8;
+    let value = match listpack::get(ele) {
+        Value::Int(value) => value as u64,
+        _ => return Err(corrupt_index()),
+    };
+    let next = listpack::skip(ele) as usize - data.as_ptr() as usize;
+    if next > end {
+        return Err(corrupt_index());
+    }
+    *pos = next;
+    Ok(value)
+}
+
+fn corrupt_index() -> StreamError {
+    StreamError::Generic(String::from("segment index is corrupt"))
+}
+
+/// Encodes the lowest bytes of value to the end of buf, little endian.
+pub fn put_le(buf: &mut Vec<u8>, value: u64, bytes: usize) {
+    for i in 0..bytes {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+/// Decodes a little endian integer.
+pub fn get_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, b| (value << 8) | *b as u64)
+}
+
+/// Name of the file a sealed segment is renamed to.
+pub fn segment_file_name(id: &StreamID) -> String {
+    format!("{}-{}.{}", id.ms, id.seq, SEGMENT_EXT)
+}
+
+/// Name of the file a segment that filled up is sealed under.
+pub fn unsealed_file_name(id: &StreamID) -> String {
+    format!("{}-{}.{}", id.ms, id.seq, UNSEALED_EXT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redis::listpack;
+    use crate::stream::format::*;
+
+    #[test]
+    fn it_encodes_the_pack_trailer() {
+        let id = StreamID { ms: 1_500_000_000_000, seq: 3 };
+        let mut buf = vec![1u8, 2, 3];
+        let crc = put_trailer(&mut buf, &id, 200, crc32c(0, &[1, 2, 3]));
+        assert_eq!(Some(crc), pack_crc(&buf));
+        assert_eq!(crc32c(0, &buf[..buf.len() - 1 - CRC_SIZE]), crc);
+        let buf = buf.split_off(3);
+
+        assert_eq!(trailer_size(&id, 200) as usize, buf.len());
+        assert_eq!(listpack::EOF, buf[0]);
+        assert_eq!(listpack::EOF, buf[buf.len() - 1]);
+
+        let ms = buf[1..].as_mut_ptr();
+        assert!(listpack::get(ms) == Value::Int(1_500_000_000_000));
+        let seq = listpack::skip(ms);
+        assert!(listpack::get(seq) == Value::Int(3));
+        assert!(listpack::get(listpack::skip(seq)) == Value::Int(200));
+        assert_eq!("1500000000000-3.dat", segment_file_name(&id));
+        assert_eq!("1500000000000-3.sealing", unsealed_file_name(&id));
+    }
+
+    #[test]
+    fn it_checksums_packs() {
+        // The check value of CRC32C.
+        assert_eq!(0xE306_9283, crc32c(0, b"123456789"));
+        assert_eq!(crc32c(0, b"123456789"), crc32c(crc32c(0, b"1234"), b"56789"));
+
+        let id = StreamID { ms: 7, seq: 0 };
+        let mut buf = b"entries".to_vec();
+        let crc = put_trailer(&mut buf, &id, 1, crc32c(0, b"entries"));
+        assert_eq!(Some(crc), pack_crc(&buf));
+
+        let path = Path::new("1-0.dat");
+        let mut data = vec![0u8; 10];
+        data.extend_from_slice(&buf);
+        let length = buf.len() as u32;
+        buf[0] ^= 0x01;
+        assert_eq!(None, pack_crc(&buf));
+        data[10] ^= 0x01;
+        match read_pack(path, &data, 10, length, length, crc) {
+            Err(StreamError::Corrupt { ref segment, offset: 10 }) => {
+                assert_eq!("1-0.dat", segment)
+            }
+            _ => panic!("expected the pack to be corrupt"),
+        }
+    }
+
+    #[test]
+    fn it_compresses_packs_on_their_own() {
+        let entries: Vec<u8> = b"0123456789abcdef".iter().cycle().take(512).cloned()
+            .collect();
+        let mut raw = entries.clone();
+        let id = StreamID { ms: 1, seq: 0 };
+        let crc = put_trailer(&mut raw, &id, 32, crc32c(0, &entries));
+        let compressed = compress_pack(&raw, 3).unwrap().unwrap();
+        assert!(compressed.len() < raw.len());
+
+        // Packs are found by their offset and length in the file.
+        let mut data = raw.clone();
+        data.extend_from_slice(&compressed);
+        let (offset, length) = (raw.len() as u32, compressed.len() as u32);
+        let unpacked = unpack(&data, offset, length, raw.len() as u32).unwrap();
+        assert_eq!(Some(crc), pack_crc(&unpacked));
+        assert!(unpack(&data, 0, offset, offset).unwrap() == &raw[..]);
+        assert!(unpack(&data, offset, length, raw.len() as u32 + 1).is_none());
+
+        // Compressing wouldn't help.
+        assert!(compress_pack(b"x", 3).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_reads_back_the_segment_index() {
+        let entry = |ms, seq, offset, length, count| IndexEntry {
+            id: StreamID { ms, seq },
+            offset,
+            length,
+            raw_length: length * 3,
+            count,
+            crc: 0xDEAD_BEEF,
+        };
+        let entries = [entry(10, 0, 0, 90, 3), entry(12, 5, 90, 300, 40)];
+        let mut data = vec![0u8; 391];
+        data[390] = listpack::EOF;
+        put_index(&mut data, 391, &entries);
+
+        let read = read_index(&data).unwrap();
+        assert_eq!(2, read.len());
+        assert_eq!((12, 5), (read[1].id.ms, read[1].id.seq));
+        assert_eq!((90, 300, 40), (read[1].offset, read[1].length, read[1].count));
+        assert_eq!((900, 0xDEAD_BEEF), (read[1].raw_length, read[1].crc));
+
+        let last = data.len() - 1;
+        data[last] = b'?';
+        assert!(read_index(&data).is_err());
+        assert!(read_index(&data[..8]).is_err());
+
+        // A pack count the index couldn't hold.
+        data[last] = INDEX_MAGIC[3];
+        let footer = data.len() - FOOTER_SIZE;
+        data[footer + 8..footer + 12].copy_from_slice(&[0xFF; 4]);
+        assert!(read_index(&data).is_err());
+    }
+}
@@ -0,0 +1,998 @@
+use std::io::Write;
+use std::mem;
+use std::ops::Bound;
+
+use super::*;
+use super::format::{get_le, put_le, GROUPS_FILE, GROUPS_TMP_FILE};
+use super::range::{self, scan_pack, Entry, Visit};
+
+/// First bytes of a groups file.
+const GROUPS_MAGIC: &'static [u8] = b"SLGR";
+
+/// Version of the layout of a groups file.
+const GROUPS_VERSION: u8 = 1;
+
+/// How a consumer group deals with records that stay pending for too long.
+#[derive(Clone, Default)]
+pub struct Reclaim {
+    /// Milliseconds a record can be pending before it's redelivered to
+    /// another consumer. Zero leaves records with their consumer.
+    pub idle_timeout: u64,
+    /// Number of deliveries after which a record that's idle goes to the
+    /// dead-letter stream rather than being delivered again.
+    pub max_deliveries: u64,
+    /// Key of the dead-letter stream, if there is one.
+    pub dead_letter: Option<String>,
+}
+
+/// A record that was delivered too many times, on its way to the dead-letter
+/// stream.
+pub struct DeadLetter {
+    pub entry: Entry,
+    /// The consumer it was last delivered to.
+    pub consumer: String,
+    pub delivery_count: u64,
+    /// Milliseconds since it was last delivered.
+    pub idle: u64,
+}
+
+impl Stream {
+    /// Creates a consumer group that's delivered the records after last_id.
+    pub fn create_group(
+        &mut self,
+        name: &str,
+        last_id: StreamID,
+    ) -> Result<(), StreamError> {
+        if self.groups.contains_key(name) {
+            return Err(StreamError::Exists);
+        }
+        self.groups.insert(String::from(name), ConsumerGroup::new(last_id));
+        self.groups_dirty = true;
+        Ok(())
+    }
+
+    /// Destroys a consumer group along with its pending records. Returns
+    /// whether there was one.
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups_dirty = true;
+        self.groups.remove(name).is_some()
+    }
+
+    /// Returns a consumer group to work with. It's taken to have changed, so
+    /// the groups of the stream are saved again.
+    #[inline]
+    pub fn group(&mut self, name: &str) -> Option<&mut ConsumerGroup> {
+        self.groups_dirty = true;
+        self.groups.get_mut(name)
+    }
+
+    /// The names of the consumer groups, and how they reclaim records.
+    pub fn reclaims(&self) -> Vec<(String, Reclaim)> {
+        self.groups
+            .iter()
+            .map(|(name, group)| (name.clone(), group.reclaim.clone()))
+            .collect()
+    }
+
+    /// Encodes the consumer groups for the groups file if any of them changed
+    /// since the last time. They're taken to be saved from then on.
+    pub fn groups_to_save(&mut self) -> Option<Vec<u8>> {
+        if !self.groups_dirty {
+            return None;
+        }
+        self.groups_dirty = false;
+        Some(encode_groups(&self.groups))
+    }
+
+    /// Marks the consumer groups to be saved again, such as when saving them
+    /// failed.
+    #[inline]
+    pub fn groups_changed(&mut self) {
+        self.groups_dirty = true;
+    }
+
+    /// Saves the consumer groups here and now.
+    pub fn save_groups(&mut self) -> Result<(), StreamError> {
+        let dir = match self.writer {
+            Some(ref writer) => writer.dir().to_path_buf(),
+            None => return Err(StreamError::NotExists),
+        };
+        let data = encode_groups(&self.groups);
+        write_groups(&dir, &data)?;
+        self.groups_dirty = false;
+        Ok(())
+    }
+
+    /// Picks the consumer groups in a groups file back up. The packs their
+    /// pending records are in are read right away to be pinned. Records that
+    /// aren't in the stream anymore are no longer pending.
+    pub fn restore_groups(&mut self, data: &[u8]) -> Result<(), StreamError> {
+        for saved in decode_groups(data)? {
+            let mut group = ConsumerGroup::new(saved.last_id);
+            group.reclaim = saved.reclaim;
+            for name in saved.consumers.iter() {
+                group.consumers.insert(name.clone(), Consumer::new());
+            }
+            for (id, consumer, delivery_time, delivery_count) in saved.pending {
+                let consumer = saved.consumers.get(consumer).ok_or_else(corrupt_groups)?;
+                let (master_id, pack, fault) = match range::locate(self, &id)? {
+                    Some(found) => found,
+                    None => continue,
+                };
+                if let Some(fault) = fault {
+                    fault.read_now()?;
+                }
+                let nack = Rc::new(NAck {
+                    delivery_time: Cell::new(delivery_time),
+                    delivery_count: Cell::new(delivery_count),
+                    consumer: RefCell::new(consumer.clone()),
+                    pack: master_id,
+                });
+                group.pending.insert(&mut id.clone(), Rc::clone(&nack))?;
+                group
+                    .consumers
+                    .entry(consumer.clone())
+                    .or_insert_with(Consumer::new)
+                    .pending
+                    .insert(&mut id.clone(), nack)?;
+                group.pins.entry(master_id).or_insert_with(|| (pack, 0)).1 += 1;
+            }
+            self.groups.insert(saved.name, group);
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Layout of a groups file. Integers are little endian and strings are their
+ * length (u32) followed by their bytes.
+ *
+ * +-----------+-------------+-------------+-----/-----+--------------+
+ * | magic (4) | version (1) | groups (u32)| group ... | crc32c (u32) |
+ * +-----------+-------------+-------------+-----/-----+--------------+
+ *
+ * Each group:
+ *
+ * +------+--------------------+--------------------+--------------------+
+ * | name | last ID (2 x u64)  | idle timeout (u64) | max deliveries(u64)|
+ * +------+--------------------+--------------------+--------------------+
+ * +--------------------------+-----------------+-----------------+-----/-----+
+ * | dead letter (u8 + name)  | consumers (u32) | consumer names  | pending   |
+ * +--------------------------+-----------------+-----------------+-----/-----+
+ *
+ * Pending records are their count (u64), then for each one its ID, the index
+ * of its consumer (u32), its delivery time and its delivery count (u64).
+ */
+
+/// A consumer group as it was read from a groups file.
+struct SavedGroup {
+    name: String,
+    last_id: StreamID,
+    reclaim: Reclaim,
+    consumers: Vec<String>,
+    /// ID, index of the consumer, delivery time and delivery count.
+    pending: Vec<(StreamID, usize, u64, u64)>,
+}
+
+fn encode_groups(groups: &HashMap<String, ConsumerGroup>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(GROUPS_MAGIC);
+    buf.push(GROUPS_VERSION);
+    put_le(&mut buf, groups.len() as u64, 4);
+    for (name, group) in groups {
+        put_str(&mut buf, name);
+        put_id(&mut buf, &group.last_id);
+        put_le(&mut buf, group.reclaim.idle_timeout, 8);
+        put_le(&mut buf, group.reclaim.max_deliveries, 8);
+        match group.reclaim.dead_letter {
+            Some(ref dead_letter) => {
+                buf.push(1);
+                put_str(&mut buf, dead_letter);
+            }
+            None => buf.push(0),
+        }
+
+        let consumers: Vec<&String> = group.consumers.keys().collect();
+        put_le(&mut buf, consumers.len() as u64, 4);
+        for consumer in consumers.iter() {
+            put_str(&mut buf, consumer);
+        }
+
+        put_le(&mut buf, group.pending.len(), 8);
+        let mut key = StreamID::MIN;
+        while let Some((id, Some(nack))) = group.pending.seek_entry(">", &key) {
+            let consumer = nack.consumer.borrow();
+            let index = consumers
+                .iter()
+                .position(|name| name.as_str() == consumer.as_str())
+                .unwrap_or(0);
+            put_id(&mut buf, &id);
+            put_le(&mut buf, index as u64, 4);
+            put_le(&mut buf, nack.delivery_time.get(), 8);
+            put_le(&mut buf, nack.delivery_count.get(), 8);
+            key = id;
+        }
+    }
+    let crc = format::crc32c(0, &buf);
+    put_le(&mut buf, crc as u64, 4);
+    buf
+}
+
+fn decode_groups(data: &[u8]) -> Result<Vec<SavedGroup>, StreamError> {
+    if data.len() < GROUPS_MAGIC.len() + 1 + 4 + 4 || !data.starts_with(GROUPS_MAGIC) {
+        return Err(corrupt_groups());
+    }
+    let end = data.len() - 4;
+    if format::crc32c(0, &data[..end]) != get_le(&data[end..]) as u32 {
+        return Err(corrupt_groups());
+    }
+    if data[GROUPS_MAGIC.len()] != GROUPS_VERSION {
+        return Err(StreamError::Generic(String::from(
+            "groups file has an unknown version",
+        )));
+    }
+
+    let mut reader = Reader { data: &data[..end], pos: GROUPS_MAGIC.len() + 1 };
+    let count = reader.u32()?;
+    let mut groups = Vec::new();
+    for _ in 0..count {
+        let name = reader.string()?;
+        let last_id = reader.id()?;
+        let idle_timeout = reader.u64()?;
+        let max_deliveries = reader.u64()?;
+        let dead_letter = match reader.u8()? {
+            0 => None,
+            _ => Some(reader.string()?),
+        };
+        let mut consumers = Vec::new();
+        for _ in 0..reader.u32()? {
+            consumers.push(reader.string()?);
+        }
+        let mut pending = Vec::new();
+        for _ in 0..reader.u64()? {
+            let id = reader.id()?;
+            let consumer = reader.u32()? as usize;
+            pending.push((id, consumer, reader.u64()?, reader.u64()?));
+        }
+        groups.push(SavedGroup {
+            name,
+            last_id,
+            reclaim: Reclaim { idle_timeout, max_deliveries, dead_letter },
+            consumers,
+            pending,
+        });
+    }
+    if reader.pos != reader.data.len() {
+        return Err(corrupt_groups());
+    }
+    Ok(groups)
+}
+
+/// Reads the groups file of the stream kept in dir, if it has one.
+pub fn read_groups(dir: &Path) -> Result<Option<Vec<u8>>, StreamError> {
+    match fs::read(dir.join(GROUPS_FILE)) {
+        Ok(data) => Ok(Some(data)),
+        Err(ref e) if e.kind() == std_io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(StreamError::from(e)),
+    }
+}
+
+/// Checks that a groups file can be read, without picking the groups up.
+/// Returns the number of groups in it.
+pub fn check_groups(data: &[u8]) -> Result<usize, StreamError> {
+    decode_groups(data).map(|groups| groups.len())
+}
+
+/// Replaces the groups file of the stream kept in dir. The file is written
+/// under another name and synced before it takes the place of the old one,
+/// so a crash leaves one or the other.
+pub fn write_groups(dir: &Path, data: &[u8]) -> Result<(), StreamError> {
+    let tmp = dir.join(GROUPS_TMP_FILE);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(GROUPS_FILE))?;
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_le(buf, value.len() as u64, 4);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn put_id(buf: &mut Vec<u8>, id: &StreamID) {
+    put_le(buf, id.ms, 8);
+    put_le(buf, id.seq, 8);
+}
+
+fn corrupt_groups() -> StreamError {
+    StreamError::Generic(String::from("groups file is corrupt"))
+}
+
+/// Reads the values of a groups file one after the other.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], StreamError> {
+        if n > self.data.len() - self.pos {
+            return Err(corrupt_groups());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StreamError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, StreamError> {
+        Ok(get_le(self.bytes(4)?) as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64, StreamError> {
+        Ok(get_le(self.bytes(8)?))
+    }
+
+    fn id(&mut self) -> Result<StreamID, StreamError> {
+        Ok(StreamID { ms: self.u64()?, seq: self.u64()? })
+    }
+
+    fn string(&mut self) -> Result<String, StreamError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| corrupt_groups())
+    }
+}
+
+impl Drop for ConsumerGroup {
+    fn drop(&mut self) {
+        // Nothing is pending anymore, so the packs can go back to disk.
+        for (_, (pack, _)) in mem::replace(&mut self.pins, BTreeMap::new()) {
+            Pack::release(pack);
+        }
+    }
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id: StreamID) -> ConsumerGroup {
+        ConsumerGroup {
+            last_id,
+            pending: map::RcRax::new(),
+            consumers: HashMap::new(),
+            pins: BTreeMap::new(),
+            reclaim: Reclaim::default(),
+            idle: BTreeSet::new(),
+            scan_after: StreamID::MIN,
+            redeliver_after: StreamID::MIN,
+        }
+    }
+
+    #[inline]
+    pub fn reclaim(&self) -> &Reclaim {
+        &self.reclaim
+    }
+
+    /// Changes how records that stay pending for too long are dealt with.
+    pub fn set_reclaim(&mut self, reclaim: Reclaim) {
+        if reclaim.idle_timeout == 0 {
+            self.idle.clear();
+        }
+        self.reclaim = reclaim;
+    }
+
+    /// Returns the ID of the last record that was delivered.
+    #[inline]
+    pub fn last_id(&self) -> StreamID {
+        self.last_id
+    }
+
+    /// Moves the ID of the last record that was delivered, like XGROUP SETID
+    /// does. Records after it are delivered next.
+    #[inline]
+    pub fn set_last_id(&mut self, last_id: StreamID) {
+        self.last_id = last_id;
+    }
+
+    /// Returns the number of records that are pending.
+    #[inline]
+    pub fn pending_len(&self) -> u64 {
+        self.pending.len()
+    }
+
+    /// Delivers records that were read past the last delivered ID to a
+    /// consumer and returns the ones that it got. packs are the packs the
+    /// records are in by master ID, in order. Records that were delivered
+    /// by another read while this one was under way are left out. Unless
+    /// noack is set, the records are pending for the consumer from now on.
+    pub fn deliver(
+        &mut self,
+        consumer: &str,
+        entries: Vec<Entry>,
+        packs: Vec<(StreamID, Pin)>,
+        noack: bool,
+        now: u64,
+    ) -> Result<Vec<Entry>, StreamError> {
+        let last_id = self.last_id;
+        let entries: Vec<Entry> =
+            entries.into_iter().filter(|entry| entry.id > last_id).collect();
+        if let Some(last) = entries.last() {
+            self.last_id = last.id;
+        }
+        if noack {
+            return Ok(entries);
+        }
+
+        let owner = self
+            .consumers
+            .entry(String::from(consumer))
+            .or_insert_with(Consumer::new);
+        let mut packs = packs.into_iter().peekable();
+        let mut current: Option<(StreamID, Pin)> = None;
+        for entry in entries.iter() {
+            // Each record is in the last pack whose master ID isn't past it.
+            while packs.peek().map_or(false, |&(ref next, _)| *next <= entry.id) {
+                current = packs.next();
+            }
+            let (master_id, pack) = match current {
+                Some((ref master_id, ref pack)) => (*master_id, pack),
+                None => return Err(StreamError::BadInput),
+            };
+
+            let nack = Rc::new(NAck {
+                delivery_time: Cell::new(now),
+                delivery_count: Cell::new(1),
+                consumer: RefCell::new(String::from(consumer)),
+                pack: master_id,
+            });
+            self.pending.insert(&mut entry.id.clone(), Rc::clone(&nack))?;
+            owner.pending.insert(&mut entry.id.clone(), nack)?;
+            self.pins
+                .entry(master_id)
+                .or_insert_with(|| (Rc::clone(pack), 0))
+                .1 += 1;
+        }
+        Ok(entries)
+    }
+
+    /// Acks a record. Returns whether it was pending.
+    pub fn ack(&mut self, id: &StreamID) -> bool {
+        let nack = match self.pending.remove(&mut id.clone()) {
+            (_, Some(nack)) => nack,
+            _ => return false,
+        };
+        if let Some(consumer) = self.consumers.get_mut(nack.consumer.borrow().as_str()) {
+            consumer.pending.remove(&mut id.clone());
+        }
+        self.idle.remove(id);
+        self.unpin(&nack.pack);
+        true
+    }
+
+    /// Claims the pending records with the given IDs that have been idle for
+    /// at least min_idle milliseconds for consumer, like XCLAIM does. Returns
+    /// the IDs of the ones it got. Unless justid is set, that's counted as a
+    /// delivery.
+    pub fn claim(
+        &mut self,
+        consumer: &str,
+        ids: &[StreamID],
+        min_idle: u64,
+        now: u64,
+        justid: bool,
+    ) -> Result<Vec<StreamID>, StreamError> {
+        let mut claimed = Vec::new();
+        for id in ids {
+            let nack = match self.pending.get(&mut id.clone()) {
+                Some(nack) => nack,
+                None => continue,
+            };
+            if now.saturating_sub(nack.delivery_time.get()) < min_idle {
+                continue;
+            }
+            self.transfer(id, nack, consumer, now, !justid)?;
+            claimed.push(*id);
+        }
+        Ok(claimed)
+    }
+
+    /// Makes records pending for consumer that aren't pending yet, like XCLAIM
+    /// does with FORCE, so that they can be claimed. Each comes with the
+    /// master ID of its pack and the pack. They count as never having been
+    /// delivered so far.
+    pub fn force(
+        &mut self,
+        consumer: &str,
+        records: Vec<(StreamID, StreamID, Pin)>,
+    ) -> Result<(), StreamError> {
+        for (id, master_id, pack) in records {
+            if self.pending.get(&mut id.clone()).is_some() {
+                continue;
+            }
+            let nack = Rc::new(NAck {
+                delivery_time: Cell::new(0),
+                delivery_count: Cell::new(0),
+                consumer: RefCell::new(String::from(consumer)),
+                pack: master_id,
+            });
+            self.pending.insert(&mut id.clone(), Rc::clone(&nack))?;
+            self.consumers
+                .entry(String::from(consumer))
+                .or_insert_with(Consumer::new)
+                .pending
+                .insert(&mut id.clone(), nack)?;
+            self.pins.entry(master_id).or_insert_with(|| (pack, 0)).1 += 1;
+        }
+        Ok(())
+    }
+
+    /// Redelivers records that were idle for too long to consumer, no more
+    /// than count of them. Its own records are left for someone else. No more
+    /// than budget idle records are looked at, picking up where the last
+    /// redelivery left off. Returns the IDs of the records it got, which are
+    /// replicated as a claim, along with the ones that could be read.
+    pub fn redeliver(
+        &mut self,
+        consumer: &str,
+        count: Option<usize>,
+        now: u64,
+        budget: usize,
+    ) -> Result<(Vec<StreamID>, Vec<Entry>), StreamError> {
+        let mut ids = Vec::new();
+        for _ in 0..budget {
+            if count.map_or(false, |count| ids.len() >= count) {
+                break;
+            }
+            let id = match self
+                .idle
+                .range((Bound::Excluded(self.redeliver_after), Bound::Unbounded))
+                .next()
+            {
+                Some(id) => *id,
+                None => {
+                    // Back to the start on the next redelivery.
+                    self.redeliver_after = StreamID::MIN;
+                    break;
+                }
+            };
+            self.redeliver_after = id;
+
+            let nack = match self.pending.get(&mut id.clone()) {
+                Some(nack) => nack,
+                None => {
+                    self.idle.remove(&id);
+                    continue;
+                }
+            };
+            if nack.consumer.borrow().as_str() == consumer {
+                continue;
+            }
+            ids.push((id, nack.pack));
+            self.transfer(&id, nack, consumer, now, true)?;
+        }
+        let entries = self.read_pinned(&ids);
+        Ok((ids.into_iter().map(|(id, _)| id).collect(), entries))
+    }
+
+    /// Looks at no more than budget pending records, picking up where the
+    /// last scan left off, for ones that have been idle for longer than the
+    /// timeout. They're set aside to be redelivered. Those that have been
+    /// delivered max_deliveries times are returned instead if there's a
+    /// dead-letter stream for them to go to. They're pending until acked.
+    pub fn scan_idle(&mut self, now: u64, budget: usize) -> Vec<DeadLetter> {
+        let timeout = self.reclaim.idle_timeout;
+        if timeout == 0 {
+            return Vec::new();
+        }
+        let max_deliveries = match self.reclaim.dead_letter {
+            Some(_) if self.reclaim.max_deliveries > 0 => self.reclaim.max_deliveries,
+            _ => u64::max_value(),
+        };
+
+        let mut dead = Vec::new();
+        for _ in 0..budget {
+            let (id, nack) = match self.pending.seek_entry(">", &self.scan_after) {
+                Some((id, Some(nack))) => (id, nack),
+                _ => {
+                    // Back to the start on the next scan.
+                    self.scan_after = StreamID::MIN;
+                    break;
+                }
+            };
+            self.scan_after = id;
+
+            let idle = now.saturating_sub(nack.delivery_time.get());
+            if idle < timeout {
+                continue;
+            }
+            if nack.delivery_count.get() >= max_deliveries {
+                dead.push((id, nack, idle));
+            } else {
+                self.idle.insert(id);
+            }
+        }
+
+        let ids: Vec<(StreamID, StreamID)> =
+            dead.iter().map(|&(id, ref nack, _)| (id, nack.pack)).collect();
+        let mut entries = self.read_pinned(&ids).into_iter().peekable();
+        let mut letters = Vec::with_capacity(dead.len());
+        for (id, nack, idle) in dead {
+            // A record that couldn't be read is left pending.
+            match entries.peek() {
+                Some(entry) if entry.id == id => {}
+                _ => continue,
+            }
+            letters.push(DeadLetter {
+                entry: entries.next().unwrap(),
+                consumer: nack.consumer.borrow().clone(),
+                delivery_count: nack.delivery_count.get(),
+                idle,
+            });
+        }
+        letters
+    }
+
+    /// Reads pending records by their IDs.
+    pub fn read_records(&self, ids: &[StreamID]) -> Vec<Entry> {
+        let ids: Vec<(StreamID, StreamID)> = ids
+            .iter()
+            .filter_map(|id| {
+                let nack = self.pending.get(&mut id.clone())?;
+                Some((*id, nack.pack))
+            })
+            .collect();
+        self.read_pinned(&ids)
+    }
+
+    /// Hands a pending record over to consumer, which counts as a delivery
+    /// if delivered is set.
+    fn transfer(
+        &mut self,
+        id: &StreamID,
+        nack: Rc<NAck>,
+        consumer: &str,
+        now: u64,
+        delivered: bool,
+    ) -> Result<(), StreamError> {
+        let previous = nack.consumer.replace(String::from(consumer));
+        if previous != consumer {
+            if let Some(previous) = self.consumers.get_mut(&previous) {
+                previous.pending.remove(&mut id.clone());
+            }
+            self.consumers
+                .entry(String::from(consumer))
+                .or_insert_with(Consumer::new)
+                .pending
+                .insert(&mut id.clone(), Rc::clone(&nack))?;
+        }
+        nack.delivery_time.set(now);
+        if delivered {
+            nack.delivery_count.set(nack.delivery_count.get() + 1);
+        }
+        self.idle.remove(id);
+        Ok(())
+    }
+
+    /// Reads the records that are pending for a consumer after the given ID,
+    /// no more than count of them. They're read from the packs that are
+    /// pinned for them so this never has to wait on the disk.
+    pub fn read_pending(
+        &self,
+        consumer: &str,
+        after: &StreamID,
+        count: Option<usize>,
+    ) -> Vec<Entry> {
+        let consumer = match self.consumers.get(consumer) {
+            Some(consumer) => consumer,
+            None => return Vec::new(),
+        };
+
+        let mut ids: Vec<(StreamID, StreamID)> = Vec::new();
+        let mut key = *after;
+        while count.map_or(true, |count| ids.len() < count) {
+            match consumer.pending.seek_entry(">", &key) {
+                Some((id, Some(nack))) => {
+                    ids.push((id, nack.pack));
+                    key = id;
+                }
+                _ => break,
+            }
+        }
+        self.read_pinned(&ids)
+    }
+
+    /// Reads records from the packs that are pinned for them. ids are in
+    /// order, each along with the master ID of its pack.
+    fn read_pinned(&self, ids: &[(StreamID, StreamID)]) -> Vec<Entry> {
+        let mut entries = Vec::with_capacity(ids.len());
+        let mut i = 0;
+        while i < ids.len() {
+            // Records of the same pack are next to each other, so each pack
+            // is scanned once.
+            let master_id = ids[i].1;
+            let n = ids[i..].iter().take_while(|&&(_, pack)| pack == master_id).count();
+            let wanted = &ids[i..i + n];
+            i += n;
+
+            let pack = match self.pins.get(&master_id) {
+                Some(&(ref pack, _)) if !pack.data.get().is_null() => pack,
+                _ => continue,
+            };
+            let mut next = 0;
+            entries.extend(scan_pack(pack.data.get(), &master_id, false, |id| {
+                while next < wanted.len() && wanted[next].0 < *id {
+                    next += 1;
+                }
+                if next == wanted.len() {
+                    Visit::Stop
+                } else if wanted[next].0 == *id {
+                    next += 1;
+                    Visit::Take
+                } else {
+                    Visit::Skip
+                }
+            }));
+        }
+        entries
+    }
+
+    /// Lets go of one pending record of the pack with master_id. The pack
+    /// goes back to disk once none of its records are pending.
+    fn unpin(&mut self, master_id: &StreamID) {
+        let unpinned = match self.pins.get_mut(master_id) {
+            Some(pin) => {
+                pin.1 -= 1;
+                pin.1 == 0
+            }
+            None => false,
+        };
+        if unpinned {
+            if let Some((pack, _)) = self.pins.remove(master_id) {
+                Pack::release(pack);
+            }
+        }
+    }
+}
+
+impl Consumer {
+    pub fn new() -> Consumer {
+        Consumer {
+            pending: map::RcRax::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fixture::{self, id, record};
+    use super::super::range::{self, RangeRead};
+    use tempdir::TempDir;
+
+    /// A stream with records 1-0 through 1-29 spread over a few packs.
+    fn stream(dir: &Path) -> Rc<UnsafeCell<Stream>> {
+        fixture::stream(dir, "group", fixture::config(128, 4096), 30, |seq| {
+            record(&["f", &format!("v{}", seq)])
+        })
+    }
+
+    /// Reads and delivers up to count new records to consumer.
+    fn read_new(
+        stream: &Rc<UnsafeCell<Stream>>,
+        consumer: &str,
+        count: usize,
+    ) -> Vec<u64> {
+        let last_id = unsafe { &mut *stream.get() }.group("g").unwrap().last_id();
+        let start = last_id.incr().unwrap();
+        let mut read =
+            RangeRead::new(Rc::clone(stream), start, StreamID::MAX, false, Some(count));
+        read.keep_packs();
+        assert!(read.resume().unwrap().is_none());
+        let (entries, packs) = read.into_parts();
+
+        let group = unsafe { &mut *stream.get() }.group("g").unwrap();
+        let entries = group.deliver(consumer, entries, packs, false, 100).unwrap();
+        entries.iter().map(|entry| entry.id.seq).collect()
+    }
+
+    fn pending(stream: &Rc<UnsafeCell<Stream>>, consumer: &str) -> Vec<u64> {
+        let group = unsafe { &mut *stream.get() }.group("g").unwrap();
+        group
+            .read_pending(consumer, &StreamID::MIN, None)
+            .iter()
+            .map(|entry| entry.id.seq)
+            .collect()
+    }
+
+    #[test]
+    fn it_delivers_records_once() {
+        let dir = TempDir::new("group").unwrap();
+        let stream = stream(dir.path());
+        let s = unsafe { &mut *stream.get() };
+        s.create_group("g", id(1, 4)).unwrap();
+        assert!(s.create_group("g", StreamID::MIN).is_err());
+
+        assert_eq!(vec![5, 6, 7], read_new(&stream, "alice", 3));
+        assert_eq!((8..30).collect::<Vec<u64>>(), read_new(&stream, "bob", 100));
+        assert!(read_new(&stream, "alice", 3).is_empty());
+
+        let group = s.group("g").unwrap();
+        assert_eq!(id(1, 29), group.last_id());
+        assert_eq!(25, group.pending_len());
+        let nack = group.pending.get(&mut id(1, 6)).unwrap();
+        assert_eq!(1, nack.delivery_count.get());
+        assert_eq!(100, nack.delivery_time.get());
+        assert_eq!("alice", nack.consumer.borrow().as_str());
+
+        assert!(s.destroy_group("g"));
+        assert!(!s.destroy_group("g"));
+    }
+
+    #[test]
+    fn it_reads_pending_records_from_pinned_packs() {
+        let dir = TempDir::new("group").unwrap();
+        let stream = stream(dir.path());
+        let s = unsafe { &mut *stream.get() };
+        s.create_group("g", StreamID::MIN).unwrap();
+        assert_eq!((0..30).collect::<Vec<u64>>(), read_new(&stream, "alice", 100));
+
+        let group = s.group("g").unwrap();
+        assert!(group.pins.len() > 1);
+        for &(ref pack, _) in group.pins.values() {
+            assert!(!pack.data.get().is_null());
+        }
+
+        let entries = group.read_pending("alice", &id(1, 10), Some(2));
+        let ids: Vec<StreamID> = entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(vec![id(1, 11), id(1, 12)], ids);
+        assert_eq!(vec![(b"f".to_vec(), b"v11".to_vec())], entries[0].fields);
+        assert!(group.read_pending("bob", &StreamID::MIN, None).is_empty());
+
+        // Acking everything in the first pack lets go of it.
+        let first_id = *group.pins.keys().next().unwrap();
+        let in_first: Vec<StreamID> = group
+            .read_pending("alice", &StreamID::MIN, None)
+            .iter()
+            .map(|entry| entry.id)
+            .filter(|id| group.pending.get(&mut id.clone()).unwrap().pack == first_id)
+            .collect();
+        for id in in_first.iter() {
+            assert!(group.ack(id));
+            assert!(!group.ack(id));
+        }
+        assert!(!group.pins.contains_key(&first_id));
+
+        let left = pending(&stream, "alice");
+        assert_eq!(30 - in_first.len(), left.len());
+        assert_eq!(in_first.len() as u64, left[0]);
+    }
+
+    #[test]
+    fn it_reclaims_idle_records() {
+        let dir = TempDir::new("group").unwrap();
+        let stream = stream(dir.path());
+        let s = unsafe { &mut *stream.get() };
+        s.create_group("g", StreamID::MIN).unwrap();
+        assert_eq!(vec![0, 1, 2], read_new(&stream, "alice", 3));
+
+        let group = s.group("g").unwrap();
+        group.set_reclaim(Reclaim {
+            idle_timeout: 50,
+            max_deliveries: 2,
+            dead_letter: Some(String::from("dead")),
+        });
+        assert!(group.scan_idle(120, 10).is_empty());
+        assert!(group.idle.is_empty());
+
+        // Each scan picks up where the last one left off.
+        assert!(group.scan_idle(200, 2).is_empty());
+        assert_eq!(2, group.idle.len());
+        assert!(group.scan_idle(200, 2).is_empty());
+        assert_eq!(3, group.idle.len());
+
+        // Records go to someone other than the consumer that had them.
+        assert!(group.redeliver("alice", None, 200, 10).unwrap().0.is_empty());
+        let (redelivered, entries) = group.redeliver("bob", Some(2), 200, 10).unwrap();
+        let ids: Vec<StreamID> = entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(vec![id(1, 0), id(1, 1)], ids);
+        assert_eq!(ids, redelivered);
+        let nack = group.pending.get(&mut id(1, 0)).unwrap();
+        assert_eq!("bob", nack.consumer.borrow().as_str());
+        assert_eq!(2, nack.delivery_count.get());
+        assert_eq!(200, nack.delivery_time.get());
+        assert_eq!(2, group.read_pending("bob", &StreamID::MIN, None).len());
+        assert_eq!(1, group.read_pending("alice", &StreamID::MIN, None).len());
+
+        // Claiming with JUSTID doesn't count as a delivery.
+        let claimed = group.claim("carol", &[id(1, 2), id(1, 9)], 50, 200, true).unwrap();
+        assert_eq!(vec![id(1, 2)], claimed);
+        assert_eq!(1, group.pending.get(&mut id(1, 2)).unwrap().delivery_count.get());
+        assert!(group.idle.is_empty());
+        assert!(group.read_pending("alice", &StreamID::MIN, None).is_empty());
+
+        // Records that were delivered twice are handed back for the
+        // dead-letter stream rather than being set aside again.
+        let dead = group.scan_idle(300, 10);
+        let ids: Vec<StreamID> = dead.iter().map(|letter| letter.entry.id).collect();
+        assert_eq!(vec![id(1, 0), id(1, 1)], ids);
+        assert_eq!("bob", dead[0].consumer);
+        assert_eq!(2, dead[0].delivery_count);
+        assert_eq!(100, dead[0].idle);
+        assert_eq!(vec![(b"f".to_vec(), b"v0".to_vec())], dead[0].entry.fields);
+        assert_eq!(vec![id(1, 2)], group.idle.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(3, group.pending_len());
+    }
+
+    #[test]
+    fn it_redelivers_a_bounded_number_of_idle_records() {
+        let dir = TempDir::new("group").unwrap();
+        let stream = stream(dir.path());
+        let s = unsafe { &mut *stream.get() };
+        s.create_group("g", StreamID::MIN).unwrap();
+        assert_eq!((0..6).collect::<Vec<u64>>(), read_new(&stream, "alice", 6));
+
+        let group = s.group("g").unwrap();
+        group.set_reclaim(Reclaim { idle_timeout: 50, ..Reclaim::default() });
+        assert!(group.scan_idle(200, 10).is_empty());
+        assert_eq!(6, group.idle.len());
+
+        let seqs = |ids: Vec<StreamID>| ids.iter().map(|id| id.seq).collect::<Vec<_>>();
+
+        // The consumer's own records still use up the budget.
+        assert!(group.redeliver("alice", None, 200, 2).unwrap().0.is_empty());
+        assert_eq!(id(1, 1), group.redeliver_after);
+
+        // Each redelivery picks up where the last one left off.
+        let (redelivered, _) = group.redeliver("bob", None, 200, 2).unwrap();
+        assert_eq!(vec![2, 3], seqs(redelivered));
+        let (redelivered, _) = group.redeliver("bob", Some(1), 200, 10).unwrap();
+        assert_eq!(vec![4], seqs(redelivered));
+        let (redelivered, _) = group.redeliver("bob", None, 200, 10).unwrap();
+        assert_eq!(vec![5], seqs(redelivered));
+        assert_eq!(StreamID::MIN, group.redeliver_after);
+
+        // Then it goes back to the start.
+        let (redelivered, _) = group.redeliver("bob", None, 200, 10).unwrap();
+        assert_eq!(vec![0, 1], seqs(redelivered));
+        assert!(group.idle.is_empty());
+    }
+
+    #[test]
+    fn it_forces_records_pending_like_a_replica() {
+        let dir = TempDir::new("group").unwrap();
+        let stream = stream(dir.path());
+        let s = unsafe { &mut *stream.get() };
+        s.create_group("g", StreamID::MIN).unwrap();
+        assert_eq!(vec![0, 1], read_new(&stream, "alice", 2));
+
+        // What a read on the master delivered to bob, followed by the last ID
+        // the group delivered there.
+        let ids = [id(1, 1), id(1, 2), id(1, 3)];
+        let located: Vec<(StreamID, StreamID, Pin)> = ids
+            .iter()
+            .map(|id| {
+                let (master_id, pack, _) = range::locate(s, id).unwrap().unwrap();
+                (*id, master_id, pack)
+            })
+            .collect();
+        let group = s.group("g").unwrap();
+        group.force("bob", located).unwrap();
+        assert_eq!(ids.to_vec(), group.claim("bob", &ids, 0, 200, false).unwrap());
+        group.set_last_id(id(1, 3));
+
+        // The one that was already pending is handed over, the others are
+        // delivered for the first time.
+        assert_eq!(4, group.pending_len());
+        assert_eq!(1, group.consumers["alice"].pending.len());
+        assert_eq!(3, group.consumers["bob"].pending.len());
+        let nack = group.pending.get(&mut id(1, 1)).unwrap();
+        assert_eq!(2, nack.delivery_count.get());
+        let nack = group.pending.get(&mut id(1, 2)).unwrap();
+        assert_eq!(1, nack.delivery_count.get());
+        assert_eq!(200, nack.delivery_time.get());
+        assert_eq!(vec![4, 5], read_new(&stream, "carol", 2));
+    }
+}
@@ -2,9 +2,9 @@ use std;
 use std::cmp;
 use std::fmt;
 use std::mem;
-use time;
 
 use super::listpack::*;
+use crate::clock::Clock;
 
 #[derive(Copy)]
 #[repr(C)]
@@ -23,6 +23,35 @@ impl StreamID {
     }
 }
 
+impl StreamID {
+    /// The smallest ID there is.
+    pub const MIN: StreamID = StreamID { ms: 0, seq: 0 };
+    /// The largest ID there is.
+    pub const MAX: StreamID = StreamID { ms: std::u64::MAX, seq: std::u64::MAX };
+
+    /// Returns the ID right after this one, or None if this is the largest.
+    pub fn incr(&self) -> Option<StreamID> {
+        if self.seq < u64::max_value() {
+            Some(StreamID { ms: self.ms, seq: self.seq + 1 })
+        } else if self.ms < u64::max_value() {
+            Some(StreamID { ms: self.ms + 1, seq: 0 })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the ID right before this one, or None if this is the smallest.
+    pub fn decr(&self) -> Option<StreamID> {
+        if self.seq > 0 {
+            Some(StreamID { ms: self.ms, seq: self.seq - 1 })
+        } else if self.ms > 0 {
+            Some(StreamID { ms: self.ms - 1, seq: u64::max_value() })
+        } else {
+            None
+        }
+    }
+}
+
 impl fmt::Display for StreamID {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "{}-{}", self.ms, self.seq);
@@ -30,6 +59,12 @@ impl fmt::Display for StreamID {
     }
 }
 
+impl fmt::Debug for StreamID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
 impl Default for StreamID {
     fn default() -> Self {
         StreamID { ms: 0, seq: 0 }
@@ -64,6 +99,14 @@ impl PartialOrd for StreamID {
     }
 }
 
+impl Eq for StreamID {}
+
+impl Ord for StreamID {
+    fn cmp(&self, other: &StreamID) -> cmp::Ordering {
+        self.partial_cmp(other).unwrap()
+    }
+}
+
 
 
 impl crate::redis::rax::RaxKeyOld for StreamID {
@@ -98,9 +141,10 @@ impl crate::redis::rax::RaxKeyOld for StreamID {
 }
 
 
+/// Returns the time told by clock in milliseconds since the epoch.
 #[inline(always)]
-pub fn mstime() -> u64 {
-    time::precise_time_ns() / 1_000_000
+pub fn mstime(clock: &Clock) -> u64 {
+    clock.milliseconds() as u64
 }
 
 /// Generate the next stream item ID given the previous one. If the current
@@ -108,8 +152,8 @@ pub fn mstime() -> u64 {
 /// as time part and start with sequence part of zero. Otherwise we use the
 /// previous time (and never go backward) and increment the sequence.
 #[inline(always)]
-pub fn next_id(last: &StreamID) -> StreamID {
-    let ms = mstime();
+pub fn next_id(last: &StreamID, clock: &Clock) -> StreamID {
+    let ms = mstime(clock);
     if ms > last.ms {
         StreamID { ms, seq: 0 }
     } else {
@@ -156,4 +200,66 @@ pub fn try_parse_master(previous: u64, value: Value) -> Option<u64> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parses an ID given as "<ms>-<seq>" or just "<ms>", in which case the
+/// sequence is missing_seq.
+pub fn parse_id(s: &str, missing_seq: u64) -> Option<StreamID> {
+    let mut parts = s.splitn(2, '-');
+    let ms = parts.next()?.parse::<u64>().ok()?;
+    let seq = match parts.next() {
+        Some(seq) => seq.parse::<u64>().ok()?,
+        None => missing_seq,
+    };
+    Some(StreamID { ms, seq })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate time;
+
+    use crate::clock::ManualClock;
+    use crate::stream::id::*;
+
+    #[test]
+    fn it_never_goes_backwards() {
+        let clock = ManualClock::new(time::at_utc(time::Timespec::new(1_000, 0)));
+
+        let id = next_id(&StreamID::default(), &clock);
+        assert_eq!((1_000_000, 0), (id.ms, id.seq));
+
+        // Within the same millisecond, or after the clock went backwards, the
+        // sequence is bumped instead.
+        let id = next_id(&id, &clock);
+        assert_eq!((1_000_000, 1), (id.ms, id.seq));
+        clock.advance(time::Duration::seconds(-1));
+        let id = next_id(&id, &clock);
+        assert_eq!((1_000_000, 2), (id.ms, id.seq));
+
+        clock.advance(time::Duration::milliseconds(1001));
+        let id = next_id(&id, &clock);
+        assert_eq!((1_000_001, 0), (id.ms, id.seq));
+    }
+
+    #[test]
+    fn it_parses_ids() {
+        let id = parse_id("1526919030474-55", 0).unwrap();
+        assert_eq!((1526919030474, 55), (id.ms, id.seq));
+        let id = parse_id("1526919030474", u64::max_value()).unwrap();
+        assert_eq!((1526919030474, u64::max_value()), (id.ms, id.seq));
+
+        assert!(parse_id("", 0).is_none());
+        assert!(parse_id("1-", 0).is_none());
+        assert!(parse_id("-1", 0).is_none());
+        assert!(parse_id("abc-1", 0).is_none());
+    }
+
+    #[test]
+    fn it_steps_ids() {
+        let id = StreamID { ms: 1, seq: u64::max_value() };
+        assert!(id.incr().unwrap() == StreamID { ms: 2, seq: 0 });
+        assert!(id.incr().unwrap().decr().unwrap() == id);
+        assert!(StreamID::MAX.incr().is_none());
+        assert!(StreamID::MIN.decr().is_none());
+    }
+}
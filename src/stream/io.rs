@@ -1,14 +1,16 @@
-use crate::alloc::{alloc, free};
+use crate::alloc::{alloc, dealloc, free};
 use crate::mmap::{Mmap, MmapMut, MmapOptions};
+use crate::redis::Redis;
+use crate::redis::listpack;
 use crate::redis::listpack::Listpack;
 use crate::redis::rax::RaxMap;
 use crate::redis::sds::SDS;
 use spin::Mutex;
 use std::cell::Cell;
 use std::fs;
-use std::marker;
 use std::mem;
-use std::path::Path;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 use std::rc::{Rc, Weak};
 use std::sync::{Arc, Weak as ArcWeak};
 use std::sync::mpsc;
@@ -21,6 +23,12 @@ use super::*;
 pub const BLOB_LOCATION_FS: u8 = 1;
 pub const BLOB_LOCATION_OBJECT: u8 = 2;
 
+/// The most finished tasks handled on a single tick of the event-loop so a
+/// burst of I/O doesn't hold up clients.
+pub const POLL_BUDGET: usize = 1024;
+/// How often finished tasks are picked up while some are pending.
+pub const POLL_INTERVAL_MS: i64 = 1;
+
 type SegmentID = StreamID;
 
 /// Future when the next writer becomes available.
@@ -28,12 +36,11 @@ struct WriterFuture {
 //    pub writer: Option<Rc<SegmentWriter>>,
 }
 
-/// Future of when Pin's data is faulted in.
-struct CreateFuture<'a> {
-    pub path: &'a Path,
-    pub size: u32,
-    pub mmap: Option<MmapMut>,
-    pub err: Option<StreamError>,
+/// Creates a segment file and preallocates it.
+pub struct CreateTask {
+    pub path: PathBuf,
+    pub size: u64,
+    pub result: Option<Result<aof::AOF, StreamError>>,
 }
 
 struct TruncateFuture<'a> {
@@ -59,19 +66,55 @@ struct ReadFuture {
     future: Arc<Box<ReadTask>>,
 }
 
-/// Reads a contiguous block of memory from a file.
-struct ReadTask {
-    mmap: Arc<Mutex<Mmap>>,
-    offset: u32,
-    length: u32,
-    count: u16,
-    data: usize,
-    err: Option<StreamError>,
-//    callbacks: Vec<Fn(Box<ReadFuture>)>,
+/// Reads a pack out of a segment and loads it into a listpack.
+pub struct ReadTask {
+    pub segment: PathBuf,
+    /// The view of a sealed segment.
+    pub mmap: Option<Arc<Mutex<Mmap>>>,
+    /// The file of the tail segment, which is read straight from it instead
+    /// so that writes to it don't wait on the read.
+    pub file: Option<fs::File>,
+    pub offset: u32,
+    pub length: u32,
+    pub raw_length: u32,
+    pub crc: u32,
+    /// The listpack that was loaded. Raw pointers aren't Send so it crosses
+    /// over to the event-loop as an address.
+    pub result: Option<Result<usize, StreamError>>,
+}
+
+/// Waits for everything written to an AOF to reach the disk.
+pub struct SyncTask {
+    pub aof: Arc<Mutex<aof::AOF>>,
+    pub result: Option<Result<(), StreamError>>,
+}
+
+/// Waits for what was written to a file through its map to reach the disk.
+/// The pages of the map are in the page cache like any other write, so only
+/// the file is needed and writes to the map carry on in the meantime.
+pub struct SyncFileTask {
+    pub file: fs::File,
+    pub result: Option<Result<(), StreamError>>,
 }
 
-struct SyncFuture {
-    mmap: MmapMut,
+/// Replaces the groups file of a stream. See `group::write_groups`.
+pub struct SaveGroupsTask {
+    /// Directory of the stream.
+    pub dir: PathBuf,
+    pub data: Vec<u8>,
+    pub result: Option<Result<(), StreamError>>,
+}
+
+/// Seals a segment that filled up. See `writer::seal_segment`.
+pub struct SealTask {
+    pub segment_id: StreamID,
+    /// Taken by the I/O thread, which is done with it once it's sealed.
+    pub aof: Option<aof::AOF>,
+    pub path: PathBuf,
+    pub index: Vec<format::IndexEntry>,
+    pub compression: i32,
+    pub compression_level: i32,
+    pub result: Option<Result<(Vec<format::IndexEntry>, Mmap), StreamError>>,
 }
 
 struct SegmentLoadTask {
@@ -82,26 +125,177 @@ struct OpenSegment {
     id: StreamID,
 }
 
-enum Task {
-    /// Create a new segment file with specified sequence number.
-    Create(Arc<Box<CreateFuture<'static>>>),
+pub enum Task {
+    /// Create a new segment file.
+    Create(CreateTask),
+
+    Read(ReadTask),
+
+    Sync(SyncTask),
+
+    SyncFile(SyncFileTask),
 
-    Read(Arc<Box<ReadTask>>),
+    /// Seal a segment that filled up.
+    Seal(SealTask),
 
-    Sync(Arc<Box<SyncFuture>>),
+    /// Save the consumer groups of a stream.
+    SaveGroups(SaveGroupsTask),
 
     /// Shutdown the store and close up all file handles and flush
     /// all pending data to disk.
     Shutdown,
 }
 
+impl Task {
+    /// Does the work on the I/O thread and fills in the result.
+    fn run(&mut self) {
+        match *self {
+            Task::Create(ref mut create) => {
+                create.result = Some(
+                    aof::AOF::create(&create.path, create.size)
+                        .and_then(|aof| aof.preallocate().map(|_| aof))
+                        .map_err(StreamError::from)
+                );
+            }
+            Task::Read(ref mut read) => {
+                let lp = read.read();
+                read.result = Some(lp.map(|lp| lp as usize));
+            }
+            Task::Sync(ref mut sync) => {
+                sync.result = Some(sync.aof.lock().sync().map_err(StreamError::from));
+            }
+            Task::SyncFile(ref mut sync) => {
+                sync.result = Some(sync.file.sync_data().map_err(StreamError::from));
+            }
+            Task::SaveGroups(ref mut save) => {
+                save.result = Some(group::write_groups(&save.dir, &save.data));
+            }
+            Task::Seal(ref mut seal) => {
+                let index = mem::replace(&mut seal.index, Vec::new());
+                seal.result = seal.aof.take().map(|aof| {
+                    writer::seal_segment(
+                        aof,
+                        &seal.path,
+                        index,
+                        seal.compression,
+                        seal.compression_level,
+                    )
+                });
+            }
+            Task::Shutdown => {}
+        }
+    }
+
+    /// Frees whatever a finished task holds that nobody picked up.
+    fn discard(self) {
+        if let Task::Read(ReadTask { result: Some(Ok(lp)), .. }) = self {
+            dealloc(lp as listpack::listpack);
+        }
+    }
+}
+
+impl ReadTask {
+    /// A read of a pack, either through the view of a sealed segment or from
+    /// the file of the tail segment. segment is the path of the file.
+    pub fn new(
+        segment: PathBuf,
+        mmap: Option<Arc<Mutex<Mmap>>>,
+        file: Option<fs::File>,
+        pack: &Pack,
+    ) -> ReadTask {
+        ReadTask {
+            segment,
+            mmap,
+            file,
+            offset: pack.offset.get(),
+            length: pack.length.get(),
+            raw_length: pack.raw_length.get(),
+            crc: pack.crc.get(),
+            result: None,
+        }
+    }
+
+    /// Reads the pack on whichever thread it's run.
+    pub fn read(&self) -> Result<listpack::listpack, StreamError> {
+        match self.mmap {
+            Some(ref mmap) => format::read_pack(
+                &self.segment,
+                &mmap.lock(),
+                self.offset,
+                self.length,
+                self.raw_length,
+                self.crc,
+            ),
+            None => read_from_file(self),
+        }
+    }
+}
+
+/// Reads a pack with a plain read of its segment file.
+fn read_from_file(read: &ReadTask) -> Result<listpack::listpack, StreamError> {
+    let file = match read.file {
+        Some(ref file) => file,
+        None => return Err(StreamError::NotExists),
+    };
+    let mut buf = vec![0u8; read.length as usize];
+    file.read_exact_at(&mut buf, read.offset as u64)?;
+
+    let lp = format::read_pack(
+        &read.segment,
+        &buf,
+        0,
+        read.length,
+        read.raw_length,
+        read.crc,
+    );
+    match lp {
+        Err(StreamError::Corrupt { segment, .. }) => {
+            Err(StreamError::Corrupt { segment, offset: read.offset })
+        }
+        lp => lp,
+    }
+}
+
 pub enum StorageType {
     File,
     Object,
 }
 
-pub struct IoFuture<T: ?Sized + Send + 'static> {
-    marker: marker::PhantomData<T>,
+/// Everything waiting on a task that's with the I/O thread.
+pub struct IoFuture {
+    /// Where a read puts the listpack it loaded.
+    pack: Option<Rc<Pack>>,
+    /// Whoever gets the file a create made.
+    created: Option<Box<FnMut(Result<aof::AOF, StreamError>)>>,
+    /// Whoever gets the index and the view of a segment that was sealed.
+    sealed: Option<Box<FnMut(Result<(Vec<format::IndexEntry>, Mmap), StreamError>)>>,
+    continuations: Vec<Box<FnMut(Result<(), StreamError>)>>,
+}
+
+impl IoFuture {
+    fn new() -> IoFuture {
+        IoFuture {
+            pack: None,
+            created: None,
+            sealed: None,
+            continuations: Vec::new(),
+        }
+    }
+}
+
+/// Boxes a continuation so it can be stored. They're only ever called once,
+/// but a FnOnce can't be called through a box.
+fn continuation<T, F>(f: F) -> Box<FnMut(T)>
+where
+    T: 'static,
+    F: FnOnce(T) + 'static,
+{
+    let mut f = Some(f);
+    Box::new(move |result| {
+        if let Some(f) = f.take() {
+            f(result)
+        }
+    })
 }
 
 enum Location {
@@ -144,17 +338,24 @@ pub struct SnapshotRecord {
 pub struct StorageService {
     dir: String,
     /// Background thread sender.
-    bg_sender: mpsc::SyncSender<Task>,
+    bg_sender: mpsc::SyncSender<(u64, Task)>,
     /// Background thread handle.
-    bg_thread: thread::JoinHandle<i32>,
-    ev_sender: mpsc::SyncSender<Task>,
-    ev_receiver: mpsc::Receiver<Task>,
+    bg_thread: Option<thread::JoinHandle<i32>>,
+    ev_sender: mpsc::SyncSender<(u64, Task)>,
+    ev_receiver: mpsc::Receiver<(u64, Task)>,
 
     /// A map of futures. Each future may hold many continuations.
     /// Each continuation is likely a client based command that all
     /// require the same Pack or AOF to complete. We handle the race
     /// through the future.
-    futures: RaxMap<u64, IoFuture<Task>>,
+    futures: RaxMap<u64, IoFuture>,
+    next_task_id: u64,
+    /// Tasks handed to the background thread that haven't been polled yet.
+    pending: usize,
+    /// The most tasks that may be pending at once.
+    backlog: usize,
+    /// Whether a timer to poll is already set.
+    polling: bool,
 
     /// Keep track of memory used by all tasks, futures and continuations.
     mem_usage: usize,
@@ -188,34 +389,46 @@ pub struct StorageService {
 
 impl StorageService {
     pub fn start(path: &Path) -> Result<StorageService, StreamError> {
+        StorageService::with_backlog(path, super::max_io_backlog())
+    }
+
+    /// Starts the service allowing up to backlog tasks to be pending on the
+    /// background thread at once.
+    pub fn with_backlog(
+        path: &Path,
+        backlog: usize,
+    ) -> Result<StorageService, StreamError> {
         // Background channel. Use a sync channel to handle back-pressure.
         let (
             bg_sender,
             bg_receiver
-        ) = mpsc::sync_channel(super::max_io_backlog());
+        ) = mpsc::sync_channel::<(u64, Task)>(backlog);
 
         // Event-loop channel. Use a sync channel to handle back-pressure.
         // The event-loop will never block and if sending a task on the background channel
         // will block, it will fail fast and the error will be pushed back to the client.
+        // No more than backlog tasks are ever pending so the background thread never
+        // blocks on it either.
         let (
             ev_sender,
             ev_receiver
-        ) = mpsc::sync_channel(super::max_io_backlog());
+        ) = mpsc::sync_channel(backlog);
 
         // Spawn background thread.
+        let completed = ev_sender.clone();
         let handle = thread::spawn(move || {
             loop {
                 match bg_receiver.recv() {
-                    Ok(task) => {
-                        match task {
-                            // Create and open a new file.
-                            Task::Create(ref create) => {}
-                            Task::Read(ref read) => {}
-                            Task::Sync(ref sync) => {}
-                            Task::Shutdown => {}
+                    Ok((_, Task::Shutdown)) | Err(_) => break,
+                    Ok((id, mut task)) => {
+                        task.run();
+                        let sent = completed.send((id, task));
+                        if let Err(mpsc::SendError((_, task))) = sent {
+                            // The event-loop is gone.
+                            task.discard();
+                            break;
                         }
                     }
-                    Err(e) => {}
                 }
             }
             0
@@ -236,25 +449,9 @@ impl StorageService {
                 String::from(path.to_str().unwrap_or("<empty>"))
             ));
         } else {
-            // Do recovery
-            match path.read_dir() {
-                Ok(dir) => {
-                    // Find "0" db index.
-                    let mut db_dir: Option<fs::DirEntry> = None;
-                    for entry in dir {
-                        if let Ok(entry) = entry {
-                            if entry.path().ends_with("0") {
-
-                            }
-                            println!("{:?}", entry.path());
-                        }
-                    }
-                }
-                Err(_) =>
-                    return Err(StreamError::ReadDir(
-                        String::from(path.to_str().unwrap_or("<empty>"))
-                    ))
-            }
+            // Streams that are already there are recovered by the
+            // StreamManager. Make sure it'll be able to find them.
+            stream_dirs(path)?;
         }
 
         Ok(StorageService {
@@ -263,12 +460,16 @@ impl StorageService {
 
             // Background channel
             bg_sender: bg_sender.clone(),
-            bg_thread: handle,
+            bg_thread: Some(handle),
 
             // Event-loop channel
             ev_sender: ev_sender.clone(),
             ev_receiver,
             futures: RaxMap::new(),
+            next_task_id: 1,
+            pending: 0,
+            backlog,
+            polling: false,
 
             mem_usage: mem::size_of::<StorageService>(),
 
@@ -291,31 +492,336 @@ impl StorageService {
         })
     }
 
+    /// Number of tasks that haven't been polled yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Creates and preallocates a segment file on the background thread.
+    pub fn create<F>(
+        &mut self,
+        path: PathBuf,
+        size: u64,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<aof::AOF, StreamError>) + 'static,
+    {
+        let mut future = IoFuture::new();
+        future.created = Some(continuation(then));
+        self.submit(Task::Create(CreateTask { path, size, result: None }), future)
+    }
+
+    /// Seals a segment that filled up on the background thread.
+    pub fn seal<F>(&mut self, task: SealTask, then: F) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(Vec<format::IndexEntry>, Mmap), StreamError>) + 'static,
+    {
+        let mut future = IoFuture::new();
+        future.sealed = Some(continuation(then));
+        self.submit(Task::Seal(task), future)
+    }
+
+    /// Loads a pack out of a sealed segment on the background thread. The
+    /// pack has its data once then is called with Ok.
+    pub fn read<F>(
+        &mut self,
+        segment: &Segment,
+        pack: Rc<Pack>,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        let mmap = match segment.handle {
+            writer::SegmentHandle::Immutable(ref mmap) => Arc::clone(mmap),
+            _ => return Err(StreamError::WouldBlock),
+        };
+        self.read_pack(segment.path.clone(), Some(mmap), None, pack, then)
+    }
+
+    /// Loads a pack out of the file of the tail segment on the background
+    /// thread, the same way `read` does. path is only for reporting.
+    pub fn read_tail<F>(
+        &mut self,
+        path: PathBuf,
+        file: fs::File,
+        pack: Rc<Pack>,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        self.read_pack(path, None, Some(file), pack, then)
+    }
+
+    fn read_pack<F>(
+        &mut self,
+        segment: PathBuf,
+        mmap: Option<Arc<Mutex<Mmap>>>,
+        file: Option<fs::File>,
+        pack: Rc<Pack>,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        let task = Task::Read(ReadTask::new(segment, mmap, file, &pack));
+        let mut future = IoFuture::new();
+        future.pack = Some(pack);
+        future.continuations.push(continuation(then));
+        self.submit(task, future)
+    }
+
+    /// Syncs an AOF to disk on the background thread.
+    pub fn sync<F>(
+        &mut self,
+        aof: Arc<Mutex<aof::AOF>>,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        let mut future = IoFuture::new();
+        future.continuations.push(continuation(then));
+        self.submit(Task::Sync(SyncTask { aof, result: None }), future)
+    }
+
+    /// Syncs a file written through a map on the background thread. Unlike
+    /// `sync` it doesn't hold up writes to the map.
+    pub fn sync_file<F>(&mut self, file: fs::File, then: F) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        let mut future = IoFuture::new();
+        future.continuations.push(continuation(then));
+        self.submit(Task::SyncFile(SyncFileTask { file, result: None }), future)
+    }
+
+    /// Saves the consumer groups of the stream kept in dir on the background
+    /// thread.
+    pub fn save_groups<F>(
+        &mut self,
+        dir: PathBuf,
+        data: Vec<u8>,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        let mut future = IoFuture::new();
+        future.continuations.push(continuation(then));
+        let task = SaveGroupsTask { dir, data, result: None };
+        self.submit(Task::SaveGroups(task), future)
+    }
+
+    /// Whether tasks are turned away until some of the pending ones are
+    /// polled.
+    pub fn is_full(&self) -> bool {
+        self.pending >= self.backlog
+    }
+
+    /// Adds another continuation to a pending read or sync so clients that
+    /// need the same pack don't each read it. Returns false if the task is
+    /// no longer pending.
+    pub fn wait<F>(&mut self, task_id: u64, then: F) -> bool
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        match self.futures.remove(task_id) {
+            (_, Some(mut future)) => {
+                future.continuations.push(continuation(then));
+                self.futures.insert(task_id, future).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Hands a task to the background thread. This never blocks. Once too
+    /// many tasks are pending it fails with IoBacklog so the client gets an
+    /// error instead of stalling the event-loop.
+    fn submit(&mut self, task: Task, future: IoFuture) -> Result<u64, StreamError> {
+        if self.pending >= self.backlog {
+            return Err(StreamError::IoBacklog);
+        }
+
+        let task_id = self.next_task_id;
+        match self.bg_sender.try_send((task_id, task)) {
+            Ok(_) => {}
+            Err(mpsc::TrySendError::Full(_)) => return Err(StreamError::IoBacklog),
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                return Err(StreamError::Generic("I/O thread has stopped".to_owned()))
+            }
+        }
+        self.next_task_id += 1;
+        self.pending += 1;
+
+        // The task can't finish before it's polled so the future is always
+        // there in time.
+        if let Err(_) = self.futures.insert(task_id, Box::new(future)) {
+            return Err(StreamError::OutOfMemory);
+        }
+        Ok(task_id)
+    }
+
     /// Must be called from the event-loop. This function polls the completed
     /// background work and invokes the continuation for each task. It will invoke
     /// the configured max so we don't cause too much lag on the event-loop.
-    pub fn poll(&self) {
-//        let mut lock = self.completed.lock();
-//        let mut count = 0;
-//        while let Some(task) = lock.pop_front() {
-//            match task {
-//                Task::Create(ref create) => {}
-//                Task::Read(ref read) => {}
-//                Task::Sync(ref sync) => {}
-//                Task::Shutdown => {}
-//            }
-//
-//            count = count + 1;
-//            if count == 1024 {
-//                break;
-//            }
-//        }
-//        drop(lock);
+    /// Returns the number of tasks still pending.
+    pub fn poll(&mut self) -> usize {
+        for _ in 0..POLL_BUDGET {
+            let (task_id, task) = match self.ev_receiver.try_recv() {
+                Ok(completed) => completed,
+                Err(_) => break,
+            };
+            self.pending -= 1;
+
+            let mut future = match self.futures.remove(task_id) {
+                (_, Some(future)) => future,
+                _ => {
+                    task.discard();
+                    continue;
+                }
+            };
+
+            let result = match task {
+                Task::Create(create) => {
+                    let result = create.result
+                        .unwrap_or_else(|| Err(StreamError::Generic(String::new())));
+                    if let Some(mut created) = future.created.take() {
+                        created(result);
+                    }
+                    continue;
+                }
+                Task::Read(read) => match read.result {
+                    Some(Ok(lp)) => {
+                        let lp = lp as listpack::listpack;
+                        match future.pack.take() {
+                            Some(ref pack) if pack.data.get().is_null() => {
+                                pack.data.set(lp)
+                            }
+                            _ => dealloc(lp),
+                        }
+                        Ok(())
+                    }
+                    Some(Err(e)) => Err(e),
+                    None => Err(StreamError::Generic(String::new())),
+                },
+                Task::Sync(sync) => sync.result
+                    .unwrap_or_else(|| Err(StreamError::Generic(String::new()))),
+                Task::SyncFile(sync) => sync.result
+                    .unwrap_or_else(|| Err(StreamError::Generic(String::new()))),
+                Task::SaveGroups(save) => save.result
+                    .unwrap_or_else(|| Err(StreamError::Generic(String::new()))),
+                Task::Seal(seal) => {
+                    let result = seal.result
+                        .unwrap_or_else(|| Err(StreamError::Generic(String::new())));
+                    if let Some(mut sealed) = future.sealed.take() {
+                        sealed(result);
+                    }
+                    continue;
+                }
+                Task::Shutdown => continue,
+            };
+
+            for then in future.continuations.iter_mut() {
+                then(result.clone());
+            }
+        }
+        self.pending
+    }
+
+    /// Polls on a timer for as long as tasks are pending. Call it after
+    /// submitting tasks from a command.
+    pub fn watch(&mut self, r: &Redis) {
+        if self.polling || self.pending == 0 {
+            return;
+        }
+        self.polling = true;
+        r.start_timer(POLL_INTERVAL_MS, |r| {
+            if let Ok(manager) = super::manager() {
+                let storage = manager.storage();
+                storage.polling = false;
+                storage.poll();
+                storage.watch(&r);
+            }
+        });
+    }
+}
+
+/// Returns the directories of the streams kept in path along with their
+/// internal IDs, in order.
+pub fn stream_dirs(path: &Path) -> Result<Vec<(u64, PathBuf)>, StreamError> {
+    let dir = match path.read_dir() {
+        Ok(dir) => dir,
+        Err(_) => {
+            return Err(StreamError::ReadDir(
+                String::from(path.to_str().unwrap_or("<empty>"))
+            ))
+        }
+    };
+
+    let mut dirs = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        let id = match entry.file_name().to_str().and_then(|n| n.parse::<u64>().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        if entry.path().is_dir() {
+            dirs.push((id, entry.path()));
+        }
+    }
+    dirs.sort_by_key(|&(id, _)| id);
+    Ok(dirs)
+}
+
+/// Returns the sealed segment files in the directory of a stream along with
+/// their IDs, in order.
+pub fn segment_files(dir: &Path) -> Result<Vec<(StreamID, PathBuf)>, StreamError> {
+    files_with_ext(dir, format::SEGMENT_EXT)
+}
+
+/// Returns the files of segments that filled up and weren't sealed yet in the
+/// directory of a stream along with their IDs, in order.
+pub fn unsealed_files(dir: &Path) -> Result<Vec<(StreamID, PathBuf)>, StreamError> {
+    files_with_ext(dir, format::UNSEALED_EXT)
+}
+
+fn files_with_ext(
+    dir: &Path,
+    ext: &str,
+) -> Result<Vec<(StreamID, PathBuf)>, StreamError> {
+    let mut files = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.extension().and_then(|found| found.to_str()) != Some(ext) {
+            continue;
+        }
+        let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) if stem.contains('-') => stem.to_owned(),
+            _ => continue,
+        };
+        if let Some(id) = id::parse_id(&stem, 0) {
+            files.push((id, path));
+        }
     }
+    files.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    Ok(files)
 }
 
 impl Drop for StorageService {
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        // This only waits on the tasks ahead of it. The background thread
+        // never blocks handing them back since at most backlog are pending.
+        let _ = self.bg_sender.send((0, Task::Shutdown));
+        if let Some(handle) = self.bg_thread.take() {
+            let _ = handle.join();
+        }
+        while let Ok((_, task)) = self.ev_receiver.try_recv() {
+            task.discard();
+        }
+    }
 }
 
 pub struct StreamStorage {
@@ -338,6 +844,25 @@ pub struct ArchiveService {}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use super::super::fixture;
+    use crate::redis::listpack::Value;
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    /// Polls until done is set, giving up after a second.
+    fn poll_until(storage: &mut StorageService, done: &Rc<Cell<bool>>) {
+        for _ in 0..1000 {
+            storage.poll();
+            if done.get() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("task never finished");
+    }
+
     #[test]
     fn start_storage_service() {
         println!("starting StorageService...");
@@ -347,4 +872,105 @@ mod tests {
 
         println!("stopping StorageService...");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_runs_tasks_on_the_io_thread() {
+        let dir = TempDir::new("io").unwrap();
+        let mut storage = StorageService::start(dir.path()).unwrap();
+
+        // Create
+        let created = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        {
+            let created = Rc::clone(&created);
+            let done = Rc::clone(&done);
+            storage.create(dir.path().join("0.dat"), 4096, move |result| {
+                *created.borrow_mut() = Some(result.unwrap());
+                done.set(true);
+            }).unwrap();
+        }
+        assert_eq!(1, storage.pending());
+        poll_until(&mut storage, &done);
+        assert_eq!(0, storage.pending());
+        let mut aof = created.borrow_mut().take().unwrap();
+        assert_eq!(4096, aof.len());
+        assert_eq!(4096, fs::metadata(dir.path().join("0.dat")).unwrap().len());
+
+        // Sync
+        assert!(aof.write_at(0, b"abc"));
+        aof.set_offset(3);
+        let aof = Arc::new(Mutex::new(aof));
+        let done = Rc::new(Cell::new(false));
+        {
+            let done = Rc::clone(&done);
+            storage.sync(Arc::clone(&aof), move |result| {
+                result.unwrap();
+                done.set(true);
+            }).unwrap();
+        }
+        poll_until(&mut storage, &done);
+
+        // Read a pack out of a sealed segment.
+        let config = fixture::config(128, 512);
+        let stream_dir = dir.path().join("1");
+        fs::create_dir(&stream_dir).unwrap();
+        let mut writer = writer::StreamWriter::open(&stream_dir, &config).unwrap();
+        for seq in 0..20 {
+            let mut kv = fixture::record(&["f", "0123456789abcdef"]);
+            fixture::write(&mut writer, fixture::id(1, seq), &mut kv);
+        }
+        writer.seal_now().unwrap();
+        assert!(!writer.take_sealed().is_empty());
+        let segment = Segment::open(&stream_dir.join("1-0.dat")).unwrap();
+        let pack = segment.packs.get(&mut StreamID { ms: 1, seq: 0 }).unwrap();
+        assert!(pack.data.get().is_null());
+
+        let done = Rc::new(Cell::new(false));
+        let task_id = {
+            let done = Rc::clone(&done);
+            storage.read(&segment, Rc::clone(&pack), move |result| {
+                result.unwrap();
+                done.set(true);
+            }).unwrap()
+        };
+        // Another client waiting on the same pack.
+        let waited = Rc::new(Cell::new(false));
+        {
+            let waited = Rc::clone(&waited);
+            assert!(storage.wait(task_id, move |result| {
+                result.unwrap();
+                waited.set(true);
+            }));
+        }
+        poll_until(&mut storage, &done);
+        assert!(waited.get());
+        assert!(!storage.wait(task_id, |_| {}));
+
+        let count = listpack::first(pack.data.get()).unwrap();
+        assert!(listpack::get(count) == Value::Int(pack.count.get() as i64));
+    }
+
+    #[test]
+    fn it_turns_tasks_away_once_the_backlog_is_full() {
+        let dir = TempDir::new("io").unwrap();
+        let mut storage = StorageService::with_backlog(dir.path(), 1).unwrap();
+        let aof = aof::AOF::create(&dir.path().join("0.dat"), 4096).unwrap();
+        let aof = Arc::new(Mutex::new(aof));
+
+        // Hold up the I/O thread.
+        let locked = aof.lock();
+        let done = Rc::new(Cell::new(false));
+        {
+            let done = Rc::clone(&done);
+            storage.sync(Arc::clone(&aof), move |_| done.set(true)).unwrap();
+        }
+        match storage.sync(Arc::clone(&aof), |_| {}) {
+            Err(StreamError::IoBacklog) => {}
+            _ => panic!("expected the backlog to be full"),
+        }
+
+        drop(locked);
+        poll_until(&mut storage, &done);
+        assert!(storage.sync(Arc::clone(&aof), |_| {}).is_ok());
+    }
+}
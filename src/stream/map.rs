@@ -507,6 +507,16 @@ impl<V> StreamIDRax<V> {
 }
 
 
+/// Hands out another reference to a value that stays in a Rax. The Rax
+/// keeps the reference it had.
+#[inline]
+unsafe fn share<V>(value: *const V) -> Rc<V> {
+    let held = Rc::from_raw(value);
+    let shared = Rc::clone(&held);
+    mem::forget(held);
+    shared
+}
+
 pub struct RcRax<K: RaxKey, V> {
     pub rax: *mut rax,
     _phantom: marker::PhantomData<(K, V)>,
@@ -616,7 +626,7 @@ impl<K: RaxKey, V> RcRax<K, V> {
                     } else {
                         // The "old" value remains in the Rax.
                         // Increment ref count.
-                        Ok(Some(Rc::from_raw(old as *const _ as *const V).clone()))
+                        Ok(Some(share(*old as *const V)))
                     }
                 }
             } else if old.is_null() {
@@ -658,7 +668,7 @@ impl<K: RaxKey, V> RcRax<K, V> {
                     } else {
                         // The "old" value remains in the Rax.
                         // Increment ref count.
-                        Ok(Some(Rc::from_raw(old as *const _ as *const V).clone()))
+                        Ok(Some(share(*old as *const V)))
                     }
                 }
             } else if old.is_null() {
@@ -749,7 +759,7 @@ impl<K: RaxKey, V> RcRax<K, V> {
                 // While the key associated to the value is in the RAX then we cannot
                 // drop it.
                 // Transmute into Rc and increment ref count.
-                (true, Some(Rc::from_raw(value as *const _ as *const V).clone()))
+                (true, Some(share(value as *const V)))
             }
         }
     }
@@ -772,7 +782,7 @@ impl<K: RaxKey, V> RcRax<K, V> {
                 // While the key associated to the value is in the RAX then we cannot
                 // drop it.
 //                Some(std::mem::transmute(value))
-                Some(Rc::from_raw(value as *const _ as *const V).clone())
+                Some(share(value as *const V))
             }
         }
     }
@@ -794,7 +804,7 @@ impl<K: RaxKey, V> RcRax<K, V> {
                 // transmute to the value so we don't drop the actual value accidentally.
                 // While the key associated to the value is in the RAX then we cannot
                 // drop it.
-                Some(Rc::from_raw(value as *const _ as *const V).clone())
+                Some(share(value as *const V))
             }
         }
     }
@@ -818,48 +828,47 @@ impl<K: RaxKey, V> RcRax<K, V> {
         }
     }
 
-    ///
+    /// Returns the smallest key.
     #[inline]
-    pub fn first_key(
-        &self
-    ) -> Option<K> {
-        unsafe {
-            // Allocate stack memory.
-            let mut iter: RaxIterator<K, Rc<V>> = mem::uninitialized();
-            // Initialize a Rax iterator. This call should be performed a single time
-            // to initialize the iterator, and must be followed by a raxSeek() call,
-            // otherwise the raxPrev()/raxNext() functions will just return EOF.
-            raxStart(&iter as *const _ as *const raxIterator, self.rax);
-            if raxSeek(
-                &iter as *const _ as *const raxIterator,
-                BEGIN.as_ptr(),
-                ptr::null_mut(),
-                0,
-            ) != 0 || iter.key_len == 0 {
-                None
-            } else {
-                iter.try_key()
-            }
-        }
+    pub fn first_key(&self) -> Option<K> {
+        self.seek_entry(BEGIN, &K::default()).map(|(key, _)| key)
     }
 
-    ///
+    /// Returns the largest key.
     #[inline]
-    pub fn last_key(
-        &self
-    ) -> Option<K> {
+    pub fn last_key(&self) -> Option<K> {
+        self.seek_entry(END, &K::default()).map(|(key, _)| key)
+    }
+
+    /// Returns the first key that satisfies op against key along with its
+    /// value, if it has one. op is any of the raxSeek() operators. "^" and
+    /// "$" are the smallest and largest key, in which case key is ignored.
+    pub fn seek_entry(&self, op: &str, key: &K) -> Option<(K, Option<Rc<V>>)> {
+        // raxSeek() looks past the first character of op.
+        let op = format!("{}\0", op);
+        let mut key = key.clone();
         unsafe {
             // Allocate stack memory.
             let mut iter: RaxIterator<K, Rc<V>> = mem::uninitialized();
-            // Initialize a Rax iterator. This call should be performed a single time
-            // to initialize the iterator, and must be followed by a raxSeek() call,
-            // otherwise the raxPrev()/raxNext() functions will just return EOF.
-            raxStart(&iter as *const _ as *const raxIterator, self.rax);
-            if raxSeek(&iter as *const _ as *const raxIterator, END.as_ptr(), ptr::null_mut(), 0) != 0 || iter.key_len == 0 {
-                None
+            let it = &mut iter as *mut _ as *const raxIterator;
+            raxStart(it, self.rax);
+
+            // The element that was seeked to is the one the first step
+            // lands on, whichever way it goes.
+            let (key_ptr, key_len) = key.into_rax();
+            let found = if raxSeek(it, op.as_ptr(), key_ptr, key_len) == 1
+                && raxNext(it) == 1 {
+                let value = if iter.data.is_null() {
+                    None
+                } else {
+                    Some(share(iter.data as *const V))
+                };
+                Some((iter.key(), value))
             } else {
-                iter.try_key()
-            }
+                None
+            };
+            raxStop(it);
+            found
         }
     }
 
@@ -1128,4 +1137,56 @@ impl<K: RaxKey, V> RaxIterator<K, V> {
     pub fn eof(&self) -> bool {
         self.flags & RAX_ITER_EOF != 0
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::*;
+
+    fn id(ms: u64) -> StreamID {
+        StreamID { ms, seq: 0 }
+    }
+
+    #[test]
+    fn it_seeks_entries() {
+        let mut map: RcRax<StreamID, u64> = RcRax::new();
+        for ms in &[10, 20, 30] {
+            map.insert(&mut id(*ms), Rc::new(*ms)).unwrap();
+        }
+
+        let seek = |op, ms| map.seek_entry(op, &id(ms)).map(|(key, _)| key.ms);
+        assert_eq!(Some(20), seek(">=", 20));
+        assert_eq!(Some(30), seek(">", 20));
+        assert_eq!(Some(20), seek("<=", 25));
+        assert_eq!(Some(10), seek("<", 20));
+        assert_eq!(None, seek("<", 10));
+        assert_eq!(None, seek(">", 30));
+        assert_eq!(Some(10), map.first_key().map(|key| key.ms));
+        assert_eq!(Some(30), map.last_key().map(|key| key.ms));
+
+        let (_, value) = map.seek_entry("=", &id(20)).unwrap();
+        assert_eq!(Some(20), value.map(|value| *value));
+    }
+
+    #[test]
+    fn it_keeps_its_reference_to_values_it_hands_out() {
+        let mut map: RcRax<StreamID, u64> = RcRax::new();
+        let value = Rc::new(1);
+        map.insert(&mut id(1), Rc::clone(&value)).unwrap();
+        assert_eq!(2, Rc::strong_count(&value));
+
+        drop(map.get(&mut id(1)));
+        drop(map.find(&mut id(1)));
+        drop(map.find_exists(&mut id(1)));
+        drop(map.seek_entry("^", &id(0)));
+        assert_eq!(2, Rc::strong_count(&value));
+
+        let found = map.get(&mut id(1)).unwrap();
+        assert_eq!(3, Rc::strong_count(&found));
+        drop(found);
+
+        let (_, removed) = map.remove(&mut id(1));
+        drop(removed);
+        assert_eq!(1, Rc::strong_count(&value));
+    }
+}
@@ -1,18 +1,23 @@
 use crate::alloc::{alloc, dealloc, free, realloc, ref_counted};
+use crate::clock;
 use crate::redis::listpack;
 use crate::redis::listpack::Listpack;
+use crate::redis::{LogLevel, Redis, Reply};
 use crate::redis::rax::{RaxMap, RaxRcMap};
 use crate::redis::sds::SDS;
 use self::id::{next_id, StreamID};
 use spin::Mutex;
 use std::error::Error;
 use std::fmt;
-use std::path::Path;
+use std::fs;
+use std::io as std_io;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::rc::{Rc, Weak};
 use std::sync::{Arc, Weak as ArcWeak};
 use std::sync::atomic;
 use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use super::*;
 
 
@@ -20,61 +25,206 @@ pub mod id;
 pub mod map;
 pub mod raw;
 pub mod aof;
+pub mod format;
 pub mod record;
 pub mod writer;
 pub mod io;
+pub mod range;
+pub mod group;
+pub mod reclaim;
+pub mod tail;
+pub mod park;
 pub mod data_type;
+pub mod cmd;
+pub mod dedupe;
 
 pub const DEFAULT_PACK_SIZE: u32 = 65500;
 // ~64KB
 pub const DEFAULT_SEGMENT_SIZE: u32 = 1024 * 1024 * 64 - 64; // ~64MB
 
 pub const COMPRESS_NONE: i32 = 0;
+/// Known, but not supported. See `unsupported_compression`.
 pub const COMPRESS_LZ4: i32 = 1;
 pub const COMPRESS_ZSTD: i32 = 2;
 
+/// zstd level packs are compressed at unless a stream says otherwise.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// The dedupe window of a stream is a number of milliseconds.
+pub const DEDUPE_MS: i32 = 0;
+/// The dedupe window of a stream is a number of records.
+pub const DEDUPE_RECORDS: i32 = 1;
+
+/// Name of the file in the directory of a stream that holds its settings.
+pub const CONFIG_FILE: &'static str = "config";
+
 pub static mut DEFAULT_MAX_IO_BACKLOG: usize = 25000;
 
 pub fn max_io_backlog() -> usize {
     unsafe { DEFAULT_MAX_IO_BACKLOG }
 }
 
+#[derive(Clone, Copy)]
 pub struct StreamConfig {
     pub max_pack_size: u32,
     pub max_segment_size: u32,
     pub compression: i32,
+    pub compression_level: i32,
+    /// How far back appends are checked for a dedupe key that was already
+    /// written. Zero turns deduplication off.
+    pub dedupe_window: u64,
+    pub dedupe_unit: i32,
 }
 
 pub const DEFAULT_CONFIG: &'static StreamConfig = &StreamConfig {
     max_pack_size: DEFAULT_PACK_SIZE, // 64KB
     max_segment_size: DEFAULT_SEGMENT_SIZE, // 1GB
     compression: COMPRESS_NONE,
+    compression_level: DEFAULT_COMPRESSION_LEVEL,
+    dedupe_window: 0,
+    dedupe_unit: DEDUPE_MS,
 };
 
-#[derive(Debug)]
+impl StreamConfig {
+    /// Reads the settings kept in the directory of a stream. Settings that
+    /// aren't there keep their default.
+    pub fn load(dir: &Path) -> Result<StreamConfig, StreamError> {
+        let mut config = *DEFAULT_CONFIG;
+        let text = match fs::read_to_string(dir.join(CONFIG_FILE)) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == std_io::ErrorKind::NotFound => return Ok(config),
+            Err(e) => return Err(StreamError::from(e)),
+        };
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("compression"), Some(name)) => {
+                    config.compression = match parse_compression(name) {
+                        Some(COMPRESS_LZ4) => {
+                            return Err(unsupported_compression(COMPRESS_LZ4));
+                        }
+                        compression => compression.unwrap_or(COMPRESS_NONE),
+                    };
+                }
+                (Some("compression-level"), Some(level)) => {
+                    if let Ok(level) = level.parse() {
+                        config.compression_level = level;
+                    }
+                }
+                (Some("dedupe-window"), Some(window)) => {
+                    if let Ok(window) = window.parse() {
+                        config.dedupe_window = window;
+                    }
+                }
+                (Some("dedupe-unit"), Some(unit)) => {
+                    config.dedupe_unit = parse_dedupe_unit(unit).unwrap_or(DEDUPE_MS);
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// Keeps the settings of a stream in its directory.
+    pub fn save(&self, dir: &Path) -> Result<(), StreamError> {
+        let text = format!(
+            "compression {}\ncompression-level {}\ndedupe-window {}\ndedupe-unit {}\n",
+            compression_name(self.compression),
+            self.compression_level,
+            self.dedupe_window,
+            dedupe_unit_name(self.dedupe_unit),
+        );
+        fs::write(dir.join(CONFIG_FILE), text)?;
+        Ok(())
+    }
+}
+
+/// Returns the name of a compression mode.
+pub fn compression_name(compression: i32) -> &'static str {
+    match compression {
+        COMPRESS_LZ4 => "lz4",
+        COMPRESS_ZSTD => "zstd",
+        _ => "none",
+    }
+}
+
+/// Parses the name of a compression mode in any case.
+pub fn parse_compression(name: &str) -> Option<i32> {
+    match name.to_lowercase().as_str() {
+        "none" => Some(COMPRESS_NONE),
+        "lz4" => Some(COMPRESS_LZ4),
+        "zstd" => Some(COMPRESS_ZSTD),
+        _ => None,
+    }
+}
+
+/// The error for a compression mode that's known but can't be used. Packs
+/// are only ever compressed with zstd, so a stream set up for LZ4 fails
+/// clearly rather than being taken for one that isn't compressed.
+pub fn unsupported_compression(compression: i32) -> StreamError {
+    StreamError::Generic(format!(
+        "{} compression isn't supported",
+        compression_name(compression)
+    ))
+}
+
+/// Returns the name of the unit of a dedupe window.
+pub fn dedupe_unit_name(unit: i32) -> &'static str {
+    match unit {
+        DEDUPE_RECORDS => "records",
+        _ => "ms",
+    }
+}
+
+/// Parses the name of the unit of a dedupe window in any case.
+pub fn parse_dedupe_unit(name: &str) -> Option<i32> {
+    match name.to_lowercase().as_str() {
+        "ms" => Some(DEDUPE_MS),
+        "records" => Some(DEDUPE_RECORDS),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum StreamError {
     OutOfMemory,
     Exists,
     NotExists,
     WouldBlock,
+    /// Too many tasks are waiting on the I/O thread to take another one.
+    IoBacklog,
     BadInput,
     Overflow,
+    /// A record with the same dedupe key was already written within the
+    /// dedupe window, with this ID.
+    Duplicate(StreamID),
     CreateDir(String),
     NotDir(String),
     ReadDir(String),
+    /// A pack in a segment file doesn't match its checksum.
+    Corrupt { segment: String, offset: u32 },
     Generic(String),
 }
 
 impl fmt::Display for StreamError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", "oops")
-//        match *self {
-//            // Both underlying errors already impl `Display`, so we defer to
-//            // their implementations.
-//            SlicedError::Generic(ref err) => write!(f, "{}", err),
-//            SlicedError::FromUtf8(ref err) => write!(f, "{}", err),
-//            SlicedError::ParseInt(ref err) => write!(f, "{}", err),
-//        }
+        match *self {
+            StreamError::CreateDir(ref d) => {
+                write!(f, "could not create directory {}", d)
+            }
+            StreamError::NotDir(ref d) => write!(f, "{} is not a directory", d),
+            StreamError::ReadDir(ref d) => write!(f, "could not read directory {}", d),
+            StreamError::Corrupt { ref segment, offset } => {
+                write!(f, "segment {} is corrupt at offset {}", segment, offset)
+            }
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl From<std_io::Error> for StreamError {
+    fn from(err: std_io::Error) -> StreamError {
+        StreamError::Generic(err.to_string())
     }
 }
 
@@ -85,13 +235,15 @@ impl Error for StreamError {
             StreamError::Exists => "exists",
             StreamError::NotExists => "not exists",
             StreamError::WouldBlock => "would block",
+            StreamError::IoBacklog => "too many pending I/O requests, try again later",
             StreamError::BadInput => "bad input",
             StreamError::Overflow => "overflow",
+            StreamError::Duplicate(_) => "duplicate",
             StreamError::CreateDir(ref d) => "create directory",
             StreamError::NotDir(ref d) => "not directory",
             StreamError::ReadDir(ref d) => "read directory failed",
-            StreamError::Generic(ref m) => "",
-            _ => "Error"
+            StreamError::Corrupt { .. } => "corrupt segment",
+            StreamError::Generic(ref m) => m,
         }
     }
 }
@@ -175,9 +327,13 @@ pub struct Stream {
 
     /// Configuration settings.
     config: StreamConfig,
+    /// What the sealed segments take up.
+    archive: writer::StreamArchiveStats,
 
-    /// Consumer groups.
-    groups: Option<RaxMap<u64, ConsumerGroup>>,
+    /// Consumer groups by name.
+    groups: HashMap<String, ConsumerGroup>,
+    /// Whether the consumer groups changed since they were last saved.
+    groups_dirty: bool,
 }
 
 impl Drop for Stream {
@@ -197,10 +353,80 @@ impl Stream {
     #[inline]
     pub fn last_id(&mut self) -> Option<StreamID> {
         match self.writer {
-            Some(ref mut writer) => Some(writer.next_id()),
+            Some(ref writer) => Some(writer.last_id()),
             _ => self.segments.last_key()
         }
     }
+
+    /// Appends a record to the tail of the stream and returns its ID.
+    /// Segments that fill up along the way are added to the segment index.
+    pub fn add(
+        &mut self,
+        id: Option<StreamID>,
+        kv: &mut [listpack::MemoizedValue],
+    ) -> Result<StreamID, StreamError> {
+        let id = match self.writer {
+            Some(ref mut writer) => writer.try_write(id, kv)?,
+            None => return Err(StreamError::NotExists),
+        };
+        self.take_segments()?;
+        Ok(id)
+    }
+
+    /// Hands what the writes left to do off to the I/O thread. See
+    /// `StreamWriter::schedule`.
+    pub fn schedule(
+        &mut self,
+        storage: &mut io::StorageService,
+    ) -> Result<(), StreamError> {
+        if let Some(ref mut writer) = self.writer {
+            writer.schedule(storage);
+        }
+        self.take_segments()
+    }
+
+    /// Adds the segments that filled up to the segment index. They're read
+    /// from what was written to them until they're sealed, at which point the
+    /// sealed segment takes their place.
+    fn take_segments(&mut self) -> Result<(), StreamError> {
+        let (rolled, sealed) = match self.writer {
+            Some(ref mut writer) => (writer.take_rolled(), writer.take_sealed()),
+            None => return Ok(()),
+        };
+        for (mut segment_id, segment) in rolled {
+            self.segments.insert(&mut segment_id, Rc::new(segment))?;
+        }
+        for (mut segment_id, segment) in sealed {
+            self.archive.add(&segment);
+            self.segments.insert(&mut segment_id, Rc::new(segment))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    /// Changes the settings of the stream and keeps them in its directory.
+    /// Compression applies to the segments that are sealed from then on.
+    pub fn configure(&mut self, config: StreamConfig) -> Result<(), StreamError> {
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(StreamError::NotExists),
+        };
+        config.save(writer.dir())?;
+        writer.configure(&config);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Returns how much the sealed segments take up on disk and how much
+    /// they would without compression.
+    #[inline]
+    pub fn archive_stats(&self) -> writer::StreamArchiveStats {
+        self.archive
+    }
 }
 
 /// Segments contain a sequence of Packs.
@@ -212,9 +438,17 @@ impl Stream {
 /// but only load on-demand. This is ideal for Streams since most consumers
 /// will be towards the tail.
 pub struct Segment {
+    /// Path of the segment file.
+    path: PathBuf,
+    /// Number of bytes of the packs once they're decompressed.
+    size: u64,
+    /// Number of bytes the packs take up in the file.
+    size_compressed: u64,
     /// A view of the segment data.
-    /// The file handle is independent of the Pack index.
-    data: Option<crate::mmap::MmapMut>,
+    /// The file handle is independent of the Pack index. The tail segment
+    /// is written through the AOF of the StreamWriter instead and switches
+    /// to an immutable view once it's sealed.
+    handle: writer::SegmentHandle,
 
     /// The pack index must be all inclusive of the entire segment.
     /// However, each packs data may be faulted in and freed based
@@ -223,47 +457,149 @@ pub struct Segment {
 }
 
 impl Segment {
-    pub fn would_block(&mut self, pack: &mut Pack) -> bool {
+    pub fn new() -> Segment {
+        Segment {
+            path: PathBuf::new(),
+            size: 0,
+            size_compressed: 0,
+            handle: writer::SegmentHandle::Local,
+            packs: map::RcRax::new(),
+        }
+    }
+
+    /// Opens a sealed segment using only the index at the end of its file.
+    /// Packs are loaded once they're needed.
+    pub fn open(path: &Path) -> Result<Segment, StreamError> {
+        let file = fs::File::open(path)?;
+        let mmap = unsafe { crate::mmap::MmapOptions::new().map(&file)? };
+        let index = format::read_index(&mmap)?;
+        Segment::from_index(path, &index, mmap)
+    }
+
+    /// Builds a sealed segment out of its index and a view of its file.
+    pub fn from_index(
+        path: &Path,
+        index: &[format::IndexEntry],
+        mmap: crate::mmap::Mmap,
+    ) -> Result<Segment, StreamError> {
+        let mut segment = Segment::new();
+        segment.path = path.to_path_buf();
+        for entry in index {
+            let pack = Pack::new();
+            pack.offset.set(entry.offset);
+            pack.length.set(entry.length);
+            pack.raw_length.set(entry.raw_length);
+            pack.count.set(entry.count);
+            pack.crc.set(entry.crc);
+            segment.size += entry.raw_length as u64;
+            segment.size_compressed += entry.length as u64;
+
+            let mut id = entry.id;
+            segment.packs.insert(&mut id, Rc::new(pack))?;
+        }
+        segment.handle = writer::SegmentHandle::Immutable(Arc::new(Mutex::new(mmap)));
+        Ok(segment)
+    }
+
+    /// Checks every pack of a sealed segment against its index without
+    /// keeping anything around. Returns the ID of the last record. A segment
+    /// a crash left without its index is checked pack by pack instead, the
+    /// way recovery would before sealing it.
+    pub fn check(path: &Path) -> Result<StreamID, StreamError> {
+        let file = fs::File::open(path)?;
+        let mmap = unsafe { crate::mmap::MmapOptions::new().map(&file)? };
+        if !mmap.ends_with(format::INDEX_MAGIC) {
+            let scan = format::scan(&mmap);
+            return match scan.packs.last() {
+                Some(last) => Ok(last.last_id),
+                None => Err(corrupt_segment(path, 0)),
+            };
+        }
+
+        let mut last_id = None;
+        for entry in format::read_index(&mmap)? {
+            let raw = format::unpack(&mmap, entry.offset, entry.length, entry.raw_length);
+            let checked = raw.and_then(|raw| format::check_pack(&raw, 0));
+            match checked {
+                Some(ref pack) if pack.entry.id == entry.id
+                    && pack.entry.length == entry.raw_length
+                    && pack.entry.count == entry.count
+                    && pack.entry.crc == entry.crc => last_id = Some(pack.last_id),
+                _ => return Err(corrupt_segment(path, entry.offset)),
+            }
+        }
+        last_id.ok_or_else(|| corrupt_segment(path, 0))
+    }
+
+    /// Returns the ID of the last record within a sealed segment.
+    pub fn last_id(&self) -> Option<StreamID> {
+        let mmap = match self.handle {
+            writer::SegmentHandle::Immutable(ref mmap) => mmap,
+            _ => return None,
+        };
+        let pack = self.packs.get(&mut self.packs.last_key()?)?;
+        let mmap = mmap.lock();
+        let raw = format::unpack(
+            &mmap,
+            pack.offset.get(),
+            pack.length.get(),
+            pack.raw_length.get(),
+        )?;
+        Some(format::check_pack(&raw, 0)?.last_id)
+    }
+
+    pub fn would_block(&self, pack: &Pack) -> bool {
         // Is the pack already loaded?
-        if !pack.data.is_null() {
+        if !pack.data.get().is_null() {
             return false;
         }
 
-        match self.data {
-            Some(ref mmap) => {
-                if mmap.is_resident(pack.offset as usize, pack.length as usize) {
-//                    unsafe {
-//                        match pack.load_segment_data(mmap.as_mut_ptr().offset(pack.offset as isize)) {}
-//                    }
-                    // mincore() optimization
-                    // If the OS pages required to load the Pack are resident in-memory,
-                    // then do load operation immediately since it won't block.
-
-                    // Allocate listpack with room for the header.
-                    let lp = crate::alloc::alloc(pack.length as usize + 6);
-                    unsafe {
-                        ptr::copy_nonoverlapping(
-                            mmap.as_ptr().offset(pack.offset as isize),
-                            // Copy to right past header.
-                            lp.offset(6),
-                            pack.length as usize,
-                        );
+        match self.handle {
+            writer::SegmentHandle::Immutable(ref mmap) => {
+                // The I/O thread may have it.
+                let mmap = match mmap.try_lock() {
+                    Some(mmap) => mmap,
+                    None => return true,
+                };
+                let offset = pack.offset.get();
+                let length = pack.length.get();
+                let (start, end) = (offset as usize, offset as usize + length as usize);
+                if end > mmap.len() || !mmap.is_resident(start, length as usize) {
+                    return true;
+                }
+
+                // mincore() optimization
+                // If the OS pages required to load the Pack are resident in-memory,
+                // then do load operation immediately since it won't block. A pack
+                // that doesn't check out is left for the I/O thread to report.
+                let read = format::read_pack(
+                    &self.path,
+                    &mmap,
+                    offset,
+                    length,
+                    pack.raw_length.get(),
+                    pack.crc.get(),
+                );
+                match read {
+                    Ok(lp) => {
+                        pack.data.set(lp);
+                        false
                     }
-                    // Set raw header.
-                    crate::redis::listpack::set_total_bytes(lp, pack.length as u32 + 6u32);
-                    crate::redis::listpack::set_num_elements(lp, pack.count);
-
-//                    pack.data = Some(Listpack::from_raw(lp));
-                    false
-                } else {
-                    true
+                    Err(_) => true,
                 }
             }
-            None => true
+            _ => true
         }
     }
 }
 
+fn corrupt_segment(path: &Path, offset: u32) -> StreamError {
+    StreamError::Corrupt {
+        segment: path.to_string_lossy().into_owned(),
+        offset,
+    }
+}
+
 impl Drop for Segment {
     fn drop(&mut self) {
         println!("dropped Segment");
@@ -274,32 +610,37 @@ impl Drop for Segment {
 /// Packs can be pinned in memory to guarantee faults will not occur. This
 /// is particulary important for Consumer Groups since it does not copy the
 /// data for it's NACK struct in it's pending entries list (pel).
-#[repr(packed)]
+///
+/// The tail pack keeps changing while it's shared, hence the cells.
 pub struct Pack {
     /// Keep a reference to it's parent segment to ensure the segment structure
     /// remains in memory for the lifetime of the pack.
     segment: Option<Rc<Segment>>,
     /// Offset within segment file.
-    offset: u32,
-    /// Number of total bytes of the listpack including it's header.
-    /// This will be redundant information once a listpack is loaded since
-    /// the standard Redis Streams listpack format has a 6 byte header (bytes, count).
-    length: u32,
+    offset: Cell<u32>,
+    /// Number of bytes the pack takes up in the segment file including its
+    /// trailer.
+    length: Cell<u32>,
+    /// Number of bytes of the pack once it's decompressed. The same as
+    /// length unless the pack was compressed.
+    raw_length: Cell<u32>,
     /// Number of records inside listpack.
     /// This will be redundant information once a listpack is loaded since
     /// the standard Redis Streams listpack format has a 6 byte header (bytes, count).
-    count: u16,
+    count: Cell<u16>,
+    /// Checksum of the pack on disk, from the segment index.
+    crc: Cell<u32>,
     /// The actual content in Redis Streams listpack format.
     /// These represent a Rax node.
-    data: listpack::listpack,
+    data: Cell<listpack::listpack>,
 }
 
 impl Drop for Pack {
     fn drop(&mut self) {
         // Force dealloc on the Listpack once Pack is only weakly referenced.
-        if !self.data.is_null() {
-            dealloc(self.data);
-            self.data = ptr::null_mut();
+        if !self.data.get().is_null() {
+            dealloc(self.data.get());
+            self.data.set(ptr::null_mut());
         }
 
         // Decrement segment ref count.
@@ -314,10 +655,22 @@ impl Pack {
     pub fn new() -> Pack {
         Pack {
             segment: None,
-            offset: 0,
-            length: 0,
-            count: 0,
-            data: ptr::null_mut(),
+            offset: Cell::new(0),
+            length: Cell::new(0),
+            raw_length: Cell::new(0),
+            count: Cell::new(0),
+            crc: Cell::new(0),
+            data: Cell::new(ptr::null_mut()),
+        }
+    }
+
+    /// Frees the listpack of a pack that's safely on disk, unless anyone
+    /// besides the segment index still holds onto it. It's faulted back in
+    /// when needed.
+    pub fn release(pack: Rc<Pack>) {
+        if Rc::strong_count(&pack) <= 2 && !pack.data.get().is_null() {
+            dealloc(pack.data.get());
+            pack.data.set(ptr::null_mut());
         }
     }
 
@@ -357,30 +710,224 @@ impl Pack {
 
 pub type Pin = Rc<Pack>;
 
-struct ConsumerGroup {
-    /// Deduplication check.
-    /// If key exists, but NACK is null then request is rejected.
-    dupe: Option<RaxMap<u64, NAck>>,
+/// A consumer group of a stream. Each record is delivered to one of its
+/// consumers, after which it's pending until it's acked.
+pub struct ConsumerGroup {
+    /// ID of the last record that was delivered to any of the consumers.
+    last_id: StreamID,
+    /// Every record that was delivered and hasn't been acked yet.
     pending: map::RcRax<StreamID, NAck>,
-
-    /// Reserve the
-    pins: Vec<Rc<Pack>>,
+    /// The consumers by name. A consumer comes about the first time it reads.
+    consumers: HashMap<String, Consumer>,
+    /// The packs that pending records are in by master ID, along with how
+    /// many of their records are pending. Pinning them keeps the records in
+    /// memory so that reading pending records never has to fault.
+    pins: BTreeMap<StreamID, (Pin, usize)>,
+    /// How records that stay pending for too long are dealt with.
+    reclaim: group::Reclaim,
+    /// Records that were pending for longer than the idle timeout. They're
+    /// redelivered to the next consumer besides their own that reads new
+    /// records.
+    idle: BTreeSet<StreamID>,
+    /// ID the scan for idle records picks up after.
+    scan_after: StreamID,
+    /// ID the next redelivery of idle records picks up after.
+    redeliver_after: StreamID,
 }
 
 /// Pending (not yet acknowledged) message in a consumer group.
-struct NAck {
-    delivery_time: u64,
-    delivery_count: u64,
-    consumer: Rc<Consumer>,
-    dupe: Option<SDS>,
+pub struct NAck {
+    /// When it was last delivered, in milliseconds since the epoch.
+    delivery_time: Cell<u64>,
+    /// Number of times it was delivered.
+    delivery_count: Cell<u64>,
+    /// Name of the consumer it was last delivered to.
+    consumer: RefCell<String>,
+    /// Master ID of the pack it's in.
+    pack: StreamID,
 }
 
-struct Consumer {
+/// A consumer within a consumer group.
+pub struct Consumer {
+    /// The records that are pending for it. They share their NAck with the
+    /// pending records of the group.
     pending: map::RcRax<StreamID, NAck>,
 }
 
+/// Directory streams are kept in, within the `dir` Redis is configured with.
+pub const DEFAULT_DIR: &'static str = "sliced";
+
+/// Extension a stream's directory is given when it can't be recovered, which
+/// keeps it from being picked up again.
+pub const QUARANTINE_EXT: &'static str = "quarantine";
+
+/// File within a stream's directory that holds the name of the stream.
+pub const NAME_FILE: &'static str = "name";
+
+/// How often the consumer groups that changed are saved, in milliseconds.
+const GROUPS_SAVE_INTERVAL_MS: i64 = 1000;
+
 static mut MANAGER: Option<StreamManager> = None;
 
+/// Where the stream manager keeps streams, once the module worked it out.
+static mut DIR: Option<PathBuf> = None;
+
+/// What recovery found for a stream.
+pub struct StreamRecovery {
+    pub name: String,
+    pub dir: PathBuf,
+    /// Number of sealed segments that are part of the stream.
+    pub segments: u32,
+    /// Sealed segments that didn't check out. They're left where they are
+    /// but aren't part of the stream.
+    pub bad_segments: Vec<PathBuf>,
+    /// What was found in the tail segment.
+    pub tail: writer::RecoveryReport,
+    /// Why the stream couldn't be picked up, if it couldn't. It was moved
+    /// aside then, to dir.
+    pub error: Option<String>,
+}
+
+impl StreamRecovery {
+    fn new(name: &str, dir: &Path) -> StreamRecovery {
+        StreamRecovery {
+            name: name.to_owned(),
+            dir: dir.to_path_buf(),
+            segments: 0,
+            bad_segments: Vec::new(),
+            tail: writer::RecoveryReport::default(),
+            error: None,
+        }
+    }
+}
+
+/// Returns the stream manager, starting it the first time it's needed.
+pub fn manager() -> Result<&'static mut StreamManager, StreamError> {
+    unsafe {
+        if MANAGER.is_none() {
+            let dir = match DIR {
+                Some(ref dir) => dir.clone(),
+                None => PathBuf::from(DEFAULT_DIR),
+            };
+            let manager = StreamManager::new(SDS::new("local"), &dir)?;
+            MANAGER = Some(manager);
+        }
+        Ok(MANAGER.as_mut().unwrap())
+    }
+}
+
+/// Starts the stream manager as the module loads, so that streams left behind
+/// by a previous run are recovered before any command comes in. Whatever
+/// recovery had to leave out is logged.
+pub fn start(r: &Redis) -> Result<(), StreamError> {
+    let config = r.call("CONFIG", &["GET", "dir"]).unwrap_or(Reply::Nil);
+    unsafe {
+        DIR = Some(stream_dir(&config));
+    }
+
+    for report in manager()?.recovered() {
+        if let Some(ref err) = report.error {
+            r.log(
+                LogLevel::Warning,
+                &format!(
+                    "Couldn't recover stream {}, moved it aside to {}: {}",
+                    report.name,
+                    report.dir.to_string_lossy(),
+                    err,
+                ),
+            );
+            continue;
+        }
+
+        let level = if report.tail.is_clean() && report.bad_segments.is_empty() {
+            LogLevel::Notice
+        } else {
+            LogLevel::Warning
+        };
+        r.log(
+            level,
+            &format!(
+                "Recovered stream {}: {} sealed segments, {} packs with {} records \
+                 in its tail segment and {} bytes discarded after them",
+                report.name,
+                report.segments,
+                report.tail.packs,
+                report.tail.records,
+                report.tail.discarded,
+            ),
+        );
+        for path in &report.bad_segments {
+            r.log(
+                LogLevel::Warning,
+                &format!(
+                    "Left segment {} out of stream {} as it didn't check out",
+                    path.to_string_lossy(),
+                    report.name,
+                ),
+            );
+        }
+    }
+
+    // Groups that were picked back up go on reclaiming records.
+    for stream in manager()?.all_streams() {
+        let stream = unsafe { &*stream.get() };
+        let key = stream.name.to_string();
+        for (group, reclaim) in stream.reclaims() {
+            if reclaim.idle_timeout > 0 {
+                reclaim::watch(r, &key, &group);
+            }
+        }
+    }
+    r.start_timer(GROUPS_SAVE_INTERVAL_MS, |r| save_groups(&r));
+    Ok(())
+}
+
+/// Saves the consumer groups of every stream whose groups changed since the
+/// last time, on the I/O thread. Groups that fail to be saved are tried again
+/// the next time around.
+fn save_groups(r: &Redis) {
+    let manager = match manager() {
+        Ok(manager) => manager,
+        Err(_) => return,
+    };
+    for stream in manager.all_streams() {
+        let s = unsafe { &mut *stream.get() };
+        let dir = match s.writer {
+            Some(ref writer) => writer.dir().to_path_buf(),
+            None => continue,
+        };
+        let data = match s.groups_to_save() {
+            Some(data) => data,
+            None => continue,
+        };
+        let saved = manager.storage().save_groups(dir, data, move |result| {
+            if result.is_err() {
+                unsafe { (*stream.get()).groups_changed() };
+            }
+        });
+        if saved.is_err() {
+            s.groups_changed();
+        }
+    }
+    manager.storage().watch(r);
+    r.start_timer(GROUPS_SAVE_INTERVAL_MS, |r| save_groups(&r));
+}
+
+/// Returns the directory streams are kept in given the reply to `CONFIG GET
+/// dir`. Redis changes into that directory as it starts, but modules can be
+/// loaded before it does.
+fn stream_dir(config: &Reply) -> PathBuf {
+    match *config {
+        Reply::Array(ref pair) => match pair.get(1) {
+            Some(&Reply::String(ref dir)) => {
+                Path::new(&*String::from_utf8_lossy(dir)).join(DEFAULT_DIR)
+            }
+            _ => PathBuf::from(DEFAULT_DIR),
+        },
+        _ => PathBuf::from(DEFAULT_DIR),
+    }
+}
+
 /// In charge of creating, reading, writing and archiving segment data.
 /// Segments have an in-memory and blob representations. Blob is used for
 /// both on-disk and in an object store like S3.
@@ -397,67 +944,249 @@ pub struct StreamManager {
     /// usage of all Streams and all of it's in-memory representations.
     max_memory: u64,
     mem_usage: u64,
-    dir: &'static Path,
+    dir: PathBuf,
     bucket: SDS,
     streams: map::RcRax<SDS, UnsafeCell<Stream>>,
     storage: io::StorageService,
+    /// What was recovered when the manager started.
+    recovered: Vec<StreamRecovery>,
 }
 
 impl StreamManager {
-    pub fn new(bucket: SDS, path: &'static Path) -> Result<StreamManager, StreamError> {
-        let storage = io::StorageService::start(path.clone())?;
-        Ok(StreamManager {
+    pub fn new(bucket: SDS, path: &Path) -> Result<StreamManager, StreamError> {
+        let storage = io::StorageService::start(path)?;
+        let mut manager = StreamManager {
             next_stream_id: 1,
             max_memory: 0,
             mem_usage: 0,
-            dir: path.clone(),
+            dir: path.to_path_buf(),
             bucket,
             streams: map::RcRax::new(),
             storage,
-        })
+            recovered: Vec::new(),
+        };
+        manager.recovered = manager.recover(false)?;
+        Ok(manager)
     }
 
-    pub fn create_stream(&mut self, name: SDS) -> Result<Rc<UnsafeCell<Stream>>, StreamError> {
-        unsafe {
-            let mut streams = &mut self.streams;
-
-            let stream = Rc::new(UnsafeCell::new(Stream {
-                id: self.next_stream_id,
-                mem_usage: 0,
-                disk_usage: 0,
-                name: name.clone(),
-                writer: None,
-                segments: map::RcRax::new(),
-                config: StreamConfig {
-                    max_pack_size: DEFAULT_PACK_SIZE,
-                    max_segment_size: DEFAULT_SEGMENT_SIZE,
-                    compression: COMPRESS_NONE,
-                },
-                groups: None,
-            }));
-
-            match streams.try_insert_raw(
-                name.as_ptr(),
-                name.len(),
-                Rc::clone(&stream),
-            ) {
-                Ok(ref existing) => {
-                    if existing.is_some() {
-                        return Err(StreamError::Exists);
-                    }
+    /// Returns what was recovered when the manager started.
+    pub fn recovered(&self) -> &[StreamRecovery] {
+        &self.recovered
+    }
+
+    /// Returns the service that runs blocking I/O off the event-loop.
+    pub fn storage(&mut self) -> &mut io::StorageService {
+        &mut self.storage
+    }
+
+    /// Finds the streams left behind by a previous run and picks them back
+    /// up: sealed segments are opened using their index and the tail segment
+    /// is checked pack by pack. In a dry run everything is checked, sealed
+    /// segments included, but nothing is modified or picked up.
+    ///
+    /// A stream that can't be picked up doesn't keep the others from it.
+    /// Its directory is moved aside to "{id}.quarantine", where it's kept as
+    /// is to be looked into, and its report says what went wrong.
+    pub fn recover(&mut self, dry_run: bool) -> Result<Vec<StreamRecovery>, StreamError> {
+        let mut reports = Vec::new();
+        for (id, dir) in io::stream_dirs(&self.dir)? {
+            // Directories without a name never made it to being a stream.
+            let name = match fs::read_to_string(dir.join(NAME_FILE)) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if self.next_stream_id <= id {
+                self.next_stream_id = id + 1;
+            }
+            if !dry_run && self.streams.exists(&mut SDS::new(&name)) {
+                continue;
+            }
 
-                    self.next_stream_id += 1;
+            match self.recover_stream(id, &name, &dir, dry_run) {
+                Ok(report) => reports.push(report),
+                Err(err) => {
+                    let mut report = StreamRecovery::new(&name, &dir);
+                    report.error = Some(err.to_string());
+                    let aside = dir.with_extension(QUARANTINE_EXT);
+                    if !dry_run && fs::rename(&dir, &aside).is_ok() {
+                        report.dir = aside;
+                    }
+                    reports.push(report);
                 }
-                Err(e) => {
-                    match e {
-                        StreamError::OutOfMemory => return Err(StreamError::OutOfMemory),
-                        _ => return Err(StreamError::Generic(String::new()))
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Picks up a single stream for `recover`.
+    fn recover_stream(
+        &mut self,
+        id: u64,
+        name: &str,
+        dir: &Path,
+        dry_run: bool,
+    ) -> Result<StreamRecovery, StreamError> {
+        let mut report = StreamRecovery::new(name, dir);
+        let config = StreamConfig::load(dir)?;
+        let mut segments = Vec::new();
+        let mut after = StreamID::default();
+
+        // Segments that filled up and that a crash kept from being sealed
+        // are sealed now. A dry run checks them pack by pack instead.
+        let mut files = io::segment_files(dir)?;
+        for (segment_id, path) in io::unsealed_files(dir)? {
+            if dry_run {
+                files.push((segment_id, path));
+                continue;
+            }
+            match writer::seal_leftover(&path, &config) {
+                Ok(sealed) => files.push((segment_id, sealed)),
+                Err(_) => report.bad_segments.push(path),
+            }
+        }
+        files.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        files.dedup_by(|a, b| a.0 == b.0);
+
+        for (segment_id, path) in files {
+            let checked = if dry_run {
+                Segment::check(&path).map(|last_id| (last_id, None))
+            } else {
+                Segment::open(&path)
+                    .and_then(|segment| match segment.last_id() {
+                        Some(last_id) => Ok((last_id, Some(segment))),
+                        None => Err(corrupt_segment(&path, 0)),
+                    })
+            };
+            match checked {
+                Ok((last_id, segment)) => {
+                    report.segments += 1;
+                    after = last_id;
+                    if let Some(segment) = segment {
+                        segments.push((segment_id, segment));
                     }
                 }
+                Err(_) => report.bad_segments.push(path),
+            }
+        }
+
+        if dry_run {
+            report.tail = writer::StreamWriter::check(dir)?;
+            if let Some(data) = group::read_groups(dir)? {
+                group::check_groups(&data)?;
+            }
+            return Ok(report);
+        }
+
+        let (writer, tail) =
+            writer::StreamWriter::recover(dir, &config, after, &segments)?;
+        report.tail = tail;
+
+        let mut stream = Stream {
+            id,
+            mem_usage: 0,
+            disk_usage: 0,
+            name: SDS::new(name),
+            writer: Some(writer),
+            segments: map::RcRax::new(),
+            config,
+            groups: HashMap::new(),
+            groups_dirty: false,
+            archive: writer::StreamArchiveStats::default(),
+        };
+        for (mut segment_id, segment) in segments {
+            stream.archive.add(&segment);
+            stream.segments.insert(&mut segment_id, Rc::new(segment))?;
+        }
+        if let Some(data) = group::read_groups(dir)? {
+            stream.restore_groups(&data)?;
+        }
+        self.register(stream)?;
+        Ok(report)
+    }
+
+    /// Creates a stream along with its directory and tail segment. Every
+    /// stream gets a directory named after its internal ID, which holds its
+    /// name so that it can be found again after a restart.
+    pub fn create_stream(&mut self, name: SDS) -> Result<Rc<UnsafeCell<Stream>>, StreamError> {
+        if self.streams.exists(&mut name.clone()) {
+            return Err(StreamError::Exists);
+        }
+
+        // Skip over directories left behind by streams that came before.
+        let mut dir = self.dir.join(self.next_stream_id.to_string());
+        while dir.exists() {
+            self.next_stream_id += 1;
+            dir = self.dir.join(self.next_stream_id.to_string());
+        }
+        if fs::create_dir_all(&dir).is_err() {
+            return Err(StreamError::CreateDir(dir.to_string_lossy().into_owned()));
+        }
+        fs::write(dir.join(NAME_FILE), name.to_string())?;
+
+        let config = *DEFAULT_CONFIG;
+        let writer = writer::StreamWriter::open(&dir, &config)?;
+
+        let stream = self.register(Stream {
+            id: self.next_stream_id,
+            mem_usage: 0,
+            disk_usage: 0,
+            name: name.clone(),
+            writer: Some(writer),
+            segments: map::RcRax::new(),
+            config,
+            groups: HashMap::new(),
+            groups_dirty: false,
+            archive: writer::StreamArchiveStats::default(),
+        })?;
+        self.next_stream_id += 1;
+        Ok(stream)
+    }
+
+    /// Adds a stream to the ones that can be looked up by name.
+    fn register(
+        &mut self,
+        stream: Stream,
+    ) -> Result<Rc<UnsafeCell<Stream>>, StreamError> {
+        let name = stream.name.clone();
+        let stream = Rc::new(UnsafeCell::new(stream));
+
+        match self.streams.try_insert_raw(
+            name.as_ptr(),
+            name.len(),
+            Rc::clone(&stream),
+        ) {
+            Ok(ref existing) => {
+                if existing.is_some() {
+                    return Err(StreamError::Exists);
+                }
             }
+            Err(e) => {
+                match e {
+                    StreamError::OutOfMemory => return Err(StreamError::OutOfMemory),
+                    _ => return Err(StreamError::Generic(String::new()))
+                }
+            }
+        }
+
+        Ok(stream)
+    }
+
+    /// Looks up a stream by name.
+    pub fn get_stream(&self, name: &str) -> Option<Rc<UnsafeCell<Stream>>> {
+        self.streams.get(&mut SDS::new(name))
+    }
 
-            Ok(stream.clone())
+    /// Returns every stream, in the order of their keys.
+    pub fn all_streams(&self) -> Vec<Rc<UnsafeCell<Stream>>> {
+        let mut streams = Vec::new();
+        let mut found = self.streams.seek_entry("^", &SDS::new(""));
+        while let Some((name, stream)) = found {
+            if let Some(stream) = stream {
+                streams.push(stream);
+            }
+            found = self.streams.seek_entry(">", &name);
         }
+        streams
     }
 
     fn write(&mut self, stream: Rc<Stream>, id: &StreamID, record: &record::Record) {}
@@ -467,17 +1196,14 @@ impl StreamManager {
         let mut s = Rc::get_mut(&mut stream).unwrap();
 
         let mut segment_id = &mut match s.last_id() {
-            Some(id) => next_id(&id),
+            Some(id) => next_id(&id, clock::system()),
             None => StreamID::default()
         };
 
         unsafe {
             let segments = &mut s.segments;
 
-            let segment = Rc::new(Segment {
-                data: None,
-                packs: map::RcRax::new(),
-            });
+            let segment = Rc::new(Segment::new());
 
             // Insert into segment Rax.
             match segments.insert(segment_id, Rc::clone(&segment)) {
@@ -517,9 +1243,95 @@ impl Drop for StreamManager {
     }
 }
 
+/// What the tests of the stream modules build their streams out of.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+    use crate::redis::listpack::MemoizedValue;
+
+    pub fn id(ms: u64, seq: u64) -> StreamID {
+        StreamID { ms, seq }
+    }
+
+    /// Parses the fields and values of a record the way commands do.
+    pub fn record(args: &[&str]) -> Vec<MemoizedValue> {
+        args.iter()
+            .map(|arg| listpack::parse_raw_memoized(arg.as_ptr(), arg.len()))
+            .collect()
+    }
+
+    /// The default settings with packs and segments small enough for tests to
+    /// fill up.
+    pub fn config(max_pack_size: u32, max_segment_size: u32) -> StreamConfig {
+        StreamConfig {
+            max_pack_size,
+            max_segment_size,
+            ..*DEFAULT_CONFIG
+        }
+    }
+
+    /// Writes a record with the given ID. The next segment is created on the
+    /// spot whenever the write has to wait for it.
+    pub fn write(
+        writer: &mut writer::StreamWriter,
+        id: StreamID,
+        kv: &mut [MemoizedValue],
+    ) -> StreamID {
+        match writer.try_write(Some(id), kv) {
+            Err(StreamError::WouldBlock) => {
+                writer.prepare_now().unwrap();
+                writer.try_write(Some(id), kv).unwrap()
+            }
+            result => result.unwrap(),
+        }
+    }
+
+    /// A stream kept in dir with records 1-0 through 1-(count - 1). kv gives
+    /// the fields and values of the record with each sequence number.
+    pub fn stream<F>(
+        dir: &Path,
+        name: &str,
+        config: StreamConfig,
+        count: u64,
+        kv: F,
+    ) -> Rc<UnsafeCell<Stream>>
+    where
+        F: Fn(u64) -> Vec<MemoizedValue>,
+    {
+        let writer = writer::StreamWriter::open(dir, &config).unwrap();
+        let mut stream = Stream {
+            id: 1,
+            name: SDS::new(name),
+            mem_usage: 0,
+            disk_usage: 0,
+            segments: map::RcRax::new(),
+            writer: Some(writer),
+            config,
+            archive: writer::StreamArchiveStats::default(),
+            groups: HashMap::new(),
+            groups_dirty: false,
+        };
+        for seq in 0..count {
+            let mut kv = kv(seq);
+            match stream.add(Some(id(1, seq)), &mut kv) {
+                Err(StreamError::WouldBlock) => {
+                    stream.writer.as_mut().unwrap().prepare_now().unwrap();
+                    stream.add(Some(id(1, seq)), &mut kv).unwrap();
+                }
+                result => {
+                    result.unwrap();
+                }
+            }
+        }
+        Rc::new(UnsafeCell::new(stream))
+    }
+}
 
-#[cfg(tests)]
+#[cfg(test)]
 pub mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
     #[test]
     pub fn construct() {
 //        let mut manager = StreamManager::new(
@@ -527,4 +1339,125 @@ pub mod tests {
 //            std::path::Path::new("/Users/clay/sliced"),
 //        );
     }
+
+    #[test]
+    fn it_keeps_streams_within_the_dir_of_redis() {
+        let config = Reply::Array(vec![
+            Reply::String(b"dir".to_vec()),
+            Reply::String(b"/var/lib/redis".to_vec()),
+        ]);
+        assert_eq!(Path::new("/var/lib/redis/sliced"), stream_dir(&config));
+        assert_eq!(Path::new(DEFAULT_DIR), stream_dir(&Reply::Nil));
+    }
+
+    #[test]
+    fn it_quarantines_streams_that_cant_be_recovered() {
+        let dir = TempDir::new("manager").unwrap();
+        {
+            let mut manager = StreamManager::new(SDS::new("local"), dir.path()).unwrap();
+            manager.create_stream(SDS::new("good")).unwrap();
+        }
+
+        // A config that can't be read keeps a stream from being picked up.
+        let bad = dir.path().join("7");
+        fs::create_dir_all(bad.join(CONFIG_FILE)).unwrap();
+        fs::write(bad.join(NAME_FILE), "bad").unwrap();
+
+        let mut manager = StreamManager::new(SDS::new("local"), dir.path()).unwrap();
+        {
+            let recovered = manager.recovered();
+            assert_eq!(2, recovered.len());
+            assert_eq!("good", recovered[0].name);
+            assert!(recovered[0].error.is_none());
+            assert_eq!("bad", recovered[1].name);
+            assert!(recovered[1].error.is_some());
+            assert_eq!(dir.path().join("7.quarantine"), recovered[1].dir);
+        }
+        assert!(!bad.exists());
+        assert!(manager.get_stream("good").is_some());
+        assert!(manager.get_stream("bad").is_none());
+
+        // It's not picked up again, nor does a new stream take its place.
+        let checked = manager.recover(true).unwrap();
+        assert_eq!(1, checked.len());
+        assert_eq!("good", checked[0].name);
+        manager.create_stream(SDS::new("bad")).unwrap();
+        assert!(dir.path().join("8").exists());
+    }
+
+    #[test]
+    fn it_rejects_configs_that_ask_for_lz4() {
+        let dir = TempDir::new("config").unwrap();
+        assert_eq!(Some(COMPRESS_LZ4), parse_compression("LZ4"));
+        assert_eq!("lz4", compression_name(COMPRESS_LZ4));
+
+        fs::write(dir.path().join(CONFIG_FILE), "compression lz4\n").unwrap();
+        match StreamConfig::load(dir.path()) {
+            Err(err) => assert_eq!("lz4 compression isn't supported", err.to_string()),
+            Ok(_) => panic!("expected LZ4 to be rejected"),
+        }
+
+        fs::write(dir.path().join(CONFIG_FILE), "compression zstd\n").unwrap();
+        let config = StreamConfig::load(dir.path()).unwrap();
+        assert_eq!(COMPRESS_ZSTD, config.compression);
+    }
+
+    #[test]
+    fn it_picks_consumer_groups_back_up_after_a_restart() {
+        let dir = TempDir::new("manager").unwrap();
+        {
+            let mut manager = StreamManager::new(SDS::new("local"), dir.path()).unwrap();
+            let stream = manager.create_stream(SDS::new("s")).unwrap();
+            let s = unsafe { &mut *stream.get() };
+            for seq in 0..10 {
+                let mut kv = fixture::record(&["f", &format!("v{}", seq)]);
+                s.add(Some(fixture::id(1, seq)), &mut kv).unwrap();
+            }
+            s.create_group("g", StreamID::MIN).unwrap();
+            s.create_group("other", fixture::id(1, 9)).unwrap();
+
+            let mut read = range::RangeRead::new(
+                Rc::clone(&stream),
+                StreamID::MIN,
+                StreamID::MAX,
+                false,
+                Some(4),
+            );
+            read.keep_packs();
+            assert!(read.resume().unwrap().is_none());
+            let (entries, packs) = read.into_parts();
+            let group = s.group("g").unwrap();
+            group.deliver("alice", entries, packs, false, 100).unwrap();
+            assert!(group.ack(&fixture::id(1, 1)));
+            group.set_reclaim(group::Reclaim {
+                idle_timeout: 50,
+                max_deliveries: 3,
+                dead_letter: Some(String::from("dead")),
+            });
+            s.save_groups().unwrap();
+        }
+
+        let manager = StreamManager::new(SDS::new("local"), dir.path()).unwrap();
+        assert!(manager.recovered()[0].error.is_none());
+        let stream = manager.get_stream("s").unwrap();
+        let s = unsafe { &mut *stream.get() };
+        assert_eq!(fixture::id(1, 9), s.group("other").unwrap().last_id());
+
+        let group = s.group("g").unwrap();
+        assert_eq!(fixture::id(1, 3), group.last_id());
+        assert_eq!(3, group.pending_len());
+        assert_eq!(50, group.reclaim().idle_timeout);
+        assert_eq!(3, group.reclaim().max_deliveries);
+        assert_eq!(Some(String::from("dead")), group.reclaim().dead_letter);
+        let nack = group.pending.get(&mut fixture::id(1, 2)).unwrap();
+        assert_eq!(1, nack.delivery_count.get());
+        assert_eq!(100, nack.delivery_time.get());
+        assert_eq!("alice", nack.consumer.borrow().as_str());
+
+        // Pending records can be read again without faulting.
+        let entries = group.read_pending("alice", &StreamID::MIN, None);
+        let seqs: Vec<u64> = entries.iter().map(|entry| entry.id.seq).collect();
+        assert_eq!(vec![0, 2, 3], seqs);
+        assert_eq!(vec![(b"f".to_vec(), b"v2".to_vec())], entries[1].fields);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+
+use spin::Mutex;
+
+use crate::redis::redmod;
+
+use super::id::StreamID;
+
+/// An `MO.XADD` that got to the end of the tail segment of its stream before
+/// the next one was created. It's written once that's done.
+pub struct ParkedAdd {
+    /// The client waiting on the reply. Clients that can't be blocked were
+    /// already replied to.
+    pub bc: Option<*mut redmod::RedisModuleBlockedClient>,
+    pub key: String,
+    pub id: Option<StreamID>,
+    /// The fields and values of the record.
+    pub fields: Vec<String>,
+}
+
+/// Every append that's waiting on the next segment of its stream, in the
+/// order they came in. An append to a stream that has appends waiting lines
+/// up behind them, so that the records of a stream are written in order.
+///
+/// Parked appends are only ever touched from the Redis event loop, but a
+/// static has to be Send regardless of that.
+#[derive(Default)]
+pub struct Parked {
+    adds: VecDeque<ParkedAdd>,
+    /// The streams that have appends waiting.
+    streams: HashSet<String>,
+}
+
+unsafe impl Send for Parked {}
+
+lazy_static! {
+    pub static ref PARKED: Mutex<Parked> = Mutex::new(Parked::default());
+}
+
+impl Parked {
+    /// Adds an append to the end of the line. Returns true if it's the first
+    /// one since the last retry, in which case a retry has to be scheduled.
+    pub fn park(&mut self, add: ParkedAdd) -> bool {
+        self.streams.insert(add.key.clone());
+        self.adds.push_back(add);
+        self.adds.len() == 1
+    }
+
+    /// Returns whether the stream at key has appends waiting.
+    pub fn holds(&self, key: &str) -> bool {
+        self.streams.contains(key)
+    }
+
+    /// Takes every append out to be retried, in order.
+    pub fn take(&mut self) -> VecDeque<ParkedAdd> {
+        self.streams.clear();
+        mem::replace(&mut self.adds, VecDeque::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(key: &str, seq: u64) -> ParkedAdd {
+        ParkedAdd {
+            bc: None,
+            key: String::from(key),
+            id: Some(StreamID { ms: 1, seq }),
+            fields: vec![String::from("f"), String::from("v")],
+        }
+    }
+
+    #[test]
+    fn it_keeps_appends_in_order() {
+        let mut parked = Parked::default();
+        assert!(parked.park(add("a", 0)));
+        assert!(!parked.park(add("b", 0)));
+        assert!(!parked.park(add("a", 1)));
+        assert!(parked.holds("a"));
+        assert!(!parked.holds("c"));
+
+        let adds = parked.take();
+        let order: Vec<(&str, u64)> = adds
+            .iter()
+            .map(|add| (add.key.as_str(), add.id.unwrap().seq))
+            .collect();
+        assert_eq!(vec![("a", 0), ("b", 0), ("a", 1)], order);
+        assert!(!parked.holds("a"));
+
+        // The first append after a retry schedules another one.
+        assert!(parked.park(add("b", 1)));
+    }
+}
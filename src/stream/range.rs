@@ -0,0 +1,547 @@
+use crate::redis::listpack;
+use crate::redis::listpack::Value;
+use std::slice;
+use super::*;
+use super::writer::{STREAM_ITEM_FLAG_DELETED, STREAM_ITEM_FLAG_SAMEFIELDS};
+
+/// A record read out of a stream.
+pub struct Entry {
+    pub id: StreamID,
+    pub fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// What to do with a record that comes up while scanning a pack.
+pub enum Visit {
+    Take,
+    Skip,
+    /// Neither this record nor any that come after it are wanted.
+    Stop,
+}
+
+/// A pack that has to be read on the I/O thread before a read can go on.
+pub enum Fault {
+    Sealed(Rc<Segment>, Rc<Pack>),
+    /// A pack of the tail segment, which is read from its file. The file is
+    /// the one the pack was in when the fault came up, so a roll-over in the
+    /// meantime doesn't have it read from the segment that took its place.
+    /// The path is only for reporting.
+    Tail(PathBuf, fs::File, Rc<Pack>),
+}
+
+impl Fault {
+    /// Has the pack read on the I/O thread. It has its data once then is
+    /// called with Ok.
+    pub fn read<F>(
+        self,
+        storage: &mut io::StorageService,
+        then: F,
+    ) -> Result<u64, StreamError>
+    where
+        F: FnOnce(Result<(), StreamError>) + 'static,
+    {
+        match self {
+            Fault::Sealed(segment, pack) => storage.read(&segment, pack, then),
+            Fault::Tail(path, file, pack) => storage.read_tail(path, file, pack, then),
+        }
+    }
+
+    /// Reads the pack here and now rather than on the I/O thread, for when
+    /// there's nothing to hold up, such as while streams are recovered.
+    pub fn read_now(self) -> Result<(), StreamError> {
+        let (read, pack) = match self {
+            Fault::Sealed(segment, pack) => {
+                let mmap = match segment.handle {
+                    writer::SegmentHandle::Immutable(ref mmap) => Arc::clone(mmap),
+                    _ => return Err(StreamError::WouldBlock),
+                };
+                let path = segment.path.clone();
+                (io::ReadTask::new(path, Some(mmap), None, &pack), pack)
+            }
+            Fault::Tail(path, file, pack) => {
+                (io::ReadTask::new(path, None, Some(file), &pack), pack)
+            }
+        };
+        pack.data.set(read.read()?);
+        Ok(())
+    }
+}
+
+/// Reads the records of a stream within a range, in order or in reverse.
+///
+/// Sealed segments are found through the segment index of the stream and
+/// packs through the pack index of their segment. The tail segment comes
+/// after them. A pack that isn't in memory, and can't be loaded without
+/// blocking, stops the read with a Fault. The read picks up where it left
+/// off once the pack has been read on the I/O thread. Packs are found again
+/// from the ID of the last one that was scanned every time, so segments
+/// that are sealed in the meantime don't throw it off.
+pub struct RangeRead {
+    stream: Rc<UnsafeCell<Stream>>,
+    /// First ID within the range.
+    start: StreamID,
+    /// Last ID within the range.
+    end: StreamID,
+    rev: bool,
+    count: Option<usize>,
+    /// Master ID of the last pack that was scanned.
+    last_pack: Option<StreamID>,
+    entries: Vec<Entry>,
+    /// The packs records were read from by master ID, if they're kept.
+    kept: Option<Vec<(StreamID, Pin)>>,
+    done: bool,
+}
+
+impl RangeRead {
+    /// Starts a read of the records from start to end, both included. In
+    /// reverse the read starts at end. No more than count records are read.
+    pub fn new(
+        stream: Rc<UnsafeCell<Stream>>,
+        start: StreamID,
+        end: StreamID,
+        rev: bool,
+        count: Option<usize>,
+    ) -> RangeRead {
+        RangeRead {
+            stream,
+            start,
+            end,
+            rev,
+            count,
+            last_pack: None,
+            entries: Vec::new(),
+            kept: None,
+            done: start > end || count == Some(0),
+        }
+    }
+
+    /// Reads as far as possible. Returns the pack that has to be read on
+    /// the I/O thread to go on, or None once the read is done.
+    pub fn resume(&mut self) -> Result<Option<Fault>, StreamError> {
+        let stream = unsafe { &*self.stream.get() };
+        while !self.done {
+            let (master_id, holder, pack) = match self.next_pack(stream)? {
+                Some(found) => found,
+                None => break,
+            };
+            if !self.rev && master_id > self.end {
+                break;
+            }
+
+            match holder {
+                Some(ref segment) if segment.would_block(&pack) => {
+                    return Ok(Some(Fault::Sealed(Rc::clone(segment), pack)));
+                }
+                None => {
+                    let writer = stream.writer.as_ref().ok_or(StreamError::NotExists)?;
+                    if writer.would_block(&pack) {
+                        let file = writer.tail_file()?;
+                        return Ok(Some(Fault::Tail(writer.tail_path(), file, pack)));
+                    }
+                }
+                _ => {}
+            }
+
+            let before = self.entries.len();
+            self.scan(&master_id, &pack);
+            self.last_pack = Some(master_id);
+            match self.kept {
+                Some(ref mut kept) if self.entries.len() > before => {
+                    kept.push((master_id, pack));
+                }
+                // It's back on disk unless someone else needs it.
+                _ => Pack::release(pack),
+            }
+
+            // Records in the packs before this one are all smaller than its
+            // master ID.
+            if self.rev && master_id <= self.start {
+                break;
+            }
+            if self.count.map_or(false, |count| self.entries.len() >= count) {
+                break;
+            }
+        }
+        self.done = true;
+        Ok(None)
+    }
+
+    /// Holds onto the packs that records are read from, rather than letting
+    /// them go back to disk, so that they can be pinned.
+    pub fn keep_packs(&mut self) {
+        self.kept = Some(Vec::new());
+    }
+
+    /// Returns the records that were read.
+    pub fn into_entries(self) -> Vec<Entry> {
+        self.entries
+    }
+
+    /// Returns the records that were read along with the packs they're in by
+    /// master ID, in the order they were read.
+    pub fn into_parts(self) -> (Vec<Entry>, Vec<(StreamID, Pin)>) {
+        (self.entries, self.kept.unwrap_or_default())
+    }
+
+    /// Finds the pack to scan next.
+    fn next_pack(&self, stream: &Stream) -> Result<Option<Found>, StreamError> {
+        match self.last_pack {
+            Some(ref last) if self.rev => find_pack(stream, "<", last),
+            Some(ref last) => find_pack(stream, ">", last),
+            None if self.rev => find_pack(stream, "<=", &self.end),
+            // The first record may be in the pack before start.
+            None => match find_pack(stream, "<=", &self.start)? {
+                Some(found) => Ok(Some(found)),
+                None => find_pack(stream, ">", &self.start),
+            },
+        }
+    }
+
+    fn scan(&mut self, master_id: &StreamID, pack: &Pack) {
+        let (start, end, rev) = (self.start, self.end, self.rev);
+        let mut left = match self.count {
+            Some(count) => count - self.entries.len(),
+            None => usize::max_value(),
+        };
+        let entries = scan_pack(pack.data.get(), master_id, rev, |id| {
+            if left == 0 || (!rev && *id > end) || (rev && *id < start) {
+                Visit::Stop
+            } else if *id < start || *id > end {
+                Visit::Skip
+            } else {
+                left -= 1;
+                Visit::Take
+            }
+        });
+        self.entries.extend(entries);
+    }
+}
+
+/// Finds the pack the record with the given ID is in, if there's one it can
+/// be in, along with its master ID. The pack comes with the Fault to read it
+/// with if it isn't in memory.
+pub fn locate(
+    stream: &Stream,
+    id: &StreamID,
+) -> Result<Option<(StreamID, Rc<Pack>, Option<Fault>)>, StreamError> {
+    let (master_id, holder, pack) = match find_pack(stream, "<=", id)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let fault = match holder {
+        Some(ref segment) if segment.would_block(&pack) => {
+            Some(Fault::Sealed(Rc::clone(segment), Rc::clone(&pack)))
+        }
+        Some(_) => None,
+        None => {
+            let writer = stream.writer.as_ref().ok_or(StreamError::NotExists)?;
+            if writer.would_block(&pack) {
+                let file = writer.tail_file()?;
+                Some(Fault::Tail(writer.tail_path(), file, Rc::clone(&pack)))
+            } else {
+                None
+            }
+        }
+    };
+    Ok(Some((master_id, pack, fault)))
+}
+
+/// A pack along with its master ID and the sealed segment it's in. The
+/// segment is None for the tail segment.
+type Found = (StreamID, Option<Rc<Segment>>, Rc<Pack>);
+
+/// Finds the pack with the closest master ID that satisfies op against key
+/// across the sealed segments and the tail segment. op is one of "<", "<="
+/// or ">".
+fn find_pack(
+    stream: &Stream,
+    op: &str,
+    key: &StreamID,
+) -> Result<Option<Found>, StreamError> {
+    let tail = stream.writer.as_ref().map(|writer| writer.packs());
+
+    if op == ">" {
+        // The segment key falls in may have a pack after it. Otherwise it's
+        // the first pack of the segment after that.
+        if let Some((_, segment)) = stream.segments.seek_entry("<=", key) {
+            let segment = indexed(segment)?;
+            if let Some((id, pack)) = segment.packs.seek_entry(">", key) {
+                return Ok(Some((id, Some(segment), indexed(pack)?)));
+            }
+        }
+        if let Some((_, segment)) = stream.segments.seek_entry(">", key) {
+            let segment = indexed(segment)?;
+            if let Some((id, pack)) = segment.packs.seek_entry("^", key) {
+                return Ok(Some((id, Some(segment), indexed(pack)?)));
+            }
+        }
+        return match tail.and_then(|packs| packs.seek_entry(">", key)) {
+            Some((id, pack)) => Ok(Some((id, None, indexed(pack)?))),
+            None => Ok(None),
+        };
+    }
+
+    // Going back, the tail segment comes first.
+    if let Some((id, pack)) = tail.and_then(|packs| packs.seek_entry(op, key)) {
+        return Ok(Some((id, None, indexed(pack)?)));
+    }
+    if let Some((_, segment)) = stream.segments.seek_entry(op, key) {
+        let segment = indexed(segment)?;
+        if let Some((id, pack)) = segment.packs.seek_entry(op, key) {
+            return Ok(Some((id, Some(segment), indexed(pack)?)));
+        }
+    }
+    Ok(None)
+}
+
+/// Segments and packs are always indexed along with their value.
+fn indexed<V>(value: Option<Rc<V>>) -> Result<Rc<V>, StreamError> {
+    value.ok_or(StreamError::NotExists)
+}
+
+/// Decodes the records of a Redis Streams listpack whose master entry has
+/// master_id, in order or in reverse. visit is asked about each record by
+/// its ID before it's decoded. Deleted records are skipped over.
+pub fn scan_pack<F>(
+    lp: listpack::listpack,
+    master_id: &StreamID,
+    rev: bool,
+    mut visit: F,
+) -> Vec<Entry>
+where
+    F: FnMut(&StreamID) -> Visit,
+{
+    let mut entries = Vec::new();
+
+    // Master entry: count, deleted, num-fields, field_1, ..., field_N, 0
+    let count = match listpack::first(lp) {
+        Some(count) => count,
+        None => return entries,
+    };
+    let mut ele = listpack::skip(listpack::skip(count));
+    let num_fields = listpack::get_int(ele) as usize;
+    let mut master_fields = Vec::with_capacity(num_fields);
+    for _ in 0..num_fields {
+        ele = listpack::skip(ele);
+        master_fields.push(ele);
+    }
+    let first = listpack::skip(listpack::skip(ele));
+    let eof = unsafe { lp.offset(listpack::get_total_bytes(lp) as isize - 1) };
+    if first >= eof {
+        return entries;
+    }
+
+    // Going back, each record is found from the lp-count that ends it.
+    let mut at = if rev { record_before(lp, eof) } else { first };
+    loop {
+        let flags = listpack::get_int(at);
+        let ms = listpack::skip(at);
+        let seq = listpack::skip(ms);
+        let id = StreamID {
+            ms: master_id.ms.wrapping_add(listpack::get_int(ms) as u64),
+            seq: master_id.seq.wrapping_add(listpack::get_int(seq) as u64),
+        };
+
+        let (fields, next) = if flags & STREAM_ITEM_FLAG_SAMEFIELDS as i64 != 0 {
+            let mut value = listpack::skip(seq);
+            let mut fields = Vec::with_capacity(num_fields);
+            for field in master_fields.iter() {
+                fields.push((*field, value));
+                value = listpack::skip(value);
+            }
+            (fields, value)
+        } else {
+            let mut field = listpack::skip(seq);
+            let n = listpack::get_int(field) as usize;
+            let mut fields = Vec::with_capacity(n);
+            for _ in 0..n {
+                field = listpack::skip(field);
+                let value = listpack::skip(field);
+                fields.push((field, value));
+                field = value;
+            }
+            (fields, listpack::skip(field))
+        };
+
+        if flags & STREAM_ITEM_FLAG_DELETED as i64 == 0 {
+            match visit(&id) {
+                Visit::Take => entries.push(Entry {
+                    id,
+                    fields: fields
+                        .into_iter()
+                        .map(|(field, value)| (to_bytes(field), to_bytes(value)))
+                        .collect(),
+                }),
+                Visit::Skip => {}
+                Visit::Stop => break,
+            }
+        }
+
+        if rev {
+            if at == first {
+                break;
+            }
+            at = record_before(lp, at);
+        } else {
+            // Skip the lp-count.
+            at = listpack::skip(next);
+            if at >= eof {
+                break;
+            }
+        }
+    }
+    entries
+}
+
+/// Returns the flags of the record that ends right before ele.
+fn record_before(lp: listpack::listpack, ele: listpack::element) -> listpack::element {
+    let mut at = listpack::prev(lp, ele).expect("record without an lp-count");
+    for _ in 0..listpack::get_int(at) {
+        at = listpack::prev(lp, at).expect("record shorter than its lp-count");
+    }
+    at
+}
+
+/// Copies the value of an element. Integers are turned back into the
+/// decimal string they were parsed from.
+fn to_bytes(ele: listpack::element) -> Vec<u8> {
+    match listpack::get(ele) {
+        Value::Int(v) => v.to_string().into_bytes(),
+        Value::String(p, len) => unsafe {
+            slice::from_raw_parts(p, len as usize).to_vec()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::fixture::{self, id, record};
+    use tempdir::TempDir;
+
+    /// A stream with a sealed segment or two and records 1-0 through 1-39.
+    /// Every third record has fields of its own.
+    fn stream(dir: &Path) -> Rc<UnsafeCell<Stream>> {
+        let stream = fixture::stream(dir, "range", fixture::config(128, 512), 40, |seq| {
+            let value = format!("v{}", seq);
+            if seq % 3 == 0 {
+                record(&["other", &value])
+            } else {
+                record(&["f", &value, "n", "12"])
+            }
+        });
+        assert!(unsafe { &*stream.get() }.segments.len() > 0);
+        stream
+    }
+
+    /// Reads a range to the end, faulting packs in on the spot.
+    fn read(
+        stream: &Rc<UnsafeCell<Stream>>,
+        start: StreamID,
+        end: StreamID,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Vec<Entry> {
+        let mut read = RangeRead::new(Rc::clone(stream), start, end, rev, count);
+        loop {
+            let (path, pack) = match read.resume().unwrap() {
+                None => return read.into_entries(),
+                Some(Fault::Sealed(segment, pack)) => (segment.path.clone(), pack),
+                Some(Fault::Tail(path, _, pack)) => (path, pack),
+            };
+            let lp = format::read_pack(
+                &path,
+                &fs::read(&path).unwrap(),
+                pack.offset.get(),
+                pack.length.get(),
+                pack.raw_length.get(),
+                pack.crc.get(),
+            ).unwrap();
+            pack.data.set(lp);
+        }
+    }
+
+    fn seqs(entries: &[Entry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id.seq).collect()
+    }
+
+    #[test]
+    fn it_decodes_packs_either_way() {
+        let dir = TempDir::new("range").unwrap();
+        let stream = stream(dir.path());
+        let writer = unsafe { &*stream.get() }.writer.as_ref().unwrap();
+        let (master_id, pack) = match writer.packs().seek_entry("$", &id(0, 0)) {
+            Some((master_id, Some(pack))) => (master_id, pack),
+            _ => panic!("no tail pack"),
+        };
+
+        let forward = scan_pack(pack.data.get(), &master_id, false, |_| Visit::Take);
+        let mut back = scan_pack(pack.data.get(), &master_id, true, |_| Visit::Take);
+        assert!(!forward.is_empty());
+        assert_eq!(39, forward.last().unwrap().id.seq);
+        back.reverse();
+        assert_eq!(seqs(&forward), seqs(&back));
+
+        for entry in forward.iter() {
+            let value = format!("v{}", entry.id.seq).into_bytes();
+            if entry.id.seq % 3 == 0 {
+                assert_eq!(vec![(b"other".to_vec(), value)], entry.fields);
+            } else {
+                let n = (b"n".to_vec(), b"12".to_vec());
+                assert_eq!(vec![(b"f".to_vec(), value), n], entry.fields);
+            }
+        }
+    }
+
+    #[test]
+    fn it_reads_ranges_across_segments() {
+        let dir = TempDir::new("range").unwrap();
+        let stream = stream(dir.path());
+        let all: Vec<u64> = (0..40).collect();
+
+        let entries = read(&stream, StreamID::MIN, StreamID::MAX, false, None);
+        assert_eq!(all, seqs(&entries));
+        let entries = read(&stream, StreamID::MIN, StreamID::MAX, true, None);
+        assert_eq!(all.iter().rev().cloned().collect::<Vec<u64>>(), seqs(&entries));
+
+        let entries = read(&stream, id(1, 5), id(1, 30), false, None);
+        assert_eq!((5..31).collect::<Vec<u64>>(), seqs(&entries));
+        let entries = read(&stream, id(1, 5), id(1, 30), true, Some(3));
+        assert_eq!(vec![30, 29, 28], seqs(&entries));
+        let entries = read(&stream, id(1, 38), StreamID::MAX, false, Some(5));
+        assert_eq!(vec![38, 39], seqs(&entries));
+
+        assert!(read(&stream, id(2, 0), StreamID::MAX, false, None).is_empty());
+        assert!(read(&stream, StreamID::MIN, id(0, 9), true, None).is_empty());
+        assert!(read(&stream, id(1, 9), id(1, 8), false, None).is_empty());
+    }
+
+    #[test]
+    fn it_stops_at_packs_that_are_not_in_memory() {
+        let dir = TempDir::new("range").unwrap();
+        let stream = stream(dir.path());
+
+        // Drop whatever the first pack has loaded.
+        let s = unsafe { &*stream.get() };
+        let (_, segment) = s.segments.seek_entry("^", &id(0, 0)).unwrap();
+        let segment = segment.unwrap();
+        let (first_id, pack) = segment.packs.seek_entry("^", &id(0, 0)).unwrap();
+        let pack = pack.unwrap();
+        if !pack.data.get().is_null() {
+            dealloc(pack.data.get());
+            pack.data.set(ptr::null_mut());
+        }
+        let mmap = match segment.handle {
+            writer::SegmentHandle::Immutable(ref mmap) => mmap,
+            _ => panic!("sealed segment without a view"),
+        };
+
+        // The I/O thread has the segment.
+        let _locked = mmap.lock();
+        let stream = Rc::clone(&stream);
+        let mut read = RangeRead::new(stream, first_id, first_id, false, None);
+        match read.resume().unwrap() {
+            Some(Fault::Sealed(_, faulted)) => assert!(Rc::ptr_eq(&pack, &faulted)),
+            _ => panic!("expected the read to stop at the first pack"),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use spin::Mutex;
+
+use crate::clock;
+use crate::error::SlicedError;
+use crate::redis::listpack;
+use crate::redis::sds::SDS;
+use crate::redis::{self, LogLevel, Redis};
+
+use super::cmd::appended;
+use super::group::DeadLetter;
+use super::id::mstime;
+use super::writer::FIELD_DUPE_KEY;
+use super::{manager, ConsumerGroup};
+
+/// Milliseconds between scans for idle records.
+const SCAN_INTERVAL: i64 = 100;
+
+/// Number of pending records of a group that a scan looks at. However many
+/// records are pending, a scan only takes so long.
+const SCAN_BUDGET: usize = 1000;
+
+/// Number of idle records a read looks at to redeliver, on top of how many
+/// it asked for. However many records are idle, a read only takes so long.
+pub const REDELIVER_BUDGET: usize = 1000;
+
+/// The consumer groups that have an idle timeout, by stream key and group
+/// name, along with the timer for the next scan.
+#[derive(Default)]
+struct Reclaiming {
+    groups: Vec<(String, String)>,
+    timer: Option<redis::TimerID>,
+}
+
+lazy_static! {
+    static ref RECLAIMING: Mutex<Reclaiming> = Mutex::new(Reclaiming::default());
+}
+
+/// Has the pending records of a group scanned for ones that are idle from
+/// now on. Groups drop out once they no longer have an idle timeout.
+pub fn watch(r: &Redis, key: &str, group: &str) {
+    let mut reclaiming = RECLAIMING.lock();
+    if !reclaiming.groups.iter().any(|&(ref k, ref g)| k == key && g == group) {
+        reclaiming.groups.push((String::from(key), String::from(group)));
+    }
+    if reclaiming.timer.is_none() {
+        reclaiming.timer = Some(r.start_timer(SCAN_INTERVAL, |r| scan(&r)));
+    }
+}
+
+/// Scans a bit of the pending records of each group, sets the ones that are
+/// idle aside to be redelivered and moves the ones that were delivered too
+/// many times to their dead-letter stream.
+fn scan(r: &Redis) {
+    let groups = {
+        let mut reclaiming = RECLAIMING.lock();
+        reclaiming.timer = None;
+        reclaiming.groups.clone()
+    };
+    let manager = match manager() {
+        Ok(manager) => manager,
+        Err(_) => return,
+    };
+
+    // Replicas follow along with what the master reclaims.
+    let replica = r.is_replica();
+    let now = mstime(clock::system());
+    let mut kept = Vec::with_capacity(groups.len());
+    for (key, name) in groups {
+        let stream = match manager.get_stream(&key) {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let group = match unsafe { (*stream.get()).group(&name) } {
+            Some(group) if group.reclaim().idle_timeout > 0 => group,
+            _ => continue,
+        };
+        if !replica {
+            let dead_letter = group.reclaim().dead_letter.clone().unwrap_or_default();
+            for letter in group.scan_idle(now, SCAN_BUDGET) {
+                let id = letter.entry.id;
+                if let Err(err) = bury(r, &key, &name, group, &dead_letter, letter) {
+                    // The record stays pending and is tried again once the
+                    // scan comes back around to it.
+                    r.log(
+                        LogLevel::Warning,
+                        &format!("Couldn't move {} to {}: {}", id, dead_letter, err),
+                    );
+                    break;
+                }
+            }
+        }
+        kept.push((key, name));
+    }
+
+    let mut reclaiming = RECLAIMING.lock();
+    reclaiming.groups = kept;
+    if !reclaiming.groups.is_empty() {
+        reclaiming.timer = Some(r.start_timer(SCAN_INTERVAL, |r| scan(&r)));
+    }
+}
+
+/// Moves a record that was delivered too many times to the dead-letter stream
+/// and acks it. Its original ID, where it came from and how it failed are in
+/// fields that come before its own.
+fn bury(
+    r: &Redis,
+    key: &str,
+    name: &str,
+    group: &mut ConsumerGroup,
+    dead_letter: &str,
+    letter: DeadLetter,
+) -> Result<(), SlicedError> {
+    let manager = manager()?;
+    let stream = match manager.get_stream(dead_letter) {
+        Some(stream) => stream,
+        None => manager.create_stream(SDS::new(dead_letter))?,
+    };
+
+    // The record's own fields are binary data, so they're kept as bytes all
+    // the way through to replicas.
+    let mut fields: Vec<Vec<u8>> = vec![
+        b"dead-letter-id".to_vec(),
+        letter.entry.id.to_string().into_bytes(),
+        b"dead-letter-stream".to_vec(),
+        key.as_bytes().to_vec(),
+        b"dead-letter-group".to_vec(),
+        name.as_bytes().to_vec(),
+        b"dead-letter-consumer".to_vec(),
+        letter.consumer.into_bytes(),
+        b"dead-letter-deliveries".to_vec(),
+        letter.delivery_count.to_string().into_bytes(),
+        b"dead-letter-idle".to_vec(),
+        letter.idle.to_string().into_bytes(),
+    ];
+    // The dedupe key of the record has to be the first field of an append,
+    // so it's kept under a field of its own.
+    for (field, value) in letter.entry.fields {
+        if field.as_slice() == FIELD_DUPE_KEY.as_bytes() {
+            fields.push(b"dead-letter-dupe-key".to_vec());
+        } else {
+            fields.push(field);
+        }
+        fields.push(value);
+    }
+
+    let mut kv: Vec<listpack::MemoizedValue> = fields
+        .iter()
+        .map(|arg| listpack::parse_raw_memoized(arg.as_ptr(), arg.len()))
+        .collect();
+    // The next segment an append that has to wait is waiting on is asked
+    // for either way, so that the letter can be buried on the next scan.
+    let added = unsafe { (*stream.get()).add(None, &mut kv) };
+    unsafe { (*stream.get()).schedule(manager.storage()) }?;
+    manager.storage().watch(r);
+    let id = added?;
+    appended(r, dead_letter, &id);
+    group.ack(&letter.entry.id);
+
+    let id = id.to_string();
+    let mut replicated: Vec<&[u8]> = Vec::with_capacity(fields.len() + 2);
+    replicated.push(dead_letter.as_bytes());
+    replicated.push(id.as_bytes());
+    replicated.extend(fields.iter().map(|field| field.as_slice()));
+    r.replicate_bytes("mo.xadd", &replicated)?;
+    r.replicate("mo.xack", &[key, name, &letter.entry.id.to_string()])
+}
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+
+use spin::Mutex;
+
+use crate::redis::redmod;
+
+use super::id::StreamID;
+
+/// A client blocked in `MO.XREAD` until records are appended to one of the
+/// streams it reads.
+pub struct Tailer {
+    pub bc: *mut redmod::RedisModuleBlockedClient,
+    /// The streams it reads along with the last ID it has seen in each.
+    pub streams: Vec<(String, StreamID)>,
+    pub count: Option<usize>,
+}
+
+/// Every client blocked in `MO.XREAD`.
+///
+/// The clients of each stream are ordered by the last ID they've seen, so an
+/// append only has to look at the one furthest behind to know whether there's
+/// anyone to wake. Streams that have clients to wake are marked ready and all
+/// of them are woken together on the next tick. However many clients are
+/// blocked, and however many records are appended in between, a stream is
+/// looked at once per append and split once per wake.
+///
+/// Tailers are only ever touched from the Redis event loop, but a static has
+/// to be Send regardless of that.
+#[derive(Default)]
+pub struct Tailers {
+    /// By the address of their blocked client handle.
+    tailers: HashMap<usize, Tailer>,
+    waiting: HashMap<String, BTreeMap<StreamID, Vec<usize>>>,
+    ready: HashSet<String>,
+}
+
+unsafe impl Send for Tailers {}
+
+lazy_static! {
+    pub static ref TAILERS: Mutex<Tailers> = Mutex::new(Tailers::default());
+}
+
+impl Tailers {
+    /// Adds a client that's waiting for records past the IDs of its streams.
+    pub fn block(&mut self, tailer: Tailer) {
+        let handle = tailer.bc as usize;
+        for &(ref key, ref id) in tailer.streams.iter() {
+            self.waiting
+                .entry(key.clone())
+                .or_insert_with(BTreeMap::new)
+                .entry(*id)
+                .or_insert_with(Vec::new)
+                .push(handle);
+        }
+        self.tailers.insert(handle, tailer);
+    }
+
+    /// Takes a client out, if it's still waiting.
+    pub fn remove(
+        &mut self,
+        bc: *mut redmod::RedisModuleBlockedClient,
+    ) -> Option<Tailer> {
+        let tailer = self.tailers.remove(&(bc as usize))?;
+        self.unlink(&tailer);
+        Some(tailer)
+    }
+
+    /// Notes that id was appended to the stream at key. Returns true if that
+    /// made the first stream ready since the last wake, in which case a wake
+    /// has to be scheduled.
+    pub fn appended(&mut self, key: &str, id: &StreamID) -> bool {
+        let behind = match self.waiting.get(key).and_then(|w| w.keys().next()) {
+            Some(first) => first < id,
+            None => false,
+        };
+        if !behind || self.ready.contains(key) {
+            return false;
+        }
+        self.ready.insert(String::from(key));
+        self.ready.len() == 1
+    }
+
+    /// Takes every client that's behind the last ID of a ready stream. The
+    /// last ID of each is looked up with last_id.
+    pub fn take_ready<F>(&mut self, mut last_id: F) -> Vec<Tailer>
+    where
+        F: FnMut(&str) -> Option<StreamID>,
+    {
+        let mut woken = Vec::new();
+        for key in mem::replace(&mut self.ready, HashSet::new()) {
+            let last = match last_id(&key) {
+                Some(last) => last,
+                None => continue,
+            };
+            let behind = match self.waiting.get_mut(&key) {
+                Some(waiting) => {
+                    // Whoever has seen the last ID or beyond stays put.
+                    let caught_up = waiting.split_off(&last);
+                    mem::replace(waiting, caught_up)
+                }
+                None => continue,
+            };
+
+            for handle in behind.into_iter().flat_map(|(_, handles)| handles) {
+                // A client that reads more than one ready stream is only woken
+                // by the first of them.
+                if let Some(tailer) = self.tailers.remove(&handle) {
+                    self.unlink(&tailer);
+                    woken.push(tailer);
+                }
+            }
+        }
+        woken
+    }
+
+    /// Drops a client from the clients of each of its streams.
+    fn unlink(&mut self, tailer: &Tailer) {
+        let handle = tailer.bc as usize;
+        for &(ref key, ref id) in tailer.streams.iter() {
+            let empty = match self.waiting.get_mut(key) {
+                Some(waiting) => {
+                    let none_left = match waiting.get_mut(id) {
+                        Some(handles) => {
+                            handles.retain(|h| *h != handle);
+                            handles.is_empty()
+                        }
+                        None => false,
+                    };
+                    if none_left {
+                        waiting.remove(id);
+                    }
+                    waiting.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.waiting.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamID {
+        StreamID { ms, seq }
+    }
+
+    fn tailer(handle: usize, streams: &[(&str, StreamID)]) -> Tailer {
+        Tailer {
+            bc: handle as *mut redmod::RedisModuleBlockedClient,
+            streams: streams.iter().map(|&(key, id)| (String::from(key), id)).collect(),
+            count: None,
+        }
+    }
+
+    fn handles(tailers: &[Tailer]) -> Vec<usize> {
+        let mut handles: Vec<usize> = tailers.iter().map(|t| t.bc as usize).collect();
+        handles.sort();
+        handles
+    }
+
+    #[test]
+    fn it_wakes_tailers_that_are_behind() {
+        let mut tailers = Tailers::default();
+        tailers.block(tailer(1, &[("a", id(5, 0))]));
+        tailers.block(tailer(2, &[("a", id(7, 0))]));
+        tailers.block(tailer(3, &[("a", id(5, 0))]));
+
+        // Nobody has to be woken for records they've already seen.
+        assert!(!tailers.appended("a", &id(5, 0)));
+        assert!(!tailers.appended("b", &id(9, 0)));
+
+        // Only the first append since the last wake schedules one.
+        assert!(tailers.appended("a", &id(6, 0)));
+        assert!(!tailers.appended("a", &id(6, 1)));
+
+        let woken = tailers.take_ready(|_| Some(id(6, 1)));
+        assert_eq!(vec![1, 3], handles(&woken));
+        assert!(tailers.take_ready(|_| Some(id(6, 1))).is_empty());
+
+        assert!(tailers.appended("a", &id(8, 0)));
+        let woken = tailers.take_ready(|_| Some(id(8, 0)));
+        assert_eq!(vec![2], handles(&woken));
+        assert!(tailers.waiting.is_empty());
+    }
+
+    #[test]
+    fn it_wakes_tailers_of_many_streams_once() {
+        let mut tailers = Tailers::default();
+        tailers.block(tailer(1, &[("a", id(1, 0)), ("b", id(1, 0))]));
+        tailers.block(tailer(2, &[("b", id(1, 0))]));
+        tailers.block(tailer(3, &[("a", id(1, 0)), ("c", id(1, 0))]));
+
+        assert!(tailers.appended("a", &id(2, 0)));
+        assert!(!tailers.appended("b", &id(2, 0)));
+        let woken = tailers.take_ready(|_| Some(id(2, 0)));
+        assert_eq!(vec![1, 2, 3], handles(&woken));
+        assert!(tailers.waiting.is_empty());
+
+        tailers.block(tailer(4, &[("a", id(2, 0)), ("b", id(2, 0))]));
+        assert!(tailers.remove(4 as *mut _).is_some());
+        assert!(tailers.remove(4 as *mut _).is_none());
+        assert!(!tailers.appended("a", &id(3, 0)));
+        assert!(tailers.waiting.is_empty());
+    }
+}
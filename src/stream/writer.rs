@@ -1,9 +1,19 @@
+use crate::alloc::ALLOCATOR;
+use crate::clock::{self, Clock};
 use crate::redis::listpack;
 use crate::redis::listpack::{MemoizedValue, UnsafeAppender, Value};
 use spin::Mutex;
+use std::cmp;
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::slice;
 use std::sync::Arc;
 use super::*;
+use super::dedupe::{self, Dedupe};
+use super::range::{scan_pack, Visit};
 use super::record::*;
 
 // Redis Streams entry flags
@@ -33,10 +43,22 @@ pub struct StreamStats {
     avg_records_per_pack: f32,
 }
 
+#[derive(Clone, Copy, Default)]
 pub struct StreamArchiveStats {
-    segments: u64,
-    total_size: u64,
-    total_size_compressed: u64,
+    pub segments: u64,
+    /// Number of bytes the sealed segments would take up uncompressed.
+    pub total_size: u64,
+    /// Number of bytes their packs actually take up.
+    pub total_size_compressed: u64,
+}
+
+impl StreamArchiveStats {
+    /// Counts a sealed segment in.
+    pub fn add(&mut self, segment: &Segment) {
+        self.segments += 1;
+        self.total_size += segment.size;
+        self.total_size_compressed += segment.size_compressed;
+    }
 }
 
 struct SegmentReader {
@@ -92,7 +114,7 @@ impl SegmentModel {
     }
 }
 
-enum SegmentHandle {
+pub enum SegmentHandle {
     /// File should be on local file-system, but it is not currently open.
     Local,
 
@@ -124,21 +146,41 @@ enum SegmentHandle {
     Error(String),
 }
 
+/// The segment set aside to take over once the tail segment fills up.
+enum NextSegment {
+    /// Nothing was asked for yet, or creating it failed.
+    Missing,
+    /// The I/O thread is creating and preallocating it.
+    Creating,
+    Ready(aof::AOF),
+}
+
 /// Mutations to a stream is managed by the StreamWriter.
 pub struct StreamWriter {
-    stream: Rc<Stream>,
+    /// Directory that holds the segment files of the stream.
+    dir: PathBuf,
 
     /// ID of the segment is the min StreamID available within it.
     segment_id: StreamID,
     /// The current segment index.
-    segment: Rc<Segment>,
+    segment: Segment,
+    /// Index entries of the packs of the tail segment that are done. They're
+    /// written to the end of its file when it's sealed.
+    index: Vec<format::IndexEntry>,
     /// Active AOF.
     /// Path = {root_dir}/stream_id/0.dat
     /// Protected by a spin Mutex since it is shared with an I/O thread.
     aof: Option<Arc<Mutex<aof::AOF>>>,
+    /// The file of the active AOF, for packs of the tail segment that are
+    /// read on the I/O thread.
+    tail_file: fs::File,
 
     /// Last used StreamID. The next ID must be greater than the previous.
     last_id: StreamID,
+    /// Tells the time that generated IDs are made from.
+    clock: Box<Clock>,
+    /// Dedupe keys of the records within the dedupe window.
+    dedupe: Dedupe,
     /// Master ID of the tail pack.
     /// All record IDs within listpack are delta encoded from the master
     /// except for the first record in which case it "is" the ID.
@@ -147,18 +189,38 @@ pub struct StreamWriter {
     tail: Option<Rc<Pack>>,
     /// Number of master fields.
     tail_num_fields: u16,
-    /// Pointer to the first field if "tail_num_fields" > 0 else null_mut()
-    tail_fields: listpack::element,
+    /// Number of bytes of the tail pack's entries that are already in the
+    /// AOF. Only what comes after needs to be copied on the next write.
+    tail_persisted: u32,
+    /// Checksum of the entries of the tail pack that are in the AOF. The
+    /// checksum in the trailer carries on from it.
+    tail_crc: u32,
     /// Size of tail Pack's memory allocation. The StreamWriter will take
     /// care of reallocating the tail as necessary and according to the
     /// configuration.
     tail_alloc: u32,
 
-    /// Next segment that is prepared.
-    next_segment: Rc<Segment>,
-    /// Next AOF for the next segment that is prepared.
+    /// Next AOF for the next segment that is prepared. It's promoted to
+    /// the tail once the tail segment fills up. Shared with the I/O thread's
+    /// continuation that hands it over.
     /// Path = {root_dir}/stream_id/next.dat
-    next_aof: Option<aof::AOF>,
+    next: Rc<RefCell<NextSegment>>,
+    /// Whether a sync of the tail segment is with the I/O thread.
+    syncing: Rc<Cell<bool>>,
+    /// Whether anything was written to the tail segment since it was last
+    /// handed off to be synced.
+    dirty: bool,
+
+    /// Segments that filled up since the stream last took them, keyed by
+    /// their ID. They're read from what was written to them until they're
+    /// sealed.
+    rolled: Vec<(StreamID, Segment)>,
+    /// Segments that filled up and still have to be handed off to the I/O
+    /// thread to be sealed.
+    unsealed: Vec<io::SealTask>,
+    /// Segments that were sealed since the stream last took them, keyed by
+    /// their ID. Shared with the I/O thread's continuations.
+    sealed: Rc<RefCell<Vec<(StreamID, Segment)>>>,
 
     /// Starting segment size. This allows the ability to start all streams
     /// as compact as possible as well as optimize away truncate operations
@@ -171,6 +233,10 @@ pub struct StreamWriter {
     /// Number of bytes to try to keep segment files within.
     seg_max: u32,
 
+    /// How packs are compressed when the segment is sealed.
+    compression: i32,
+    compression_level: i32,
+
     /// Simple optimization to balance performance with memory usage.
     /// For new streams or streams that are very sparse, we can be very
     /// conservative and only allocate the minimum required. However, for
@@ -197,7 +263,38 @@ pub struct StreamWriter {
     growing: bool,
 }
 
-struct NeedMoreDiskSpace {}
+/// What recovering the tail segment of a stream found.
+#[derive(Clone, Copy, Default)]
+pub struct RecoveryReport {
+    /// Number of intact packs that were kept.
+    pub packs: u32,
+    /// Number of records within them.
+    pub records: u64,
+    /// Offset at which the intact packs end.
+    pub end: u32,
+    /// Number of bytes past the intact packs that were discarded, or would
+    /// be in a dry run. This is where a torn pack ends up.
+    pub discarded: u32,
+}
+
+impl RecoveryReport {
+    fn new(scan: &format::Scan) -> RecoveryReport {
+        RecoveryReport {
+            packs: scan.packs.len() as u32,
+            records: scan.packs.iter().map(|pack| pack.entry.count as u64).sum(),
+            end: scan.end as u32,
+            discarded: scan.torn as u32,
+        }
+    }
+
+    /// Whether everything that was found was kept.
+    pub fn is_clean(&self) -> bool {
+        self.discarded == 0
+    }
+}
+
+/// Smallest allocation made for a new pack.
+const PACK_MIN: u32 = 4096;
 
 #[inline]
 ///
@@ -206,69 +303,693 @@ pub fn realloc_for_write(p: *mut u8, size: usize) -> *mut u8 {
 }
 
 impl StreamWriter {
-    pub fn next_id(&mut self) -> StreamID {
-        self.last_id = id::next_id(&self.last_id);
+    /// Opens the writer of the stream kept in dir and creates its tail
+    /// segment, preallocated so that a full disk fails here rather than on a
+    /// write to its mapping. The next segment is left to `schedule`.
+    pub fn open(dir: &Path, config: &StreamConfig) -> Result<StreamWriter, StreamError> {
+        StreamWriter::open_with_clock(dir, config, Box::new(clock::system()))
+    }
+
+    /// Like `open`, but generates IDs from the time told by the given clock
+    /// instead of the system's.
+    pub fn open_with_clock(
+        dir: &Path,
+        config: &StreamConfig,
+        clock: Box<Clock>,
+    ) -> Result<StreamWriter, StreamError> {
+        let path = dir.join(format::TAIL_FILE);
+        if path.exists() {
+            // Picking up a tail segment left behind by a previous run takes
+            // recovery.
+            return Err(StreamError::Exists);
+        }
+        let aof = aof::AOF::create(&path, config.max_segment_size as u64)?;
+        aof.preallocate()?;
+        StreamWriter::new(dir, config, aof, clock)
+    }
+
+    /// Picks up the tail segment left behind in dir by a previous run. Every
+    /// pack is checked, and a torn pack at the end is cut off along with
+    /// anything after it. after is the last ID within the sealed segments
+    /// of the stream. They're in sealed, in order, for the dedupe window to
+    /// reach back into.
+    pub fn recover(
+        dir: &Path,
+        config: &StreamConfig,
+        after: StreamID,
+        sealed: &[(StreamID, Segment)],
+    ) -> Result<(StreamWriter, RecoveryReport), StreamError> {
+        let clock = Box::new(clock::system());
+        StreamWriter::recover_with_clock(dir, config, after, sealed, clock)
+    }
+
+    /// Like `recover`, but generates IDs from the time told by the given
+    /// clock instead of the system's.
+    pub fn recover_with_clock(
+        dir: &Path,
+        config: &StreamConfig,
+        after: StreamID,
+        sealed: &[(StreamID, Segment)],
+        clock: Box<Clock>,
+    ) -> Result<(StreamWriter, RecoveryReport), StreamError> {
+        // The segment that was set aside is simply made again, and so is a
+        // compressed copy that never got put in place.
+        for name in &[format::NEXT_FILE, format::SEALING_FILE] {
+            let leftover = dir.join(name);
+            if leftover.exists() {
+                fs::remove_file(&leftover)?;
+            }
+        }
+
+        // A crash may have come before anything was written to the tail
+        // segment, or before the next one took its place.
+        let path = dir.join(format::TAIL_FILE);
+        if path.exists() && fs::metadata(&path)?.len() == 0 {
+            fs::remove_file(&path)?;
+        }
+        if !path.exists() {
+            let mut writer = StreamWriter::open_with_clock(dir, config, clock)?;
+            writer.last_id = after;
+            writer.rebuild_dedupe(&[], sealed)?;
+            return Ok((writer, RecoveryReport::default()));
+        }
+
+        let mut aof = aof::AOF::open(&path)?;
+        let scan = format::scan(aof.as_slice());
+
+        // A crash came after a compressed copy took the place of the tail
+        // segment and before the tail segment was removed.
+        if scan.packs.first().map_or(false, |first| first.entry.id <= after) {
+            drop(aof);
+            fs::remove_file(&path)?;
+            return StreamWriter::recover_with_clock(dir, config, after, sealed, clock);
+        }
+        let report = RecoveryReport::new(&scan);
+
+        // Cut off whatever comes after the intact packs.
+        if scan.end < aof.len() {
+            let mut eof = vec![0u8; cmp::max(scan.torn, 1)];
+            eof[0] = listpack::EOF;
+            aof.write_at(scan.end, &eof);
+        }
+        aof.set_offset(scan.end);
+        aof.flush()?;
+
+        // Only the tail pack needs to be in memory. Its checksum was already
+        // verified by the scan.
+        let tail_lp = match scan.packs.last() {
+            Some(last) => {
+                let start = last.entry.offset as usize;
+                let end = start + last.entry.length as usize;
+                let lp = format::load_pack(&aof.as_slice()[start..end]);
+                let persisted = last.entry.length
+                    - format::trailer_size(&last.entry.id, last.entry.count);
+                let entries = &aof.as_slice()[start..start + persisted as usize];
+                let crc = format::crc32c(0, entries);
+                Some((lp.ok_or(StreamError::OutOfMemory)?, persisted, crc))
+            }
+            None => None,
+        };
+
+        let mut writer = StreamWriter::new(dir, config, aof, clock)?;
+        writer.last_id = after;
+        for (index, checked) in scan.packs.iter().enumerate() {
+            let pack = Rc::new(Pack::new());
+            pack.offset.set(checked.entry.offset);
+            pack.length.set(checked.entry.length);
+            pack.raw_length.set(checked.entry.raw_length);
+            pack.count.set(checked.entry.count);
+            pack.crc.set(checked.entry.crc);
+            writer.segment.packs.insert(&mut checked.entry.id.clone(), Rc::clone(&pack))?;
+
+            if index == 0 {
+                writer.segment_id = checked.entry.id;
+            }
+            if index + 1 < scan.packs.len() {
+                writer.index.push(checked.entry);
+                continue;
+            }
+
+            let (lp, persisted, crc) = tail_lp.unwrap();
+            pack.data.set(lp);
+            writer.tail_master_id = checked.entry.id;
+            writer.tail_num_fields = checked.num_fields;
+            writer.tail_persisted = persisted;
+            writer.tail_crc = crc;
+            writer.tail_alloc = listpack::get_total_bytes(lp);
+            writer.tail = Some(pack);
+            if checked.last_id > writer.last_id {
+                writer.last_id = checked.last_id;
+            }
+        }
+
+        writer.rebuild_dedupe(&scan.packs, sealed)?;
+        Ok((writer, report))
+    }
+
+    /// Checks the tail segment in dir the same way `recover` does, without
+    /// changing anything.
+    pub fn check(dir: &Path) -> Result<RecoveryReport, StreamError> {
+        let path = dir.join(format::TAIL_FILE);
+        if !path.exists() || fs::metadata(&path)?.len() == 0 {
+            return Ok(RecoveryReport::default());
+        }
+
+        let file = fs::File::open(&path)?;
+        let mmap = unsafe { crate::mmap::MmapOptions::new().map(&file)? };
+        Ok(RecoveryReport::new(&format::scan(&mmap)))
+    }
+
+    fn new(
+        dir: &Path,
+        config: &StreamConfig,
+        aof: aof::AOF,
+        clock: Box<Clock>,
+    ) -> Result<StreamWriter, StreamError> {
+        Ok(StreamWriter {
+            dir: dir.to_path_buf(),
+            segment_id: StreamID::default(),
+            segment: Segment::new(),
+            index: Vec::new(),
+            tail_file: aof.try_clone_file()?,
+            aof: Some(Arc::new(Mutex::new(aof))),
+            last_id: StreamID::default(),
+            clock,
+            dedupe: Dedupe::new(config),
+            tail_master_id: StreamID::default(),
+            tail: None,
+            tail_num_fields: 0,
+            tail_persisted: 0,
+            tail_crc: 0,
+            tail_alloc: 0,
+            next: Rc::new(RefCell::new(NextSegment::Missing)),
+            syncing: Rc::new(Cell::new(false)),
+            dirty: false,
+            rolled: Vec::new(),
+            unsealed: Vec::new(),
+            sealed: Rc::new(RefCell::new(Vec::new())),
+            seg_min: config.max_segment_size,
+            seg_max: config.max_segment_size,
+            compression: config.compression,
+            compression_level: config.compression_level,
+            pack_min: cmp::min(PACK_MIN, config.max_pack_size),
+            pack_max: config.max_pack_size,
+            growing: false,
+        })
+    }
+
+    #[inline]
+    pub fn last_id(&self) -> StreamID {
         self.last_id
     }
 
-    /// Attempts to
-    pub fn try_write(&mut self, kv: &mut [listpack::Value]) -> Result<(), StreamError> {
-        match self.aof {
-            Some(ref aof) => {
-                match aof.try_lock() {
-                    Some(ref mut locked) => {
-                        // We have exclusive access to the AOF.
-                        let segment_length = locked.len();
-                        let offset = locked.offset();
-
-                        // Get the tail pack.
-                        match self.tail {
-                            Some(ref mut tail) => {}
-                            None => {
-                                // Determine new
-                                let mut pack = Pack::new();
-//                                pack.data = Some(record::PackData::new());
-                                self.tail = Some(Rc::new(pack));
-                            }
-                        };
-
-                        // Get listpack.
-//                        let mut data = match tail.data {
-//                            Some(mut lp) => lp,
-//                            None => Listpack::new(),
-//                        };
-//
-//                        let marker = data.bytes();
-
-                        // Create record ID.
-//                        let id = self.next_id();
-
-                        // Write it to the file.
-//                        locked.try_append(ptr::null_mut(), 0);
-
-
-//                        drop(locked);
-
-                        Ok(())
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Picks up changes to the configuration of the stream. Only compression
+    /// and the dedupe window can be changed once a stream has been created.
+    pub fn configure(&mut self, config: &StreamConfig) {
+        self.compression = config.compression;
+        self.compression_level = config.compression_level;
+        self.dedupe.configure(config);
+    }
+
+    /// Index of the packs of the tail segment, the tail pack included.
+    #[inline]
+    pub fn packs(&self) -> &map::RcRax<StreamID, Pack> {
+        &self.segment.packs
+    }
+
+    /// Path of the tail segment file.
+    #[inline]
+    pub fn tail_path(&self) -> PathBuf {
+        self.dir.join(format::TAIL_FILE)
+    }
+
+    /// The file of the tail segment, for a read of one of its packs on the
+    /// I/O thread. Unlike the path, it still refers to the same segment once
+    /// that's rolled over and set aside.
+    pub fn tail_file(&self) -> Result<fs::File, StreamError> {
+        Ok(self.tail_file.try_clone()?)
+    }
+
+    /// Same as `Segment::would_block` for the packs of the tail segment.
+    /// The tail pack is always in memory. The others are loaded right away
+    /// if their pages are.
+    pub fn would_block(&self, pack: &Pack) -> bool {
+        if !pack.data.get().is_null() {
+            return false;
+        }
+
+        // The I/O thread may have the AOF.
+        let aof = match self.aof {
+            Some(ref aof) => aof,
+            None => return true,
+        };
+        let aof = match aof.try_lock() {
+            Some(aof) => aof,
+            None => return true,
+        };
+        let (offset, length) = (pack.offset.get(), pack.length.get());
+        let end = offset as usize + length as usize;
+        if end > aof.offset() || !aof.is_resident(offset as usize, length as usize) {
+            return true;
+        }
+
+        let read = format::read_pack(
+            &self.tail_path(),
+            aof.as_slice(),
+            offset,
+            length,
+            pack.raw_length.get(),
+            pack.crc.get(),
+        );
+        match read {
+            Ok(lp) => {
+                pack.data.set(lp);
+                false
+            }
+            Err(_) => true,
+        }
+    }
+
+    /// Appends a record to the stream and returns its ID. A new ID is
+    /// generated unless one is given, in which case it must be greater than
+    /// the last one.
+    ///
+    /// Nothing is written when an error is returned. WouldBlock means the
+    /// write has to wait, either for the I/O thread to let go of the AOF or,
+    /// when the record starts the next segment, for it to be created.
+    pub fn try_write(
+        &mut self,
+        id: Option<StreamID>,
+        kv: &mut [MemoizedValue],
+    ) -> Result<StreamID, StreamError> {
+        // Ensure we have key-values.
+        if kv.len() % 2 != 0 {
+            return Err(StreamError::BadInput);
+        }
+
+        let id = match id {
+            Some(id) => {
+                if id <= self.last_id {
+                    return Err(StreamError::BadInput);
+                }
+                id
+            }
+            None => id::next_id(&self.last_id, &*self.clock),
+        };
+
+        // A record with a dedupe key that was already written within the
+        // window isn't written again.
+        let key = dedupe::dupe_key(kv)?;
+        if let Some(ref key) = key {
+            if let Some(original) = self.dedupe.check(key, &id) {
+                return Err(StreamError::Duplicate(original));
+            }
+        }
+        let flags = match key {
+            Some(_) => STREAM_ITEM_FLAG_DEDUPE,
+            None => STREAM_ITEM_FLAG_NONE,
+        };
+
+        let aof = self.aof()?;
+        let mut locked = match aof.try_lock() {
+            Some(locked) => locked,
+            // Background thread has the lock.
+            // It's the commands responsibility to determine if it wants
+            // to create a Future and wait for the availability of the AOF.
+            None => return Err(StreamError::WouldBlock),
+        };
+
+        // Try the tail pack first.
+        if let Some(tail) = self.tail.clone() {
+            let lp = tail.data.get();
+            let bytes = listpack::get_total_bytes(lp);
+            let elements = listpack::get_num_elements(lp);
+
+            match self.append(&id, kv, &tail, flags) {
+                Ok(()) => {
+                    let master_id = self.tail_master_id;
+                    if self.fits(&locked, &tail, &master_id, tail.count.get() + 1) {
+                        self.incr_count(&tail)?;
+                        self.persist(&mut locked, &tail);
+                        self.dirty = true;
+                        self.last_id = id;
+                        self.dedupe.written(&id, key);
+                        return Ok(id);
                     }
-                    // Background thread has the lock.
-                    // It's the commands responsibility to determine if it wants
-                    // to create a Future and wait for the availability of the AOF.
-                    None => return Err(StreamError::WouldBlock)
+
+                    // The segment is full. Take the record back out so that
+                    // it can start a pack in the next segment instead.
+                    listpack::revert(tail.data.get(), bytes, elements);
                 }
+                // The pack is full.
+                Err(StreamError::Overflow) => {}
+                Err(e) => return Err(e),
             }
-            None => return Err(StreamError::WouldBlock)
         }
+
+        // Start a new pack right after the tail pack.
+        let (pack, alloc_size) = self.new_pack(&id, kv, flags)?;
+        let pack = Rc::new(pack);
+        if let Some(ref tail) = self.tail {
+            pack.offset.set(tail.offset.get() + tail.length.get());
+        }
+
+        if self.fits(&locked, &pack, &id, 1) {
+            self.place_pack(&mut locked, &id, pack, alloc_size)?;
+        } else {
+            if pack.offset.get() == 0 {
+                // A single record that doesn't fit in an empty segment.
+                return Err(StreamError::Overflow);
+            }
+
+            // Roll over to a new segment that starts with the new pack. The
+            // AOF can only be sealed once nothing else refers to it.
+            drop(locked);
+            drop(aof);
+            self.roll_segment()?;
+            pack.offset.set(0);
+
+            let aof = self.aof()?;
+            let mut locked = aof.try_lock().ok_or(StreamError::WouldBlock)?;
+            self.place_pack(&mut locked, &id, pack, alloc_size)?;
+        }
+
+        self.dirty = true;
+        self.last_id = id;
+        self.dedupe.written(&id, key);
+        Ok(id)
     }
 
-    fn new_pack(&mut self, id: &StreamID, kv: &mut [Value]) -> Result<PackData, StreamError> {
-        let raw_lp = alloc(self.pack_min as usize);
-        if raw_lp.is_null() {
-            return Err(StreamError::OutOfMemory);
+    /// Picks the dedupe keys that are within the window back up. Only the
+    /// packs at the end that may hold records within the window are read,
+    /// going back from the packs of the tail segment into the sealed segments
+    /// before it for as long as the window reaches.
+    fn rebuild_dedupe(
+        &mut self,
+        packs: &[format::CheckedPack],
+        sealed: &[(StreamID, Segment)],
+    ) -> Result<(), StreamError> {
+        if !self.dedupe.is_enabled() {
+            return Ok(());
+        }
+        let last_id = packs.last().map_or(self.last_id, |last| last.last_id);
+
+        let mut first = packs.len();
+        let mut records_after = 0;
+        while first > 0
+            && self.dedupe.reaches(&packs[first - 1].last_id, records_after, &last_id)
+        {
+            first -= 1;
+            records_after += packs[first].entry.count as u64;
+        }
+
+        // None of the records of a sealed pack come after the master ID of
+        // the pack that follows it, which stands in for its last ID.
+        let mut older = Vec::new();
+        let mut next_id = packs.first().map_or(last_id, |first| first.entry.id);
+        if first == 0 {
+            'segments: for &(_, ref segment) in sealed.iter().rev() {
+                let mut found = segment.packs.seek_entry("$", &StreamID::MIN);
+                while let Some((master_id, pack)) = found {
+                    if !self.dedupe.reaches(&next_id, records_after, &last_id) {
+                        break 'segments;
+                    }
+                    let pack = pack.ok_or(StreamError::NotExists)?;
+                    records_after += pack.count.get() as u64;
+                    older.push((segment, master_id, pack));
+                    next_id = master_id;
+                    found = segment.packs.seek_entry("<", &master_id);
+                }
+            }
+        }
+
+        // Keys are picked up in the order they were written. A sealed pack
+        // that doesn't check out is left out, as its records can't be read
+        // anyway.
+        for (segment, master_id, pack) in older.into_iter().rev() {
+            let mmap = match segment.handle {
+                SegmentHandle::Immutable(ref mmap) => Arc::clone(mmap),
+                _ => continue,
+            };
+            let mmap = mmap.lock();
+            let read = format::read_pack(
+                &segment.path,
+                &mmap,
+                pack.offset.get(),
+                pack.length.get(),
+                pack.raw_length.get(),
+                pack.crc.get(),
+            );
+            if let Ok(lp) = read {
+                self.note_dedupe_keys(lp, &master_id);
+                dealloc(lp);
+            }
         }
 
-        let mut lp = Listpack::from_raw(raw_lp);
+        let aof = self.aof()?;
+        let locked = aof.lock();
+        for checked in packs[first..].iter() {
+            let start = checked.entry.offset as usize;
+            let end = start + checked.entry.length as usize;
+            let lp = format::load_pack(&locked.as_slice()[start..end])
+                .ok_or(StreamError::OutOfMemory)?;
+            self.note_dedupe_keys(lp, &checked.entry.id);
+            dealloc(lp);
+        }
+        Ok(())
+    }
+
+    fn note_dedupe_keys(&mut self, lp: listpack::listpack, master_id: &StreamID) {
+        for entry in scan_pack(lp, master_id, false, |_| Visit::Take) {
+            self.dedupe.written(&entry.id, dedupe::entry_dupe_key(&entry));
+        }
+    }
 
+    /// Hands over the segments that filled up since the last call. They
+    /// aren't sealed yet.
+    pub fn take_rolled(&mut self) -> Vec<(StreamID, Segment)> {
+        mem::replace(&mut self.rolled, Vec::new())
+    }
+
+    /// Hands over the segments that were sealed since the last call.
+    pub fn take_sealed(&mut self) -> Vec<(StreamID, Segment)> {
+        mem::replace(&mut *self.sealed.borrow_mut(), Vec::new())
+    }
+
+    /// Hands the blocking work that writes leave behind off to the I/O
+    /// thread: creating the next segment ahead of time, sealing the segments
+    /// that filled up and syncing the tail segment. Whatever the I/O thread
+    /// turns away is tried again the next time around.
+    pub fn schedule(&mut self, storage: &mut io::StorageService) {
+        let missing = match *self.next.borrow() {
+            NextSegment::Missing => true,
+            _ => false,
+        };
+        if missing {
+            let next = Rc::clone(&self.next);
+            let path = self.dir.join(format::NEXT_FILE);
+            let created = storage.create(path, self.seg_min as u64, move |result| {
+                *next.borrow_mut() = match result {
+                    Ok(aof) => NextSegment::Ready(aof),
+                    Err(_) => NextSegment::Missing,
+                };
+            });
+            if created.is_ok() {
+                *self.next.borrow_mut() = NextSegment::Creating;
+            }
+        }
+
+        // A segment that couldn't be sealed is read from what was written to
+        // it and sealed during recovery.
+        while !self.unsealed.is_empty() && !storage.is_full() {
+            let task = self.unsealed.remove(0);
+            let sealed = Rc::clone(&self.sealed);
+            let segment_id = task.segment_id;
+            let path = task.path.with_extension(format::SEGMENT_EXT);
+            let _ = storage.seal(task, move |result| {
+                let segment = result.and_then(|(index, mmap)| {
+                    Segment::from_index(&path, &index, mmap)
+                });
+                if let Ok(segment) = segment {
+                    sealed.borrow_mut().push((segment_id, segment));
+                }
+            });
+        }
+
+        if self.dirty && !self.syncing.get() {
+            let file = match self.aof.as_ref().and_then(|aof| aof.try_lock()) {
+                Some(aof) => aof.try_clone_file(),
+                None => return,
+            };
+            let syncing = Rc::clone(&self.syncing);
+            let submitted = file.map_err(StreamError::from).and_then(|file| {
+                storage.sync_file(file, move |_| syncing.set(false))
+            });
+            if submitted.is_ok() {
+                self.syncing.set(true);
+                self.dirty = false;
+            }
+        }
+    }
+
+    /// Creates the next segment here and now unless it's already on its way.
+    pub fn prepare_now(&mut self) -> Result<(), StreamError> {
+        let missing = match *self.next.borrow() {
+            NextSegment::Missing => true,
+            _ => false,
+        };
+        if missing {
+            let path = self.dir.join(format::NEXT_FILE);
+            let aof = aof::AOF::create(&path, self.seg_min as u64)?;
+            aof.preallocate()?;
+            *self.next.borrow_mut() = NextSegment::Ready(aof);
+        }
+        Ok(())
+    }
+
+    /// Seals the segments that are waiting on the I/O thread here and now.
+    pub fn seal_now(&mut self) -> Result<(), StreamError> {
+        for mut task in self.unsealed.drain(..) {
+            let aof = task.aof.take().ok_or(StreamError::WouldBlock)?;
+            let (index, mmap) = seal_segment(
+                aof,
+                &task.path,
+                task.index,
+                task.compression,
+                task.compression_level,
+            )?;
+            let path = task.path.with_extension(format::SEGMENT_EXT);
+            let segment = Segment::from_index(&path, &index, mmap)?;
+            self.sealed.borrow_mut().push((task.segment_id, segment));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn aof(&self) -> Result<Arc<Mutex<aof::AOF>>, StreamError> {
+        match self.aof {
+            Some(ref aof) => Ok(Arc::clone(aof)),
+            None => Err(StreamError::WouldBlock),
+        }
+    }
+
+    /// Determines whether a pack with the specified number of records, and
+    /// the EOF that follows it, fits within the segment.
+    fn fits(
+        &self,
+        aof: &aof::AOF,
+        pack: &Pack,
+        master_id: &StreamID,
+        count: u16,
+    ) -> bool {
+        let lp = pack.data.get();
+        let entries = listpack::get_total_bytes(lp) - 1 - format::entries_offset(lp);
+        let length = entries + format::trailer_size(master_id, count);
+        pack.offset.get() as usize + length as usize + 1 <= aof.len()
+    }
+
+    /// Makes a new pack the tail pack and writes it to the AOF.
+    fn place_pack(
+        &mut self,
+        aof: &mut aof::AOF,
+        id: &StreamID,
+        pack: Rc<Pack>,
+        alloc_size: u32,
+    ) -> Result<(), StreamError> {
+        self.segment.packs.insert(&mut id.clone(), Rc::clone(&pack))?;
+        if pack.offset.get() == 0 {
+            self.segment_id = *id;
+        }
+
+        // The old tail pack is all on disk now.
+        if let Some(entry) = self.tail_entry() {
+            self.index.push(entry);
+        }
+        if let Some(tail) = self.tail.take() {
+            Pack::release(tail);
+        }
+
+        let lp = pack.data.get();
+        let num_fields = unsafe { lp.offset(format::entries_offset(lp) as isize) };
+        self.tail_num_fields = listpack::get_u16(num_fields);
+        self.tail_master_id = *id;
+        self.tail_persisted = 0;
+        self.tail_crc = 0;
+        self.tail_alloc = alloc_size;
+        self.persist(aof, &pack);
+        self.tail = Some(pack);
+        Ok(())
+    }
+
+    /// Copies the entries of the tail pack that aren't in the AOF yet,
+    /// followed by a new trailer and the EOF that ends the packs.
+    fn persist(&mut self, aof: &mut aof::AOF, pack: &Pack) {
+        let lp = pack.data.get();
+        let start = format::entries_offset(lp);
+        let entries = listpack::get_total_bytes(lp) - 1 - start;
+
+        let offset = (pack.offset.get() + self.tail_persisted) as usize;
+        let unwritten = unsafe {
+            slice::from_raw_parts(
+                lp.offset((start + self.tail_persisted) as isize),
+                (entries - self.tail_persisted) as usize,
+            )
+        };
+        aof.write_at(offset, unwritten);
+
+        self.tail_crc = format::crc32c(self.tail_crc, unwritten);
+        let mut trailer = Vec::with_capacity(40);
+        let count = pack.count.get();
+        let master_id = self.tail_master_id;
+        let crc = format::put_trailer(&mut trailer, &master_id, count, self.tail_crc);
+        trailer.push(listpack::EOF);
+        aof.write_at(offset + unwritten.len(), &trailer);
+
+        pack.crc.set(crc);
+        self.tail_persisted = entries;
+        pack.length.set(entries + trailer.len() as u32 - 1);
+        pack.raw_length.set(pack.length.get());
+        aof.set_offset((pack.offset.get() + pack.length.get()) as usize);
+    }
+
+    /// Bumps the count of records kept in the master entry of a pack.
+    fn incr_count(&mut self, pack: &Pack) -> Result<(), StreamError> {
+        let count = pack.count.get() + 1;
+        let lp = pack.data.get();
+        let bytes = listpack::get_total_bytes(lp);
+        let first = listpack::first(lp).ok_or(StreamError::BadInput)?;
+
+        let value = Value::Int(count as i64);
+        match unsafe { listpack::replace(ALLOCATOR, lp, first, value) } {
+            Some((lp, _)) => {
+                // The allocation is resized to fit whenever the count takes
+                // up a different number of bytes.
+                if listpack::get_total_bytes(lp) != bytes {
+                    self.tail_alloc = listpack::get_total_bytes(lp);
+                }
+                pack.data.set(lp);
+                pack.count.set(count);
+                Ok(())
+            }
+            None => Err(StreamError::OutOfMemory),
+        }
+    }
+
+    /// Builds a new pack whose master entry takes the fields of the record,
+    /// which then becomes its first entry. Returns the pack along with the
+    /// size of the allocation of its listpack. flags are the flags of the
+    /// record besides SAMEFIELDS.
+    fn new_pack(
+        &self,
+        id: &StreamID,
+        kv: &mut [MemoizedValue],
+        flags: i32,
+    ) -> Result<(Pack, u32), StreamError> {
         /*
          * The master entry "in-memory" layout is composed like in the following example:
          *
@@ -279,55 +1000,99 @@ impl StreamWriter {
          * The "on-disk" layout is a bit different to ensure append-only writes.
          * Header (bytes, items), Count, Deleted are not in the on-disk representation
          * at the same location. Instead, it's encoded to the end of the listpack
-         * between 2 EOF bytes.
-         *
-         * +----------+--------+----------+----------+---------+----------+
-         * | LP-count |   EOF  | ID (ms)  | ID (seq) |   EOF   | LP-first |
-         * +----------+--------+----------+--=-------+---------+----------+
+         * between 2 EOF bytes along with the master ID. See the "format" module.
          *
-         * Between Packs and Index sections
-         * +--------+--------+
-         * |   EOF  |   IDX  |
-         * +--------+--------+
-         *
-         * Index Entry - After all Packs at the end of the file
-         * +----+-----+----------+----------+---------+----------+
-         * | ms | seq |  offset  |  length  |  count  |    EOF   |
-         * +----+-----+----------+--=-------+---------+----------+
+         * +----------+--------+----------+----------+---------+---------+----------+
+         * | LP-count |   EOF  | ID (ms)  | ID (seq) |  count  |   EOF   | LP-first |
+         * +----------+--------+----------+--=-------+---------+---------+----------+
          */
-        // count = 1
-        if !lp.append(1) {
-            return Err(StreamError::OutOfMemory);
+        let num_fields = kv.len() / 2;
+        let count = MemoizedValue::new(Value::Int(1));
+        let deleted = MemoizedValue::new(Value::Int(0));
+        let num_fields_val = MemoizedValue::new(Value::Int(num_fields as i64));
+        let master_end = MemoizedValue::new(Value::Int(0));
+
+        // The first entry always has the same fields as the master entry and
+        // its ID is the master ID.
+        let flags =
+            MemoizedValue::new(Value::Int((STREAM_ITEM_FLAG_SAMEFIELDS | flags) as i64));
+        let id_diff = MemoizedValue::new(Value::Int(0));
+        let lp_count = MemoizedValue::new(Value::Int((3 + num_fields) as i64));
+
+        let mut size = listpack::HDR_USIZE as u32
+            + count.encoded_size
+            + deleted.encoded_size
+            + num_fields_val.encoded_size
+            + master_end.encoded_size
+            + flags.encoded_size
+            + id_diff.encoded_size * 2
+            + lp_count.encoded_size
+            + 1;
+        for value in kv.iter() {
+            size += value.encoded_size;
         }
-        // deleted = 0
-        if !lp.append(0) {
+
+        let alloc_size = cmp::max(size, self.pack_min);
+        let lp = alloc(alloc_size as usize);
+        if lp.is_null() {
             return Err(StreamError::OutOfMemory);
         }
 
-//        lp = append_int(A, lp, 1).unwrap_or_else(|| return Err(StreamError::OutOfMemory)); // count = 1
-//        lp = append_int(A, lp, 0).unwrap(); // deleted = 0
-//        lp = append_int(A, lp, first.len()).unwrap(); // num_fields = 0
+        unsafe {
+            let mut writer = UnsafeAppender::new(lp, listpack::HDR_USIZE as u32 + 1);
+            writer.append(&count);
+            writer.append(&deleted);
+            writer.append(&num_fields_val);
+            for index in 0..num_fields {
+                writer.append(&kv[index * 2]);
+            }
+            writer.append(&master_end);
 
-//        // append master fields.
-//
-//        lp = append_int(A, lp, 0).unwrap(); // count = 1
+            writer.append(&flags);
+            writer.append(&id_diff);
+            writer.append(&id_diff);
+            for index in 0..num_fields {
+                writer.append(&kv[index * 2 + 1]);
+            }
+            writer.append(&lp_count);
+            writer.eof();
+        }
+        listpack::set_total_bytes(lp, size);
+        listpack::set_num_elements(lp, (8 + num_fields * 2) as u16);
 
-        Err(StreamError::OutOfMemory)
+        let pack = Pack::new();
+        pack.count.set(1);
+        pack.data.set(lp);
+        Ok((pack, alloc_size))
     }
 
-    fn begin_pack(
-        &mut self,
-        id_ms: &MemoizedValue,
-        id_seq: &MemoizedValue,
-        kv: &mut [MemoizedValue],
-    ) {}
+    /// Determines whether the record has the same fields, in the same order,
+    /// as the master entry of the pack.
+    fn same_fields(&self, pack: &Pack, kv: &[MemoizedValue]) -> bool {
+        let num_fields = kv.len() / 2;
+        if self.tail_num_fields as usize != num_fields {
+            return false;
+        }
+
+        let lp = pack.data.get();
+        let mut ele = unsafe { lp.offset(format::entries_offset(lp) as isize) };
+        for index in 0..num_fields {
+            ele = listpack::skip(ele);
+            if listpack::get(ele) != kv[index * 2].value {
+                return false;
+            }
+        }
+        true
+    }
 
-    /// Adds a new record only if it fits within the max_size.
+    /// Adds a new record only if it fits within the max_size. flags are the
+    /// flags of the record besides SAMEFIELDS.
     fn append(
         &mut self,
         id: &StreamID,
         kv: &mut [MemoizedValue],
-        pack: &mut Pack,
+        pack: &Pack,
+        flags: i32,
     ) -> Result<(), StreamError> {
         /* Populate the listpack with the new entry. We use the following
          * encoding:
@@ -351,279 +1116,892 @@ impl StreamWriter {
          * in reverse order: we can just start from the end of the listpack, read
          * the entry, and jump back N times to seek the "flags" field to read
          * the stream full entry. */
+        if pack.count.get() == u16::max_value() {
+            return Err(StreamError::Overflow);
+        }
 
-        // Size check.
-        // We calculate the total encoded size to determine overflow of the pack
-        // and/or segment.
-        // NOTE that this includes field names regardless of SAMEFIELDS.
-        // We determine whether the existing Pack can handle the write
-        // and if the AOF can take it immediately.
-        // If Not, then we need to handle this as a blocking command.
-
-        // Create StreamID diff values.
+        // Create StreamID diff values. Like Redis, the seq difference wraps
+        // when the ms part moved on.
         let id_ms = MemoizedValue::new(
-            Value::Int((id.ms - self.tail_master_id.ms) as i64)
+            Value::Int(id.ms.wrapping_sub(self.tail_master_id.ms) as i64)
         );
         let id_seq = MemoizedValue::new(
-            Value::Int((id.seq - self.tail_master_id.seq) as i64)
+            Value::Int(id.seq.wrapping_sub(self.tail_master_id.seq) as i64)
         );
 
-        let mut lp_size = listpack::get_total_bytes(pack.data);
+        let num_fields = kv.len() / 2;
+        let samefields = self.same_fields(pack, kv);
+        let (flags, lp_count, elements) = if samefields {
+            (STREAM_ITEM_FLAG_SAMEFIELDS | flags, 3 + num_fields, 4 + num_fields)
+        } else {
+            (flags, 4 + num_fields * 2, 5 + num_fields * 2)
+        };
+        let flag_val = MemoizedValue::new(Value::Int(flags as i64));
+        let num_fields_val = MemoizedValue::new(Value::Int(num_fields as i64));
+        let lp_count = MemoizedValue::new(Value::Int(lp_count as i64));
 
-        // Are there any key-values to store?
-        if kv.is_empty() {
-            let flag_val = MemoizedValue::new(
-                Value::Int(STREAM_ITEM_FLAG_NONE as i64)
-            );
-            let num_fields_val = MemoizedValue::new(
-                Value::Int(0)
-            );
-            let lp_count = MemoizedValue::new(
-                Value::Int((5) as i64)
-            );
+        // Size check.
+        // We calculate the total encoded size to determine overflow of the
+        // pack. Only values are stored when it has the SAMEFIELDS flag.
+        let mut encoded_size =
+            flag_val.encoded_size +
+                id_ms.encoded_size +
+                id_seq.encoded_size +
+                lp_count.encoded_size;
+        if samefields {
+            for index in 0..num_fields {
+                encoded_size += kv[index * 2 + 1].encoded_size;
+            }
+        } else {
+            encoded_size += num_fields_val.encoded_size;
+            for value in kv.iter() {
+                encoded_size += value.encoded_size;
+            }
+        }
 
-            let mut encoded_size =
-                flag_val.encoded_size +
-                    id_ms.encoded_size +
-                    id_seq.encoded_size +
-                    num_fields_val.encoded_size +
-                    lp_count.encoded_size;
-
-            // Calculate new listpack size.
-            let new_lp_size = encoded_size + lp_size;
-            // Would it overflow?
-            if new_lp_size > self.pack_max {
-                //
-                return Err(StreamError::Overflow);
+        // Calculate new listpack size.
+        let lp_size = listpack::get_total_bytes(pack.data.get());
+        let new_lp_size = encoded_size + lp_size;
+        // Would it overflow?
+        if new_lp_size > self.pack_max {
+            return Err(StreamError::Overflow);
+        }
+        // Maybe increase allocation? Double it each time so that small
+        // records don't end up reallocating on every write.
+        if self.tail_alloc < new_lp_size {
+            let alloc_size = cmp::max(
+                new_lp_size,
+                cmp::min(self.tail_alloc * 2, self.pack_max),
+            );
+            let new_lp = realloc_for_write(pack.data.get(), alloc_size as usize);
+            // OOM?
+            if new_lp.is_null() {
+                return Err(StreamError::OutOfMemory);
             }
-            // Maybe increase allocation?
-            if self.tail_alloc < new_lp_size {
-                let new_lp = realloc_for_write(
-                    pack.data,
-                    new_lp_size as usize,
-                );
-                // OOM?
-                if new_lp.is_null() {
-                    return Err(StreamError::OutOfMemory);
+            self.tail_alloc = alloc_size;
+            pack.data.set(new_lp);
+        }
+
+        // Do actual writes.
+        let lp = pack.data.get();
+        unsafe {
+            let mut writer = UnsafeAppender::new(lp, lp_size);
+            writer.append(&flag_val);
+            writer.append(&id_ms);
+            writer.append(&id_seq);
+
+            if samefields {
+                // Only write values since we have SAMEFIELDS flag.
+                for index in 0..num_fields {
+                    writer.append(&kv[index * 2 + 1]);
                 }
-                // Update tail_alloc
-                self.tail_alloc = new_lp_size;
-                // New allocation?
-                if new_lp != pack.data {
-                    pack.data = new_lp;
+            } else {
+                // Store keys and values.
+                writer.append(&num_fields_val);
+                for value in kv.iter() {
+                    writer.append(value);
                 }
             }
 
-            // Do actual writes.
-            unsafe {
-                let mut writer = UnsafeAppender::new(
-                    pack.data,
-                    lp_size,
-                );
-                writer.append(&flag_val);
-                writer.append(&id_ms);
-                writer.append(&id_seq);
-                writer.append(&num_fields_val);
-                writer.append(&lp_count);
+            writer.append(&lp_count);
+            writer.eof();
+        }
 
-                listpack::set_total_bytes(pack.data, new_lp_size);
-                listpack::incr_num_elements(pack.data);
-            }
+        listpack::set_total_bytes(lp, new_lp_size);
+        let num_elements = listpack::get_num_elements(lp);
+        if num_elements != listpack::HDR_NUMELE_UNKNOWN {
+            listpack::set_num_elements(
+                lp,
+                num_elements.saturating_add(elements as u16),
+            );
+        }
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            // Ensure we have key-values.
-            if kv.len() % 2 != 0 {
-                return Err(StreamError::BadInput);
-            }
-
-            // Calculate the number of fields.
-            let num_fields = kv.len() / 2;
-            let mut flags = STREAM_ITEM_FLAG_NONE;
-
-            // Do the SAMEFIELDS check.
-            if self.tail_num_fields == num_fields as u16 {
-                let mut ele = self.tail_fields;
-                if listpack::get(ele) == kv[0].value {
-                    flags |= STREAM_ITEM_FLAG_SAMEFIELDS;
-                    for index in 1..num_fields {
-                        match listpack::next(pack.data, ele) {
-                            Some(n) => {
-                                ele = n;
-
-                                if listpack::get(ele) != kv[index * 2].value {
-                                    flags = STREAM_ITEM_FLAG_NONE;
-                                    break;
-                                }
-                            }
-                            None => {
-                                flags = STREAM_ITEM_FLAG_NONE;
-                                break;
-                            }
-                        }
-                    }
+    /// Returns the index entry of the tail pack.
+    fn tail_entry(&self) -> Option<format::IndexEntry> {
+        self.tail.as_ref().map(|tail| format::IndexEntry {
+            id: self.tail_master_id,
+            offset: tail.offset.get(),
+            length: tail.length.get(),
+            raw_length: tail.length.get(),
+            count: tail.count.get(),
+            crc: tail.crc.get(),
+        })
+    }
+
+    /// Promotes the next segment to be the tail segment. The one that filled
+    /// up keeps being read from what was written to it until it's sealed,
+    /// which is left to `schedule`.
+    fn roll_segment(&mut self) -> Result<(), StreamError> {
+        // The I/O thread may still be holding onto the AOF.
+        let aof = match self.aof.take() {
+            Some(aof) => match Arc::try_unwrap(aof) {
+                Ok(aof) => aof.into_inner(),
+                Err(aof) => {
+                    self.aof = Some(aof);
+                    return Err(StreamError::WouldBlock);
                 }
+            },
+            None => return Err(StreamError::WouldBlock),
+        };
+
+        // The next segment is only ever created on the I/O thread, so that
+        // preallocating it never holds up the event loop. Until `schedule`
+        // got it there, the write has to wait.
+        let next_path = self.dir.join(format::NEXT_FILE);
+        let next = mem::replace(&mut *self.next.borrow_mut(), NextSegment::Missing);
+        let next = match next {
+            NextSegment::Ready(next) => next,
+            next => {
+                *self.next.borrow_mut() = next;
+                self.aof = Some(Arc::new(Mutex::new(aof)));
+                return Err(StreamError::WouldBlock);
             }
+        };
+        let next_file = match next.try_clone_file() {
+            Ok(file) => file,
+            Err(e) => {
+                *self.next.borrow_mut() = NextSegment::Ready(next);
+                self.aof = Some(Arc::new(Mutex::new(aof)));
+                return Err(StreamError::from(e));
+            }
+        };
 
-            let flag_val = MemoizedValue::new(
-                Value::Int(flags as i64)
-            );
+        if let Some(entry) = self.tail_entry() {
+            self.index.push(entry);
+        }
+        if let Some(tail) = self.tail.take() {
+            Pack::release(tail);
+        }
+        self.tail_num_fields = 0;
+        self.tail_persisted = 0;
+        self.tail_crc = 0;
+        self.tail_alloc = 0;
+
+        // Set the file aside as "{ms}-{seq}.sealing" so that the next segment
+        // can take the place of "0.dat" right away. It only goes by
+        // "{ms}-{seq}.dat" once its index is on disk. Should a crash come
+        // before the I/O thread got to it, it's sealed during recovery.
+        let path = self.dir.join(format::unsealed_file_name(&self.segment_id));
+        fs::rename(self.dir.join(format::TAIL_FILE), &path)?;
+        fs::rename(&next_path, self.dir.join(format::TAIL_FILE))?;
+        self.aof = Some(Arc::new(Mutex::new(next)));
+        self.tail_file = next_file;
+        self.dirty = false;
+
+        let mut segment = mem::replace(&mut self.segment, Segment::new());
+        let mmap = {
+            let file = fs::File::open(&path)?;
+            unsafe { crate::mmap::MmapOptions::new().map(&file)? }
+        };
+        segment.path = path.clone();
+        segment.handle = SegmentHandle::Immutable(Arc::new(Mutex::new(mmap)));
+        self.rolled.push((self.segment_id, segment));
+
+        self.unsealed.push(io::SealTask {
+            segment_id: self.segment_id,
+            aof: Some(aof),
+            path,
+            index: mem::replace(&mut self.index, Vec::new()),
+            compression: self.compression,
+            compression_level: self.compression_level,
+            result: None,
+        });
+        Ok(())
+    }
+}
 
-            let mut encoded_size =
-                flag_val.encoded_size +
-                    id_ms.encoded_size +
-                    id_seq.encoded_size;
+/// Seals a segment so that nothing is written to it anymore. Its file ends
+/// with an index of its packs so that it can be opened without reading them.
+/// With compression, a copy with each of its packs compressed on its own, so
+/// that they can still be read one at a time, takes its place instead.
+///
+/// path is the "{ms}-{seq}.sealing" file the segment was set aside as. It's
+/// only renamed to "{ms}-{seq}.dat" once the index is on disk, so a segment
+/// file by that name always has one. Returns the index as it ended up along
+/// with a view of the file. This blocks so it belongs on the I/O thread.
+pub fn seal_segment(
+    aof: aof::AOF,
+    path: &Path,
+    mut index: Vec<format::IndexEntry>,
+    compression: i32,
+    compression_level: i32,
+) -> Result<(Vec<format::IndexEntry>, crate::mmap::Mmap), StreamError> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let sealed = path.with_extension(format::SEGMENT_EXT);
+    if compression != COMPRESS_ZSTD {
+        let mut tail = Vec::new();
+        format::put_index(&mut tail, aof.offset() as u64 + 1, &index);
+        let mmap = aof.seal(&tail)?;
+        fs::rename(path, &sealed)?;
+        // Syncing the directory makes the rename stick.
+        fs::File::open(dir)?.sync_all()?;
+        return Ok((index, mmap));
+    }
 
-            if flags == STREAM_ITEM_FLAG_SAMEFIELDS {
-                let lp_count = MemoizedValue::new(
-                    Value::Int((4 + num_fields) as i64)
-                );
-                encoded_size += lp_count.encoded_size;
+    let mut buf = Vec::with_capacity(aof.offset() + 1);
+    for entry in index.iter_mut() {
+        let start = entry.offset as usize;
+        let raw = &aof.as_slice()[start..start + entry.raw_length as usize];
+        entry.offset = buf.len() as u32;
+        match format::compress_pack(raw, compression_level)? {
+            Some(compressed) => buf.extend_from_slice(&compressed),
+            None => buf.extend_from_slice(raw),
+        }
+        entry.length = buf.len() as u32 - entry.offset;
+    }
+    buf.push(listpack::EOF);
+    let index_offset = buf.len() as u64;
+    format::put_index(&mut buf, index_offset, &index);
+
+    // The copy only takes the segment's place once it's all on disk.
+    let sealing = dir.join(format::SEALING_FILE);
+    {
+        let mut file = fs::File::create(&sealing)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+    }
+    drop(aof);
+    fs::rename(&sealing, &sealed)?;
+    // Syncing the directory makes the rename stick. The uncompressed file is
+    // only removed after, so recovery can always find one or the other.
+    fs::File::open(dir)?.sync_all()?;
+    fs::remove_file(path)?;
+
+    let file = fs::File::open(&sealed)?;
+    Ok((index, unsafe { crate::mmap::MmapOptions::new().map(&file)? }))
+}
 
-                for index in 0..num_fields {
-                    encoded_size += kv[index * 2 + 1].encoded_size;
-                }
+/// Seals a "{ms}-{seq}.sealing" file that a crash left behind. Its packs are
+/// checked the same way the tail segment's are, anything past the intact ones
+/// is cut off and it's sealed the way it would have been. A file whose index
+/// made it to disk is only renamed, and one whose compressed copy was already
+/// put in place is removed. Returns the path of the sealed segment.
+pub fn seal_leftover(path: &Path, config: &StreamConfig) -> Result<PathBuf, StreamError> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let sealed = path.with_extension(format::SEGMENT_EXT);
+    if sealed.exists() {
+        fs::remove_file(path)?;
+        return Ok(sealed);
+    }
 
-                // Calculate new listpack size.
-                let new_lp_size = encoded_size + lp_size;
-                // Would it overflow?
-                if new_lp_size > self.pack_max {
-                    return Err(StreamError::Overflow);
-                }
-                // Maybe increase allocation?
-                if self.tail_alloc < new_lp_size {
-                    let new_lp = realloc_for_write(
-                        pack.data,
-                        new_lp_size as usize,
-                    );
-                    // OOM?
-                    if new_lp.is_null() {
-                        return Err(StreamError::OutOfMemory);
-                    }
-                    // Update tail_alloc
-                    self.tail_alloc = new_lp_size;
-                    // New allocation?
-                    if new_lp != pack.data {
-                        pack.data = new_lp;
-                    }
-                }
+    let mut aof = aof::AOF::open(path)?;
+    if aof.as_slice().ends_with(format::INDEX_MAGIC) {
+        drop(aof);
+        fs::rename(path, &sealed)?;
+        fs::File::open(dir)?.sync_all()?;
+        return Ok(sealed);
+    }
+    let scan = format::scan(aof.as_slice());
+    if scan.packs.is_empty() {
+        return Err(corrupt_segment(path, 0));
+    }
 
-                // Do actual writes.
-                unsafe {
-                    let mut writer = UnsafeAppender::new(
-                        pack.data,
-                        lp_size,
-                    );
-                    writer.append(&flag_val);
-                    writer.append(&id_ms);
-                    writer.append(&id_seq);
-
-                    // Only write values since we have SAMEFIELDS flag.
-                    for index in 0..num_fields {
-                        writer.append(&kv[index * 2 + 1]);
-                    }
+    aof.write_at(scan.end, &[listpack::EOF]);
+    aof.set_offset(scan.end);
+    let index = scan.packs.iter().map(|checked| checked.entry).collect();
+    seal_segment(aof, path, index, config.compression, config.compression_level)?;
+    Ok(sealed)
+}
 
-                    writer.append(&lp_count);
+#[cfg(test)]
+pub mod tests {
+    extern crate time;
 
-                    listpack::set_total_bytes(pack.data, new_lp_size);
-                    listpack::incr_num_elements(pack.data);
-                }
-            } else {
-                // Store keys and values.
-                let num_fields_val = MemoizedValue::new(
-                    Value::Int(num_fields as i64)
-                );
-                let lp_count = MemoizedValue::new(
-                    Value::Int((5 + kv.len()) as i64)
-                );
-                encoded_size += num_fields_val.encoded_size + lp_count.encoded_size;
-
-                // Calculate encoded size of all field-values.
-                for index in 0..num_fields {
-                    // Add field and value encoded sizes
-                    encoded_size +=
-                        kv[index * 2].encoded_size +
-                            kv[index * 2 + 1].encoded_size;
-                }
+    use super::*;
+    use super::super::fixture::{self, record};
+    use tempdir::TempDir;
 
-                // Calculate new listpack size.
-                let new_lp_size = encoded_size + lp_size;
-                // Would it overflow?
-                if new_lp_size > self.pack_max {
-                    // Change to master record.
+    fn written(writer: &StreamWriter) -> Vec<u8> {
+        writer.aof.as_ref().unwrap().lock().as_slice().to_vec()
+    }
 
-                    // Can we
-                    // Can add another pack to AOF?
+    /// The trailer a pack that ends data should have.
+    fn trailer(data: &[u8], id: &StreamID, count: u16) -> Vec<u8> {
+        let entries = data.len() - format::trailer_size(id, count) as usize;
+        let mut trailer = Vec::new();
+        format::put_trailer(&mut trailer, id, count, format::crc32c(0, &data[..entries]));
+        trailer
+    }
 
-                    // Get next pack.
-                    return Err(StreamError::Overflow);
-                }
-                // Maybe increase allocation?
-                if self.tail_alloc < new_lp_size {
-                    let new_lp = realloc_for_write(
-                        pack.data,
-                        new_lp_size as usize,
-                    );
-                    // OOM?
-                    if new_lp.is_null() {
-                        return Err(StreamError::OutOfMemory);
-                    }
-                    // Update tail_alloc
-                    self.tail_alloc = new_lp_size;
-                    // New allocation?
-                    if new_lp != pack.data {
-                        pack.data = new_lp;
-                    }
-                }
+    fn skip(mut ele: listpack::element, n: usize) -> listpack::element {
+        for _ in 0..n {
+            ele = listpack::skip(ele);
+        }
+        ele
+    }
 
-                // Do actual writes.
-                unsafe {
-                    let mut writer = UnsafeAppender::new(
-                        pack.data,
-                        lp_size,
-                    );
-                    writer.append(&flag_val);
-                    writer.append(&id_ms);
-                    writer.append(&id_seq);
-                    writer.append(&num_fields_val);
-
-                    for index in 0..num_fields {
-                        // Add field and value encoded sizes
-                        writer.append(&kv[index * 2]);
-                        writer.append(&kv[index * 2 + 1]);
-                    }
+    #[test]
+    fn it_appends_records_to_the_tail_pack() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(1024, 4096);
+        let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+
+        let id = StreamID { ms: 1, seq: 0 };
+        writer.try_write(Some(id), &mut record(&["a", "1"])).unwrap();
+        let data = written(&writer);
+        assert!(data.ends_with(&trailer(&data, &id, 1)));
+        assert!(format::pack_crc(&data).is_some());
+
+        let id2 = StreamID { ms: 1, seq: 1 };
+        writer.try_write(Some(id2), &mut record(&["a", "2"])).unwrap();
+        let id3 = StreamID { ms: 2, seq: 0 };
+        writer.try_write(Some(id3), &mut record(&["b", "x", "c", "y"])).unwrap();
+        assert_eq!((2, 0), (writer.last_id().ms, writer.last_id().seq));
+
+        let mut data = written(&writer);
+        assert!(data.ends_with(&trailer(&data, &id, 3)));
+        let crc = writer.tail.as_ref().unwrap().crc.get();
+        assert_eq!(Some(crc), format::pack_crc(&data));
+
+        // num-fields, the field and the end of the master entry come first.
+        let start = data.as_mut_ptr();
+        assert!(listpack::get(start) == Value::Int(1));
+
+        // The second record only has its values since the fields match.
+        let second = skip(start, 3 + 5);
+        assert!(listpack::get(second) == Value::Int(STREAM_ITEM_FLAG_SAMEFIELDS as i64));
+        assert!(listpack::get(skip(second, 2)) == Value::Int(1));
+        assert!(listpack::get(skip(second, 4)) == Value::Int(4));
+
+        // The third has fields of its own.
+        let third = skip(second, 5);
+        assert!(listpack::get(third) == Value::Int(STREAM_ITEM_FLAG_NONE as i64));
+        assert!(listpack::get(skip(third, 1)) == Value::Int(1));
+        assert!(listpack::get(skip(third, 3)) == Value::Int(2));
+        assert!(listpack::get(skip(third, 8)) == Value::Int(8));
+        assert_eq!(listpack::EOF, *skip(third, 9));
+
+        // IDs have to keep going up.
+        match writer.try_write(Some(id), &mut record(&["a", "3"])) {
+            Err(StreamError::BadInput) => {}
+            _ => panic!("accepted an ID smaller than the last one"),
+        }
+    }
 
-                    writer.append(&lp_count);
+    #[test]
+    fn it_keeps_generated_ids_going_up_when_the_clock_goes_back() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(1024, 4096);
+        let start = time::at_utc(time::Timespec::new(1_000, 0));
+        let clock = Rc::new(clock::ManualClock::new(start));
+        let mut writer =
+            StreamWriter::open_with_clock(dir.path(), &config, Box::new(clock.clone()))
+                .unwrap();
+
+        let first = writer.try_write(None, &mut record(&["a", "1"])).unwrap();
+        assert_eq!((1_000_000, 0), (first.ms, first.seq));
+
+        // IDs stay ahead of the last one rather than following the clock back.
+        clock.advance(time::Duration::seconds(-1));
+        let second = writer.try_write(None, &mut record(&["a", "2"])).unwrap();
+        assert_eq!((1_000_000, 1), (second.ms, second.seq));
+
+        // They follow it again once it has caught up.
+        clock.advance(time::Duration::milliseconds(1005));
+        let third = writer.try_write(None, &mut record(&["a", "3"])).unwrap();
+        assert_eq!((1_000_005, 0), (third.ms, third.seq));
+    }
 
-                    listpack::set_total_bytes(pack.data, new_lp_size);
-                    listpack::incr_num_elements(pack.data);
-                }
+    #[test]
+    fn it_rolls_over_packs_and_segments() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(128, 512);
+        let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+
+        for seq in 0..40 {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
+        }
+
+        // Segments that filled up are only sealed once they're handed off.
+        let rolled = writer.take_rolled();
+        assert!(!rolled.is_empty());
+        assert!(writer.take_sealed().is_empty());
+        writer.seal_now().unwrap();
+
+        let sealed = writer.take_sealed();
+        assert_eq!(rolled.len(), sealed.len());
+        assert_eq!((1, 0), (sealed[0].0.ms, sealed[0].0.seq));
+        assert!(sealed[0].1.packs.len() > 1);
+        assert!(dir.path().join("1-0.dat").exists());
+        assert!(!dir.path().join("1-0.sealing").exists());
+        assert!(dir.path().join(format::TAIL_FILE).exists());
+
+        // Every sealed segment is named after its first record.
+        let last = &sealed[sealed.len() - 1];
+        let id = writer.segment_id;
+        assert!(last.0 < id);
+        assert!(dir.path().join(format::segment_file_name(&last.0)).exists());
+        assert!(writer.take_sealed().is_empty());
+    }
+
+    #[test]
+    fn it_opens_sealed_segments_from_their_index() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(128, 512);
+        let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+        // The next segment is left to the I/O thread.
+        assert!(!dir.path().join(format::NEXT_FILE).exists());
+
+        for seq in 0..20 {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
+        }
+        // Until it's sealed, the segment that filled up doesn't go by a
+        // name that would have it opened from its index.
+        let path = dir.path().join("1-0.dat");
+        assert!(!path.exists());
+        assert!(dir.path().join("1-0.sealing").exists());
+        writer.seal_now().unwrap();
+        let sealed = writer.take_sealed();
+        assert!(!sealed.is_empty());
+        // The next segment that was created in place of the I/O thread was
+        // promoted.
+        assert!(dir.path().join(format::TAIL_FILE).exists());
+        assert!(!dir.path().join(format::NEXT_FILE).exists());
+
+        assert!(fs::read(&path).unwrap().ends_with(format::INDEX_MAGIC));
+
+        let mut segment = Segment::open(&path).unwrap();
+        assert_eq!(sealed[0].1.packs.len(), segment.packs.len());
+
+        let pack = segment.packs.get(&mut StreamID { ms: 1, seq: 0 }).unwrap();
+        assert_eq!(0, pack.offset.get());
+        assert!(pack.count.get() > 1);
+        assert!(!segment.would_block(&pack));
+
+        // The pack is loaded back in the Redis Streams format.
+        let count = listpack::first(pack.data.get()).unwrap();
+        assert!(listpack::get(count) == Value::Int(pack.count.get() as i64));
+        assert!(listpack::get(listpack::skip(count)) == Value::Int(0));
+        assert!(listpack::get(skip(count, 2)) == Value::Int(1));
+    }
+
+    /// Polls until the I/O thread is done with every task.
+    fn settle(storage: &mut io::StorageService) {
+        for _ in 0..1000 {
+            if storage.poll() == 0 {
+                return;
             }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        panic!("tasks never finished");
+    }
 
-            Err(StreamError::Overflow)
+    #[test]
+    fn it_hands_blocking_work_to_the_io_thread() {
+        let dir = TempDir::new("writer").unwrap();
+        let mut storage = io::StorageService::start(dir.path()).unwrap();
+        let config = fixture::config(128, 512);
+        let stream_dir = dir.path().join("1");
+        fs::create_dir(&stream_dir).unwrap();
+        let mut writer = StreamWriter::open(&stream_dir, &config).unwrap();
+
+        writer.schedule(&mut storage);
+        settle(&mut storage);
+        assert!(stream_dir.join(format::NEXT_FILE).exists());
+        match *writer.next.borrow() {
+            NextSegment::Ready(_) => {}
+            _ => panic!("expected the next segment to be ready"),
+        }
+
+        for seq in 0..20 {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
         }
+
+        // The segment that filled up is read from what was written to it
+        // until it's sealed.
+        let rolled = writer.take_rolled();
+        assert!(!rolled.is_empty());
+        let path = stream_dir.join("1-0.dat");
+        assert!(!path.exists());
+        let (_, ref segment) = rolled[0];
+        let pack = segment.packs.get(&mut StreamID { ms: 1, seq: 0 }).unwrap();
+        assert!(!segment.would_block(&pack));
+        let count = listpack::first(pack.data.get()).unwrap();
+        assert!(listpack::get(count) == Value::Int(pack.count.get() as i64));
+
+        writer.schedule(&mut storage);
+        assert!(writer.syncing.get());
+        settle(&mut storage);
+        assert!(!writer.syncing.get());
+        let sealed = writer.take_sealed();
+        assert_eq!(rolled.len(), sealed.len());
+        assert_eq!(segment.packs.len(), sealed[0].1.packs.len());
+        assert!(fs::read(&path).unwrap().ends_with(format::INDEX_MAGIC));
+
+        // Another one is set aside for when the tail segment fills up again.
+        assert!(stream_dir.join(format::NEXT_FILE).exists());
     }
 
-    pub fn finish_segment(&mut self) {
-        // Write segment index to the end of it's file.
+    #[test]
+    fn it_waits_for_the_next_segment_at_the_end_of_a_segment() {
+        let dir = TempDir::new("writer").unwrap();
+        let mut storage = io::StorageService::start(dir.path()).unwrap();
+        let config = fixture::config(128, 512);
+        let stream_dir = dir.path().join("1");
+        fs::create_dir(&stream_dir).unwrap();
+        let mut writer = StreamWriter::open(&stream_dir, &config).unwrap();
+
+        let mut seq = 0;
+        let last_id = loop {
+            let id = StreamID { ms: 1, seq };
+            let mut kv = record(&["f", "0123456789abcdef"]);
+            match writer.try_write(Some(id), &mut kv) {
+                Ok(_) => seq += 1,
+                Err(StreamError::WouldBlock) => break writer.last_id(),
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        };
+        // Nothing was written and the segment wasn't rolled over.
+        assert_eq!(StreamID { ms: 1, seq: seq - 1 }, last_id);
+        assert!(writer.take_rolled().is_empty());
+        assert!(!stream_dir.join(format::NEXT_FILE).exists());
+
+        // The write goes through once the I/O thread created the segment.
+        writer.schedule(&mut storage);
+        settle(&mut storage);
+        let id = StreamID { ms: 1, seq };
+        let mut kv = record(&["f", "0123456789abcdef"]);
+        assert_eq!(id, writer.try_write(Some(id), &mut kv).unwrap());
+        assert_eq!(1, writer.take_rolled().len());
+    }
 
-        // Rename file to the segment ID in string format "{ms}-{seq}.dat"
-        // Once a file's name is changed it is guaranteed to be complete and correct.
-        // If a crash happens then only the "0.dat" file in each stream needs
-        // to be recovered.
+    #[test]
+    fn it_reads_tail_packs_from_their_own_segment_after_a_roll_over() {
+        let dir = TempDir::new("writer").unwrap();
+        let mut storage = io::StorageService::start(dir.path()).unwrap();
+        let config = fixture::config(128, 512);
+        let stream_dir = dir.path().join("1");
+        fs::create_dir(&stream_dir).unwrap();
+        let mut writer = StreamWriter::open(&stream_dir, &config).unwrap();
+        let mut seq = 0;
+        while writer.packs().len() < 2 {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
+            seq += 1;
+        }
+        assert!(writer.take_rolled().is_empty());
+
+        // The first pack isn't the tail pack, so it can be dropped.
+        let first = writer.packs().seek_entry("^", &StreamID::default());
+        let pack = first.unwrap().1.unwrap();
+        if !pack.data.get().is_null() {
+            crate::alloc::dealloc(pack.data.get());
+            pack.data.set(ptr::null_mut());
+        }
+
+        // The tail segment rolls over before the I/O thread gets to the read.
+        let file = writer.tail_file().unwrap();
+        while writer.take_rolled().is_empty() {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
+            seq += 1;
+        }
+
+        let read = Rc::new(RefCell::new(None));
+        {
+            let read = Rc::clone(&read);
+            let path = writer.tail_path();
+            storage.read_tail(path, file, Rc::clone(&pack), move |result| {
+                *read.borrow_mut() = Some(result);
+            }).unwrap();
+        }
+        settle(&mut storage);
+        assert!(read.borrow_mut().take().unwrap().is_ok());
+        let count = listpack::first(pack.data.get()).unwrap();
+        assert!(listpack::get(count) == Value::Int(pack.count.get() as i64));
     }
 
-    /// After a crash or restart we need to figure out what the state
-    /// of affairs is and fix up any issues.
-    pub fn recover(&mut self) {}
+    #[test]
+    fn it_seals_segments_a_crash_left_unsealed() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(128, 512);
+        let rolled = {
+            let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+            for seq in 0..20 {
+                let id = StreamID { ms: 1, seq };
+                let mut kv = record(&["f", "0123456789abcdef"]);
+                fixture::write(&mut writer, id, &mut kv);
+            }
+            writer.take_rolled()
+        };
 
-    pub fn append_file(&mut self, lp_write: listpack::WriteResult) {}
-}
+        let leftover = dir.path().join("1-0.sealing");
+        assert!(Segment::open(&leftover).is_err());
+        let last_id = Segment::check(&leftover).unwrap();
+
+        let path = seal_leftover(&leftover, &config).unwrap();
+        assert_eq!(dir.path().join("1-0.dat"), path);
+        assert!(!leftover.exists());
+        let segment = Segment::open(&path).unwrap();
+        assert_eq!(Some(last_id), segment.last_id());
+        assert_eq!(rolled[0].1.packs.len(), segment.packs.len());
+
+        // A crash after the index made it to disk only leaves the rename.
+        fs::rename(&path, &leftover).unwrap();
+        let data = fs::read(&leftover).unwrap();
+        assert_eq!(path, seal_leftover(&leftover, &config).unwrap());
+        assert_eq!(data, fs::read(&path).unwrap());
+
+        // So does a crash after a compressed copy was put in place.
+        fs::write(&leftover, b"uncompressed").unwrap();
+        assert_eq!(path, seal_leftover(&leftover, &config).unwrap());
+        assert!(!leftover.exists());
+        assert_eq!(data, fs::read(&path).unwrap());
+    }
 
+    #[test]
+    fn it_recovers_the_tail_segment() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(128, 4096);
+        let (tail_offset, tail_length) = {
+            let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+            for seq in 0..10 {
+                let mut kv = record(&["f", "0123456789abcdef"]);
+                fixture::write(&mut writer, StreamID { ms: 1, seq }, &mut kv);
+            }
+            let tail = writer.tail.as_ref().unwrap();
+            (tail.offset.get(), tail.length.get())
+        };
+        assert!(tail_offset > 0);
+
+        let report = StreamWriter::check(dir.path()).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(10, report.records);
+        assert_eq!(tail_offset + tail_length, report.end);
+
+        // Tear the trailer of the last pack.
+        let path = dir.path().join(format::TAIL_FILE);
+        let mut data = fs::read(&path).unwrap();
+        data[(tail_offset + tail_length - 1) as usize] = 0x01;
+        fs::write(&path, &data).unwrap();
+
+        // A dry run finds it without changing anything.
+        let report = StreamWriter::check(dir.path()).unwrap();
+        assert_eq!(tail_offset, report.end);
+        assert_eq!(tail_length + 1, report.discarded);
+        assert_eq!(data, fs::read(&path).unwrap());
+
+        let (mut writer, report) =
+            StreamWriter::recover(dir.path(), &config, StreamID::default(), &[]).unwrap();
+        assert!(!report.is_clean());
+        assert!(report.records > 0 && report.records < 10);
+        let last_id = writer.last_id();
+        assert_eq!((1, report.records - 1), (last_id.ms, last_id.seq));
+        assert_eq!(listpack::EOF, fs::read(&path).unwrap()[tail_offset as usize]);
+
+        // Writes pick up from the last intact pack.
+        let id = StreamID { ms: 1, seq: 100 };
+        writer.try_write(Some(id), &mut record(&["f", "0123456789abcdef"])).unwrap();
+        drop(writer);
+        let recovered = report.records;
+        let report = StreamWriter::check(dir.path()).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(recovered + 1, report.records);
+    }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
+    #[test]
+    fn it_compresses_packs_when_sealing() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = StreamConfig {
+            compression: COMPRESS_ZSTD,
+            compression_level: 3,
+            ..fixture::config(256, 2048)
+        };
+        let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+        for seq in 0..60 {
+            let mut kv = record(&["f", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"]);
+            fixture::write(&mut writer, StreamID { ms: 1, seq }, &mut kv);
+        }
+        writer.seal_now().unwrap();
+        let mut sealed = writer.take_sealed();
+        assert!(!sealed.is_empty());
+        assert!(!dir.path().join(format::SEALING_FILE).exists());
+        assert!(dir.path().join(format::TAIL_FILE).exists());
+
+        let (_, ref mut segment) = sealed[0];
+        assert!(segment.size_compressed < segment.size);
+        let mut stats = StreamArchiveStats::default();
+        stats.add(segment);
+        assert_eq!(segment.size_compressed, stats.total_size_compressed);
+
+        // Packs are decompressed when they're faulted in.
+        let pack = segment.packs.get(&mut StreamID { ms: 1, seq: 0 }).unwrap();
+        assert!(pack.length.get() < pack.raw_length.get());
+        assert!(pack.data.get().is_null());
+        assert!(!segment.would_block(&pack));
+        let count = listpack::first(pack.data.get()).unwrap();
+        assert!(listpack::get(count) == Value::Int(pack.count.get() as i64));
+
+        // The file can be opened and checked from its index alone.
+        let path = dir.path().join("1-0.dat");
+        let last_id = Segment::check(&path).unwrap();
+        let opened = Segment::open(&path).unwrap();
+        assert_eq!(segment.size, opened.size);
+        assert_eq!(Some(last_id), opened.last_id());
+
+        // A tail segment left behind by a crash right after its compressed
+        // copy was put in place is thrown out during recovery.
+        drop(writer);
+        let data = fs::read(&path).unwrap();
+        let mut tail = Vec::new();
+        for entry in format::read_index(&data).unwrap() {
+            let raw = format::unpack(&data, entry.offset, entry.length, entry.raw_length);
+            tail.extend_from_slice(&raw.unwrap());
+        }
+        tail.push(listpack::EOF);
+        fs::write(dir.path().join(format::TAIL_FILE), &tail).unwrap();
+
+        let (writer, report) =
+            StreamWriter::recover(dir.path(), &config, last_id, &[]).unwrap();
+        assert_eq!(0, report.packs);
+        assert!(writer.last_id() == last_id);
+        assert!(writer.tail.is_none());
+        assert_eq!(data, fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn it_detects_corrupt_packs() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = fixture::config(128, 512);
+        let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+        for seq in 0..20 {
+            let id = StreamID { ms: 1, seq };
+            fixture::write(&mut writer, id, &mut record(&["f", "0123456789abcdef"]));
+        }
+        let tail = writer.tail.as_ref().unwrap().offset.get();
+        writer.seal_now().unwrap();
+        drop(writer);
+
+        // Flip a bit within a value of the first pack of the sealed segment.
+        let path = dir.path().join("1-0.dat");
+        let mut data = fs::read(&path).unwrap();
+        let value = data.iter().position(|b| *b == b'a').unwrap();
+        data[value] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+
+        match Segment::check(&path) {
+            Err(StreamError::Corrupt { ref segment, offset: 0 }) => {
+                assert!(segment.ends_with("1-0.dat"));
+            }
+            _ => panic!("expected the first pack to be corrupt"),
+        }
+        let mut segment = Segment::open(&path).unwrap();
+        let pack = segment.packs.get(&mut StreamID { ms: 1, seq: 0 }).unwrap();
+        assert!(segment.would_block(&pack));
+        assert!(pack.data.get().is_null());
+        let length = pack.length.get();
+        let read = format::read_pack(&path, &data, 0, length, length, pack.crc.get());
+        match read {
+            Err(StreamError::Corrupt { offset: 0, .. }) => {}
+            _ => panic!("expected the pack to be corrupt"),
+        }
+
+        // The same goes for the tail segment, where the pack is cut off.
+        let path = dir.path().join(format::TAIL_FILE);
+        let mut data = fs::read(&path).unwrap();
+        let tail = tail as usize;
+        let value = tail + data[tail..].iter().position(|b| *b == b'a').unwrap();
+        data[value] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+        let report = StreamWriter::check(dir.path()).unwrap();
+        assert_eq!(tail as u32, report.end);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn it_rejects_duplicates_across_recovery() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = StreamConfig {
+            dedupe_window: 4,
+            dedupe_unit: DEDUPE_RECORDS,
+            ..fixture::config(128, 4096)
+        };
+        let keys = ["k0", "k1", "k2", "k3", "k4", "k5", "k6", "k7", "k8", "k9"];
+        let id = |seq| StreamID { ms: 1, seq };
+        {
+            let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+            for (seq, key) in keys.iter().enumerate() {
+                let mut kv = record(&["?", *key, "f", "0123456789abcdef"]);
+                fixture::write(&mut writer, id(seq as u64), &mut kv);
+            }
+            assert!(writer.packs().len() > 1);
+
+            // Records with a dedupe key are flagged as such.
+            let mut data = written(&writer);
+            let flags = (STREAM_ITEM_FLAG_SAMEFIELDS | STREAM_ITEM_FLAG_DEDUPE) as i64;
+            assert!(listpack::get(skip(data.as_mut_ptr(), 4)) == Value::Int(flags));
+
+            // The record isn't written and the ID of the original comes back.
+            match writer.try_write(None, &mut record(&["?", "k9", "f", "v"])) {
+                Err(StreamError::Duplicate(original)) => assert_eq!(id(9), original),
+                _ => panic!("duplicate was written"),
+            }
+            assert_eq!(id(9), writer.last_id());
+
+            // Records without a dedupe key are never duplicates.
+            writer.try_write(Some(id(10)), &mut record(&["f", "v"])).unwrap();
+            writer.try_write(Some(id(11)), &mut record(&["f", "?"])).unwrap();
+
+            // Nor is a record with a dedupe field anywhere but first written.
+            let mut kv = record(&["f", "v", "?", "k0"]);
+            assert!(writer.try_write(Some(id(12)), &mut kv).is_err());
+            assert_eq!(id(11), writer.last_id());
+        }
+
+        let (mut writer, _) =
+            StreamWriter::recover(dir.path(), &config, StreamID::default(), &[]).unwrap();
+
+        // Only the last four records are within the window.
+        match writer.try_write(Some(id(12)), &mut record(&["?", "k9", "f", "v"])) {
+            Err(StreamError::Duplicate(original)) => assert_eq!(id(9), original),
+            _ => panic!("duplicate was written after recovery"),
+        }
+        let mut kv = record(&["?", "k7", "f", "v"]);
+        assert_eq!(id(12), writer.try_write(Some(id(12)), &mut kv).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_duplicates_from_sealed_segments_after_recovery() {
+        let dir = TempDir::new("writer").unwrap();
+        let config = StreamConfig {
+            dedupe_window: 100,
+            dedupe_unit: DEDUPE_RECORDS,
+            ..fixture::config(128, 512)
+        };
+        let id = |seq| StreamID { ms: 1, seq };
+        {
+            let mut writer = StreamWriter::open(dir.path(), &config).unwrap();
+            for seq in 0..20 {
+                let key = format!("k{}", seq);
+                let mut kv = record(&["?", &key, "f", "0123456789abcdef"]);
+                fixture::write(&mut writer, id(seq), &mut kv);
+            }
+            writer.seal_now().unwrap();
+        }
+
+        // The segments are opened the way the module does when it loads.
+        let sealed: Vec<(StreamID, Segment)> = io::segment_files(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|(segment_id, path)| (segment_id, Segment::open(&path).unwrap()))
+            .collect();
+        assert!(!sealed.is_empty());
+        let after = sealed.last().unwrap().1.last_id().unwrap();
+        let (mut writer, _) =
+            StreamWriter::recover(dir.path(), &config, after, &sealed).unwrap();
+
+        // The first record is long sealed but still within the window.
+        match writer.try_write(None, &mut record(&["?", "k0", "f", "v"])) {
+            Err(StreamError::Duplicate(original)) => assert_eq!(id(0), original),
+            _ => panic!("duplicate of a sealed record was written after recovery"),
+        }
+        assert_eq!(20, writer.dedupe.len());
+    }
 
     #[test]
     fn segment() {
@@ -34,9 +34,10 @@ impl HistogramType {
     #[allow(unused_variables)]
     #[no_mangle]
     pub extern "C" fn Histogram_RDBLoad(rdb: *mut redmod::RedisModuleIO,
-                                        encver: libc::c_int) {
+                                        encver: libc::c_int) -> *mut u8 {
 //        log_debug!(self, "Histogram_RDBLoad");
         println!("Histogram_RDBLoad");
+        std::ptr::null_mut()
     }
 
     #[allow(non_snake_case)]
@@ -60,9 +61,7 @@ impl HistogramType {
     #[allow(non_snake_case)]
     #[allow(unused_variables)]
     #[no_mangle]
-    pub extern "C" fn Histogram_MemUsage(rdb: *mut redmod::RedisModuleIO,
-                                         key: *mut redmod::RedisModuleString,
-                                         value: *mut u8) -> libc::size_t {
+    pub extern "C" fn Histogram_MemUsage(value: *const u8) -> libc::size_t {
         println!("Histogram_MemUsage");
         return 0;
     }